@@ -0,0 +1,78 @@
+// Emulated frames per second, to catch a refactor which makes the emulation slower. Each workload runs res/smoke.gb,
+// which keeps the CPU, the GPU and the APU busy, one frame per iteration without the speed limit:
+//
+//   cpu      The CPU alone, on a flat 64KB memory with the rom in it. The I/O registers are plain bytes.
+//   cpu_gpu  The whole machine, without the waveforms and the mixing of the APU.
+//   cpu_gpu_cached
+//            The same with the block cache of the CPU on, as with --cpu-cache.
+//   full     The whole machine with the sound, the samples taken from the APU as the frontend does.
+//
+// Run them with:
+//
+//   $ cargo bench --bench frames
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gameboy::convention::Term;
+use gameboy::cpu::{BlockCache, Cpu};
+use gameboy::link::FRAME_CYCLES;
+use gameboy::memory::Memory;
+use gameboy::motherboard::MotherBoard;
+
+const ROM: &str = "./res/smoke.gb";
+
+struct Flat {
+    data: Vec<u8>,
+}
+
+impl Memory for Flat {
+    fn get(&self, a: u16) -> u8 {
+        self.data[a as usize]
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        self.data[a as usize] = v;
+    }
+}
+
+fn motherboard(output: bool) -> MotherBoard {
+    let mut mbrd = MotherBoard::power_up(ROM).unwrap();
+    mbrd.cpu.throttle = false;
+    mbrd.mmu.apu.output = output;
+    mbrd
+}
+
+fn frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frames");
+    group.throughput(Throughput::Elements(1));
+
+    let mut data = std::fs::read(ROM).unwrap();
+    data.resize(0x10000, 0x00);
+    let mut flat = Flat { data };
+    let mut cpu = Cpu::power_up(Term::GB);
+    group.bench_function("cpu", |b| {
+        b.iter(|| {
+            let mut cycles = 0;
+            while cycles < FRAME_CYCLES {
+                cycles += cpu.next(&mut flat);
+            }
+        })
+    });
+
+    let mut mbrd = motherboard(false);
+    group.bench_function("cpu_gpu", |b| b.iter(|| mbrd.run_to_next_vblank()));
+
+    let mut mbrd = motherboard(false);
+    mbrd.cpu.cpu.cache = Some(BlockCache::power_up());
+    group.bench_function("cpu_gpu_cached", |b| b.iter(|| mbrd.run_to_next_vblank()));
+
+    let mut mbrd = motherboard(true);
+    group.bench_function("full", |b| {
+        b.iter(|| {
+            mbrd.run_to_next_vblank();
+            mbrd.mmu.apu.buffer.drain()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, frames);
+criterion_main!(benches);
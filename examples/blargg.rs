@@ -1,3 +1,65 @@
+// Run Blargg's test roms without a window and tell whether they passed. The roms print their results to the link
+// port as well as to the screen, one byte per transfer with the internal clock: the cable is plugged into a device
+// which keeps the text, and a test is done when the text says Passed or Failed. A test which says neither in TIMEOUT
+// seconds of emulated time failed as well.
+use gameboy::cpu::CLOCK_FREQUENCY;
+use gameboy::link::Message;
+use gameboy::motherboard::MotherBoard;
+use gameboy::serial::SerialDevice;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+const TIMEOUT: u64 = 120;
+
+const TESTS: &[&str] = &["instr_timing/instr_timing.gb", "cpu_instrs/cpu_instrs.gb"];
+
+// The other end of the cable: it keeps what the game sends and answers FFh, as with no cable.
+struct Printer {
+    text: Arc<Mutex<String>>,
+    queue: VecDeque<Message>,
+}
+
+impl SerialDevice for Printer {
+    fn send(&mut self, m: Message) {
+        if let Message::Data(v) = m {
+            self.text.lock().unwrap().push(char::from(v));
+            self.queue.push_back(Message::Reply(0xff));
+        }
+    }
+
+    fn poll(&mut self) -> Option<Message> {
+        self.queue.pop_front()
+    }
+
+    fn wait(&mut self) -> Option<Message> {
+        self.poll()
+    }
+
+    fn budget(&self) -> u32 {
+        0
+    }
+}
+
+// The text the rom printed, and whether it passed.
+fn run(path: &str) -> Result<(String, bool), Box<dyn std::error::Error>> {
+    let mut mbrd = MotherBoard::power_up(path)?;
+    mbrd.cpu.throttle = false;
+    let text = Arc::new(Mutex::new(String::new()));
+    mbrd.mmu.serial.link = Some(Box::new(Printer { text: text.clone(), queue: VecDeque::new() }));
+    let mut cycles: u64 = 0;
+    while cycles < TIMEOUT * u64::from(CLOCK_FREQUENCY) {
+        cycles += u64::from(mbrd.next());
+        let text = text.lock().unwrap();
+        // The last line, e.g. "Failed #2", is complete once it ends.
+        if text.ends_with('\n') && (text.contains("Passed") || text.contains("Failed")) {
+            return Ok((text.clone(), !text.contains("Failed")));
+        }
+    }
+    let mut text = std::mem::take(&mut *text.lock().unwrap());
+    text.push_str("\nTimeout\n");
+    Ok((text, false))
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     if !std::path::Path::new("./res/gb-test-roms").exists() {
         rog::println!("$ git clone --depth=1 https://github.com/retrio/gb-test-roms ./res/gb-test-roms");
@@ -9,21 +71,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .spawn()?
             .wait()?;
     }
-    rog::println!("$ cargo run -- ./res/gb-test-roms/instr_timing/instr_timing.gb");
-    std::process::Command::new("cargo")
-        .arg("run")
-        .arg("--")
-        .arg("./res/gb-test-roms/instr_timing/instr_timing.gb")
-        .spawn()?
-        .wait()?;
-
-    rog::println!("$ cargo run -- ./res/gb-test-roms/cpu_instrs/cpu_instrs.gb");
-    std::process::Command::new("cargo")
-        .arg("run")
-        .arg("--")
-        .arg("./res/gb-test-roms/cpu_instrs/cpu_instrs.gb")
-        .spawn()?
-        .wait()?;
-
+    let mut failed = 0;
+    for test in TESTS {
+        let path = format!("./res/gb-test-roms/{}", test);
+        rog::println!("$ {}", path);
+        let ok = match run(&path) {
+            Ok((text, ok)) => {
+                for line in text.lines().filter(|l| !l.trim().is_empty()) {
+                    rog::println!("  {}", line);
+                }
+                ok
+            }
+            Err(e) => {
+                rog::println!("  {}", e);
+                false
+            }
+        };
+        rog::println!("{:<40} {}", test, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failed += 1;
+        }
+    }
+    if failed != 0 {
+        std::process::exit(1);
+    }
     Ok(())
 }
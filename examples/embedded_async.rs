@@ -0,0 +1,82 @@
+// Drive the emulator from an async runtime, as a GUI application with its own event loop would. A timer ticks at the
+// frame rate of the Game Boy, input arrives on a channel, and the finished frames are sent to the rendering side on
+// another channel. The emulator itself never sleeps: Emulator::run_frame runs one frame as fast as it can and the
+// timer does the pacing.
+//
+// The Emulator is Send, so it moves into a task of its own on a multi-thread runtime, next to the other tasks. Here a
+// scripted player presses Start on the title screen of the built-in game, and the renderer counts the frames that
+// changed.
+use gameboy::gpu::Framebuffer;
+use gameboy::joypad::JoypadKey;
+use gameboy::Emulator;
+use tokio::sync::mpsc;
+use tokio::time;
+
+type Frame = Box<Framebuffer>;
+
+const FRAMES: u32 = 300;
+
+enum Input {
+    Down(JoypadKey),
+    Up(JoypadKey),
+}
+
+async fn player(tx: mpsc::Sender<Input>) {
+    time::sleep(time::Duration::from_secs(2)).await;
+    tx.send(Input::Down(JoypadKey::Start)).await.unwrap();
+    time::sleep(time::Duration::from_millis(100)).await;
+    tx.send(Input::Up(JoypadKey::Start)).await.unwrap();
+}
+
+async fn renderer(mut rx: mpsc::Receiver<Frame>) -> (u32, u32) {
+    let mut frames = 0;
+    let mut changed = 0;
+    let mut last: Option<Frame> = None;
+    while let Some(frame) = rx.recv().await {
+        frames += 1;
+        if last.as_ref() != Some(&frame) {
+            changed += 1;
+        }
+        last = Some(frame);
+    }
+    (frames, changed)
+}
+
+async fn emulate(mut emulator: Emulator, mut input_rx: mpsc::Receiver<Input>, frame_tx: mpsc::Sender<Frame>) -> u32 {
+    // 70224 clock cycles at 4.194304 MHz.
+    let mut interval = time::interval(time::Duration::from_nanos(16_742_706));
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+    let mut n = 0;
+    while n < FRAMES {
+        tokio::select! {
+            _ = interval.tick() => {
+                let frame = Box::new(*emulator.run_frame());
+                // A slow renderer drops frames instead of holding back the emulation.
+                let _ = frame_tx.try_send(frame);
+                n += 1;
+            }
+            Some(input) = input_rx.recv() => match input {
+                Input::Down(k) => emulator.push_input(k, true),
+                Input::Up(k) => emulator.push_input(k, false),
+            },
+        }
+    }
+    n
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main() {
+    let mut emulator = Emulator::power_up("./res/sml.gb").unwrap();
+    emulator.mbrd.mmu.apu.output = false;
+
+    let (input_tx, input_rx) = mpsc::channel(16);
+    let (frame_tx, frame_rx) = mpsc::channel(4);
+    let zero = time::Instant::now();
+    tokio::spawn(player(input_tx));
+    let renderer = tokio::spawn(renderer(frame_rx));
+    // The frame sender is dropped with the task, which ends the renderer.
+    let n = tokio::spawn(emulate(emulator, input_rx, frame_tx)).await.unwrap();
+
+    let (frames, changed) = renderer.await.unwrap();
+    rog::println!("{} frames in {:?}, {} rendered, {} changed", n, zero.elapsed(), frames, changed);
+}
@@ -0,0 +1,101 @@
+// Check the hooks of MotherBoard on res/smoke.gb, whose VBlank handler at 0040h scrolls the background by a pixel every
+// frame: the handler runs once a frame, but for the first few while the rom sets up the LCD with interrupts off, SCX
+// is written once a frame with the next value, every frame is handed to on_frame, and the hooks don't change the
+// emulation, the last frame is the one of the smoke example. A watchpoint on SCX sees the same writes, and the RAM
+// published at the end of each frame is the RAM the bus reads, while a copy kept from earlier stays as it was. The
+// performance statistics count every frame, with the clock cycles of a frame of the LCD and no sleep while unthrottled.
+use gameboy::memory::Memory;
+use gameboy::motherboard::MotherBoard;
+use gameboy::watch::Watchpoint;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const FRAMES: usize = 600;
+const FRAME_HASH: u64 = 0xea00_22e3_25cc_6a55;
+
+// FNV-1a of the pixels.
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3))
+}
+
+fn check(name: &str, ok: bool) -> bool {
+    rog::println!("{:<40} {}", name, if ok { "ok" } else { "FAILED" });
+    ok
+}
+
+fn main() {
+    let mut mbrd = MotherBoard::power_up("./res/smoke.gb").unwrap();
+    mbrd.cpu.throttle = false;
+    let entry = Arc::new(AtomicUsize::new(0));
+    let vblank = Arc::new(AtomicUsize::new(0));
+    let scx = Arc::new(Mutex::new(vec![]));
+    let frames = Arc::new(Mutex::new((0, 0)));
+    let hits = Arc::new(Mutex::new(vec![]));
+    {
+        let entry = entry.clone();
+        mbrd.on_pc(0x0100, move |_| {
+            entry.fetch_add(1, Ordering::Relaxed);
+        });
+        let vblank = vblank.clone();
+        mbrd.on_pc(0x0040, move |cpu| {
+            assert_eq!(cpu.reg.pc, 0x0040);
+            vblank.fetch_add(1, Ordering::Relaxed);
+        });
+        let scx = scx.clone();
+        mbrd.on_mem_write(0xff43..=0xff43, move |a, v| scx.lock().unwrap().push((a, v)));
+        let hits = hits.clone();
+        mbrd.on_watch("ff43".parse::<Watchpoint>().unwrap(), move |hit| hits.lock().unwrap().push(*hit));
+        let frames = frames.clone();
+        mbrd.on_frame(move |data| {
+            let pixels: Vec<u8> = data.iter().flatten().flatten().copied().collect();
+            let mut frames = frames.lock().unwrap();
+            *frames = (frames.0 + 1, hash(&pixels));
+        });
+    }
+    mbrd.publish_memory();
+    mbrd.measure();
+    // The LCD is off for a while at first, without frames.
+    let mut drawn = 0;
+    let mut kept = None;
+    for i in 0..FRAMES {
+        drawn += usize::from(mbrd.run_to_next_vblank());
+        if i == FRAMES / 2 {
+            let peek = mbrd.peek.clone().unwrap();
+            kept = Some((peek.clone(), peek.wram.clone()));
+        }
+    }
+    mbrd.check_and_reset_gpu_updated();
+    let peek = mbrd.peek.clone().unwrap();
+    let (kept, kept_wram) = kept.unwrap();
+
+    let vblank = vblank.load(Ordering::Relaxed);
+    let scx = scx.lock().unwrap();
+    let (frames, last) = *frames.lock().unwrap();
+    let hits = hits.lock().unwrap();
+    let stats = mbrd.stats().unwrap();
+    let parts = stats.cpu + stats.gpu + stats.apu + stats.other;
+    let mut ok = true;
+    ok &= check("on_pc: entry once", entry.load(Ordering::Relaxed) == 1);
+    ok &= check("on_pc: vblank handler every frame", vblank <= drawn && drawn - vblank < 5);
+    ok &= check("on_mem_write: scx once a frame", scx.len() == vblank && scx.iter().all(|&(a, _)| a == 0xff43));
+    ok &= check("on_mem_write: scx values", scx.windows(2).all(|w| w[1].1 == w[0].1.wrapping_add(1)));
+    ok &= check(
+        "on_watch: scx writes",
+        hits.len() == scx.len()
+            && hits.iter().zip(scx.iter()).all(|(h, &(a, v))| h.write && h.addr == a && h.value == v),
+    );
+    ok &=
+        check("peek: ram of the bus", (0xc000..=0xfdff).chain(0xff80..=0xfffe).all(|a| peek.get(a) == mbrd.mmu.get(a)));
+    ok &= check("peek: every frame", peek.frame == frames as u64);
+    ok &= check("peek: kept copy unchanged", kept.frame < peek.frame && kept.wram == kept_wram);
+    ok &= check("stats: every frame", stats.frame == frames as u64);
+    ok &= check(
+        "stats: cycles and time of a frame",
+        stats.cycles.abs_diff(70224) < 32 && stats.sleep.is_zero() && parts <= stats.total,
+    );
+    ok &= check("on_frame: every frame", frames == drawn);
+    ok &= check("on_frame: last frame", last == FRAME_HASH);
+    if !ok {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,42 @@
+// Two infrared ports in the same process, facing each other through a LocalInfrared pair. One side sends a byte a bit
+// at a time with its LED, the other one reads them with its sensor, then they swap. A sensor with reading disabled
+// sees nothing, and the unused bits of RP read 1.
+use gameboy::infrared::{Infrared, LocalInfrared};
+
+fn port(device: LocalInfrared) -> Infrared {
+    let mut r = Infrared::power_up();
+    r.device = Some(Box::new(device));
+    r
+}
+
+// Send a byte, most significant bit first, the receiver reads each bit while the LED holds it.
+fn send(tx: &mut Infrared, rx: &Infrared, b: u8) -> u8 {
+    let mut r = 0x00;
+    for i in (0..8).rev() {
+        tx.set(0xff56, 0xc0 | (b >> i) & 0x01);
+        let v = rx.get(0xff56);
+        assert_eq!(v & 0x3c, 0x3c);
+        r = r << 1 | u8::from(v & 0x02 == 0x00);
+    }
+    tx.set(0xff56, 0xc0);
+    r
+}
+
+fn main() {
+    let (a, b) = LocalInfrared::pair();
+    let mut a = port(a);
+    let mut b = port(b);
+    a.set(0xff56, 0xc0);
+    b.set(0xff56, 0xc0);
+    let data = [0x00, 0xff, 0x5a, 0xa5, 0x3c];
+    for &d in &data {
+        assert_eq!(send(&mut a, &b, d), d);
+        assert_eq!(send(&mut b, &a, d), d);
+    }
+
+    b.set(0xff56, 0x00);
+    a.set(0xff56, 0xc1);
+    assert_eq!(b.get(0xff56), 0x3e);
+    assert_eq!(a.get(0xff56), 0xff);
+    rog::println!("Infrared: {} bytes exchanged in process", data.len() * 2);
+}
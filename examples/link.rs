@@ -0,0 +1,108 @@
+// Simulates a link cable over a slow network: two serial ports are connected through a local proxy which delays
+// every packet, and exchange a sequence of bytes the way games do, the slave loads its next byte and waits on the
+// external clock while the master starts the transfers. Every byte must arrive at the other side in order. A peer
+// sending garbage is dropped, the cable then reads FFh as if unplugged.
+use gameboy::clock::Scheduler;
+use gameboy::link::TcpLink;
+use gameboy::serial::Serial;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::{thread, time};
+
+const DELAY: time::Duration = time::Duration::from_millis(20);
+const COUNT: u8 = 32;
+
+// Forward everything from src to dst, each chunk DELAY late.
+fn forward(mut src: TcpStream, mut dst: TcpStream) {
+    thread::spawn(move || {
+        let mut b = [0x00; 64];
+        loop {
+            let n = match src.read(&mut b) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            thread::sleep(DELAY);
+            if dst.write_all(&b[..n]).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+// Run the serial port for 64 clock cycles.
+fn run(serial: &mut Serial, clock: &mut Scheduler) {
+    clock.next(64, 1);
+    serial.next(clock);
+}
+
+// Run the serial port until the transfer completes, returns the received byte.
+fn transfer(serial: &mut Serial, clock: &mut Scheduler, data: u8, control: u8) -> u8 {
+    serial.set(0xff01, data);
+    serial.set(0xff02, control);
+    while serial.intf.data & 0x08 == 0x00 {
+        run(serial, clock);
+    }
+    serial.intf.data &= !0x08;
+    serial.get(0xff01)
+}
+
+fn main() {
+    // Both listeners are bound before anyone connects, so the connections wait in their backlog.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let slave_addr = listener.local_addr().unwrap();
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+
+    let slave = thread::spawn(move || {
+        let mut clock = Scheduler::power_up();
+        let mut serial = Serial::power_up(&mut clock);
+        serial.link = Some(Box::new(TcpLink::accept(&listener, 2).unwrap()));
+        let mut recv = vec![];
+        for i in 0..COUNT {
+            recv.push(transfer(&mut serial, &mut clock, 0x80 | i, 0x80));
+        }
+        recv
+    });
+
+    thread::spawn(move || {
+        let (a, _) = proxy.accept().unwrap();
+        let b = TcpStream::connect(slave_addr).unwrap();
+        forward(a.try_clone().unwrap(), b.try_clone().unwrap());
+        forward(b, a);
+    });
+
+    let mut clock = Scheduler::power_up();
+    let mut serial = Serial::power_up(&mut clock);
+    serial.link = Some(Box::new(TcpLink::connect(proxy_addr, 2).unwrap()));
+    let mut recv = vec![];
+    for i in 0..COUNT {
+        recv.push(transfer(&mut serial, &mut clock, i, 0x81));
+        // Give the slave some time to prepare the next byte, like a game would.
+        for _ in 0..64 {
+            run(&mut serial, &mut clock);
+        }
+    }
+
+    let slave_recv = slave.join().unwrap();
+    assert_eq!(slave_recv, (0..COUNT).collect::<Vec<u8>>());
+    assert_eq!(recv, (0..COUNT).map(|i| 0x80 | i).collect::<Vec<u8>>());
+
+    // A peer which sends an unknown message after the hello.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let rogue = thread::spawn(move || {
+        let mut s = TcpStream::connect(addr).unwrap();
+        let mut hello = [0x00; 6];
+        s.read_exact(&mut hello).unwrap();
+        s.write_all(&hello).unwrap();
+        s.write_all(&[0x7f, 0x00]).unwrap();
+        // Hold the connection until the other side drops it.
+        let _ = s.read(&mut hello);
+    });
+    let mut clock = Scheduler::power_up();
+    let mut serial = Serial::power_up(&mut clock);
+    serial.link = Some(Box::new(TcpLink::accept(&listener, 0).unwrap()));
+    assert_eq!(transfer(&mut serial, &mut clock, 0x00, 0x81), 0xff);
+    rogue.join().unwrap();
+    rog::println!("Link: {} bytes exchanged with {:?} delay each way", COUNT, DELAY);
+}
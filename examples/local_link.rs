@@ -0,0 +1,73 @@
+// Two serial ports in the same process, connected by a LocalLink. The host steps them in turns the way a program
+// running two emulators side by side would, and they exchange a sequence of bytes: the slave loads its next byte and
+// waits on the external clock while the master starts the transfers. Every byte must arrive at the other side in
+// order.
+use gameboy::clock::Scheduler;
+use gameboy::link::LocalLink;
+use gameboy::serial::Serial;
+
+const COUNT: u8 = 32;
+
+struct Port {
+    serial: Serial,
+    clock: Scheduler,
+}
+
+impl Port {
+    fn power_up(link: LocalLink) -> Self {
+        let mut clock = Scheduler::power_up();
+        let mut serial = Serial::power_up(&mut clock);
+        serial.link = Some(Box::new(link));
+        Self { serial, clock }
+    }
+
+    // Run the serial port for 64 clock cycles.
+    fn run(&mut self) {
+        self.clock.next(64, 1);
+        self.serial.next(&mut self.clock);
+    }
+
+    fn start(&mut self, data: u8, control: u8) {
+        self.serial.set(0xff01, data);
+        self.serial.set(0xff02, control);
+    }
+
+    // The received byte, once the transfer completed.
+    fn done(&mut self) -> Option<u8> {
+        if self.serial.intf.data & 0x08 == 0x00 {
+            return None;
+        }
+        self.serial.intf.data &= !0x08;
+        Some(self.serial.get(0xff01))
+    }
+}
+
+fn main() {
+    let (a, b) = LocalLink::pair(2);
+    let mut master = Port::power_up(a);
+    let mut slave = Port::power_up(b);
+    let mut master_recv = vec![];
+    let mut slave_recv = vec![];
+    for i in 0..COUNT {
+        slave.start(0x80 | i, 0x80);
+        master.start(i, 0x81);
+        let (mut m, mut s) = (None, None);
+        while m.is_none() || s.is_none() {
+            // Each machine runs for a while before the host switches to the other one.
+            for _ in 0..16 {
+                master.run();
+                m = m.or_else(|| master.done());
+            }
+            for _ in 0..16 {
+                slave.run();
+                s = s.or_else(|| slave.done());
+            }
+        }
+        master_recv.push(m.unwrap());
+        slave_recv.push(s.unwrap());
+    }
+
+    assert_eq!(slave_recv, (0..COUNT).collect::<Vec<u8>>());
+    assert_eq!(master_recv, (0..COUNT).map(|i| 0x80 | i).collect::<Vec<u8>>());
+    rog::println!("Link: {} bytes exchanged in process", COUNT);
+}
@@ -0,0 +1,133 @@
+// Run the acceptance tests of the mooneye-gb test suite without a window and report which pass. A test ends on the
+// instruction LD B,B, the software breakpoint of the suite, with the Fibonacci numbers 3, 5, 8, 13, 21 and 34 in B, C,
+// D, E, H and L when it passed. A test which never gets there in TIMEOUT seconds of emulated time failed as well.
+//
+// The suite is built by its author at https://gekkio.fi/files/mooneye-test-suite/, unpack it into
+// ./res/mooneye-test-suite. Other directories or single roms can be given on the command line:
+//
+//   $ cargo run --release --example mooneye -- ./res/mooneye-test-suite/acceptance/timer
+//
+// The name of a test ends with the models it is meant for, e.g. di_timing-GS or boot_regs-dmgABC. The tests for
+// models other than the DMG are skipped.
+use gameboy::cpu::CLOCK_FREQUENCY;
+use gameboy::motherboard::MotherBoard;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const TIMEOUT: u64 = 20;
+
+// LD B,B.
+const BREAKPOINT: u8 = 0x40;
+
+#[derive(PartialEq)]
+enum Outcome {
+    Passed,
+    Failed,
+    Timeout,
+}
+
+// Whether a test runs on the DMG, from the models at the end of its name. A name without models runs on all.
+fn for_dmg(path: &Path) -> bool {
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let Some((_, models)) = stem.rsplit_once('-') else {
+        return true;
+    };
+    if models.chars().all(|c| c.is_ascii_uppercase()) {
+        // G is the DMG family: DMG, MGB. S is the SGB family, C the CGB, A the AGB.
+        return models.contains('G');
+    }
+    models.starts_with("dmgABC")
+}
+
+fn roms(path: &Path, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if path.is_file() {
+        out.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)?.map(|e| e.map(|e| e.path())).collect::<Result<_, _>>()?;
+    entries.sort();
+    for p in entries {
+        if p.is_dir() {
+            roms(&p, out)?;
+        } else if p.extension().is_some_and(|e| e == "gb") {
+            out.push(p);
+        }
+    }
+    Ok(())
+}
+
+fn run(path: &Path) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let mut mbrd = MotherBoard::power_up(path)?;
+    mbrd.cpu.throttle = false;
+    // The hook sees every instruction before it is executed, and tells when the breakpoint is next.
+    let hit = Arc::new(AtomicBool::new(false));
+    {
+        let hit = hit.clone();
+        mbrd.cpu.cpu.hook = Some(Box::new(move |cpu, mem| {
+            if mem.get(cpu.reg.pc) == BREAKPOINT {
+                hit.store(true, Ordering::Relaxed);
+            }
+        }));
+    }
+    let mut cycles: u64 = 0;
+    while cycles < TIMEOUT * u64::from(CLOCK_FREQUENCY) {
+        cycles += u64::from(mbrd.next());
+        if hit.load(Ordering::Relaxed) {
+            let r = &mbrd.cpu.cpu.reg;
+            let fibonacci = [r.b, r.c, r.d, r.e, r.h, r.l] == [3, 5, 8, 13, 21, 34];
+            return Ok(if fibonacci { Outcome::Passed } else { Outcome::Failed });
+        }
+    }
+    Ok(Outcome::Timeout)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() {
+        let dir = "./res/mooneye-test-suite/acceptance";
+        if !Path::new(dir).exists() {
+            rog::println!("Download the mooneye-gb test suite from https://gekkio.fi/files/mooneye-test-suite/ and");
+            rog::println!("unpack it into ./res/mooneye-test-suite");
+            std::process::exit(1);
+        }
+        args.push(String::from(dir));
+    }
+    let mut paths = vec![];
+    for a in &args {
+        roms(Path::new(a), &mut paths)?;
+    }
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in &paths {
+        let name = path.display();
+        if !for_dmg(path) {
+            skipped += 1;
+            continue;
+        }
+        let outcome = match run(path) {
+            Ok(ok) => ok,
+            Err(e) => {
+                rog::println!("{:<72} {}", name, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let result = match outcome {
+            Outcome::Passed => "ok",
+            Outcome::Failed => "FAILED",
+            Outcome::Timeout => "TIMEOUT",
+        };
+        rog::println!("{:<72} {}", name, result);
+        if outcome == Outcome::Passed {
+            passed += 1;
+        } else {
+            failed += 1;
+        }
+    }
+    rog::println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+    if failed != 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+// Run res/raster.gb, which changes SCX and SCY on every scanline, and compare the screen to the picture it must
+// produce. See res/raster.asm for what the rom does.
+//
+// Then check when the registers are read, line by line, on res/smoke.gb: the rom only touches the scroll in VBlank, so
+// the registers are written from here at chosen points of the frame.
+use gameboy::gpu::{SCREEN_H, SCREEN_W};
+use gameboy::memory::Memory;
+use gameboy::motherboard::MotherBoard;
+
+// Whether the pixel at (x, y) of the screen is black. The tile is made of 4x4 checkers, black in the top left.
+fn expect(x: usize, y: usize) -> bool {
+    let (scx, scy) = if y == 0 { (144, 72) } else { (y, y / 2) };
+    let bx = (x + scx) % 8;
+    let by = (y + scy) % 8;
+    (bx < 4) == (by < 4)
+}
+
+// Run until the LCD is on the given line and mode.
+fn run_to(mbrd: &mut MotherBoard, ly: u8, mode: u8) {
+    loop {
+        let (l, m) = {
+            let mmu = &mbrd.mmu;
+            (mmu.get(0xff44), mmu.get(0xff41) & 0x03)
+        };
+        if l == ly && m == mode {
+            return;
+        }
+        mbrd.next();
+    }
+}
+
+fn set(mbrd: &mut MotherBoard, a: u16, v: u8) {
+    mbrd.mmu.set(a, v);
+}
+
+fn main() {
+    let mut mbrd = MotherBoard::power_up("./res/raster.gb").unwrap();
+    mbrd.cpu.throttle = false;
+    // The first frames are drawn before the rom set up the background, the next one starts with the LCD turned on.
+    for _ in 0..5 {
+        mbrd.run_to_next_vblank();
+    }
+    let mmu = &mbrd.mmu;
+    let mut wrong = vec![];
+    for y in 0..SCREEN_H {
+        let (scx, scy) = if y == 0 { (144, 72) } else { (y as u8, y as u8 / 2) };
+        let line = mmu.gpu.scanlines[y];
+        let ok = (0..SCREEN_W).all(|x| (mmu.gpu.data[y][x][0] == 0x00) == expect(x, y));
+        if !ok || line.scx != scx || line.scy != scy {
+            wrong.push(y);
+        }
+    }
+    if !wrong.is_empty() {
+        rog::println!("Raster: wrong scroll on lines {:?}", wrong);
+        std::process::exit(1);
+    }
+    rog::println!("Raster: {} lines scrolled as expected", SCREEN_H);
+
+    let mut failed = vec![];
+    let mut check = |name: &str, ok: bool| {
+        rog::println!("{:<40} {}", name, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failed.push(name.to_string());
+        }
+    };
+    let mut mbrd = MotherBoard::power_up("./res/smoke.gb").unwrap();
+    mbrd.cpu.throttle = false;
+    for _ in 0..4 {
+        mbrd.run_to_next_vblank();
+    }
+
+    // A write in mode 3 is for the next line, a write in H-Blank too.
+    run_to(&mut mbrd, 20, 3);
+    set(&mut mbrd, 0xff43, 0x55);
+    run_to(&mut mbrd, 30, 0);
+    set(&mut mbrd, 0xff43, 0x66);
+    run_to(&mut mbrd, 32, 0);
+    let lines = mbrd.mmu.gpu.scanlines;
+    check("scx: mode 3 write waits a line", lines[20].scx != 0x55 && lines[21].scx == 0x55);
+    check("scx: h-blank write for the next line", lines[30].scx == 0x55 && lines[31].scx == 0x66);
+
+    // The window turns on at WY, goes off for ten lines and resumes at the row where it stopped.
+    run_to(&mut mbrd, 0, 2);
+    set(&mut mbrd, 0xff4a, 10);
+    set(&mut mbrd, 0xff4b, 7);
+    set(&mut mbrd, 0xff40, 0xb1);
+    run_to(&mut mbrd, 29, 0);
+    set(&mut mbrd, 0xff40, 0x91);
+    run_to(&mut mbrd, 39, 0);
+    set(&mut mbrd, 0xff40, 0xb1);
+    run_to(&mut mbrd, 50, 0);
+    let lines = mbrd.mmu.gpu.scanlines;
+    check("window: off above wy", lines[9].window.is_none());
+    check("window: first row at wy", lines[10].window == Some(0));
+    check("window: rows drawn", lines[29].window == Some(19));
+    check("window: hidden lines", lines[30..40].iter().all(|l| l.window.is_none()));
+    check("window: resumes where it stopped", lines[40].window == Some(20) && lines[50].window == Some(30));
+
+    // WX below 7 starts the window left of the screen.
+    run_to(&mut mbrd, 0, 2);
+    set(&mut mbrd, 0xff4b, 3);
+    run_to(&mut mbrd, 20, 0);
+    let lines = mbrd.mmu.gpu.scanlines;
+    check("window: wx below 7 shown", lines[10].wx == 3 && lines[10].window == Some(0));
+
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,67 @@
+// A quick end to end check, without test suites to download: boot res/smoke.gb, a small demo rom that scrolls a
+// pattern and plays a tune, run 600 frames without a window or speed limit, and compare the last frame and the sound
+// with the ones of a known good run. See res/smoke.asm for what the rom does. The machine runs on a worker thread, as a
+// frontend which keeps its window responsive would do, which also checks that it can be sent to one.
+//
+// A change to the emulation may change the picture or the sound on purpose. Check the new ones, e.g. with a screenshot
+// and --wav-out, then update the expected values below.
+use gameboy::apu::SampleSink;
+use gameboy::motherboard::MotherBoard;
+use std::sync::{Arc, Mutex};
+
+const FRAMES: usize = 600;
+const FRAME_HASH: u64 = 0xea00_22e3_25cc_6a55;
+// About 10 seconds at 48000 Hz. Each note fades out before the next one, the rest is silence.
+const SAMPLES: usize = 481125;
+const SOUNDING: usize = 354818;
+
+// Count the samples, and those which aren't silent.
+struct Counter {
+    n: Arc<Mutex<(usize, usize)>>,
+}
+
+impl SampleSink for Counter {
+    fn push(&mut self, samples: &[(f32, f32)]) -> std::io::Result<()> {
+        let mut n = self.n.lock().unwrap();
+        n.0 += samples.len();
+        n.1 += samples.iter().filter(|s| s.0 != 0.0 || s.1 != 0.0).count();
+        Ok(())
+    }
+}
+
+// FNV-1a of the pixels.
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3))
+}
+
+fn main() {
+    let mut mbrd = MotherBoard::power_up("./res/smoke.gb").unwrap();
+    mbrd.cpu.throttle = false;
+    let n = Arc::new(Mutex::new((0, 0)));
+    mbrd.mmu.apu.sinks.push(Box::new(Counter { n: n.clone() }));
+    let mbrd = std::thread::spawn(move || {
+        for _ in 0..FRAMES {
+            mbrd.run_to_next_vblank();
+        }
+        mbrd
+    })
+    .join()
+    .unwrap();
+    let mmu = &mbrd.mmu;
+    let pixels: Vec<u8> = mmu.gpu.data.iter().flatten().flatten().copied().collect();
+    let frame = hash(&pixels);
+    let (samples, sounding) = *n.lock().unwrap();
+    rog::println!("Smoke: frame {:016x}, {} samples, {} sounding", frame, samples, sounding);
+    let mut failed = false;
+    if frame != FRAME_HASH {
+        rog::println!("Smoke: frame {:016x} expected", FRAME_HASH);
+        failed = true;
+    }
+    if samples != SAMPLES || sounding != SOUNDING {
+        rog::println!("Smoke: {} samples, {} sounding expected", SAMPLES, SOUNDING);
+        failed = true;
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,84 @@
+// Run res/sprites.gb, a repro of a sprite swap: a sprite changes its tile once a second, on top of sprites that
+// overlap each other and a sprite behind the background. Check that the swap happens every 60 frames, about 1000 ms
+// of emulated time, and that every sprite is drawn with the right priority in each frame. See res/sprites.asm for
+// what the rom does.
+use gameboy::cpu::CLOCK_FREQUENCY;
+use gameboy::gpu::{Framebuffer, GrayShades};
+use gameboy::memory::Memory;
+use gameboy::motherboard::MotherBoard;
+
+const LIGHT: u8 = GrayShades::Light as u8;
+const BLACK: u8 = GrayShades::Black as u8;
+const WHITE: u8 = GrayShades::White as u8;
+
+// The shade expected at each x of a span of a line, with the tile of the swapping sprite.
+fn expect(swap: u8) -> Vec<(usize, std::ops::Range<usize>, u8)> {
+    vec![
+        // The swapping sprite.
+        (8, 0..8, WHITE),
+        (8, 8..16, swap),
+        // Where two sprites overlap, the one with the smaller X wins on the DMG, whatever their order in OAM.
+        (8, 36..44, BLACK),
+        (8, 44..48, LIGHT),
+        (8, 56..64, BLACK),
+        (8, 64..68, LIGHT),
+        // Sprite 1 is behind the background: it shows over color 0 and is hidden by color 3. Sprite 4, at the same X
+        // but later in OAM, shows through neither.
+        (16, 12..16, LIGHT),
+        (16, 16..24, BLACK),
+        (16, 24..32, WHITE),
+        // 11 sprites on the line: the first is off the left edge but takes one of the ten places, the last is dropped.
+        (32, 0..72, BLACK),
+        (32, 72..80, WHITE),
+    ]
+}
+
+fn check(frame: &Framebuffer, swap: u8) -> bool {
+    expect(swap).into_iter().all(|(y, xs, shade)| xs.into_iter().all(|x| frame[y][x][0] == shade))
+}
+
+fn main() {
+    let mut mbrd = MotherBoard::power_up("./res/sprites.gb").unwrap();
+    mbrd.cpu.throttle = false;
+    let mut failed = vec![];
+    // The tile shown and the time of the last swap, None until the first swap.
+    let mut shown: Option<u8> = None;
+    let mut last: Option<u64> = None;
+    let mut swaps = vec![];
+    for frame in 0..400 {
+        mbrd.run_to_next_vblank();
+        let mmu = &mbrd.mmu;
+        // The rom sets up the screen with the LCD off, and the first frame after it is turned on is not a whole one.
+        let shade = mmu.gpu.data[8][8][0];
+        if shown.is_none() && (mmu.get(0xff40) & 0x80 == 0x00 || shade != BLACK) {
+            continue;
+        }
+        if !check(&mmu.gpu.data, shade) || (shade != LIGHT && shade != BLACK) {
+            failed.push(format!("frame {}: wrong pixels", frame));
+        }
+        let now = mmu.clock.now;
+        if shown.is_some_and(|s| s != shade) {
+            // The time from the LCD on to the first swap is not a whole second.
+            if let Some(at) = last {
+                swaps.push((now - at) * 1000 / u64::from(CLOCK_FREQUENCY));
+            }
+            last = Some(now);
+        }
+        shown = Some(shade);
+    }
+    for &ms in &swaps {
+        if !(995..=1010).contains(&ms) {
+            failed.push(format!("swap after {} ms", ms));
+        }
+    }
+    if swaps.len() < 5 {
+        failed.push(format!("{} swaps in 400 frames", swaps.len()));
+    }
+    rog::println!("Sprites: swapped every {:?} ms", swaps);
+    if !failed.is_empty() {
+        for f in &failed {
+            rog::println!("Sprites: {}", f);
+        }
+        std::process::exit(1);
+    }
+}
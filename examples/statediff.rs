@@ -0,0 +1,174 @@
+// Compare two save states of the same game and print what differs, e.g. to find where two runs of a movie or the two
+// sides of a link session went apart:
+//
+//   cargo run --example statediff -- game.gb a.state b.state
+//
+// Both states are loaded into a machine running the rom. The CPU registers, the IO registers and the memory as the
+// CPU sees it, with the banks mapped at that time, are compared first. The state of the components that isn't
+// visible on the bus, e.g. the unmapped banks or the internals of the APU, is then compared byte by byte in each
+// section of the files. The exit status is 1 when the states differ.
+use gameboy::memory::Memory;
+use gameboy::motherboard::MotherBoard;
+use gameboy::state;
+
+// The names of the IO registers, as in the Pan Docs.
+const IO: &[(u16, &str)] = &[
+    (0xff00, "P1"),
+    (0xff01, "SB"),
+    (0xff02, "SC"),
+    (0xff04, "DIV"),
+    (0xff05, "TIMA"),
+    (0xff06, "TMA"),
+    (0xff07, "TAC"),
+    (0xff0f, "IF"),
+    (0xff10, "NR10"),
+    (0xff11, "NR11"),
+    (0xff12, "NR12"),
+    (0xff13, "NR13"),
+    (0xff14, "NR14"),
+    (0xff16, "NR21"),
+    (0xff17, "NR22"),
+    (0xff18, "NR23"),
+    (0xff19, "NR24"),
+    (0xff1a, "NR30"),
+    (0xff1b, "NR31"),
+    (0xff1c, "NR32"),
+    (0xff1d, "NR33"),
+    (0xff1e, "NR34"),
+    (0xff20, "NR41"),
+    (0xff21, "NR42"),
+    (0xff22, "NR43"),
+    (0xff23, "NR44"),
+    (0xff24, "NR50"),
+    (0xff25, "NR51"),
+    (0xff26, "NR52"),
+    (0xff40, "LCDC"),
+    (0xff41, "STAT"),
+    (0xff42, "SCY"),
+    (0xff43, "SCX"),
+    (0xff44, "LY"),
+    (0xff45, "LYC"),
+    (0xff46, "DMA"),
+    (0xff47, "BGP"),
+    (0xff48, "OBP0"),
+    (0xff49, "OBP1"),
+    (0xff4a, "WY"),
+    (0xff4b, "WX"),
+    (0xff4d, "KEY1"),
+    (0xff4f, "VBK"),
+    (0xff51, "HDMA1"),
+    (0xff52, "HDMA2"),
+    (0xff53, "HDMA3"),
+    (0xff54, "HDMA4"),
+    (0xff55, "HDMA5"),
+    (0xff68, "BCPS"),
+    (0xff69, "BCPD"),
+    (0xff6a, "OCPS"),
+    (0xff6b, "OCPD"),
+    (0xff70, "SVBK"),
+    (0xffff, "IE"),
+];
+
+// Differences shown per memory region or section, the rest is only counted.
+const SHOW: usize = 16;
+
+fn load(rom: &str, path: &str) -> (MotherBoard, Vec<u8>) {
+    let mut mbrd = MotherBoard::power_up(rom).unwrap_or_else(|e| panic!("Rom not loaded: {}", e));
+    let data = std::fs::read(path).unwrap_or_else(|e| panic!("State not read: {}: {}", path, e));
+    mbrd.restore(&data).unwrap_or_else(|e| panic!("State not loaded: {}: {}", path, e));
+    (mbrd, data)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 4 {
+        rog::println!("Usage: statediff <rom> <state> <state>");
+        std::process::exit(2);
+    }
+    let (a, data_a) = load(&args[1], &args[2]);
+    let (b, data_b) = load(&args[1], &args[3]);
+    let mut n = 0;
+
+    let (ra, rb) = (&a.cpu.cpu.reg, &b.cpu.cpu.reg);
+    let regs = [
+        ("A", ra.a as u16, rb.a as u16),
+        ("F", ra.f as u16, rb.f as u16),
+        ("B", ra.b as u16, rb.b as u16),
+        ("C", ra.c as u16, rb.c as u16),
+        ("D", ra.d as u16, rb.d as u16),
+        ("E", ra.e as u16, rb.e as u16),
+        ("H", ra.h as u16, rb.h as u16),
+        ("L", ra.l as u16, rb.l as u16),
+        ("SP", ra.sp, rb.sp),
+        ("PC", ra.pc, rb.pc),
+        ("IME", a.cpu.cpu.ei as u16, b.cpu.cpu.ei as u16),
+        ("HALT", a.cpu.cpu.halted as u16, b.cpu.cpu.halted as u16),
+        ("STOP", a.cpu.cpu.stopped as u16, b.cpu.cpu.stopped as u16),
+    ];
+    for (name, x, y) in regs {
+        if x != y {
+            rog::println!("CPU {:<5} {:04x} -> {:04x}", name, x, y);
+            n += 1;
+        }
+    }
+
+    let (ma, mb) = (&a.mmu, &b.mmu);
+    for &(addr, name) in IO {
+        let (x, y) = (ma.get(addr), mb.get(addr));
+        if x != y {
+            rog::println!("IO  {:<5} {:02x} -> {:02x}", name, x, y);
+            n += 1;
+        }
+    }
+
+    // The ROM is the same on both sides, the echo RAM repeats the WRAM and the IO registers are compared above.
+    let skip = ["ROM0", "ROMX", "ECHO0", "ECHOX", "UNUSED", "BOOT"];
+    let (regions_a, regions_b) = (ma.memory_map(), mb.memory_map());
+    for (ra, rb) in regions_a.iter().zip(regions_b.iter()) {
+        let io = (0xff00..0xff80).contains(&ra.start) || ra.start == 0xffff;
+        if io || skip.contains(&ra.name) {
+            continue;
+        }
+        let bank = |b: Option<usize>| b.map_or(String::new(), |b| format!(" bank {}", b));
+        if ra.bank != rb.bank {
+            rog::println!("{} mapped{} -> mapped{}", ra.name, bank(ra.bank), bank(rb.bank));
+            n += 1;
+            continue;
+        }
+        let diff: Vec<u16> = (ra.start..=ra.end).filter(|&i| ma.get(i) != mb.get(i)).collect();
+        if diff.is_empty() {
+            continue;
+        }
+        rog::println!("{}{}: {} bytes differ", ra.name, bank(ra.bank), diff.len());
+        for &i in diff.iter().take(SHOW) {
+            rog::println!("  {:04x}   {:02x} -> {:02x}", i, ma.get(i), mb.get(i));
+        }
+        n += diff.len();
+    }
+
+    // Everything else, including what is already listed above.
+    let (sa, sb) = (state::decode(&data_a).unwrap(), state::decode(&data_b).unwrap());
+    for ((tag, x), (_, y)) in sa.iter().zip(sb.iter()) {
+        let tag = String::from_utf8_lossy(tag);
+        if x.len() != y.len() {
+            rog::println!("Section {}: {} bytes -> {} bytes", tag, x.len(), y.len());
+            n += 1;
+            continue;
+        }
+        let diff: Vec<usize> = (0..x.len()).filter(|&i| x[i] != y[i]).collect();
+        if diff.is_empty() {
+            continue;
+        }
+        rog::println!("Section {}: {} bytes differ", tag.trim(), diff.len());
+        for &i in diff.iter().take(SHOW) {
+            rog::println!("  +{:05x} {:02x} -> {:02x}", i, x[i], y[i]);
+        }
+        n += diff.len();
+    }
+
+    // Like diff, for scripts.
+    if n != 0 {
+        std::process::exit(1);
+    }
+    rog::println!("No differences");
+}
@@ -0,0 +1,29 @@
+// Random code run by the CPU. The input is the program, put at 0x0100 of a 32KB rom without a mapper, so the CPU runs
+// it right after the boot ROM would hand over. The program writes to the I/O registers and jumps around as it likes,
+// the emulator must not panic on any of it.
+#![no_main]
+
+use gameboy::cartridge::{self, NINTENDO_LOGO};
+use gameboy::motherboard::MotherBoard;
+use libfuzzer_sys::fuzz_target;
+
+// Instructions run for each input.
+const STEPS: usize = 20_000;
+
+fuzz_target!(|data: &[u8]| {
+    let mut rom = vec![0x00; 0x8000];
+    let n = data.len().min(0x8000 - 0x0150);
+    rom[0x0150..0x0150 + n].copy_from_slice(&data[..n]);
+    // Jump over the header to the program. The first byte picks a DMG or a CGB game.
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x0143] = if data.first().is_some_and(|b| b & 0x01 != 0) { 0x80 } else { 0x00 };
+    rom[0x0147] = 0x00;
+    rom[0x0148] = 0x00;
+    rom[0x014d] = cartridge::header_checksum(&rom);
+    let mut mbrd = MotherBoard::power_up_cartridge(cartridge::power_up_rom(rom).unwrap());
+    mbrd.cpu.throttle = false;
+    for _ in 0..STEPS {
+        mbrd.next();
+    }
+});
@@ -0,0 +1,40 @@
+// Random reads and writes on the bus, with the clock running between them, against each kind of mapper. The first 3
+// bytes of the input are the cartridge type, rom size and ram size of the header, the rest are writes of 4 bytes:
+// the address, the value and the clock cycles to run after it. Every address written is read back.
+#![no_main]
+
+use gameboy::cartridge::{self, NINTENDO_LOGO};
+use gameboy::memory::Memory;
+use gameboy::mmunit::Mmunit;
+use libfuzzer_sys::fuzz_target;
+
+const TYPES: [u8; 24] = [
+    0x00, 0x01, 0x02, 0x03, 0x05, 0x06, 0x08, 0x09, 0x0b, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+    0x1e, 0x20, 0x22, 0xfe, 0xff,
+];
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    // Up to 256KB of rom, bigger ones only make each run slower.
+    let size = usize::from(data[1] % 4);
+    let mut rom = vec![0x00; 0x8000 << size];
+    rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x0143] = data[0] & 0x80;
+    rom[0x0147] = TYPES[usize::from(data[0] & 0x7f) % TYPES.len()];
+    rom[0x0148] = size as u8;
+    rom[0x0149] = data[2] % 6;
+    rom[0x014d] = cartridge::header_checksum(&rom);
+    // Not every type is supported, those are not what this target is after.
+    let Ok(cart) = cartridge::power_up_rom(rom) else {
+        return;
+    };
+    let mut mmu = Mmunit::power_up_cartridge(cart);
+    for w in data[3..].chunks_exact(4) {
+        let a = u16::from_le_bytes([w[0], w[1]]);
+        mmu.set(a, w[2]);
+        mmu.get(a);
+        mmu.next(u32::from(w[3]) * 4);
+    }
+});
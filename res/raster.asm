@@ -0,0 +1,89 @@
+; Source of raster.gb, a raster effect test: SCX and SCY are changed on every scanline. The background is a tile of
+; 4x4 checkers repeated over the whole map, so the scroll of each line can be read back from the screen. Line y is
+; drawn with SCX = y and SCY = y / 2, the values written during the HBlank of the line above. Line 0 is drawn with
+; the values written at the end of line 143, which give the same picture.
+;
+; Build with rgbds:
+;   rgbasm -o raster.o raster.asm
+;   rgblink -o raster.gb raster.o
+;   rgbfix -v -p 0 -t RASTER raster.gb
+;
+; Checked by examples/raster.rs.
+
+rLCDC EQU $ff40
+rSTAT EQU $ff41
+rSCY  EQU $ff42
+rSCX  EQU $ff43
+rLY   EQU $ff44
+rBGP  EQU $ff47
+
+SECTION "Header", ROM0[$100]
+    nop
+    jp Start
+    ds $150 - @, 0
+
+SECTION "Main", ROM0[$150]
+Start:
+    di
+    ld sp, $fffe
+    ; The LCD may only be turned off during VBlank.
+.waitVBlank
+    ldh a, [rLY]
+    cp 144
+    jr c, .waitVBlank
+    xor a
+    ldh [rLCDC], a
+
+    ; Tile 1: the top half is $f0 on both bit planes, the bottom half $0f. Color 3 on the left then on the right.
+    ld hl, $8010
+    ld b, 4
+.top
+    ld a, $f0
+    ld [hl+], a
+    ld [hl+], a
+    dec b
+    jr nz, .top
+    ld b, 4
+.bottom
+    ld a, $0f
+    ld [hl+], a
+    ld [hl+], a
+    dec b
+    jr nz, .bottom
+
+    ; Fill the background map with tile 1.
+    ld hl, $9800
+    ld bc, $0400
+.map
+    ld a, 1
+    ld [hl+], a
+    dec bc
+    ld a, b
+    or c
+    jr nz, .map
+
+    ld a, $e4
+    ldh [rBGP], a
+    xor a
+    ldh [rSCY], a
+    ldh [rSCX], a
+    ; LCD on, tile data at $8000, background map at $9800, background on.
+    ld a, $91
+    ldh [rLCDC], a
+
+    ; Wait for HBlank, then set the scroll of the next line.
+Main:
+.waitHBlank
+    ldh a, [rSTAT]
+    and 3
+    jr nz, .waitHBlank
+    ldh a, [rLY]
+    inc a
+    ldh [rSCX], a
+    srl a
+    ldh [rSCY], a
+.waitEnd
+    ldh a, [rSTAT]
+    and 3
+    jr z, .waitEnd
+    jr Main
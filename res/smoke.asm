@@ -0,0 +1,159 @@
+; Source of smoke.gb, a small demo rom for examples/smoke.rs: a diagonal pattern scrolls one pixel to the left every
+; frame, and a tune of eight notes plays on the second square channel, a note every 16 frames, with a noise hit on
+; every other note. Everything happens in the VBlank interrupt.
+;
+; Written for this repository and released into the public domain.
+;
+; Build with rgbds:
+;   rgbasm -o smoke.o smoke.asm
+;   rgblink -o smoke.gb smoke.o
+;   rgbfix -v -p 0 -t SMOKE smoke.gb
+
+rIF   EQU $ff0f
+rNR21 EQU $ff16
+rNR22 EQU $ff17
+rNR23 EQU $ff18
+rNR24 EQU $ff19
+rNR42 EQU $ff21
+rNR43 EQU $ff22
+rNR44 EQU $ff23
+rNR50 EQU $ff24
+rNR51 EQU $ff25
+rNR52 EQU $ff26
+rLCDC EQU $ff40
+rSCX  EQU $ff43
+rLY   EQU $ff44
+rBGP  EQU $ff47
+rIE   EQU $ffff
+; VBlanks so far, and the note playing.
+hFrames EQU $ff80
+hNote   EQU $ff81
+
+SECTION "VBlank", ROM0[$40]
+    jp VBlank
+
+SECTION "Header", ROM0[$100]
+    nop
+    jp Start
+    ds $150 - @, 0
+
+SECTION "Main", ROM0[$150]
+Start:
+    di
+    ld sp, $fffe
+    ; The LCD may only be turned off during VBlank.
+.waitVBlank
+    ldh a, [rLY]
+    cp 144
+    jr c, .waitVBlank
+    xor a
+    ldh [rLCDC], a
+
+    ld hl, Tiles
+    ld de, $8000
+    ld b, TilesEnd - Tiles
+.copy
+    ld a, [hl+]
+    ld [de], a
+    inc de
+    dec b
+    jr nz, .copy
+
+    ; The tile at (x, y) of the background map is (x + y) & 3.
+    ld hl, $9800
+    ld d, 0
+.mapRow
+    ld e, 0
+.mapCol
+    ld a, d
+    add a, e
+    and 3
+    ld [hl+], a
+    inc e
+    ld a, e
+    cp 32
+    jr nz, .mapCol
+    inc d
+    ld a, d
+    cp 32
+    jr nz, .mapRow
+
+    ; Sound on, full volume on both sides. Square 2 at 50% duty, both channels fading out from the full volume.
+    ld a, $80
+    ldh [rNR52], a
+    ld a, $77
+    ldh [rNR50], a
+    ld a, $ff
+    ldh [rNR51], a
+    ld a, $80
+    ldh [rNR21], a
+    ld a, $f3
+    ldh [rNR22], a
+    ld a, $f1
+    ldh [rNR42], a
+    ld a, $53
+    ldh [rNR43], a
+
+    ld a, $e4
+    ldh [rBGP], a
+    xor a
+    ldh [hFrames], a
+    ldh [hNote], a
+    ldh [rIF], a
+    inc a
+    ldh [rIE], a
+    ; LCD on, tile data at $8000, background map at $9800, background on.
+    ld a, $91
+    ldh [rLCDC], a
+    ei
+
+Main:
+    halt
+    jr Main
+
+VBlank:
+    push af
+    push hl
+    ldh a, [rSCX]
+    inc a
+    ldh [rSCX], a
+    ldh a, [hFrames]
+    inc a
+    ldh [hFrames], a
+    and 15
+    jr nz, .done
+    ldh a, [hNote]
+    inc a
+    and 7
+    ldh [hNote], a
+    ; The period of the note, from the table at $0300.
+    add a, a
+    ld h, HIGH(Notes)
+    ld l, a
+    ld a, [hl+]
+    ldh [rNR23], a
+    ld a, [hl]
+    or $80
+    ldh [rNR24], a
+    ldh a, [hNote]
+    and 1
+    jr nz, .done
+    ld a, $80
+    ldh [rNR44], a
+.done
+    pop hl
+    pop af
+    reti
+
+; Blank, a diagonal line in color 1, color 2, and stripes of colors 1 and 3.
+Tiles:
+    ds 16, $00
+    db $80, $00, $40, $00, $20, $00, $10, $00, $08, $00, $04, $00, $02, $00, $01, $00
+    db $00, $ff, $00, $ff, $00, $ff, $00, $ff, $00, $ff, $00, $ff, $00, $ff, $00, $ff
+    db $ff, $00, $ff, $ff, $ff, $00, $ff, $ff, $ff, $00, $ff, $ff, $ff, $00, $ff, $ff
+TilesEnd:
+
+; C5 E5 G5 C6 G5 E5 D5 B5, as 2048 - 131072 / Hz.
+SECTION "Notes", ROM0[$300]
+Notes:
+    dw 1797, 1849, 1881, 1923, 1881, 1849, 1825, 1915
@@ -0,0 +1,146 @@
+; Source of sprites.gb, a sprite test modelled on the gbdk repro of a sprite swap reported on macOS: a sprite changes
+; its tile once a second, 60 VBlanks, in the VBlank interrupt. Around it other sprites check the priorities:
+;   - Line 8: two pairs of overlapping sprites. On the DMG the one with the smaller X wins, in OAM order or not.
+;   - Line 16: a sprite behind a background tile of color 3, and a sprite at the same X later in OAM which must not
+;     show through it.
+;   - Line 32: 11 sprites, the first off the left edge. Only the first ten are drawn, the last one is dropped.
+; Tile 1 is color 3 and tile 2 color 1, with BGP and OBP0 set to E4h: black and light gray.
+;
+; Build with rgbds:
+;   rgbasm -o sprites.o sprites.asm
+;   rgblink -o sprites.gb sprites.o
+;   rgbfix -v -p 0 -t SPRITES sprites.gb
+;
+; Checked by examples/sprites.rs.
+
+rIF   EQU $ff0f
+rLCDC EQU $ff40
+rLY   EQU $ff44
+rBGP  EQU $ff47
+rOBP0 EQU $ff48
+rIE   EQU $ffff
+; VBlanks since the last swap.
+hFrames EQU $ff80
+
+SECTION "VBlank", ROM0[$40]
+    jp VBlank
+
+SECTION "Header", ROM0[$100]
+    nop
+    jp Start
+    ds $150 - @, 0
+
+SECTION "Main", ROM0[$150]
+Start:
+    di
+    ld sp, $fffe
+    ; The LCD may only be turned off during VBlank.
+.waitVBlank
+    ldh a, [rLY]
+    cp 144
+    jr c, .waitVBlank
+    xor a
+    ldh [rLCDC], a
+
+    ; Clear the tiles, the background maps and OAM.
+    ld hl, $8000
+    ld bc, $2000
+.clear
+    xor a
+    ld [hl+], a
+    dec bc
+    ld a, b
+    or c
+    jr nz, .clear
+    ld hl, $fe00
+    ld b, $a0
+.clearOam
+    ld [hl+], a
+    dec b
+    jr nz, .clearOam
+
+    ; Tile 1 is color 3, tile 2 is color 1.
+    ld hl, $8010
+    ld b, 16
+    ld a, $ff
+.tile1
+    ld [hl+], a
+    dec b
+    jr nz, .tile1
+    ld b, 8
+.tile2
+    ld a, $ff
+    ld [hl+], a
+    xor a
+    ld [hl+], a
+    dec b
+    jr nz, .tile2
+
+    ; One background tile of color 3, at (16, 16).
+    ld a, 1
+    ld [$9842], a
+
+    ld hl, Sprites
+    ld de, $fe00
+    ld b, SpritesEnd - Sprites
+.oam
+    ld a, [hl+]
+    ld [de], a
+    inc de
+    dec b
+    jr nz, .oam
+
+    ld a, $e4
+    ldh [rBGP], a
+    ldh [rOBP0], a
+    xor a
+    ldh [hFrames], a
+    ldh [rIF], a
+    inc a
+    ldh [rIE], a
+    ; LCD on, tile data at $8000, background map at $9800, sprites and background on.
+    ld a, $93
+    ldh [rLCDC], a
+    ei
+
+Main:
+    halt
+    jr Main
+
+; Swap the tile of sprite 0 between 1 and 2 every 60 VBlanks.
+VBlank:
+    push af
+    ldh a, [hFrames]
+    inc a
+    cp 60
+    jr nz, .done
+    ld a, [$fe02]
+    xor 3
+    ld [$fe02], a
+    xor a
+.done
+    ldh [hFrames], a
+    pop af
+    reti
+
+; Y + 16, X + 8, tile, attributes.
+Sprites:
+    db 24, 16, 1, $00  ; 0: the swapping sprite at (8, 8)
+    db 32, 20, 2, $80  ; 1: behind the background at (12, 16)
+    db 24, 48, 2, $00  ; 2: at (40, 8), below sprite 3 though first in OAM
+    db 24, 44, 1, $00  ; 3: at (36, 8)
+    db 32, 20, 2, $00  ; 4: same X as sprite 1, hidden by it
+    db 24, 64, 1, $00  ; 5: at (56, 8)
+    db 24, 68, 2, $00  ; 6: at (60, 8), below sprite 5
+    db 48, 0, 1, $00   ; 7: off the left edge of line 32
+    db 48, 8, 1, $00   ; 8-17: along line 32, 17 is the eleventh sprite of the line
+    db 48, 16, 1, $00
+    db 48, 24, 1, $00
+    db 48, 32, 1, $00
+    db 48, 40, 1, $00
+    db 48, 48, 1, $00
+    db 48, 56, 1, $00
+    db 48, 64, 1, $00
+    db 48, 72, 1, $00
+    db 48, 80, 1, $00
+SpritesEnd:
@@ -1,10 +1,12 @@
-use super::clock::Clock;
+use super::clock::{Clock, Domain, Scheduler, Tick};
+use super::convention::Revision;
 use super::cpu;
 use super::memory::Memory;
+use super::ring::Ring;
+use super::state::{Reader, Snapshot, Writer};
+use super::stretch::Stretch;
 use blip_buf::BlipBuf;
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 #[derive(Clone, Eq, PartialEq)]
 enum Channel {
@@ -211,6 +213,13 @@ struct FrameSequencer {
     step: u8,
 }
 
+// Clock cycles between two steps of the frame sequencer. The channels generate their waveform in chunks of this size.
+const FRAME_SEQUENCER_PERIOD: u32 = cpu::CLOCK_FREQUENCY / 512;
+
+// The seconds of sound the rate control keeps in the buffer, and the most it changes the rate by.
+const RATE_TARGET: f64 = 0.05;
+const RATE_MAX_DELTA: f64 = 0.005;
+
 impl FrameSequencer {
     fn power_up() -> Self {
         Self { step: 0x00 }
@@ -221,6 +230,11 @@ impl FrameSequencer {
         self.step %= 8;
         self.step
     }
+
+    // The next clock is step 0.
+    fn restart(&mut self) {
+        self.step = 0x07;
+    }
 }
 
 // A length counter disables a channel when it decrements to zero. It contains an internal counter and enabled flag.
@@ -232,27 +246,26 @@ impl FrameSequencer {
 // Each length counter is clocked at 256 Hz by the frame sequencer. When clocked while enabled by NRx4 and the counter
 // is not zero, it is decremented. If it becomes zero, the channel is disabled.
 struct LengthCounter {
-    reg: Rc<RefCell<Register>>,
     n: u16,
 }
 
 impl LengthCounter {
-    fn power_up(reg: Rc<RefCell<Register>>) -> Self {
-        Self { reg, n: 0x0000 }
+    fn power_up() -> Self {
+        Self { n: 0x0000 }
     }
 
-    fn next(&mut self) {
-        if self.reg.borrow().get_length_enable() && self.n != 0 {
+    fn next(&mut self, reg: &mut Register) {
+        if reg.get_length_enable() && self.n != 0 {
             self.n -= 1;
             if self.n == 0 {
-                self.reg.borrow_mut().set_trigger(false);
+                reg.set_trigger(false);
             }
         }
     }
 
-    fn reload(&mut self) {
+    fn reload(&mut self, reg: &Register) {
         if self.n == 0x0000 {
-            self.n = if self.reg.borrow().channel == Channel::Wave { 1 << 8 } else { 1 << 6 };
+            self.n = if reg.channel == Channel::Wave { 1 << 8 } else { 1 << 6 };
         }
     }
 }
@@ -265,36 +278,31 @@ impl LengthCounter {
 // When the waveform input is zero the envelope outputs zero, otherwise it outputs the current volume.
 // Writing to NRx2 causes obscure effects on the volume that differ on different Game Boy models (see obscure behavior).
 struct VolumeEnvelope {
-    reg: Rc<RefCell<Register>>,
     timer: Clock,
     volume: u8,
 }
 
 impl VolumeEnvelope {
-    fn power_up(reg: Rc<RefCell<Register>>) -> Self {
-        Self { reg, timer: Clock::power_up(8), volume: 0x00 }
+    fn power_up() -> Self {
+        Self { timer: Clock::power_up(8), volume: 0x00 }
     }
 
-    fn reload(&mut self) {
-        let p = self.reg.borrow().get_period();
+    fn reload(&mut self, reg: &Register) {
+        let p = reg.get_period();
         // The volume envelope and sweep timers treat a period of 0 as 8.
         self.timer.period = if p == 0 { 8 } else { u32::from(p) };
-        self.volume = self.reg.borrow().get_starting_volume();
+        self.volume = reg.get_starting_volume();
     }
 
-    fn next(&mut self) {
-        if self.reg.borrow().get_period() == 0 {
+    fn next(&mut self, reg: &Register) {
+        if reg.get_period() == 0 {
             return;
         }
         if self.timer.next(1) == 0x00 {
             return;
         };
         // If this new volume within the 0 to 15 range, the volume is updated
-        let v = if self.reg.borrow().get_envelope_add_mode() {
-            self.volume.wrapping_add(1)
-        } else {
-            self.volume.wrapping_sub(1)
-        };
+        let v = if reg.get_envelope_add_mode() { self.volume.wrapping_add(1) } else { self.volume.wrapping_sub(1) };
         if v <= 15 {
             self.volume = v;
         }
@@ -323,7 +331,6 @@ impl VolumeEnvelope {
 // Square 1's frequency can be modified via NR13 and NR14 while sweep is active, but the shadow frequency won't be
 // affected so the next time the sweep updates the channel's frequency this modification will be lost.
 struct FrequencySweep {
-    reg: Rc<RefCell<Register>>,
     timer: Clock,
     enable: bool,
     shadow: u16,
@@ -331,52 +338,52 @@ struct FrequencySweep {
 }
 
 impl FrequencySweep {
-    fn power_up(reg: Rc<RefCell<Register>>) -> Self {
-        Self { reg, timer: Clock::power_up(8), enable: false, shadow: 0x0000, newfeq: 0x0000 }
+    fn power_up() -> Self {
+        Self { timer: Clock::power_up(8), enable: false, shadow: 0x0000, newfeq: 0x0000 }
     }
 
-    fn reload(&mut self) {
-        self.shadow = self.reg.borrow().get_frequency();
-        let p = self.reg.borrow().get_sweep_period();
+    fn reload(&mut self, reg: &mut Register) {
+        self.shadow = reg.get_frequency();
+        let p = reg.get_sweep_period();
         // The volume envelope and sweep timers treat a period of 0 as 8.
         self.timer.period = if p == 0 { 8 } else { u32::from(p) };
-        self.enable = p != 0x00 || self.reg.borrow().get_shift() != 0x00;
-        if self.reg.borrow().get_shift() != 0x00 {
-            self.frequency_calculation();
-            self.overflow_check();
+        self.enable = p != 0x00 || reg.get_shift() != 0x00;
+        if reg.get_shift() != 0x00 {
+            self.frequency_calculation(reg);
+            self.overflow_check(reg);
         }
     }
 
-    fn frequency_calculation(&mut self) {
-        let offset = self.shadow >> self.reg.borrow().get_shift();
-        if self.reg.borrow().get_negate() {
+    fn frequency_calculation(&mut self, reg: &Register) {
+        let offset = self.shadow >> reg.get_shift();
+        if reg.get_negate() {
             self.newfeq = self.shadow.wrapping_sub(offset);
         } else {
             self.newfeq = self.shadow.wrapping_add(offset);
         }
     }
 
-    fn overflow_check(&mut self) {
+    fn overflow_check(&mut self, reg: &mut Register) {
         if self.newfeq >= 2048 {
-            self.reg.borrow_mut().set_trigger(false);
+            reg.set_trigger(false);
         }
     }
 
-    fn next(&mut self) {
-        if !self.enable || self.reg.borrow().get_sweep_period() == 0 {
+    fn next(&mut self, reg: &mut Register) {
+        if !self.enable || reg.get_sweep_period() == 0 {
             return;
         }
         if self.timer.next(1) == 0x00 {
             return;
         }
-        self.frequency_calculation();
-        self.overflow_check();
+        self.frequency_calculation(reg);
+        self.overflow_check(reg);
 
-        if self.newfeq < 2048 && self.reg.borrow().get_shift() != 0 {
-            self.reg.borrow_mut().set_frequency(self.newfeq);
+        if self.newfeq < 2048 && reg.get_shift() != 0 {
+            reg.set_frequency(self.newfeq);
             self.shadow = self.newfeq;
-            self.frequency_calculation();
-            self.overflow_check();
+            self.frequency_calculation(reg);
+            self.overflow_check(reg);
         }
     }
 }
@@ -409,33 +416,40 @@ impl Blip {
 // 1      10000001    25%
 // 2      10000111    50%
 // 3      01111110    75%
+//
+// Triggering the channel reloads the frequency timer, but neither resets the position in the duty waveform nor
+// modifies the low two bits of the frequency timer. Rapidly retriggered notes therefore continue the waveform where it
+// was instead of restarting it.
 struct ChannelSquare {
-    reg: Rc<RefCell<Register>>,
+    reg: Register,
     timer: Clock,
     lc: LengthCounter,
     ve: VolumeEnvelope,
     fs: FrequencySweep,
     blip: Blip,
+    // Position in the duty waveform, the waveforms above are output from left to right.
     idx: u8,
+    // Clock cycles the frequency timer waits before counting again, the low two bits kept over a trigger.
+    delay: u32,
 }
 
 impl ChannelSquare {
     fn power_up(blip: BlipBuf, mode: Channel) -> ChannelSquare {
-        let reg = Rc::new(RefCell::new(Register::power_up(mode.clone())));
         ChannelSquare {
-            reg: reg.clone(),
+            reg: Register::power_up(mode),
             timer: Clock::power_up(8192),
-            lc: LengthCounter::power_up(reg.clone()),
-            ve: VolumeEnvelope::power_up(reg.clone()),
-            fs: FrequencySweep::power_up(reg.clone()),
+            lc: LengthCounter::power_up(),
+            ve: VolumeEnvelope::power_up(),
+            fs: FrequencySweep::power_up(),
             blip: Blip::power_up(blip),
-            idx: 1,
+            idx: 0,
+            delay: 0,
         }
     }
 
     // This assumes no volume or sweep adjustments need to be done in the meantime
     fn next(&mut self, cycles: u32) {
-        let pat = match self.reg.borrow().get_duty() {
+        let pat = match self.reg.get_duty() {
             0 => 0b0000_0001,
             1 => 0b1000_0001,
             2 => 0b1000_0111,
@@ -443,13 +457,15 @@ impl ChannelSquare {
             _ => unreachable!(),
         };
         let vol = i32::from(self.ve.volume);
-        for _ in 0..self.timer.next(cycles) {
-            let ampl = if !self.reg.borrow().get_trigger() || self.ve.volume == 0 {
+        let delay = self.delay.min(cycles);
+        self.delay -= delay;
+        for _ in 0..self.timer.next(cycles - delay) {
+            let ampl = if !self.reg.get_trigger() || self.ve.volume == 0 {
                 0x00
-            } else if (pat >> self.idx) & 0x01 != 0x00 {
+            } else if (pat >> (7 - self.idx)) & 0x01 != 0x00 {
                 vol
             } else {
-                vol * -1
+                -vol
             };
             self.blip.set(self.blip.from.wrapping_add(self.timer.period), ampl);
             self.idx = (self.idx + 1) % 8;
@@ -460,30 +476,31 @@ impl ChannelSquare {
 impl Memory for ChannelSquare {
     fn get(&self, a: u16) -> u8 {
         match a {
-            0xff10 | 0xff15 => self.reg.borrow().nrx0,
-            0xff11 | 0xff16 => self.reg.borrow().nrx1,
-            0xff12 | 0xff17 => self.reg.borrow().nrx2,
-            0xff13 | 0xff18 => self.reg.borrow().nrx3,
-            0xff14 | 0xff19 => self.reg.borrow().nrx4,
+            0xff10 | 0xff15 => self.reg.nrx0,
+            0xff11 | 0xff16 => self.reg.nrx1,
+            0xff12 | 0xff17 => self.reg.nrx2,
+            0xff13 | 0xff18 => self.reg.nrx3,
+            0xff14 | 0xff19 => self.reg.nrx4,
             _ => unreachable!(),
         }
     }
 
     fn set(&mut self, a: u16, v: u8) {
         match a {
-            0xff10 | 0xff15 => self.reg.borrow_mut().nrx0 = v,
+            0xff10 | 0xff15 => self.reg.nrx0 = v,
             0xff11 | 0xff16 => {
-                self.reg.borrow_mut().nrx1 = v;
-                self.lc.n = self.reg.borrow().get_length_load();
+                self.reg.nrx1 = v;
+                self.lc.n = self.reg.get_length_load();
             }
-            0xff12 | 0xff17 => self.reg.borrow_mut().nrx2 = v,
+            0xff12 | 0xff17 => self.reg.nrx2 = v,
             0xff13 | 0xff18 => {
-                self.reg.borrow_mut().nrx3 = v;
-                self.timer.period = period(self.reg.clone());
+                self.reg.nrx3 = v;
+                self.timer.period = period(&self.reg);
             }
             0xff14 | 0xff19 => {
-                self.reg.borrow_mut().nrx4 = v;
-                self.timer.period = period(self.reg.clone());
+                let remain = self.timer.period.saturating_sub(self.timer.n);
+                self.reg.nrx4 = v;
+                self.timer.period = period(&self.reg);
                 // Trigger Event
                 //
                 // Writing a value to NRx4 with bit 7 set causes the following things to occur:
@@ -499,11 +516,15 @@ impl Memory for ChannelSquare {
                 //
                 // Note that if the channel's DAC is off, after the above actions occur the channel will be immediately
                 // disabled again.
-                if self.reg.borrow().get_trigger() {
-                    self.lc.reload();
-                    self.ve.reload();
-                    if self.reg.borrow().channel == Channel::Square1 {
-                        self.fs.reload();
+                if self.reg.get_trigger() {
+                    // The period is a multiple of 4, keeping the low two bits makes the first step up to 3 clocks
+                    // longer.
+                    self.timer.n = 0;
+                    self.delay = remain & 0x03;
+                    self.lc.reload(&self.reg);
+                    self.ve.reload(&self.reg);
+                    if self.reg.channel == Channel::Square1 {
+                        self.fs.reload(&mut self.reg);
                     }
                 }
             }
@@ -528,7 +549,7 @@ impl Memory for ChannelSquare {
 // 3      2        25%
 // Wave RAM can only be properly accessed when the channel is disabled (see obscure behavior).
 struct ChannelWave {
-    reg: Rc<RefCell<Register>>,
+    reg: Register,
     timer: Clock,
     lc: LengthCounter,
     blip: Blip,
@@ -538,11 +559,10 @@ struct ChannelWave {
 
 impl ChannelWave {
     fn power_up(blip: BlipBuf) -> ChannelWave {
-        let reg = Rc::new(RefCell::new(Register::power_up(Channel::Wave)));
         ChannelWave {
-            reg: reg.clone(),
+            reg: Register::power_up(Channel::Wave),
             timer: Clock::power_up(8192),
-            lc: LengthCounter::power_up(reg.clone()),
+            lc: LengthCounter::power_up(),
             blip: Blip::power_up(blip),
             waveram: [0x00; 16],
             waveidx: 0x00,
@@ -550,7 +570,7 @@ impl ChannelWave {
     }
 
     fn next(&mut self, cycles: u32) {
-        let s = match self.reg.borrow().get_volume_code() {
+        let s = match self.reg.get_volume_code() {
             0 => 4,
             1 => 0,
             2 => 1,
@@ -563,11 +583,7 @@ impl ChannelWave {
             } else {
                 self.waveram[self.waveidx / 2] >> 4
             };
-            let ampl = if !self.reg.borrow().get_trigger() || !self.reg.borrow().get_dac_power() {
-                0x00
-            } else {
-                i32::from(sample >> s)
-            };
+            let ampl = if !self.reg.get_trigger() || !self.reg.get_dac_power() { 0x00 } else { i32::from(sample >> s) };
             self.blip.set(self.blip.from.wrapping_add(self.timer.period), ampl);
             self.waveidx = (self.waveidx + 1) % 32;
         }
@@ -577,11 +593,11 @@ impl ChannelWave {
 impl Memory for ChannelWave {
     fn get(&self, a: u16) -> u8 {
         match a {
-            0xff1a => self.reg.borrow().nrx0,
-            0xff1b => self.reg.borrow().nrx1,
-            0xff1c => self.reg.borrow().nrx2,
-            0xff1d => self.reg.borrow().nrx3,
-            0xff1e => self.reg.borrow().nrx4,
+            0xff1a => self.reg.nrx0,
+            0xff1b => self.reg.nrx1,
+            0xff1c => self.reg.nrx2,
+            0xff1d => self.reg.nrx3,
+            0xff1e => self.reg.nrx4,
             0xff30..=0xff3f => self.waveram[a as usize - 0xff30],
             _ => unreachable!(),
         }
@@ -589,21 +605,21 @@ impl Memory for ChannelWave {
 
     fn set(&mut self, a: u16, v: u8) {
         match a {
-            0xff1a => self.reg.borrow_mut().nrx0 = v,
+            0xff1a => self.reg.nrx0 = v,
             0xff1b => {
-                self.reg.borrow_mut().nrx1 = v;
-                self.lc.n = self.reg.borrow().get_length_load();
+                self.reg.nrx1 = v;
+                self.lc.n = self.reg.get_length_load();
             }
-            0xff1c => self.reg.borrow_mut().nrx2 = v,
+            0xff1c => self.reg.nrx2 = v,
             0xff1d => {
-                self.reg.borrow_mut().nrx3 = v;
-                self.timer.period = period(self.reg.clone());
+                self.reg.nrx3 = v;
+                self.timer.period = period(&self.reg);
             }
             0xff1e => {
-                self.reg.borrow_mut().nrx4 = v;
-                self.timer.period = period(self.reg.clone());
-                if self.reg.borrow().get_trigger() {
-                    self.lc.reload();
+                self.reg.nrx4 = v;
+                self.timer.period = period(&self.reg);
+                if self.reg.get_trigger() {
+                    self.lc.reload(&self.reg);
                     self.waveidx = 0x00;
                 }
             }
@@ -617,32 +633,37 @@ impl Memory for ChannelWave {
 // with feedback. When clocked by the frequency timer, the low two bits (0 and 1) are XORed, all bits are shifted right
 // by one, and the result of the XOR is put into the now-empty high bit. If width mode is 1 (NR43), the XOR result is
 // ALSO put into bit 6 AFTER the shift, resulting in a 7-bit LFSR. The waveform output is bit 0 of the LFSR, INVERTED.
+//
+// The 15-bit mode has a period of 32767 clocks, the 7-bit mode a period of 127 clocks.
 struct Lfsr {
-    reg: Rc<RefCell<Register>>,
     n: u16,
 }
 
 impl Lfsr {
-    fn power_up(reg: Rc<RefCell<Register>>) -> Self {
-        Self { reg, n: 0x0001 }
+    fn power_up() -> Self {
+        Self { n: 0x7fff }
     }
 
-    fn next(&mut self) -> bool {
-        let s = if self.reg.borrow().get_width_mode_of_lfsr() { 0x06 } else { 0x0e };
-        let src = self.n;
-        self.n <<= 1;
-        let bit = ((src >> s) ^ (self.n >> s)) & 0x0001;
-        self.n |= bit;
-        (src >> s) & 0x0001 != 0x0000
+    fn next(&mut self, reg: &Register) -> bool {
+        let x = (self.n ^ (self.n >> 1)) & 0x0001;
+        self.n = (self.n >> 1) | (x << 14);
+        if reg.get_width_mode_of_lfsr() {
+            self.n = (self.n & !0x0040) | (x << 6);
+        }
+        self.out()
+    }
+
+    fn out(&self) -> bool {
+        self.n & 0x0001 == 0x0000
     }
 
     fn reload(&mut self) {
-        self.n = 0x0001
+        self.n = 0x7fff
     }
 }
 
 struct ChannelNoise {
-    reg: Rc<RefCell<Register>>,
+    reg: Register,
     timer: Clock,
     lc: LengthCounter,
     ve: VolumeEnvelope,
@@ -652,25 +673,26 @@ struct ChannelNoise {
 
 impl ChannelNoise {
     fn power_up(blip: BlipBuf) -> ChannelNoise {
-        let reg = Rc::new(RefCell::new(Register::power_up(Channel::Noise)));
         ChannelNoise {
-            reg: reg.clone(),
+            reg: Register::power_up(Channel::Noise),
             timer: Clock::power_up(4096),
-            lc: LengthCounter::power_up(reg.clone()),
-            ve: VolumeEnvelope::power_up(reg.clone()),
-            lfsr: Lfsr::power_up(reg.clone()),
+            lc: LengthCounter::power_up(),
+            ve: VolumeEnvelope::power_up(),
+            lfsr: Lfsr::power_up(),
             blip: Blip::power_up(blip),
         }
     }
 
     fn next(&mut self, cycles: u32) {
         for _ in 0..self.timer.next(cycles) {
-            let ampl = if !self.reg.borrow().get_trigger() || self.ve.volume == 0 {
+            // Using a noise channel clock shift of 14 or 15 results in the LFSR receiving no clocks.
+            let high = if self.reg.get_clock_shift() >= 14 { self.lfsr.out() } else { self.lfsr.next(&self.reg) };
+            let ampl = if !self.reg.get_trigger() || self.ve.volume == 0 {
                 0x00
-            } else if self.lfsr.next() {
+            } else if high {
                 i32::from(self.ve.volume)
             } else {
-                i32::from(self.ve.volume) * -1
+                -i32::from(self.ve.volume)
             };
             self.blip.set(self.blip.from.wrapping_add(self.timer.period), ampl);
         }
@@ -680,32 +702,32 @@ impl ChannelNoise {
 impl Memory for ChannelNoise {
     fn get(&self, a: u16) -> u8 {
         match a {
-            0xff1f => self.reg.borrow().nrx0,
-            0xff20 => self.reg.borrow().nrx1,
-            0xff21 => self.reg.borrow().nrx2,
-            0xff22 => self.reg.borrow().nrx3,
-            0xff23 => self.reg.borrow().nrx4,
+            0xff1f => self.reg.nrx0,
+            0xff20 => self.reg.nrx1,
+            0xff21 => self.reg.nrx2,
+            0xff22 => self.reg.nrx3,
+            0xff23 => self.reg.nrx4,
             _ => unreachable!(),
         }
     }
 
     fn set(&mut self, a: u16, v: u8) {
         match a {
-            0xff1f => self.reg.borrow_mut().nrx0 = v,
+            0xff1f => self.reg.nrx0 = v,
             0xff20 => {
-                self.reg.borrow_mut().nrx1 = v;
-                self.lc.n = self.reg.borrow().get_length_load();
+                self.reg.nrx1 = v;
+                self.lc.n = self.reg.get_length_load();
             }
-            0xff21 => self.reg.borrow_mut().nrx2 = v,
+            0xff21 => self.reg.nrx2 = v,
             0xff22 => {
-                self.reg.borrow_mut().nrx3 = v;
-                self.timer.period = period(self.reg.clone());
+                self.reg.nrx3 = v;
+                self.timer.period = period(&self.reg);
             }
             0xff23 => {
-                self.reg.borrow_mut().nrx4 = v;
-                if self.reg.borrow().get_trigger() {
-                    self.lc.reload();
-                    self.ve.reload();
+                self.reg.nrx4 = v;
+                if self.reg.get_trigger() {
+                    self.lc.reload(&self.reg);
+                    self.ve.reload(&self.reg);
                     self.lfsr.reload();
                 }
             }
@@ -714,88 +736,247 @@ impl Memory for ChannelNoise {
     }
 }
 
+// Decoded state of one channel, as reported by Apu::state().
+#[derive(Clone, Debug, Default)]
+pub struct ChannelState {
+    // Raw NRx0-NRx4 registers. NR10 for square 2 and NR40 for noise do not exist and read 0.
+    pub regs: [u8; 5],
+    // The channel is enabled, ie. triggered and not yet silenced by its length counter or sweep overflow.
+    pub enabled: bool,
+    // Current volume (0-15) of the volume envelope. For the wave channel, the volume code of NR32.
+    pub volume: u8,
+    // The 11 bits frequency register of square and wave channels. For the noise channel, the NR43 byte.
+    pub frequency: u16,
+    // Resulting tone frequency in Hz. For the noise channel, the rate of the LFSR clocks.
+    pub hz: f64,
+    // Length counter clocks remaining before the channel is disabled.
+    pub length: u16,
+    // Routed to the left and right outputs by NR51.
+    pub left: bool,
+    pub right: bool,
+}
+
+// A typed snapshot of all APU registers and derived channel state, for visualizers and other tools.
+#[derive(Clone, Debug, Default)]
+pub struct ApuState {
+    // NR52 bit 7.
+    pub power: bool,
+    pub nr50: u8,
+    pub nr51: u8,
+    pub nr52: u8,
+    // Master volume (0-7) of the left and right outputs.
+    pub l_vol: u8,
+    pub r_vol: u8,
+    // Square 1, square 2, wave and noise, in this order.
+    pub channels: [ChannelState; 4],
+    pub wave_ram: [u8; 16],
+}
+
+// Receives every sample of the mixed stereo output, e.g. to write it to a file. Unlike the buffer played by the
+// frontend, nothing is dropped when the emulation runs faster than real time.
+pub trait SampleSink: Send {
+    fn push(&mut self, samples: &[(f32, f32)]) -> std::io::Result<()>;
+
+    // Called once after the last sample.
+    fn finish(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 pub struct Apu {
-    pub buffer: Arc<Mutex<Vec<(f32, f32)>>>,
+    // The samples to play, at most one second of them.
+    pub buffer: Arc<Ring>,
+    // A sink which fails is removed.
+    pub sinks: Vec<Box<dyn SampleSink>>,
+    // Generate samples into the buffer. When disabled the registers, length counters and envelopes keep running so
+    // that games observe the same state, only the waveform generation and mixing are skipped.
+    pub output: bool,
+    // Channels left out of the mix: square 1, square 2, wave and noise, in this order. They keep running, only their
+    // sound isn't heard, e.g. to listen to one part of a tune alone.
+    pub muted: [bool; 4],
+    // Fits the samples played to real time while the emulation runs faster, the sinks still get all of them.
+    pub stretch: Stretch,
+    // Dynamic rate control, for a buffer played by a sound card. The emulation is paced by the clock of the host and
+    // the sound card plays at the rate of its own clock, which is never quite the same: the buffer slowly fills up
+    // until samples are dropped, or runs dry, and each time the sound pops. With rate control, the number of samples
+    // made per emulated second is slewed by up to 0.5%, too little to hear, to keep the buffer about RATE_TARGET full.
+    pub rate_control: bool,
+    // The fill of the buffer in samples, averaged over a fraction of a second, and the rate it gave.
+    fill: f64,
+    ratio: f64,
+    pub revision: Revision,
     reg: Register,
-    timer: Clock,
     fs: FrameSequencer,
     channel1: ChannelSquare,
     channel2: ChannelSquare,
     channel3: ChannelWave,
     channel4: ChannelNoise,
     sample_rate: u32,
+    // Scratch buffers of mix, kept between calls to not allocate.
+    mixed: Vec<(f32, f32)>,
+    samples: Vec<i16>,
+    played: Vec<(f32, f32)>,
 }
 
 impl Apu {
-    pub fn power_up(sample_rate: u32) -> Self {
+    pub fn power_up(sample_rate: u32, clock: &mut Scheduler) -> Self {
+        // The frame sequencer only runs while the APU is powered.
+        clock.register(Tick::FrameSequencer, Domain::Fixed, FRAME_SEQUENCER_PERIOD);
+        clock.enable(Tick::FrameSequencer, false);
         let blipbuf1 = create_blipbuf(sample_rate);
         let blipbuf2 = create_blipbuf(sample_rate);
         let blipbuf3 = create_blipbuf(sample_rate);
         let blipbuf4 = create_blipbuf(sample_rate);
         Self {
-            buffer: Arc::new(Mutex::new(Vec::new())),
+            buffer: Arc::new(Ring::power_up(sample_rate as usize + 1)),
+            sinks: Vec::new(),
+            output: true,
+            muted: [false; 4],
+            stretch: Stretch::power_up(sample_rate),
+            rate_control: false,
+            fill: 0.0,
+            ratio: 1.0,
+            revision: Revision::DmgB,
             reg: Register::power_up(Channel::Mixer),
-            timer: Clock::power_up(cpu::CLOCK_FREQUENCY / 512),
             fs: FrameSequencer::power_up(),
             channel1: ChannelSquare::power_up(blipbuf1, Channel::Square1),
             channel2: ChannelSquare::power_up(blipbuf2, Channel::Square2),
             channel3: ChannelWave::power_up(blipbuf3),
             channel4: ChannelNoise::power_up(blipbuf4),
             sample_rate,
+            mixed: Vec::new(),
+            samples: Vec::new(),
+            played: Vec::new(),
         }
     }
 
-    fn play(&mut self, l: &[f32], r: &[f32]) {
-        assert_eq!(l.len(), r.len());
-        let mut buffer = self.buffer.lock().unwrap();
-        for (l, r) in l.iter().zip(r) {
-            // Do not fill the buffer with more than 1 second of data
-            // This speeds up the resync after the turning on and off the speed limiter
-            if buffer.len() > self.sample_rate as usize {
-                return;
-            }
-            buffer.push((*l, *r));
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn play(&mut self, samples: &[(f32, f32)]) {
+        if !self.sinks.is_empty() {
+            self.sinks.retain_mut(|sink| match sink.push(samples) {
+                Ok(()) => true,
+                Err(e) => {
+                    rog::debugln!("APU: sink removed {}", e);
+                    false
+                }
+            });
+        }
+        // The buffer holds no more than 1 second of data, the rest is dropped.
+        // This speeds up the resync after the turning on and off the speed limiter
+        let mut played = std::mem::take(&mut self.played);
+        played.clear();
+        self.stretch.push(samples, &mut played);
+        self.buffer.push(&played);
+        self.played = played;
+        if self.rate_control {
+            self.control_rate();
         }
     }
 
-    pub fn next(&mut self, cycles: u32) {
-        if !self.reg.get_power() {
+    // Called 512 times per emulated second. The samples are made in bursts, a frame of them at a time, and played in
+    // chunks, so the fill is averaged before it is compared to the target.
+    fn control_rate(&mut self) {
+        let target = f64::from(self.sample_rate) * RATE_TARGET;
+        self.fill += (self.buffer.len() as f64 - self.fill) * 0.01;
+        let ratio = 1.0 + (RATE_MAX_DELTA * (target - self.fill) / target).clamp(-RATE_MAX_DELTA, RATE_MAX_DELTA);
+        if (ratio - self.ratio).abs() < 1e-5 {
             return;
         }
+        self.ratio = ratio;
+        // The four buffers must keep making the same number of samples, see mix.
+        let rate = f64::from(self.sample_rate) * ratio;
+        for blip in [
+            &mut self.channel1.blip.data,
+            &mut self.channel2.blip.data,
+            &mut self.channel3.blip.data,
+            &mut self.channel4.blip.data,
+        ] {
+            blip.set_rates(f64::from(cpu::CLOCK_FREQUENCY), rate);
+        }
+    }
 
-        for _ in 0..self.timer.next(cycles) {
-            self.channel1.next(self.timer.period);
-            self.channel2.next(self.timer.period);
-            self.channel3.next(self.timer.period);
-            self.channel4.next(self.timer.period);
+    pub fn next(&mut self, clock: &mut Scheduler) {
+        let ticks = clock.take(Tick::FrameSequencer);
+        for _ in 0..ticks {
+            if self.output {
+                self.channel1.next(FRAME_SEQUENCER_PERIOD);
+                self.channel2.next(FRAME_SEQUENCER_PERIOD);
+                self.channel3.next(FRAME_SEQUENCER_PERIOD);
+                self.channel4.next(FRAME_SEQUENCER_PERIOD);
+            }
 
             let step = self.fs.next();
             if step == 0 || step == 2 || step == 4 || step == 6 {
-                self.channel1.lc.next();
-                self.channel2.lc.next();
-                self.channel3.lc.next();
-                self.channel4.lc.next();
+                self.channel1.lc.next(&mut self.channel1.reg);
+                self.channel2.lc.next(&mut self.channel2.reg);
+                self.channel3.lc.next(&mut self.channel3.reg);
+                self.channel4.lc.next(&mut self.channel4.reg);
             }
             if step == 7 {
-                self.channel1.ve.next();
-                self.channel2.ve.next();
-                self.channel4.ve.next();
+                self.channel1.ve.next(&self.channel1.reg);
+                self.channel2.ve.next(&self.channel2.reg);
+                self.channel4.ve.next(&self.channel4.reg);
             }
             if step == 2 || step == 6 {
-                self.channel1.fs.next();
-                self.channel1.timer.period = period(self.channel1.reg.clone());
+                self.channel1.fs.next(&mut self.channel1.reg);
+                self.channel1.timer.period = period(&self.channel1.reg);
+            }
+
+            if self.output {
+                self.channel1.blip.data.end_frame(FRAME_SEQUENCER_PERIOD);
+                self.channel2.blip.data.end_frame(FRAME_SEQUENCER_PERIOD);
+                self.channel3.blip.data.end_frame(FRAME_SEQUENCER_PERIOD);
+                self.channel4.blip.data.end_frame(FRAME_SEQUENCER_PERIOD);
+                self.channel1.blip.from = self.channel1.blip.from.wrapping_sub(FRAME_SEQUENCER_PERIOD);
+                self.channel2.blip.from = self.channel2.blip.from.wrapping_sub(FRAME_SEQUENCER_PERIOD);
+                self.channel3.blip.from = self.channel3.blip.from.wrapping_sub(FRAME_SEQUENCER_PERIOD);
+                self.channel4.blip.from = self.channel4.blip.from.wrapping_sub(FRAME_SEQUENCER_PERIOD);
+                self.mix();
             }
+        }
+    }
 
-            self.channel1.blip.data.end_frame(self.timer.period);
-            self.channel2.blip.data.end_frame(self.timer.period);
-            self.channel3.blip.data.end_frame(self.timer.period);
-            self.channel4.blip.data.end_frame(self.timer.period);
-            self.channel1.blip.from = self.channel1.blip.from.wrapping_sub(self.timer.period);
-            self.channel2.blip.from = self.channel2.blip.from.wrapping_sub(self.timer.period);
-            self.channel3.blip.from = self.channel3.blip.from.wrapping_sub(self.timer.period);
-            self.channel4.blip.from = self.channel4.blip.from.wrapping_sub(self.timer.period);
-            self.mix();
+    pub fn state(&self) -> ApuState {
+        let mut r = ApuState {
+            power: self.reg.get_power(),
+            nr50: self.reg.nrx0,
+            nr51: self.reg.nrx1,
+            nr52: self.get(0xff26),
+            l_vol: self.reg.get_l_vol(),
+            r_vol: self.reg.get_r_vol(),
+            channels: Default::default(),
+            wave_ram: self.channel3.waveram,
+        };
+        let regs = [&self.channel1.reg, &self.channel2.reg, &self.channel3.reg, &self.channel4.reg];
+        let lengths = [self.channel1.lc.n, self.channel2.lc.n, self.channel3.lc.n, self.channel4.lc.n];
+        let volumes = [
+            self.channel1.ve.volume,
+            self.channel2.ve.volume,
+            self.channel3.reg.get_volume_code(),
+            self.channel4.ve.volume,
+        ];
+        for (i, reg) in regs.iter().enumerate() {
+            let c = &mut r.channels[i];
+            let period = period(reg);
+            c.regs = [reg.nrx0, reg.nrx1, reg.nrx2, reg.nrx3, reg.nrx4];
+            c.enabled = r.nr52 & (1 << i) != 0x00;
+            c.volume = volumes[i];
+            c.length = lengths[i];
+            c.left = r.nr51 & (0x01 << i) != 0x00;
+            c.right = r.nr51 & (0x10 << i) != 0x00;
+            // One waveform is 8 timer steps for square channels and 32 for the wave channel.
+            let steps = match reg.channel {
+                Channel::Wave => 32.0,
+                Channel::Noise => 1.0,
+                _ => 8.0,
+            };
+            c.frequency = if reg.channel == Channel::Noise { u16::from(reg.nrx3) } else { reg.get_frequency() };
+            c.hz = f64::from(cpu::CLOCK_FREQUENCY) / f64::from(period) / steps;
         }
+        r
     }
 
     fn mix(&mut self) {
@@ -808,63 +989,39 @@ impl Apu {
         assert_eq!(sc3, sc4);
 
         let sample_count = sc1 as usize;
-        let mut sum = 0;
 
         let l_vol = (f32::from(self.reg.get_l_vol()) / 7.0) * (1.0 / 15.0) * 0.25;
         let r_vol = (f32::from(self.reg.get_r_vol()) / 7.0) * (1.0 / 15.0) * 0.25;
 
-        while sum < sample_count {
-            let buf_l = &mut [0f32; 2048];
-            let buf_r = &mut [0f32; 2048];
-            let buf = &mut [0i16; 2048];
-
-            let count1 = self.channel1.blip.data.read_samples(buf, false);
-            for (i, v) in buf[..count1].iter().enumerate() {
-                if self.reg.nrx1 & 0x01 == 0x01 {
-                    buf_l[i] += f32::from(*v) * l_vol;
-                }
-                if self.reg.nrx1 & 0x10 == 0x10 {
-                    buf_r[i] += f32::from(*v) * r_vol;
-                }
-            }
-
-            let count2 = self.channel2.blip.data.read_samples(buf, false);
-            for (i, v) in buf[..count2].iter().enumerate() {
-                if self.reg.nrx1 & 0x02 == 0x02 {
-                    buf_l[i] += f32::from(*v) * l_vol;
-                }
-                if self.reg.nrx1 & 0x20 == 0x20 {
-                    buf_r[i] += f32::from(*v) * r_vol;
-                }
-            }
-
-            let count3 = self.channel3.blip.data.read_samples(buf, false);
-            for (i, v) in buf[..count3].iter().enumerate() {
-                if self.reg.nrx1 & 0x04 == 0x04 {
-                    buf_l[i] += f32::from(*v) * l_vol;
-                }
-                if self.reg.nrx1 & 0x40 == 0x40 {
-                    buf_r[i] += f32::from(*v) * r_vol;
-                }
-            }
-
-            let count4 = self.channel4.blip.data.read_samples(buf, false);
-            for (i, v) in buf[..count4].iter().enumerate() {
-                if self.reg.nrx1 & 0x08 == 0x08 {
-                    buf_l[i] += f32::from(*v) * l_vol;
+        let mut mixed = std::mem::take(&mut self.mixed);
+        mixed.clear();
+        mixed.resize(sample_count, (0.0, 0.0));
+        if self.samples.len() < sample_count {
+            self.samples.resize(sample_count, 0);
+        }
+        let buf = &mut self.samples[..sample_count];
+        let blips = [
+            &mut self.channel1.blip.data,
+            &mut self.channel2.blip.data,
+            &mut self.channel3.blip.data,
+            &mut self.channel4.blip.data,
+        ];
+        for (n, blip) in blips.into_iter().enumerate() {
+            let count = blip.read_samples(buf, false);
+            assert_eq!(count, sample_count);
+            let l = self.reg.nrx1 & (0x01 << n) != 0x00 && !self.muted[n];
+            let r = self.reg.nrx1 & (0x10 << n) != 0x00 && !self.muted[n];
+            for (o, v) in mixed.iter_mut().zip(buf.iter()) {
+                if l {
+                    o.0 += f32::from(*v) * l_vol;
                 }
-                if self.reg.nrx1 & 0x80 == 0x80 {
-                    buf_r[i] += f32::from(*v) * r_vol;
+                if r {
+                    o.1 += f32::from(*v) * r_vol;
                 }
             }
-
-            assert_eq!(count1, count2);
-            assert_eq!(count2, count3);
-            assert_eq!(count3, count4);
-
-            self.play(&buf_l[..count1], &buf_r[..count1]);
-            sum += count1;
         }
+        self.play(&mixed);
+        self.mixed = mixed;
     }
 }
 
@@ -875,8 +1032,10 @@ const RD_MASK: [u8; 48] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-impl Memory for Apu {
-    fn get(&self, a: u16) -> u8 {
+// The registers are not a Memory of their own: powering the APU on or off starts or stops the frame sequencer, which
+// is a tick source of the scheduler.
+impl Apu {
+    pub fn get(&self, a: u16) -> u8 {
         let r = match a {
             0xff10..=0xff14 => self.channel1.get(a),
             0xff15..=0xff19 => self.channel2.get(a),
@@ -886,14 +1045,10 @@ impl Memory for Apu {
             0xff25 => self.reg.nrx1,
             0xff26 => {
                 let a = self.reg.nrx2 & 0xf0;
-                let b = if self.channel1.reg.borrow().get_trigger() { 1 } else { 0 };
-                let c = if self.channel2.reg.borrow().get_trigger() { 2 } else { 0 };
-                let d = if self.channel3.reg.borrow().get_trigger() && self.channel3.reg.borrow().get_dac_power() {
-                    4
-                } else {
-                    0
-                };
-                let e = if self.channel4.reg.borrow().get_trigger() { 8 } else { 0 };
+                let b = if self.channel1.reg.get_trigger() { 1 } else { 0 };
+                let c = if self.channel2.reg.get_trigger() { 2 } else { 0 };
+                let d = if self.channel3.reg.get_trigger() && self.channel3.reg.get_dac_power() { 4 } else { 0 };
+                let e = if self.channel4.reg.get_trigger() { 8 } else { 0 };
                 a | b | c | d | e
             }
             0xff27..=0xff2f => 0x00,
@@ -903,8 +1058,18 @@ impl Memory for Apu {
         r | RD_MASK[a as usize - 0xff10]
     }
 
-    fn set(&mut self, a: u16, v: u8) {
+    pub fn set(&mut self, clock: &mut Scheduler, a: u16, v: u8) {
         if a != 0xff26 && !self.reg.get_power() {
+            // The length counters of the DMG can be loaded while powered off, the duty bits stay cleared.
+            if self.revision.apu_keeps_length() {
+                match a {
+                    0xff11 => self.channel1.set(a, v & 0x3f),
+                    0xff16 => self.channel2.set(a, v & 0x3f),
+                    0xff1b => self.channel3.set(a, v),
+                    0xff20 => self.channel4.set(a, v),
+                    _ => {}
+                }
+            }
             return;
         }
         match a {
@@ -915,35 +1080,51 @@ impl Memory for Apu {
             0xff24 => self.reg.nrx0 = v,
             0xff25 => self.reg.nrx1 = v,
             0xff26 => {
+                let powered = self.reg.get_power();
                 self.reg.nrx2 = v;
+                clock.enable(Tick::FrameSequencer, self.reg.get_power());
+                // Powering the APU on restarts the frame sequencer at step 0 and the duty units of the square channels
+                // at the start of their waveform, so that the first length, sweep and envelope clocks come at the
+                // same time after every power on.
+                if !powered && self.reg.get_power() {
+                    self.fs.restart();
+                    self.channel1.idx = 0;
+                    self.channel2.idx = 0;
+                }
                 // Powering APU off should write 0 to all regs
                 // Powering APU off shouldn't affect wave, that wave RAM is unchanged
                 if !self.reg.get_power() {
-                    self.channel1.reg.borrow_mut().nrx0 = 0x00;
-                    self.channel1.reg.borrow_mut().nrx1 = 0x00;
-                    self.channel1.reg.borrow_mut().nrx2 = 0x00;
-                    self.channel1.reg.borrow_mut().nrx3 = 0x00;
-                    self.channel1.reg.borrow_mut().nrx4 = 0x00;
-                    self.channel2.reg.borrow_mut().nrx0 = 0x00;
-                    self.channel2.reg.borrow_mut().nrx1 = 0x00;
-                    self.channel2.reg.borrow_mut().nrx2 = 0x00;
-                    self.channel2.reg.borrow_mut().nrx3 = 0x00;
-                    self.channel2.reg.borrow_mut().nrx4 = 0x00;
-                    self.channel3.reg.borrow_mut().nrx0 = 0x00;
-                    self.channel3.reg.borrow_mut().nrx1 = 0x00;
-                    self.channel3.reg.borrow_mut().nrx2 = 0x00;
-                    self.channel3.reg.borrow_mut().nrx3 = 0x00;
-                    self.channel3.reg.borrow_mut().nrx4 = 0x00;
-                    self.channel4.reg.borrow_mut().nrx0 = 0x00;
-                    self.channel4.reg.borrow_mut().nrx1 = 0x00;
-                    self.channel4.reg.borrow_mut().nrx2 = 0x00;
-                    self.channel4.reg.borrow_mut().nrx3 = 0x00;
-                    self.channel4.reg.borrow_mut().nrx4 = 0x00;
+                    self.channel1.reg.nrx0 = 0x00;
+                    self.channel1.reg.nrx1 = 0x00;
+                    self.channel1.reg.nrx2 = 0x00;
+                    self.channel1.reg.nrx3 = 0x00;
+                    self.channel1.reg.nrx4 = 0x00;
+                    self.channel2.reg.nrx0 = 0x00;
+                    self.channel2.reg.nrx1 = 0x00;
+                    self.channel2.reg.nrx2 = 0x00;
+                    self.channel2.reg.nrx3 = 0x00;
+                    self.channel2.reg.nrx4 = 0x00;
+                    self.channel3.reg.nrx0 = 0x00;
+                    self.channel3.reg.nrx1 = 0x00;
+                    self.channel3.reg.nrx2 = 0x00;
+                    self.channel3.reg.nrx3 = 0x00;
+                    self.channel3.reg.nrx4 = 0x00;
+                    self.channel4.reg.nrx0 = 0x00;
+                    self.channel4.reg.nrx1 = 0x00;
+                    self.channel4.reg.nrx2 = 0x00;
+                    self.channel4.reg.nrx3 = 0x00;
+                    self.channel4.reg.nrx4 = 0x00;
                     self.reg.nrx0 = 0x00;
                     self.reg.nrx1 = 0x00;
                     self.reg.nrx2 = 0x00;
                     self.reg.nrx3 = 0x00;
                     self.reg.nrx4 = 0x00;
+                    if !self.revision.apu_keeps_length() {
+                        self.channel1.lc.n = 0x00;
+                        self.channel2.lc.n = 0x00;
+                        self.channel3.lc.n = 0x00;
+                        self.channel4.lc.n = 0x00;
+                    }
                 }
             }
             0xff27..=0xff2f => {}
@@ -959,17 +1140,293 @@ fn create_blipbuf(sample_rate: u32) -> BlipBuf {
     blipbuf
 }
 
-fn period(reg: Rc<RefCell<Register>>) -> u32 {
-    match reg.borrow().channel {
-        Channel::Square1 | Channel::Square2 => 4 * (2048 - u32::from(reg.borrow().get_frequency())),
-        Channel::Wave => 2 * (2048 - u32::from(reg.borrow().get_frequency())),
+fn period(reg: &Register) -> u32 {
+    match reg.channel {
+        Channel::Square1 | Channel::Square2 => 4 * (2048 - u32::from(reg.get_frequency())),
+        Channel::Wave => 2 * (2048 - u32::from(reg.get_frequency())),
         Channel::Noise => {
-            let d = match reg.borrow().get_dividor_code() {
+            // The noise channel's frequency timer period is set by a base divisor shifted left some number of bits.
+            //
+            // Divisor code   Divisor
+            // -----------------------
+            //    0             8
+            //    1            16
+            //    2            32
+            //    3            48
+            //    4            64
+            //    5            80
+            //    6            96
+            //    7           112
+            let d = match reg.get_dividor_code() {
                 0 => 8,
-                n => (u32::from(n) + 1) * 16,
+                n => u32::from(n) * 16,
             };
-            d << reg.borrow().get_clock_shift()
+            d << reg.get_clock_shift()
         }
         Channel::Mixer => cpu::CLOCK_FREQUENCY / 512,
     }
 }
+
+impl Snapshot for Register {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.nrx0);
+        w.u8(self.nrx1);
+        w.u8(self.nrx2);
+        w.u8(self.nrx3);
+        w.u8(self.nrx4);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.nrx0 = r.u8()?;
+        self.nrx1 = r.u8()?;
+        self.nrx2 = r.u8()?;
+        self.nrx3 = r.u8()?;
+        self.nrx4 = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for VolumeEnvelope {
+    fn save(&self, w: &mut Writer) {
+        self.timer.save(w);
+        w.u8(self.volume);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.timer.load(r)?;
+        self.volume = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for FrequencySweep {
+    fn save(&self, w: &mut Writer) {
+        self.timer.save(w);
+        w.bool(self.enable);
+        w.u16(self.shadow);
+        w.u16(self.newfeq);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.timer.load(r)?;
+        self.enable = r.bool()?;
+        self.shadow = r.u16()?;
+        self.newfeq = r.u16()?;
+        Ok(())
+    }
+}
+
+// Samples already in the buffer are dropped, only the current amplitude matters to continue the waveform.
+impl Snapshot for Blip {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.from);
+        w.u32(self.ampl as u32);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.data.clear();
+        self.from = r.u32()?;
+        self.ampl = r.u32()? as i32;
+        Ok(())
+    }
+}
+
+impl Snapshot for ChannelSquare {
+    fn save(&self, w: &mut Writer) {
+        self.reg.save(w);
+        self.timer.save(w);
+        w.u16(self.lc.n);
+        self.ve.save(w);
+        self.fs.save(w);
+        self.blip.save(w);
+        w.u8(self.idx);
+        w.u32(self.delay);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.reg.load(r)?;
+        self.timer.load(r)?;
+        self.lc.n = r.u16()?;
+        self.ve.load(r)?;
+        self.fs.load(r)?;
+        self.blip.load(r)?;
+        self.idx = r.u8()?;
+        self.delay = r.u32()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for ChannelWave {
+    fn save(&self, w: &mut Writer) {
+        self.reg.save(w);
+        self.timer.save(w);
+        w.u16(self.lc.n);
+        self.blip.save(w);
+        w.bytes(&self.waveram);
+        w.u8(self.waveidx as u8);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.reg.load(r)?;
+        self.timer.load(r)?;
+        self.lc.n = r.u16()?;
+        self.blip.load(r)?;
+        r.bytes(&mut self.waveram)?;
+        self.waveidx = usize::from(r.u8()?);
+        if self.waveidx >= 32 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "State: wave position out of range"));
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot for ChannelNoise {
+    fn save(&self, w: &mut Writer) {
+        self.reg.save(w);
+        self.timer.save(w);
+        w.u16(self.lc.n);
+        self.ve.save(w);
+        w.u16(self.lfsr.n);
+        self.blip.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.reg.load(r)?;
+        self.timer.load(r)?;
+        self.lc.n = r.u16()?;
+        self.ve.load(r)?;
+        self.lfsr.n = r.u16()?;
+        self.blip.load(r)?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Apu {
+    fn save(&self, w: &mut Writer) {
+        self.reg.save(w);
+        w.u8(self.fs.step);
+        self.channel1.save(w);
+        self.channel2.save(w);
+        self.channel3.save(w);
+        self.channel4.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.reg.load(r)?;
+        self.fs.step = r.u8()?;
+        self.channel1.load(r)?;
+        self.channel2.load(r)?;
+        self.channel3.load(r)?;
+        self.channel4.load(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise() -> ChannelNoise {
+        ChannelNoise::power_up(BlipBuf::new(1024))
+    }
+
+    #[test]
+    fn noise_divisor() {
+        let mut c = noise();
+        let periods: Vec<u32> = (0..8)
+            .map(|code| {
+                c.set(0xff22, code);
+                c.timer.period
+            })
+            .collect();
+        assert_eq!(periods, [8, 16, 32, 48, 64, 80, 96, 112]);
+        c.set(0xff22, 0x31);
+        assert_eq!(c.timer.period, 16 << 3);
+    }
+
+    #[test]
+    fn noise_clock_shift_14_and_15() {
+        for nr43 in [0xe0, 0xf0, 0xe8] {
+            let mut c = noise();
+            c.set(0xff22, nr43);
+            c.set(0xff21, 0xf0);
+            c.set(0xff23, 0x80);
+            c.next(c.timer.period * 4);
+            assert_eq!(c.lfsr.n, 0x7fff);
+        }
+    }
+
+    #[test]
+    fn lfsr_15_bit() {
+        let reg = Register::power_up(Channel::Noise);
+        let mut lfsr = Lfsr::power_up();
+        let states: Vec<u16> = (0..16)
+            .map(|_| {
+                lfsr.next(&reg);
+                lfsr.n
+            })
+            .collect();
+        assert_eq!(
+            states,
+            [
+                0x3fff, 0x1fff, 0x0fff, 0x07ff, 0x03ff, 0x01ff, 0x00ff, 0x007f, 0x003f, 0x001f, 0x000f, 0x0007, 0x0003,
+                0x0001, 0x4000, 0x2000
+            ]
+        );
+        // All 32767 states but zero, then the start again.
+        let mut lfsr = Lfsr::power_up();
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..32767 {
+            lfsr.next(&reg);
+            seen.insert(lfsr.n);
+        }
+        assert_eq!(seen.len(), 32767);
+        assert_eq!(lfsr.n, 0x7fff);
+    }
+
+    #[test]
+    fn lfsr_7_bit() {
+        let mut reg = Register::power_up(Channel::Noise);
+        reg.nrx3 = 0x08;
+        let mut lfsr = Lfsr::power_up();
+        let states: Vec<u16> = (0..8)
+            .map(|_| {
+                lfsr.next(&reg);
+                lfsr.n & 0x7f
+            })
+            .collect();
+        assert_eq!(states, [0x3f, 0x1f, 0x0f, 0x07, 0x03, 0x01, 0x40, 0x20]);
+        // The low 7 bits go through all 127 states but zero, the output repeats every 127 clocks.
+        let mut seen = std::collections::HashSet::new();
+        let out: Vec<bool> = (0..254)
+            .map(|_| {
+                let o = lfsr.next(&reg);
+                seen.insert(lfsr.n & 0x7f);
+                o
+            })
+            .collect();
+        assert_eq!(seen.len(), 127);
+        assert_eq!(out[..127], out[127..]);
+        assert!(out.contains(&true) && out.contains(&false));
+    }
+
+    #[test]
+    fn square_duty_step() {
+        let mut c = ChannelSquare::power_up(BlipBuf::new(1024), Channel::Square2);
+        // Duty 2, the highest frequency: the duty position steps every 4 clocks.
+        c.set(0xff16, 0x80);
+        c.set(0xff17, 0xf0);
+        c.set(0xff18, 0xff);
+        c.set(0xff19, 0x87);
+        assert_eq!(c.timer.period, 4);
+        c.next(3);
+        assert_eq!(c.idx, 0);
+        c.next(1);
+        assert_eq!(c.idx, 1);
+        // Across a boundary, the rest of the clocks count towards the next step.
+        c.next(5);
+        assert_eq!((c.idx, c.timer.n), (2, 1));
+        c.next(3 + 4 * 5);
+        assert_eq!((c.idx, c.timer.n), (0, 0));
+    }
+}
@@ -8,37 +8,72 @@
 // Reference:
 //   - http://gbdev.gg8.se/wiki/articles/The_Cartridge_Header
 //   - http://gbdev.gg8.se/wiki/articles/Memory_Bank_Controllers
+use super::cpu::CLOCK_FREQUENCY;
+use super::error::{self, Error};
 use super::memory::Memory;
+use super::rom::Rom;
+use super::state::{Reader, Snapshot, Writer};
+use super::verify::Digest;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
+#[cfg(not(target_arch = "wasm32"))]
 use std::time::SystemTime;
 
 pub trait Stable {
-    fn sav(&self);
+    fn sav(&self) -> std::io::Result<()>;
+}
+
+// Read a byte in a bank of ROM or RAM. Bank numbers past the memory actually present wrap around, like the unused high
+// address lines of the real chip, which some dumps and homebrew with a wrong header size rely on.
+//
+// Reads from memory the cartridge doesn't have, or from RAM that is disabled, return FFh in all mappers: nothing
+// drives the data bus and it is pulled up.
+fn bank_get(mem: &[u8], size: usize, bank: usize, offset: usize) -> u8 {
+    if mem.is_empty() {
+        return 0xff;
+    }
+    let count = mem.len().div_ceil(size);
+    mem.get(bank % count * size + offset).copied().unwrap_or(0xff)
+}
+
+fn bank_set(mem: &mut [u8], size: usize, bank: usize, offset: usize, v: u8) {
+    if mem.is_empty() {
+        return;
+    }
+    let count = mem.len().div_ceil(size);
+    if let Some(b) = mem.get_mut(bank % count * size + offset) {
+        *b = v;
+    }
 }
 
 // This is a 32kB (256kb) ROM and occupies 0000-7FFF.
 pub struct RomOnly {
-    rom: Vec<u8>,
+    rom: Rom,
 }
 
 impl RomOnly {
-    pub fn power_up(rom: Vec<u8>) -> Self {
+    pub fn power_up(rom: impl Into<Rom>) -> Self {
+        let rom = rom.into();
         RomOnly { rom }
     }
 }
 
 impl Memory for RomOnly {
     fn get(&self, a: u16) -> u8 {
-        self.rom[a as usize]
+        match a {
+            0x0000..=0x7fff => bank_get(&self.rom, 0x8000, 0, a as usize),
+            _ => 0xff,
+        }
     }
 
     fn set(&mut self, _: u16, _: u8) {}
 }
 
 impl Stable for RomOnly {
-    fn sav(&self) {}
+    fn sav(&self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 enum BankMode {
@@ -91,33 +126,63 @@ enum BankMode {
 //   01h = RAM Banking Mode (up to 32KByte RAM, 512KByte ROM)
 // The program may freely switch between both modes, the only limitiation is that only RAM Bank 00h can be used during
 // Mode 0, and only ROM Banks 00-1Fh can be used during Mode 1.
+//
+// In Mode 1 the two bits also select the bank seen at 0000-3FFF: 00h, 20h, 40h or 60h. This is what the 4 games of a
+// multicart are switched with.
+//
+// MBC1M
+// Multicarts such as Mortal Kombat I & II or Bomberman Collection wire the MBC1 differently: bit 4 of the ROM Bank
+// Number is not connected, and the two upper bits are bits 4-5 of the bank, so each game is 256KByte. Their header
+// doesn't tell them apart, but each game has its own, with the Nintendo logo, at the start of bank 10h of a 1MByte rom.
 pub struct Mbc1 {
-    rom: Vec<u8>,
+    rom: Rom,
     ram: Vec<u8>,
     bank_mode: BankMode, // MBC1 has two different maximum memory modes: 16Mbit ROM/8KByte RAM or 4Mbit ROM/32KByte RAM.
     bank: u8,
     ram_enable: bool,
+    multicart: bool,
     sav_path: PathBuf,
 }
 
 impl Mbc1 {
-    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+    pub fn power_up(rom: impl Into<Rom>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+        let rom = rom.into();
+        let multicart = rom.len() == 0x10_0000 && rom[0x4_0104..0x4_0134] == NINTENDO_LOGO;
+        if multicart {
+            rog::debugln!("Cartridge is an MBC1 multicart");
+        }
         Mbc1 {
             rom,
             ram,
             bank_mode: BankMode::Rom, // The MBC1 defaults to 16Mbit ROM/8KByte RAM mode on power up.
             bank: 0x01,
             ram_enable: false,
+            multicart,
             sav_path: PathBuf::from(sav.as_ref()),
         }
     }
 
+    // The two upper bits, shifted to their place in the bank number.
+    fn upper(&self) -> u8 {
+        let upper = (self.bank & 0x60) >> 5;
+        if self.multicart {
+            upper << 4
+        } else {
+            upper << 5
+        }
+    }
+
     fn rom_bank(&self) -> usize {
-        let n = match self.bank_mode {
-            BankMode::Rom => self.bank & 0x7f,
-            BankMode::Ram => self.bank & 0x1f,
-        };
-        n as usize
+        let lower = if self.multicart { self.bank & 0x0f } else { self.bank & 0x1f };
+        usize::from(self.upper() | lower)
+    }
+
+    // The bank at 0000-3FFF.
+    fn rom_bank_0(&self) -> usize {
+        match self.bank_mode {
+            BankMode::Rom => 0x00,
+            BankMode::Ram => usize::from(self.upper()),
+        }
     }
 
     fn ram_bank(&self) -> usize {
@@ -132,30 +197,18 @@ impl Mbc1 {
 impl Memory for Mbc1 {
     fn get(&self, a: u16) -> u8 {
         match a {
-            0x0000..=0x3fff => self.rom[a as usize],
-            0x4000..=0x7fff => {
-                let i = self.rom_bank() * 0x4000 + a as usize - 0x4000;
-                self.rom[i]
-            }
-            0xa000..=0xbfff => {
-                if self.ram_enable {
-                    let i = self.ram_bank() * 0x2000 + a as usize - 0xa000;
-                    self.ram[i]
-                } else {
-                    0x00
-                }
-            }
-            _ => 0x00,
+            0x0000..=0x3fff => bank_get(&self.rom, 0x4000, self.rom_bank_0(), a as usize),
+            0x4000..=0x7fff => bank_get(&self.rom, 0x4000, self.rom_bank(), a as usize - 0x4000),
+            0xa000..=0xbfff if self.ram_enable => bank_get(&self.ram, 0x2000, self.ram_bank(), a as usize - 0xa000),
+            _ => 0xff,
         }
     }
 
     fn set(&mut self, a: u16, v: u8) {
         match a {
-            0xa000..=0xbfff => {
-                if self.ram_enable {
-                    let i = self.ram_bank() * 0x2000 + a as usize - 0xa000;
-                    self.ram[i] = v;
-                }
+            0xa000..=0xbfff if self.ram_enable => {
+                let bank = self.ram_bank();
+                bank_set(&mut self.ram, 0x2000, bank, a as usize - 0xa000, v);
             }
             0x0000..=0x1fff => {
                 self.ram_enable = v & 0x0f == 0x0a;
@@ -172,10 +225,10 @@ impl Memory for Mbc1 {
                 let n = v & 0x03;
                 self.bank = self.bank & 0x9f | (n << 5)
             }
-            0x6000..=0x7fff => match v {
+            // Only the lowest bit is connected.
+            0x6000..=0x7fff => match v & 0x01 {
                 0x00 => self.bank_mode = BankMode::Rom,
-                0x01 => self.bank_mode = BankMode::Ram,
-                n => panic!("Invalid cartridge type {}", n),
+                _ => self.bank_mode = BankMode::Ram,
             },
             _ => {}
         }
@@ -183,12 +236,12 @@ impl Memory for Mbc1 {
 }
 
 impl Stable for Mbc1 {
-    fn sav(&self) {
+    fn sav(&self) -> std::io::Result<()> {
         rog::debugln!("Ram is being persisted");
-        if self.sav_path.to_str().unwrap().is_empty() {
-            return;
+        if self.sav_path.as_os_str().is_empty() {
+            return Ok(());
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap()
+        File::create(&self.sav_path)?.write_all(&self.ram)
     }
 }
 
@@ -199,6 +252,7 @@ impl Stable for Mbc1 {
 // Same as for MBC1, but only a total of 16 ROM banks is supported.
 //
 // A000-A1FF - 512x4bits RAM, built-in into the MBC2 chip (Read/Write)
+// The 512 half-bytes repeat through A000-BFFF, only the lower 9 bits of the address select one.
 // The MBC2 doesn't support external RAM, instead it includes 512x4 bits of built-in RAM (in the MBC2 chip itself). It
 // still requires an external battery to save data during power-off though. As the data consists of 4bit values, only
 // the lower 4 bits of the "bytes" in this memory area are used.
@@ -215,7 +269,7 @@ impl Stable for Mbc1 {
 // addresses can be used to select a ROM bank: 2100-21FF, 2300-23FF, 2500-25FF, ..., 3F00-3FFF. The suggested address
 // range to use for MBC2 rom bank selection is 2100-21FF.
 pub struct Mbc2 {
-    rom: Vec<u8>,
+    rom: Rom,
     ram: Vec<u8>,
     rom_bank: usize,
     ram_enable: bool,
@@ -223,7 +277,10 @@ pub struct Mbc2 {
 }
 
 impl Mbc2 {
-    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+    pub fn power_up(rom: impl Into<Rom>, mut ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+        let rom = rom.into();
+        // A save file written by another emulator may be short or empty, the chip always has the full 512 half-bytes.
+        ram.resize(0x0200, 0);
         Self { rom, ram, rom_bank: 1, ram_enable: false, sav_path: PathBuf::from(sav.as_ref()) }
     }
 }
@@ -231,19 +288,10 @@ impl Mbc2 {
 impl Memory for Mbc2 {
     fn get(&self, a: u16) -> u8 {
         match a {
-            0x0000..=0x3fff => self.rom[a as usize],
-            0x4000..=0x7fff => {
-                let i = self.rom_bank * 0x4000 + a as usize - 0x4000;
-                self.rom[i]
-            }
-            0xa000..=0xa1ff => {
-                if self.ram_enable {
-                    self.ram[(a - 0xa000) as usize]
-                } else {
-                    0x00
-                }
-            }
-            _ => 0x00,
+            0x0000..=0x3fff => bank_get(&self.rom, 0x4000, 0, a as usize),
+            0x4000..=0x7fff => bank_get(&self.rom, 0x4000, self.rom_bank, a as usize - 0x4000),
+            0xa000..=0xbfff if self.ram_enable => bank_get(&self.ram, 0x0200, 0, a as usize & 0x01ff),
+            _ => 0xff,
         }
     }
 
@@ -251,61 +299,122 @@ impl Memory for Mbc2 {
         // Only the lower 4 bits of the "bytes" in this memory area are used.
         let v = v & 0x0f;
         match a {
-            0xa000..=0xa1ff => {
-                if self.ram_enable {
-                    self.ram[(a - 0xa000) as usize] = v
-                }
-            }
-            0x0000..=0x1fff => {
-                if a & 0x0100 == 0 {
-                    self.ram_enable = v == 0x0a;
-                }
-            }
-            0x2000..=0x3fff => {
-                if a & 0x0100 != 0 {
-                    self.rom_bank = v as usize;
-                }
-            }
+            0xa000..=0xbfff if self.ram_enable => bank_set(&mut self.ram, 0x0200, 0, a as usize & 0x01ff, v),
+            0x0000..=0x1fff if a & 0x0100 == 0 => self.ram_enable = v == 0x0a,
+            0x2000..=0x3fff if a & 0x0100 != 0 => self.rom_bank = (v as usize).max(1),
             _ => {}
         }
     }
 }
 
 impl Stable for Mbc2 {
-    fn sav(&self) {
+    fn sav(&self) -> std::io::Result<()> {
         rog::debugln!("Ram is being persisted");
-        if self.sav_path.to_str().unwrap().is_empty() {
-            return;
+        if self.sav_path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        File::create(&self.sav_path)?.write_all(&self.ram)
+    }
+}
+
+// Seconds since the unix epoch, 0 for a host clock set before it.
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// There is no system clock on wasm32-unknown-unknown, the clock of the cartridge stands still in the browser.
+#[cfg(target_arch = "wasm32")]
+fn now() -> u64 {
+    0
+}
+
+// What loading a save state does to the real time clock.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RtcSync {
+    // The clock keeps the real time: it jumps forward by the time since the state was saved, as the clock of a
+    // cartridge left on the shelf would. The game sees the time of day it expects.
+    RealTime,
+    // The clock goes back to the time it had when the state was saved, to the clock cycle. Loading the same state
+    // always gives the same run, as tool-assisted runs and movies need.
+    Emulated,
+}
+
+impl std::str::FromStr for RtcSync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "real" => Ok(RtcSync::RealTime),
+            "emulated" => Ok(RtcSync::Emulated),
+            _ => Err(format!("Unsupported clock sync: {}", s)),
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap()
     }
 }
 
-struct RealTimeClock {
+pub struct RealTimeClock {
+    // Applied when a save state is loaded, real time by default.
+    pub sync: RtcSync,
     s: u8,
     m: u8,
     h: u8,
     dl: u8,
     dh: u8,
     zero: u64,
+    // Clock cycles since the clock started, when it is driven by the emulation instead of the host clock.
+    cycles: Option<u64>,
     sav_path: PathBuf,
 }
 
 impl RealTimeClock {
     fn power_up(sav_path: impl AsRef<Path>) -> Self {
-        let zero = match std::fs::read(sav_path.as_ref()) {
-            Ok(ok) => {
-                let mut b: [u8; 8] = Default::default();
-                b.copy_from_slice(&ok);
-                u64::from_be_bytes(b)
-            }
-            Err(_) => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
-        };
-        Self { zero, s: 0, m: 0, h: 0, dl: 0, dh: 0, sav_path: sav_path.as_ref().to_path_buf() }
+        // A missing or truncated file starts a new clock.
+        let zero =
+            std::fs::read(sav_path.as_ref()).ok().and_then(|b| b.try_into().ok()).map_or_else(now, u64::from_be_bytes);
+        let sav_path = sav_path.as_ref().to_path_buf();
+        Self { sync: RtcSync::RealTime, zero, s: 0, m: 0, h: 0, dl: 0, dh: 0, cycles: None, sav_path }
+    }
+
+    // Seconds since the clock started.
+    fn elapsed(&self) -> u64 {
+        match self.cycles {
+            Some(c) => c / u64::from(CLOCK_FREQUENCY),
+            None => now().saturating_sub(self.zero),
+        }
+    }
+
+    fn set_elapsed(&mut self, d: u64) {
+        match &mut self.cycles {
+            Some(c) => *c = d * u64::from(CLOCK_FREQUENCY),
+            None => self.zero = now().saturating_sub(d),
+        }
+    }
+
+    // Move the clock forward. The game sees the new time at the next latch.
+    pub fn advance(&mut self, seconds: u64) {
+        match &mut self.cycles {
+            Some(c) => *c += seconds * u64::from(CLOCK_FREQUENCY),
+            None => self.zero = self.zero.saturating_sub(seconds),
+        }
+    }
+
+    // Drive the clock by the emulated clock cycles instead of the host clock, so that it runs with the emulation: it
+    // stands still when paused and runs fast without throttle. The time counted so far is kept.
+    pub fn set_emulated(&mut self, emulated: bool) {
+        let d = self.elapsed();
+        self.cycles = if emulated { Some(0) } else { None };
+        self.set_elapsed(d);
+    }
+
+    // Called with the clock cycles at normal speed.
+    pub fn next(&mut self, cycles: u32) {
+        if let Some(c) = &mut self.cycles {
+            *c += u64::from(cycles);
+        }
     }
 
     fn tic(&mut self) {
-        let d = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() - self.zero;
+        let d = self.elapsed();
 
         self.s = (d % 60) as u8;
         self.m = (d / 60 % 60) as u8;
@@ -333,7 +442,7 @@ impl Memory for RealTimeClock {
             0x0a => self.h,
             0x0b => self.dl,
             0x0c => self.dh,
-            _ => panic!("No entry"),
+            _ => 0xff,
         }
     }
 
@@ -344,17 +453,17 @@ impl Memory for RealTimeClock {
             0x0a => self.h = v,
             0x0b => self.dl = v,
             0x0c => self.dh = v,
-            _ => panic!("No entry"),
+            _ => {}
         }
     }
 }
 
 impl Stable for RealTimeClock {
-    fn sav(&self) {
-        if self.sav_path.to_str().unwrap().is_empty() {
-            return;
+    fn sav(&self) -> std::io::Result<()> {
+        if self.sav_path.as_os_str().is_empty() {
+            return Ok(());
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.zero.to_be_bytes())).unwrap()
+        File::create(&self.sav_path)?.write_all(&self.zero.to_be_bytes())
     }
 }
 
@@ -415,7 +524,7 @@ impl Stable for RealTimeClock {
 // When accessing the RTC Registers it is recommended to execute a 4ms delay (4 Cycles in Normal Speed Mode) between
 // the separate accesses.
 pub struct Mbc3 {
-    rom: Vec<u8>,
+    rom: Rom,
     ram: Vec<u8>,
     rtc: RealTimeClock,
     rom_bank: usize,
@@ -425,7 +534,8 @@ pub struct Mbc3 {
 }
 
 impl Mbc3 {
-    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>, rtc: impl AsRef<Path>) -> Self {
+    pub fn power_up(rom: impl Into<Rom>, ram: Vec<u8>, sav: impl AsRef<Path>, rtc: impl AsRef<Path>) -> Self {
+        let rom = rom.into();
         Self {
             rom,
             ram,
@@ -441,37 +551,26 @@ impl Mbc3 {
 impl Memory for Mbc3 {
     fn get(&self, a: u16) -> u8 {
         match a {
-            0x0000..=0x3fff => self.rom[a as usize],
-            0x4000..=0x7fff => {
-                let i = self.rom_bank * 0x4000 + a as usize - 0x4000;
-                self.rom[i]
-            }
-            0xa000..=0xbfff => {
-                if self.ram_enable {
-                    if self.ram_bank <= 0x03 {
-                        let i = self.ram_bank * 0x2000 + a as usize - 0xa000;
-                        self.ram[i]
-                    } else {
-                        self.rtc.get(self.ram_bank as u16)
-                    }
+            0x0000..=0x3fff => bank_get(&self.rom, 0x4000, 0, a as usize),
+            0x4000..=0x7fff => bank_get(&self.rom, 0x4000, self.rom_bank, a as usize - 0x4000),
+            0xa000..=0xbfff if self.ram_enable => {
+                if self.ram_bank <= 0x03 {
+                    bank_get(&self.ram, 0x2000, self.ram_bank, a as usize - 0xa000)
                 } else {
-                    0x00
+                    self.rtc.get(self.ram_bank as u16)
                 }
             }
-            _ => 0x00,
+            _ => 0xff,
         }
     }
 
     fn set(&mut self, a: u16, v: u8) {
         match a {
-            0xa000..=0xbfff => {
-                if self.ram_enable {
-                    if self.ram_bank <= 0x03 {
-                        let i = self.ram_bank * 0x2000 + a as usize - 0xa000;
-                        self.ram[i] = v;
-                    } else {
-                        self.rtc.set(self.ram_bank as u16, v)
-                    }
+            0xa000..=0xbfff if self.ram_enable => {
+                if self.ram_bank <= 0x03 {
+                    bank_set(&mut self.ram, 0x2000, self.ram_bank, a as usize - 0xa000, v);
+                } else {
+                    self.rtc.set(self.ram_bank as u16, v)
                 }
             }
             0x0000..=0x1fff => {
@@ -489,75 +588,82 @@ impl Memory for Mbc3 {
                 let n = (v & 0x0f) as usize;
                 self.ram_bank = n;
             }
-            0x6000..=0x7fff => {
-                if v & 0x01 != 0 {
-                    self.rtc.tic();
-                }
-            }
+            0x6000..=0x7fff if v & 0x01 != 0 => self.rtc.tic(),
             _ => {}
         }
     }
 }
 
 impl Stable for Mbc3 {
-    fn sav(&self) {
+    fn sav(&self) -> std::io::Result<()> {
         rog::debugln!("Ram is being persisted");
-        self.rtc.sav();
-        if self.sav_path.to_str().unwrap().is_empty() {
-            return;
+        self.rtc.sav()?;
+        if self.sav_path.as_os_str().is_empty() {
+            return Ok(());
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap();
+        File::create(&self.sav_path)?.write_all(&self.ram)
     }
 }
 
+// The MBC5 maps up to 8MB of ROM (512 banks) and 128KB of RAM (16 banks). The carts with a rumble motor, types 1Ch-1Eh,
+// wire bit 3 of the RAM bank number to the motor instead, and have at most 8 banks of RAM:
+// 4000-5FFF - RAM Bank Number, bit 3 turns the motor on on a rumble cart (Write Only)
+// The games drive the motor in short pulses to set how hard it shakes.
 pub struct Mbc5 {
-    rom: Vec<u8>,
+    rom: Rom,
     ram: Vec<u8>,
     rom_bank: usize,
     ram_bank: usize,
     ram_enable: bool,
     sav_path: PathBuf,
+    // Whether the cart has a rumble motor, whether it is on and whether it ran since the last Cartridge::rumble.
+    rumble: bool,
+    motor: bool,
+    ran: bool,
 }
 
 impl Mbc5 {
-    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
-        Self { rom, ram, rom_bank: 1, ram_bank: 0, ram_enable: false, sav_path: PathBuf::from(sav.as_ref()) }
+    pub fn power_up(rom: impl Into<Rom>, ram: Vec<u8>, sav: impl AsRef<Path>, rumble: bool) -> Self {
+        let rom = rom.into();
+        Self {
+            rom,
+            ram,
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enable: false,
+            sav_path: PathBuf::from(sav.as_ref()),
+            rumble,
+            motor: false,
+            ran: false,
+        }
     }
 }
 
 impl Memory for Mbc5 {
     fn get(&self, a: u16) -> u8 {
         match a {
-            0x0000..=0x3fff => self.rom[a as usize],
-            0x4000..=0x7fff => {
-                let i = self.rom_bank * 0x4000 + a as usize - 0x4000;
-                self.rom[i]
-            }
-            0xa000..=0xbfff => {
-                if self.ram_enable {
-                    let i = self.ram_bank * 0x2000 + a as usize - 0xa000;
-                    self.ram[i]
-                } else {
-                    0x00
-                }
-            }
-            _ => 0x00,
+            0x0000..=0x3fff => bank_get(&self.rom, 0x4000, 0, a as usize),
+            0x4000..=0x7fff => bank_get(&self.rom, 0x4000, self.rom_bank, a as usize - 0x4000),
+            0xa000..=0xbfff if self.ram_enable => bank_get(&self.ram, 0x2000, self.ram_bank, a as usize - 0xa000),
+            _ => 0xff,
         }
     }
 
     fn set(&mut self, a: u16, v: u8) {
         match a {
-            0xa000..=0xbfff => {
-                if self.ram_enable {
-                    let i = self.ram_bank * 0x2000 + a as usize - 0xa000;
-                    self.ram[i] = v;
-                }
+            0xa000..=0xbfff if self.ram_enable => {
+                bank_set(&mut self.ram, 0x2000, self.ram_bank, a as usize - 0xa000, v);
             }
             0x0000..=0x1fff => {
                 self.ram_enable = v & 0x0f == 0x0a;
             }
             0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | (v as usize),
             0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0x0ff) | (((v & 0x01) as usize) << 8),
+            0x4000..=0x5fff if self.rumble => {
+                self.ram_bank = (v & 0x07) as usize;
+                self.motor = v & 0x08 == 0x08;
+                self.ran |= self.motor;
+            }
             0x4000..=0x5fff => self.ram_bank = (v & 0x0f) as usize,
             _ => {}
         }
@@ -565,12 +671,12 @@ impl Memory for Mbc5 {
 }
 
 impl Stable for Mbc5 {
-    fn sav(&self) {
+    fn sav(&self) -> std::io::Result<()> {
         rog::debugln!("Ram is being persisted");
-        if self.sav_path.to_str().unwrap().is_empty() {
-            return;
+        if self.sav_path.as_os_str().is_empty() {
+            return Ok(());
         }
-        File::create(self.sav_path.clone()).and_then(|mut f| f.write_all(&self.ram)).unwrap()
+        File::create(&self.sav_path)?.write_all(&self.ram)
     }
 }
 
@@ -582,7 +688,7 @@ pub struct HuC1 {
 }
 
 impl HuC1 {
-    pub fn power_up(rom: Vec<u8>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+    pub fn power_up(rom: impl Into<Rom>, ram: Vec<u8>, sav: impl AsRef<Path>) -> Self {
         Self { cart: Mbc1::power_up(rom, ram, sav) }
     }
 }
@@ -598,11 +704,275 @@ impl Memory for HuC1 {
 }
 
 impl Stable for HuC1 {
-    fn sav(&self) {
+    fn sav(&self) -> std::io::Result<()> {
         self.cart.sav()
     }
 }
 
+// The MBC7 has no RAM but a 93LC56 EEPROM of 256 bytes, and a two-axis accelerometer: Kirby Tilt 'n' Tumble is played
+// by tilting the console.
+// 0000-1FFF - RAM Enable 1, 0Ah enables
+// 2000-3FFF - ROM Bank Number
+// 4000-5FFF - RAM Enable 2, 40h enables
+// A000-AFFF - Registers, when both are enabled. Bits 4-7 of the address select the register:
+//   Ax0x  Write 55h to erase the latched accelerometer values
+//   Ax1x  Write AAh to latch the accelerometer, after an erase
+//   Ax2x  Accelerometer X, low byte
+//   Ax3x  Accelerometer X, high byte
+//   Ax4x  Accelerometer Y, low byte
+//   Ax5x  Accelerometer Y, high byte
+//   Ax6x  Always 00h
+//   Ax7x  Always FFh
+//   Ax8x  EEPROM: bit 7 CS, bit 6 CLK, bit 1 DI, bit 0 DO (read only)
+pub struct Mbc7 {
+    rom: Rom,
+    rom_bank: usize,
+    ram_enable: [bool; 2],
+    accelerometer: Accelerometer,
+    // The latched values, 8000h after an erase.
+    x: u16,
+    y: u16,
+    erased: bool,
+    eeprom: Eeprom,
+    sav_path: PathBuf,
+}
+
+impl Mbc7 {
+    pub fn power_up(rom: impl Into<Rom>, mut eeprom: Vec<u8>, sav: impl AsRef<Path>) -> Self {
+        let rom = rom.into();
+        eeprom.resize(256, 0xff);
+        Self {
+            rom,
+            rom_bank: 1,
+            ram_enable: [false; 2],
+            accelerometer: Accelerometer { x: 0.0, y: 0.0 },
+            x: 0x8000,
+            y: 0x8000,
+            erased: false,
+            eeprom: Eeprom::power_up(eeprom),
+            sav_path: PathBuf::from(sav.as_ref()),
+        }
+    }
+}
+
+impl Memory for Mbc7 {
+    fn get(&self, a: u16) -> u8 {
+        match a {
+            0x0000..=0x3fff => bank_get(&self.rom, 0x4000, 0, a as usize),
+            0x4000..=0x7fff => bank_get(&self.rom, 0x4000, self.rom_bank, a as usize - 0x4000),
+            0xa000..=0xafff if self.ram_enable == [true; 2] => match (a >> 4) & 0x0f {
+                0x02 => self.x as u8,
+                0x03 => (self.x >> 8) as u8,
+                0x04 => self.y as u8,
+                0x05 => (self.y >> 8) as u8,
+                0x06 => 0x00,
+                0x08 => self.eeprom.get(),
+                _ => 0xff,
+            },
+            _ => 0xff,
+        }
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000..=0x1fff => self.ram_enable[0] = v & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = v as usize,
+            0x4000..=0x5fff => self.ram_enable[1] = v == 0x40,
+            0xa000..=0xafff if self.ram_enable == [true; 2] => match (a >> 4) & 0x0f {
+                0x00 if v == 0x55 => {
+                    self.x = 0x8000;
+                    self.y = 0x8000;
+                    self.erased = true;
+                }
+                0x01 if v == 0xaa && self.erased => {
+                    (self.x, self.y) = self.accelerometer.read();
+                    self.erased = false;
+                }
+                0x08 => self.eeprom.set(v),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+impl Stable for Mbc7 {
+    fn sav(&self) -> std::io::Result<()> {
+        rog::debugln!("Ram is being persisted");
+        if self.sav_path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        File::create(&self.sav_path)?.write_all(&self.eeprom.data)
+    }
+}
+
+// The tilt of the console in g, set by the frontend. x grows when tilted to the right and y when tilted towards the
+// player.
+pub struct Accelerometer {
+    x: f32,
+    y: f32,
+}
+
+impl Accelerometer {
+    pub fn set(&mut self, x: f32, y: f32) {
+        self.x = x.clamp(-2.0, 2.0);
+        self.y = y.clamp(-2.0, 2.0);
+    }
+
+    // Lying flat reads 81D0h, and each g moves the value by about 70h.
+    fn read(&self) -> (u16, u16) {
+        let f = |v: f32| (f32::from(0x81d0u16) + v * f32::from(0x70u8)) as u16;
+        (f(self.x), f(self.y))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum EepromState {
+    Idle,
+    // Reading the opcode and the address.
+    Command,
+    // Sending the words from the address.
+    Read(u8),
+    // Reading the word to write at the address, or to all of them.
+    Write(u8),
+    WriteAll,
+}
+
+// A 93LC56 in its 16-bit organization: 128 words behind a serial protocol. A command is a start bit 1, a 2-bit opcode
+// and 8 address bits, of which the top one is ignored:
+//   10 READ    Send a dummy 0, then the word at the address, and the next ones for as long as the clock runs
+//   01 WRITE   Write the 16 bits that follow
+//   11 ERASE   Set the word to FFFFh
+//   00 11xxxxxx  EWEN  Enable writes and erases, they are disabled at power up
+//   00 00xxxxxx  EWDS  Disable them
+//   00 10xxxxxx  ERAL  Erase all the words
+//   00 01xxxxxx  WRAL  Write the 16 bits that follow to all the words
+// Bits are shifted on the rising edge of the clock while CS is high. Writes complete at once, DO then reads 1: ready.
+struct Eeprom {
+    // The words in little endian, as in the save files of other emulators.
+    data: Vec<u8>,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    dout: bool,
+    write_enable: bool,
+    state: EepromState,
+    // Bits shifted in since the start bit or out since the last word, and their count.
+    sr: u16,
+    bits: u8,
+}
+
+impl Eeprom {
+    fn power_up(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            cs: false,
+            clk: false,
+            di: false,
+            dout: true,
+            write_enable: false,
+            state: EepromState::Idle,
+            sr: 0,
+            bits: 0,
+        }
+    }
+
+    fn word(&self, addr: u8) -> u16 {
+        let i = usize::from(addr & 0x7f) * 2;
+        u16::from_le_bytes([self.data[i], self.data[i + 1]])
+    }
+
+    fn set_word(&mut self, addr: u8, v: u16) {
+        if self.write_enable {
+            let i = usize::from(addr & 0x7f) * 2;
+            self.data[i..i + 2].copy_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    fn get(&self) -> u8 {
+        let b = |v: bool, n: u8| u8::from(v) << n;
+        b(self.cs, 7) | b(self.clk, 6) | b(self.di, 1) | b(self.dout, 0)
+    }
+
+    fn set(&mut self, v: u8) {
+        let cs = v & 0x80 != 0x00;
+        let clk = v & 0x40 != 0x00;
+        let di = v & 0x02 != 0x00;
+        if !cs {
+            self.state = EepromState::Idle;
+            self.dout = true;
+        } else if self.cs && clk && !self.clk {
+            self.rise(di);
+        }
+        self.cs = cs;
+        self.clk = clk;
+        self.di = di;
+    }
+
+    fn rise(&mut self, di: bool) {
+        match self.state {
+            EepromState::Idle => {
+                if di {
+                    self.state = EepromState::Command;
+                    self.sr = 0;
+                    self.bits = 0;
+                }
+            }
+            EepromState::Command => {
+                self.sr = (self.sr << 1) | u16::from(di);
+                self.bits += 1;
+                if self.bits == 10 {
+                    self.command();
+                }
+            }
+            EepromState::Read(addr) => {
+                self.dout = self.sr & 0x8000 != 0x0000;
+                self.sr <<= 1;
+                self.bits += 1;
+                if self.bits == 16 {
+                    let addr = addr.wrapping_add(1) & 0x7f;
+                    self.state = EepromState::Read(addr);
+                    self.sr = self.word(addr);
+                    self.bits = 0;
+                }
+            }
+            EepromState::Write(_) | EepromState::WriteAll => {
+                self.sr = (self.sr << 1) | u16::from(di);
+                self.bits += 1;
+                if self.bits == 16 {
+                    match self.state {
+                        EepromState::Write(addr) => self.set_word(addr, self.sr),
+                        _ => (0..128).for_each(|addr| self.set_word(addr, self.sr)),
+                    }
+                    self.state = EepromState::Idle;
+                    self.dout = true;
+                }
+            }
+        }
+    }
+
+    fn command(&mut self) {
+        let opcode = (self.sr >> 8) & 0x03;
+        let addr = self.sr as u8;
+        self.state = EepromState::Idle;
+        self.sr = 0;
+        self.bits = 0;
+        match (opcode, addr >> 6) {
+            (0b10, _) => {
+                self.dout = false;
+                self.sr = self.word(addr);
+                self.state = EepromState::Read(addr & 0x7f);
+            }
+            (0b01, _) => self.state = EepromState::Write(addr),
+            (0b11, _) => self.set_word(addr, 0xffff),
+            (_, 0b11) => self.write_enable = true,
+            (_, 0b00) => self.write_enable = false,
+            (_, 0b10) => (0..128).for_each(|addr| self.set_word(addr, 0xffff)),
+            (_, _) => self.state = EepromState::WriteAll,
+        }
+    }
+}
+
 // Specifies which Memory Bank Controller (if any) is used in the cartridge, and if further external hardware exists in
 // the cartridge.
 //  00h  ROM ONLY                 19h  MBC5
@@ -621,29 +991,46 @@ impl Stable for HuC1 {
 //  11h  MBC3                     FDh  BANDAI TAMA5
 //  12h  MBC3+RAM                 FEh  HuC3
 //  13h  MBC3+RAM+BATTERY         FFh  HuC1+RAM+BATTERY
-pub fn power_up(path: impl AsRef<Path>) -> Box<dyn Cartridge> {
+pub fn power_up(path: impl AsRef<Path>) -> error::Result<Box<dyn Cartridge>> {
     rog::debugln!("Loading cartridge from {:?}", path.as_ref());
-    let mut f = File::open(path.as_ref()).unwrap();
-    let mut rom = Vec::new();
-    f.read_to_end(&mut rom).unwrap();
+    let rom = std::fs::read(path.as_ref())?;
+    power_up_from(Rom::from(rom), path)
+}
+
+// Like power_up, with the rom mapped read-only from its file rather than read into memory, see rom::Rom. Without the
+// mmap feature, or off unix, the rom is read.
+pub fn power_up_mapped(path: impl AsRef<Path>) -> error::Result<Box<dyn Cartridge>> {
+    rog::debugln!("Mapping cartridge from {:?}", path.as_ref());
+    let rom = Rom::map(path.as_ref())?;
+    power_up_from(rom, path)
+}
+
+// Load a cartridge from a rom already in memory, e.g. one picked by the user in a browser. There is no file next to it,
+// so the battery backed RAM and clock start empty and are never persisted.
+pub fn power_up_rom(rom: Vec<u8>) -> error::Result<Box<dyn Cartridge>> {
+    power_up_from(Rom::from(rom), "")
+}
+
+// The save files are looked up next to path. An empty path disables them.
+fn power_up_from(rom: Rom, path: impl AsRef<Path>) -> error::Result<Box<dyn Cartridge>> {
     if rom.len() < 0x150 {
-        panic!("Missing required information area which located at 0100-014F")
+        return Err(Error::Rom(String::from("missing required information area which located at 0100-014F")));
     }
-    let rom_max = rom_size(rom[0x0148]);
+    let rom_max = rom_size(rom[0x0148])?;
     if rom.len() > rom_max {
-        panic!("Rom size more than {}", rom_max);
+        return Err(Error::Rom(format!("rom size more than {}", rom_max)));
     }
     let cart: Box<dyn Cartridge> = match rom[0x0147] {
         0x00 => Box::new(RomOnly::power_up(rom)),
         0x01 => Box::new(Mbc1::power_up(rom, vec![], "")),
         0x02 => {
-            let ram_max = ram_size(rom[0x0149]);
+            let ram_max = ram_size(rom[0x0149])?;
             Box::new(Mbc1::power_up(rom, vec![0; ram_max], ""))
         }
         0x03 => {
-            let ram_max = ram_size(rom[0x0149]);
+            let ram_max = ram_size(rom[0x0149])?;
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
-            let ram = ram_read(sav_path.clone(), ram_max);
+            let ram = ram_read(sav_path.clone(), ram_max)?;
             Box::new(Mbc1::power_up(rom, ram, sav_path))
         }
         0x05 => {
@@ -653,7 +1040,7 @@ pub fn power_up(path: impl AsRef<Path>) -> Box<dyn Cartridge> {
         0x06 => {
             let ram_max = 512;
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
-            let ram = ram_read(sav_path.clone(), ram_max);
+            let ram = ram_read(sav_path.clone(), ram_max)?;
             Box::new(Mbc2::power_up(rom, ram, sav_path))
         }
         0x0f => {
@@ -662,53 +1049,78 @@ pub fn power_up(path: impl AsRef<Path>) -> Box<dyn Cartridge> {
             Box::new(Mbc3::power_up(rom, vec![], sav_path, rtc_path))
         }
         0x10 => {
-            let ram_max = ram_size(rom[0x0149]);
+            let ram_max = ram_size(rom[0x0149])?;
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
-            let ram = ram_read(sav_path.clone(), ram_max);
+            let ram = ram_read(sav_path.clone(), ram_max)?;
             let rtc_path = path.as_ref().to_path_buf().with_extension("rtc");
             Box::new(Mbc3::power_up(rom, ram, sav_path, rtc_path))
         }
         0x11 => Box::new(Mbc3::power_up(rom, vec![], "", "")),
         0x12 => {
-            let ram_max = ram_size(rom[0x0149]);
+            let ram_max = ram_size(rom[0x0149])?;
             Box::new(Mbc3::power_up(rom, vec![0; ram_max], "", ""))
         }
         0x13 => {
-            let ram_max = ram_size(rom[0x0149]);
+            let ram_max = ram_size(rom[0x0149])?;
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
-            let ram = ram_read(sav_path.clone(), ram_max);
+            let ram = ram_read(sav_path.clone(), ram_max)?;
             Box::new(Mbc3::power_up(rom, ram, sav_path, ""))
         }
-        0x19 => Box::new(Mbc5::power_up(rom, vec![], "")),
-        0x1a => {
-            let ram_max = ram_size(rom[0x0149]);
-            Box::new(Mbc5::power_up(rom, vec![0; ram_max], ""))
+        0x19 | 0x1c => {
+            let rumble = rom[0x0147] == 0x1c;
+            Box::new(Mbc5::power_up(rom, vec![], "", rumble))
+        }
+        0x1a | 0x1d => {
+            let rumble = rom[0x0147] == 0x1d;
+            let ram_max = ram_size(rom[0x0149])?;
+            Box::new(Mbc5::power_up(rom, vec![0; ram_max], "", rumble))
         }
-        0x1b => {
-            let ram_max = ram_size(rom[0x0149]);
+        0x1b | 0x1e => {
+            let rumble = rom[0x0147] == 0x1e;
+            let ram_max = ram_size(rom[0x0149])?;
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
-            let ram = ram_read(sav_path.clone(), ram_max);
-            Box::new(Mbc5::power_up(rom, ram, sav_path))
+            let ram = ram_read(sav_path.clone(), ram_max)?;
+            Box::new(Mbc5::power_up(rom, ram, sav_path, rumble))
+        }
+        0x22 => {
+            // A blank EEPROM reads FFh.
+            let sav_path = path.as_ref().to_path_buf().with_extension("sav");
+            let eeprom = std::fs::read(&sav_path).unwrap_or_default();
+            Box::new(Mbc7::power_up(rom, eeprom, sav_path))
         }
         0xff => {
-            let ram_max = ram_size(rom[0x0149]);
+            let ram_max = ram_size(rom[0x0149])?;
             let sav_path = path.as_ref().to_path_buf().with_extension("sav");
-            let ram = ram_read(sav_path.clone(), ram_max);
+            let ram = ram_read(sav_path.clone(), ram_max)?;
             Box::new(HuC1::power_up(rom, ram, sav_path))
         }
-        n => panic!("Unsupported cartridge type: 0x{:02x}", n),
+        n => return Err(Error::Rom(format!("unsupported cartridge type: 0x{:02x}", n))),
     };
     rog::debugln!("Cartridge name is {}", cart.title());
     rog::debugln!("Cartridge type is {}", mbc_info(cart.get(0x0147)));
-    ensure_logo(cart.as_ref());
-    ensure_header_checksum(cart.as_ref());
-    cart
+    rog::debugln!("Cartridge digest is {}", Digest::of(cart.rom()));
+    ensure_logo(cart.as_ref())?;
+    ensure_header_checksum(cart.as_ref())?;
+    Ok(cart)
+}
+
+// Load a file as a plain 32KB ROM without looking at its header, for test harnesses and homebrew that are raw code
+// blobs or carry a dummy header. Smaller files are padded with zeros, the part of bigger files past 7FFF is not
+// mapped.
+pub fn power_up_raw(path: impl AsRef<Path>) -> error::Result<Box<dyn Cartridge>> {
+    rog::debugln!("Loading raw rom from {:?}", path.as_ref());
+    let mut rom = std::fs::read(path.as_ref())?;
+    if rom.len() > 0x8000 {
+        rog::debugln!("Rom size {} more than 32768, the rest is not mapped", rom.len());
+    }
+    rom.resize(0x8000, 0x00);
+    Ok(Box::new(RomOnly::power_up(rom)))
 }
 
 // Specifies the ROM Size of the cartridge. Typically calculated as "32KB shl N".
-fn rom_size(b: u8) -> usize {
+fn rom_size(b: u8) -> error::Result<usize> {
     let bank = 16384;
-    match b {
+    Ok(match b {
         0x00 => bank * 2,
         0x01 => bank * 4,
         0x02 => bank * 8,
@@ -721,37 +1133,34 @@ fn rom_size(b: u8) -> usize {
         0x52 => bank * 72,
         0x53 => bank * 80,
         0x54 => bank * 96,
-        n => panic!("Unsupported rom size: 0x{:02x}", n),
-    }
+        n => return Err(Error::Rom(format!("unsupported rom size: 0x{:02x}", n))),
+    })
 }
 
 // Specifies the size of the external RAM in the cartridge (if any).
-fn ram_size(b: u8) -> usize {
-    match b {
+fn ram_size(b: u8) -> error::Result<usize> {
+    Ok(match b {
         0x00 => 0,
         0x01 => 1024 * 2,
         0x02 => 1024 * 8,
         0x03 => 1024 * 32,
         0x04 => 1024 * 128,
         0x05 => 1024 * 64,
-        n => panic!("Unsupported ram size: 0x{:02x}", n),
-    }
+        n => return Err(Error::Rom(format!("unsupported ram size: 0x{:02x}", n))),
+    })
 }
 
 // Specifies the size of the external RAM in the cartridge (if any).
-fn ram_read(path: impl AsRef<Path>, size: usize) -> Vec<u8> {
-    match File::open(path) {
-        Ok(mut ok) => {
-            let mut ram = Vec::new();
-            ok.read_to_end(&mut ram).unwrap();
-            ram
-        }
-        Err(_) => vec![0; size],
+fn ram_read(path: impl AsRef<Path>, size: usize) -> error::Result<Vec<u8>> {
+    match std::fs::read(path) {
+        Ok(ram) => Ok(ram),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![0; size]),
+        Err(e) => Err(e.into()),
     }
 }
 
 // Readable form of MBC representation
-fn mbc_info(b: u8) -> String {
+pub fn mbc_info(b: u8) -> String {
     String::from(match b {
         0x00 => "ROM ONLY",
         0x01 => "MBC1",
@@ -778,11 +1187,12 @@ fn mbc_info(b: u8) -> String {
         0x1c => "MBC5+RUMBLE",
         0x1d => "MBC5+RUMBLE+RAM",
         0x1e => "MBC5+RUMBLE+RAM+BATTERY",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
         0xfc => "POCKET CAMERA",
         0xfd => "BANDAI TAMA5",
         0xfe => "HuC3",
-        0x1f => "HuC1+RAM+BATTERY",
-        n => panic!("Unsupported cartridge type: 0x{:02x}", n),
+        0xff => "HuC1+RAM+BATTERY",
+        _ => "UNKNOWN",
     })
 }
 
@@ -791,19 +1201,20 @@ fn mbc_info(b: u8) -> String {
 // which infringes the trademark law. In the early days, the copyright law is not perfect for the determination of
 // electronic data.
 // The hexdump of this bitmap is:
-const NINTENDO_LOGO: [u8; 48] = [
+pub const NINTENDO_LOGO: [u8; 48] = [
     0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11,
     0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E,
     0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
 ];
 
 // Ensure Nintendo Logo.
-fn ensure_logo(cart: &dyn Cartridge) {
+fn ensure_logo(cart: &dyn Cartridge) -> error::Result<()> {
     for i in 0..48 {
         if cart.get(0x0104 + i as u16) != NINTENDO_LOGO[i as usize] {
-            panic!("Nintendo logo is incorrect")
+            return Err(Error::Rom(String::from("the Nintendo logo is incorrect")));
         }
     }
+    Ok(())
 }
 
 // In position 0x14d, contains an 8 bit checksum across the cartridge header bytes 0134-014C. The checksum is
@@ -813,17 +1224,21 @@ fn ensure_logo(cart: &dyn Cartridge) {
 //
 // The lower 8 bits of the result must be the same than the value in this entry. The GAME WON'T WORK if this
 // checksum is incorrect.
-fn ensure_header_checksum(cart: &dyn Cartridge) {
-    let mut v: u8 = 0;
-    for i in 0x0134..0x014d {
-        v = v.wrapping_sub(cart.get(i)).wrapping_sub(1);
-    }
-    if cart.get(0x014d) != v {
-        panic!("Cartridge's header checksum is incorrect")
+fn ensure_header_checksum(cart: &dyn Cartridge) -> error::Result<()> {
+    // Read through the mapper, which may show another bank than the start of the rom, e.g. on multicarts.
+    let header: Vec<u8> = (0x0000..0x0150).map(|a| cart.get(a)).collect();
+    if cart.get(0x014d) != header_checksum(&header) {
+        return Err(Error::Rom(String::from("header checksum is incorrect")));
     }
+    Ok(())
+}
+
+// The checksum of the header of a rom, e.g. to make a rom which passes the check above.
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..0x014d].iter().fold(0u8, |v, &b| v.wrapping_sub(b).wrapping_sub(1))
 }
 
-pub trait Cartridge: Memory + Stable + Send {
+pub trait Cartridge: Memory + Stable + Snapshot + Send {
     // Title of the game in UPPER CASE ASCII. If it is less than 16 characters then the remaining bytes are filled with
     // 00's. When inventing the CGB, Nintendo has reduced the length of this area to 15 characters, and some months
     // later they had the fantastic idea to reduce it to 11 characters only. The new meaning of the ex-title bytes is
@@ -840,11 +1255,366 @@ pub trait Cartridge: Memory + Stable + Send {
         }
         buf
     }
+
+    // The sum of the 16 bytes of the title, by which the CGB boot ROM recognizes the games licensed by Nintendo, None
+    // for the games of other licensees.
+    fn title_sum(&self) -> Option<u8> {
+        let nintendo = match self.get(0x014b) {
+            0x01 => true,
+            0x33 => self.get(0x0144) == b'0' && self.get(0x0145) == b'1',
+            _ => false,
+        };
+        nintendo.then(|| (0x0134..=0x0143).fold(0u8, |s, a| s.wrapping_add(self.get(a))))
+    }
+
+    // The whole ROM as dumped, e.g. to verify the dump.
+    fn rom(&self) -> &[u8];
+
+    // The real time clock of the cartridge, if it has one.
+    fn rtc(&mut self) -> Option<&mut RealTimeClock> {
+        None
+    }
+
+    // The accelerometer of the cartridge, if it has one.
+    fn accelerometer(&mut self) -> Option<&mut Accelerometer> {
+        None
+    }
+
+    // The ROM bank mapped at 0000-3FFF.
+    fn rom_bank_0(&self) -> usize {
+        0
+    }
+
+    // The ROM bank mapped at 4000-7FFF.
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    // The RAM bank mapped at A000-BFFF, if the cartridge has RAM there. Banks 08-0C of the MBC3 are the registers of
+    // its clock.
+    fn ram_bank(&self) -> Option<usize> {
+        None
+    }
+
+    // Whether the rumble motor ran since the last call, if the cartridge has one. The motor is driven in pulses
+    // shorter than a frame, a frontend calls this once a frame.
+    fn rumble(&mut self) -> Option<bool> {
+        None
+    }
+
+    // The whole RAM, all banks, as the battery keeps it. The EEPROM of the MBC7 isn't mapped and isn't part of it.
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+}
+
+// The ROM is not part of the state, only the RAM and the registers of the memory bank controller.
+impl Snapshot for RomOnly {
+    fn save(&self, _: &mut Writer) {}
+
+    fn load(&mut self, _: &mut Reader) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Snapshot for Mbc1 {
+    fn save(&self, w: &mut Writer) {
+        w.vec(&self.ram);
+        w.bool(matches!(self.bank_mode, BankMode::Ram));
+        w.u8(self.bank);
+        w.bool(self.ram_enable);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        r.vec(&mut self.ram)?;
+        self.bank_mode = if r.bool()? { BankMode::Ram } else { BankMode::Rom };
+        self.bank = r.u8()?;
+        self.ram_enable = r.bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Mbc2 {
+    fn save(&self, w: &mut Writer) {
+        w.vec(&self.ram);
+        w.u16(self.rom_bank as u16);
+        w.bool(self.ram_enable);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        r.vec(&mut self.ram)?;
+        self.rom_bank = usize::from(r.u16()?);
+        self.ram_enable = r.bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for RealTimeClock {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.s);
+        w.u8(self.m);
+        w.u8(self.h);
+        w.u8(self.dl);
+        w.u8(self.dh);
+        // The start of the clock in host time, whatever drives it, and the clock cycles it counted.
+        w.u64(now().saturating_sub(self.elapsed()));
+        w.u64(self.cycles.unwrap_or(self.elapsed() * u64::from(CLOCK_FREQUENCY)));
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.s = r.u8()?;
+        self.m = r.u8()?;
+        self.h = r.u8()?;
+        self.dl = r.u8()?;
+        self.dh = r.u8()?;
+        let zero = r.u64()?;
+        let cycles = r.u64()?;
+        match (self.sync, &mut self.cycles) {
+            (RtcSync::RealTime, _) => self.set_elapsed(now().saturating_sub(zero)),
+            (RtcSync::Emulated, Some(c)) => *c = cycles,
+            (RtcSync::Emulated, None) => self.set_elapsed(cycles / u64::from(CLOCK_FREQUENCY)),
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot for Mbc3 {
+    fn save(&self, w: &mut Writer) {
+        w.vec(&self.ram);
+        self.rtc.save(w);
+        w.u16(self.rom_bank as u16);
+        w.u16(self.ram_bank as u16);
+        w.bool(self.ram_enable);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        r.vec(&mut self.ram)?;
+        self.rtc.load(r)?;
+        self.rom_bank = usize::from(r.u16()?);
+        self.ram_bank = usize::from(r.u16()?);
+        self.ram_enable = r.bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Mbc5 {
+    fn save(&self, w: &mut Writer) {
+        w.vec(&self.ram);
+        w.u16(self.rom_bank as u16);
+        w.u16(self.ram_bank as u16);
+        w.bool(self.ram_enable);
+        w.bool(self.motor);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        r.vec(&mut self.ram)?;
+        self.rom_bank = usize::from(r.u16()?);
+        self.ram_bank = usize::from(r.u16()?);
+        self.ram_enable = r.bool()?;
+        self.motor = r.bool()?;
+        self.ran = self.motor;
+        Ok(())
+    }
+}
+
+impl Snapshot for HuC1 {
+    fn save(&self, w: &mut Writer) {
+        self.cart.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.cart.load(r)
+    }
 }
 
-impl Cartridge for RomOnly {}
-impl Cartridge for Mbc1 {}
-impl Cartridge for Mbc2 {}
-impl Cartridge for Mbc3 {}
-impl Cartridge for Mbc5 {}
-impl Cartridge for HuC1 {}
+impl Snapshot for Eeprom {
+    fn save(&self, w: &mut Writer) {
+        w.vec(&self.data);
+        w.bool(self.cs);
+        w.bool(self.clk);
+        w.bool(self.di);
+        w.bool(self.dout);
+        w.bool(self.write_enable);
+        let (state, addr) = match self.state {
+            EepromState::Idle => (0, 0),
+            EepromState::Command => (1, 0),
+            EepromState::Read(addr) => (2, addr),
+            EepromState::Write(addr) => (3, addr),
+            EepromState::WriteAll => (4, 0),
+        };
+        w.u8(state);
+        w.u8(addr);
+        w.u16(self.sr);
+        w.u8(self.bits);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        r.vec(&mut self.data)?;
+        self.cs = r.bool()?;
+        self.clk = r.bool()?;
+        self.di = r.bool()?;
+        self.dout = r.bool()?;
+        self.write_enable = r.bool()?;
+        let state = r.u8()?;
+        let addr = r.u8()?;
+        self.state = match state {
+            1 => EepromState::Command,
+            2 => EepromState::Read(addr),
+            3 => EepromState::Write(addr),
+            4 => EepromState::WriteAll,
+            _ => EepromState::Idle,
+        };
+        self.sr = r.u16()?;
+        self.bits = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Mbc7 {
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.rom_bank as u16);
+        w.bool(self.ram_enable[0]);
+        w.bool(self.ram_enable[1]);
+        w.u16(self.x);
+        w.u16(self.y);
+        w.bool(self.erased);
+        self.eeprom.save(w);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.rom_bank = usize::from(r.u16()?);
+        self.ram_enable[0] = r.bool()?;
+        self.ram_enable[1] = r.bool()?;
+        self.x = r.u16()?;
+        self.y = r.u16()?;
+        self.erased = r.bool()?;
+        self.eeprom.load(r)
+    }
+}
+
+impl Cartridge for RomOnly {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+}
+
+impl Cartridge for Mbc1 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn rom_bank_0(&self) -> usize {
+        Mbc1::rom_bank_0(self)
+    }
+
+    fn rom_bank(&self) -> usize {
+        Mbc1::rom_bank(self)
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        (!self.ram.is_empty()).then(|| Mbc1::ram_bank(self))
+    }
+}
+
+impl Cartridge for Mbc2 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank
+    }
+
+    // The built-in RAM is not banked.
+    fn ram_bank(&self) -> Option<usize> {
+        Some(0)
+    }
+}
+
+impl Cartridge for Mbc3 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn rtc(&mut self) -> Option<&mut RealTimeClock> {
+        Some(&mut self.rtc)
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        (!self.ram.is_empty() || self.ram_bank >= 0x08).then_some(self.ram_bank)
+    }
+}
+
+impl Cartridge for Mbc5 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rumble(&mut self) -> Option<bool> {
+        self.rumble.then(|| std::mem::replace(&mut self.ran, self.motor))
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        (!self.ram.is_empty()).then_some(self.ram_bank)
+    }
+}
+
+impl Cartridge for Mbc7 {
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn accelerometer(&mut self) -> Option<&mut Accelerometer> {
+        Some(&mut self.accelerometer)
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.rom_bank
+    }
+}
+
+impl Cartridge for HuC1 {
+    fn rom(&self) -> &[u8] {
+        Cartridge::rom(&self.cart)
+    }
+
+    fn ram(&self) -> &[u8] {
+        Cartridge::ram(&self.cart)
+    }
+
+    fn rom_bank_0(&self) -> usize {
+        Cartridge::rom_bank_0(&self.cart)
+    }
+
+    fn rom_bank(&self) -> usize {
+        Cartridge::rom_bank(&self.cart)
+    }
+
+    fn ram_bank(&self) -> Option<usize> {
+        Cartridge::ram_bank(&self.cart)
+    }
+}
@@ -1,18 +1,154 @@
-// Clock is outputed 1 cycle every N cycles.
-pub struct Clock {
-    pub period: u32,
-    pub n: u32,
-}
-
-impl Clock {
-    pub fn power_up(period: u32) -> Self {
-        Self { period, n: 0x00 }
-    }
-
-    pub fn next(&mut self, cycles: u32) -> u32 {
-        self.n += cycles;
-        let rs = self.n / self.period;
-        self.n = self.n % self.period;
-        rs
-    }
-}
+// Every component clocked by time rather than by another unit takes its ticks from the Scheduler, the single tick
+// source of the machine. Mmunit::next advances it with the cycles the CPU spent, then each component takes the ticks
+// of the source it registered:
+//
+//   Source           Domain   Period
+//   DIV              CPU      256
+//   TIMA             CPU      16, 64, 256 or 1024 (TAC)
+//   Serial           CPU      1
+//   PPU dots         Fixed    1
+//   Frame sequencer  Fixed    8192 (512 Hz)
+//
+// The CPU domain runs twice as fast in CGB double speed mode, the fixed domain always runs at 4.194304 MHz. The
+// counters of all sources live here, so they are saved and restored in one place.
+use super::state::{Reader, Snapshot, Writer};
+use std::io::{Error, ErrorKind};
+
+// Clock is outputed 1 cycle every N cycles.
+pub struct Clock {
+    pub period: u32,
+    pub n: u32,
+}
+
+impl Clock {
+    pub fn power_up(period: u32) -> Self {
+        Self { period, n: 0x00 }
+    }
+
+    pub fn next(&mut self, cycles: u32) -> u32 {
+        self.n += cycles;
+        let rs = self.n / self.period;
+        self.n %= self.period;
+        rs
+    }
+}
+
+impl Snapshot for Clock {
+    fn save(&self, w: &mut Writer) {
+        w.u32(self.period);
+        w.u32(self.n);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.period = r.u32()?;
+        self.n = r.u32()?;
+        if self.period == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "State: clock with a period of 0"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tick {
+    Div,
+    Tima,
+    Serial,
+    Dot,
+    FrameSequencer,
+}
+
+const TICKS: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Domain {
+    // The clock of the CPU, doubled in CGB double speed mode.
+    Cpu,
+    // 4.194304 MHz whatever the speed of the CPU.
+    Fixed,
+}
+
+struct Source {
+    domain: Domain,
+    clock: Clock,
+    // A disabled source doesn't count, and keeps its phase until it is enabled again.
+    enabled: bool,
+    // Ticks not taken yet by the component.
+    ticks: u32,
+}
+
+pub struct Scheduler {
+    // Clock cycles of the fixed domain since power up.
+    pub now: u64,
+    sources: [Source; TICKS],
+}
+
+impl Scheduler {
+    pub fn power_up() -> Self {
+        let source = || Source { domain: Domain::Fixed, clock: Clock::power_up(1), enabled: false, ticks: 0 };
+        Self { now: 0, sources: [source(), source(), source(), source(), source()] }
+    }
+
+    // Enable a source with a period in cycles of its domain, starting a new period.
+    pub fn register(&mut self, tick: Tick, domain: Domain, period: u32) {
+        self.sources[tick as usize] = Source { domain, clock: Clock::power_up(period), enabled: true, ticks: 0 };
+    }
+
+    // Change the period of a source and start a new period.
+    pub fn set_period(&mut self, tick: Tick, period: u32) {
+        let source = &mut self.sources[tick as usize];
+        source.clock = Clock::power_up(period);
+    }
+
+    // Start a new period, as when a divider is reset.
+    pub fn reset(&mut self, tick: Tick) {
+        self.sources[tick as usize].clock.n = 0;
+    }
+
+    pub fn enable(&mut self, tick: Tick, enabled: bool) {
+        self.sources[tick as usize].enabled = enabled;
+    }
+
+    // Advance by the given cycles of the fixed domain, with the CPU running at speed times that rate.
+    pub fn next(&mut self, cycles: u32, speed: u32) {
+        self.now += u64::from(cycles);
+        for source in self.sources.iter_mut().filter(|s| s.enabled) {
+            let cycles = match source.domain {
+                Domain::Cpu => cycles * speed,
+                Domain::Fixed => cycles,
+            };
+            source.ticks += source.clock.next(cycles);
+        }
+    }
+
+    // The ticks of a source since they were last taken.
+    pub fn take(&mut self, tick: Tick) -> u32 {
+        std::mem::take(&mut self.sources[tick as usize].ticks)
+    }
+}
+
+// The ticks are always taken within Mmunit::next, so there are none left to save. The domains are fixed by the
+// components and not saved either.
+impl Snapshot for Scheduler {
+    fn save(&self, w: &mut Writer) {
+        w.u64(self.now);
+        w.u8(TICKS as u8);
+        for source in &self.sources {
+            source.clock.save(w);
+            w.bool(source.enabled);
+        }
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.now = r.u64()?;
+        if usize::from(r.u8()?) != TICKS {
+            return Err(Error::new(ErrorKind::InvalidData, "State: unknown tick sources"));
+        }
+        for source in &mut self.sources {
+            source.clock.load(r)?;
+            source.enabled = r.bool()?;
+            source.ticks = 0;
+        }
+        Ok(())
+    }
+}
@@ -5,3 +5,70 @@ pub enum Term {
     GBC, // GameBoy Color
     SGB, // Super GameBoy
 }
+
+impl std::str::FromStr for Term {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dmg" | "gb" => Ok(Term::GB),
+            "mgb" | "pocket" => Ok(Term::GBP),
+            "cgb" | "gbc" => Ok(Term::GBC),
+            "sgb" => Ok(Term::SGB),
+            _ => Err(format!("Unsupported model: {}", s)),
+        }
+    }
+}
+
+// The chip revision of the console. Revisions of the same model run the same games, but differ in a handful of
+// details some test ROMs and demos depend on. Only the revisions whose differences are emulated are listed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Revision {
+    Dmg0, // DMG-CPU, the very first Japanese units
+    DmgB, // DMG-CPU B, the most common original GameBoy
+    CgbE, // CGB-CPU E, the last GameBoy Color revision
+}
+
+impl Revision {
+    // The model the chip is in. A revision is only emulated on its own model.
+    pub fn model(self) -> Term {
+        match self {
+            Revision::Dmg0 | Revision::DmgB => Term::GB,
+            Revision::CgbE => Term::GBC,
+        }
+    }
+
+    // Value of DIV when the boot ROM hands over to the cartridge. The CGB boot ROM takes a variable time depending
+    // on the cartridge header, it is left at zero there.
+    pub fn div(self) -> u8 {
+        match self {
+            Revision::Dmg0 => 0x18,
+            Revision::DmgB => 0xab,
+            Revision::CgbE => 0x00,
+        }
+    }
+
+    // Writing to OAM while the PPU is searching it corrupts a row of sprites. Fixed in the CGB.
+    pub fn oam_bug(self) -> bool {
+        matches!(self, Revision::Dmg0 | Revision::DmgB)
+    }
+
+    // The DMG keeps the length counters when the APU is powered off, and they can still be written. The CGB clears
+    // them.
+    pub fn apu_keeps_length(self) -> bool {
+        matches!(self, Revision::Dmg0 | Revision::DmgB)
+    }
+}
+
+impl std::str::FromStr for Revision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dmg0" => Ok(Revision::Dmg0),
+            "dmgb" | "dmg" => Ok(Revision::DmgB),
+            "cgbe" | "cgb" => Ok(Revision::CgbE),
+            _ => Err(format!("Unsupported revision: {}", s)),
+        }
+    }
+}
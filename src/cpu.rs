@@ -3,14 +3,14 @@ use super::convention::Term;
 use super::memory::Memory;
 use super::register::Flag::{C, H, N, Z};
 use super::register::Register;
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::thread;
+use super::state::{Reader, Snapshot, Writer};
+use super::watch::{Hit, Watched, Watchpoint};
+use std::io::Write;
 use std::time;
 
 pub const CLOCK_FREQUENCY: u32 = 4_194_304;
-pub const STEP_TIME: u32 = 16;
-pub const STEP_CYCLES: u32 = (STEP_TIME as f64 / (1000_f64 / CLOCK_FREQUENCY as f64)) as u32;
+// The speed limiter sleeps once per frame by default, 154 scanlines of 456 dots: the pace of the LCD, 59.73 Hz.
+pub const STEP_CYCLES: u32 = 70224;
 
 // Nintendo documents describe the CPU & instructions speed in machine cycles while this document describes them in
 // clock cycles. Here is the translation:
@@ -20,7 +20,7 @@ pub const STEP_CYCLES: u32 = (STEP_TIME as f64 / (1000_f64 / CLOCK_FREQUENCY as
 // Clock Cycles      4.19MHz         4 cycles
 //
 //  0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
-const OP_CYCLES: [u32; 256] = [
+pub const OP_CYCLES: [u32; 256] = [
     1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1, // 0
     0, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1, // 1
     2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1, // 2
@@ -40,7 +40,7 @@ const OP_CYCLES: [u32; 256] = [
 ];
 
 //  0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
-const CB_CYCLES: [u32; 256] = [
+pub const CB_CYCLES: [u32; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, // 0
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, // 1
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, // 2
@@ -59,36 +59,229 @@ const CB_CYCLES: [u32; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2, // f
 ];
 
+// Called before each instruction is executed, e.g. by the debugger, with the bus the CPU runs on.
+pub type Hook = Box<dyn FnMut(&mut Cpu, &mut dyn Memory) + Send>;
+
+// Called before the instruction at an address is executed, see MotherBoard::on_pc.
+pub type PcHook = Box<dyn FnMut(&Cpu) + Send>;
+
+// The length of each instruction with its operands, by opcode, 1 for the opcodes which don't exist. The block cache
+// decodes the rom with it, and the disassembler too.
+//
+//  0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
+pub const OP_LEN: [u8; 256] = [
+    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 1
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 2
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 3
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 4
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 5
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 6
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 7
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 8
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 9
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // a
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // b
+    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // c
+    1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // d
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // e
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // f
+];
+
+// Whether a basic block ends with the instruction: it jumps, calls, returns, stops the CPU or doesn't exist.
+fn ends_block(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0x76 // STOP, JR, HALT
+            | 0xc0 | 0xc8 | 0xc9 | 0xd0 | 0xd8 | 0xd9 // RET, RETI
+            | 0xc2 | 0xc3 | 0xca | 0xd2 | 0xda | 0xe9 // JP
+            | 0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc // CALL
+            | 0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff // RST
+            | 0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd
+    )
+}
+
+// A cached interpreter of the rom, off by default. Most of the code of a game runs from the rom, and without the cache
+// each byte of each instruction goes through the bus, the MMU and the mapper every time it runs. The cache keeps the
+// instructions decoded with OP_LEN, keyed by the bank of the rom they are in and their address in it. When the CPU
+// gets to an address not decoded yet, the basic block from there is decoded: the instructions up to the next one which
+// jumps or stops the CPU, or up to the end of the bank.
+//
+// The rom itself never changes, only which of its banks are mapped. The banks at 0000-3FFF and 4000-7FFF are asked to
+// the bus, see Memory::rom_bank_at, and forgotten when the CPU writes to the mapper, to DMA (FF46) as the CPU reads FFh
+// from the rom during an OAM DMA, or to BANK (FF50) which unmaps the boot ROM. Writes which don't come from the CPU,
+// e.g. from a debugger or a save state, must call flush(). The instructions are executed by the same interpreter with
+// or without the cache, at the same timings: only the way their bytes get to the CPU differs. The bus is read as
+// before for the HALT bug, under watchpoints, and for an instruction across the end of a bank.
+pub struct BlockCache {
+    // The instructions of each bank by their address in it, allocated the first time code of the bank runs. An entry
+    // is the bytes of an instruction with its length in the bits 24-25, 0 until decoded.
+    banks: Vec<Option<Box<[u32]>>>,
+    // The banks mapped at 0000-3FFF and 4000-7FFF, None until asked to the bus.
+    mapped: [Option<usize>; 2],
+}
+
+impl BlockCache {
+    pub fn power_up() -> Self {
+        Self { banks: Vec::new(), mapped: [None; 2] }
+    }
+
+    // Forget which banks are mapped. The decoded instructions stay, they are those of the rom.
+    pub fn flush(&mut self) {
+        self.mapped = [None; 2];
+    }
+
+    // The instruction at a, None when it can't be taken from the cache.
+    fn get(&mut self, mem: &dyn Memory, a: u16) -> Option<u32> {
+        if a >= 0x8000 {
+            return None;
+        }
+        let area = usize::from(a >> 14);
+        let bank = match self.mapped[area] {
+            Some(bank) => bank,
+            None => *self.mapped[area].insert(mem.rom_bank_at(a)?),
+        };
+        if bank >= self.banks.len() {
+            self.banks.resize_with(bank + 1, || None);
+        }
+        let page = self.banks[bank].get_or_insert_with(|| vec![0; 0x4000].into_boxed_slice());
+        let i = usize::from(a & 0x3fff);
+        if page[i] == 0 {
+            Self::decode(page, mem, a);
+        }
+        Some(page[i]).filter(|&e| e != 0)
+    }
+
+    // Decode the basic block at a, up to an instruction decoded already. An instruction across the end of the bank is
+    // left out, the next bank is mapped separately.
+    fn decode(page: &mut [u32], mem: &dyn Memory, mut a: u16) {
+        loop {
+            let i = usize::from(a & 0x3fff);
+            if page[i] != 0 {
+                return;
+            }
+            let opcode = mem.get(a);
+            let len = OP_LEN[usize::from(opcode)];
+            if i + usize::from(len) > 0x4000 {
+                return;
+            }
+            let mut e = u32::from(len) << 24 | u32::from(opcode);
+            for n in 1..len {
+                e |= u32::from(mem.get(a + u16::from(n))) << (n * 8);
+            }
+            page[i] = e;
+            if ends_block(opcode) || i + usize::from(len) == 0x4000 {
+                return;
+            }
+            a += u16::from(len);
+        }
+    }
+
+    fn write(&mut self, a: u16) {
+        if matches!(a, 0x0000..=0x7fff | 0xff46 | 0xff50) {
+            self.flush();
+        }
+    }
+}
+
 pub struct Cpu {
     pub reg: Register,
-    pub mem: Rc<RefCell<dyn Memory>>,
     pub halted: bool,
     pub ei: bool,
+    // EI only enables the interrupts after the instruction that follows it.
+    ei_next: bool,
+    // HALT executed with IME=0 while an interrupt is pending doesn't halt, and the byte after it is read twice.
+    halt_bug: bool,
+    // STOP mode: the oscillator is off until a joypad line goes low.
+    pub stopped: bool,
+    // An illegal opcode was executed. The CPU stops for good, interrupts don't wake it.
+    locked: bool,
+    // Set by a STOP that switches the CGB speed, for the MMU to switch it.
+    pub speed_switch: bool,
+    // M-cycles left of the pause of a speed switch.
+    stall: u32,
+    pub hook: Option<Hook>,
+    // The hooks of MotherBoard::on_pc, with their addresses.
+    pub pc_hooks: Vec<(u16, PcHook)>,
+    // See the watch module. The first access which hit a watchpoint is kept until taken.
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hit: Option<Hit>,
+    // Off by default, see BlockCache.
+    pub cache: Option<BlockCache>,
+    // The bytes of the current instruction taken from the cache, their count, and how many of them were read.
+    fetch: u32,
+    fetch_len: u32,
+    fetch_pos: u32,
+    // Every instruction executed is logged here in the format of Gameboy Doctor, see trace().
+    pub trace: Option<Box<dyn Write + Send>>,
 }
 
 // The GameBoy CPU is based on a subset of the Z80 microprocessor. A summary of these commands is given below.
 // If 'Flags affected' is not given for a command then none are affected.
 impl Cpu {
-    fn imm(&mut self) -> u8 {
-        let v = self.mem.borrow().get(self.reg.pc);
-        self.reg.pc += 1;
+    fn imm(&mut self, mem: &dyn Memory) -> u8 {
+        let v = if self.fetch_pos < self.fetch_len {
+            let v = (self.fetch >> (self.fetch_pos * 8)) as u8;
+            self.fetch_pos += 1;
+            v
+        } else {
+            mem.get(self.reg.pc)
+        };
+        self.reg.pc = self.reg.pc.wrapping_add(1);
         v
     }
 
-    fn imm_word(&mut self) -> u16 {
-        let v = self.mem.borrow().get_word(self.reg.pc);
-        self.reg.pc += 2;
-        v
+    fn imm_word(&mut self, mem: &dyn Memory) -> u16 {
+        let lo = self.imm(mem);
+        let hi = self.imm(mem);
+        u16::from(lo) | u16::from(hi) << 8
     }
 
-    fn stack_add(&mut self, v: u16) {
-        self.reg.sp -= 2;
-        self.mem.borrow_mut().set_word(self.reg.sp, v);
+    // Every write of the CPU goes through here, for the block cache to see the ones which change the rom under it.
+    fn write(&mut self, mem: &mut dyn Memory, a: u16, v: u8) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.write(a);
+        }
+        mem.set(a, v);
     }
 
-    fn stack_pop(&mut self) -> u16 {
-        let r = self.mem.borrow().get_word(self.reg.sp);
-        self.reg.sp += 2;
+    fn write_word(&mut self, mem: &mut dyn Memory, a: u16, v: u16) {
+        self.write(mem, a, v as u8);
+        self.write(mem, a.wrapping_add(1), (v >> 8) as u8);
+    }
+
+    // Take the bytes of the instruction at PC from the block cache, if it is on and they are rom. The HALT bug reads
+    // a byte twice, it is left to the bus.
+    fn prefetch(&mut self, mem: &dyn Memory) {
+        self.fetch_pos = 0;
+        self.fetch_len = 0;
+        if self.halt_bug {
+            return;
+        }
+        let Some(cache) = self.cache.as_mut() else {
+            return;
+        };
+        if let Some(e) = cache.get(mem, self.reg.pc) {
+            self.fetch = e & 0x00ff_ffff;
+            self.fetch_len = e >> 24;
+        }
+    }
+
+    // Tell the block cache the banks of the rom may have been switched, after a write to the bus from outside the CPU.
+    pub fn flush(&mut self) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.flush();
+        }
+    }
+
+    fn stack_add(&mut self, mem: &mut dyn Memory, v: u16) {
+        self.reg.sp = self.reg.sp.wrapping_sub(2);
+        self.write_word(mem, self.reg.sp, v);
+    }
+
+    fn stack_pop(&mut self, mem: &dyn Memory) -> u16 {
+        let r = mem.get_word(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(2);
         r
     }
 
@@ -288,9 +481,9 @@ impl Cpu {
     // N - Reset.
     // H - Set or reset according to operation.
     // C - Set or reset according to operation.
-    fn alu_add_sp(&mut self) {
+    fn alu_add_sp(&mut self, mem: &dyn Memory) {
         let a = self.reg.sp;
-        let b = i16::from(self.imm() as i8) as u16;
+        let b = i16::from(self.imm(mem) as i8) as u16;
         self.reg.set_flag(C, (a & 0x00ff) + (b & 0x00ff) > 0x00ff);
         self.reg.set_flag(H, (a & 0x000f) + (b & 0x000f) > 0x000f);
         self.reg.set_flag(N, false);
@@ -311,7 +504,7 @@ impl Cpu {
         self.reg.set_flag(H, false);
         self.reg.set_flag(N, false);
         self.reg.set_flag(Z, a == 0x00);
-        (a >> 4) | (a << 4)
+        a.rotate_left(4)
     }
 
     // Decimal adjust register A. This instruction adjusts register A so that the correct representation of Binary
@@ -547,8 +740,27 @@ impl Cpu {
 }
 
 impl Cpu {
-    pub fn power_up(term: Term, mem: Rc<RefCell<dyn Memory>>) -> Self {
-        Self { reg: Register::power_up(term), mem, halted: false, ei: true }
+    pub fn power_up(term: Term) -> Self {
+        Self {
+            reg: Register::power_up(term),
+            halted: false,
+            ei: true,
+            ei_next: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
+            speed_switch: false,
+            stall: 0,
+            hook: None,
+            pc_hooks: vec![],
+            watchpoints: vec![],
+            watch_hit: None,
+            cache: None,
+            fetch: 0,
+            fetch_len: 0,
+            fetch_pos: 0,
+            trace: None,
+        }
     }
 
     // The IME (interrupt master enable) flag is reset by DI and prohibits all interrupts. It is set by EI and
@@ -558,28 +770,74 @@ impl Cpu {
     // 3. Reset the IME flag and prevent all interrupts.
     // 4. The PC (program counter) is pushed onto the stack.
     // 5. Jump to the starting address of the interrupt.
-    fn hi(&mut self) -> u32 {
+    //
+    // A pending interrupt ends HALT whether IME is set or not. With IME=0 the CPU simply resumes after HALT: the
+    // interrupt isn't serviced and stays requested in IF.
+    fn pending(&self, mem: &dyn Memory) -> u8 {
+        let intf = mem.get(0xff0f);
+        let inte = mem.get(0xffff);
+        intf & inte & 0x1f
+    }
+
+    // STOP resets DIV and turns the oscillator off until a joypad line goes low. It is 2 bytes long, but the second
+    // byte is not skipped while an interrupt is pending. It ends up in another mode in the corrupted cases:
+    // - A key is already held: no STOP and DIV keeps counting, it halts instead, unless an interrupt is pending.
+    // - A CGB speed switch is armed in KEY1: the speed switches, and the CPU pauses for 2050 M-cycles unless an
+    //   interrupt is pending. With IME=1 and an interrupt pending the real CPU glitches, it switches cleanly here.
+    //
+    // See: https://gbdev.io/pandocs/Reducing_Power_Consumption.html#using-the-stop-instruction
+    fn stop(&mut self, mem: &mut dyn Memory) {
+        let pending = self.pending(mem) != 0x00;
+        if !pending {
+            self.reg.pc = self.reg.pc.wrapping_add(1);
+        }
+        if mem.get(0xff00) & 0x0f != 0x0f {
+            self.halted = !pending;
+            return;
+        }
+        self.write(mem, 0xff04, 0x00);
+        if mem.get(0xff4d) & 0x01 != 0x00 {
+            self.speed_switch = true;
+            if !pending {
+                self.stall = 2050;
+            }
+            return;
+        }
+        self.stopped = true;
+    }
+
+    // Servicing an interrupt takes 5 M-cycles: 2 idle ones, 2 to push PC and 1 to jump. The interrupt to service is
+    // chosen after the high byte of PC is pushed, so a push that overwrites IE can cancel it: PC is then set to 0000h
+    // and the request stays in IF. Leaving HALT takes 1 more M-cycle, whether the interrupt is serviced or not.
+    fn hi(&mut self, mem: &mut dyn Memory) -> u32 {
         if !self.halted && !self.ei {
             return 0;
         }
-        let intf = self.mem.borrow().get(0xff0f);
-        let inte = self.mem.borrow().get(0xffff);
-        let ii = intf & inte;
-        if ii == 0x00 {
+        if self.pending(mem) == 0x00 {
             return 0;
         }
+        let wake = u32::from(self.halted);
         self.halted = false;
         if !self.ei {
-            return 0;
+            return wake;
         }
         self.ei = false;
 
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write(mem, self.reg.sp, (self.reg.pc >> 8) as u8);
+        let ii = self.pending(mem);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write(mem, self.reg.sp, self.reg.pc as u8);
+        if ii == 0x00 {
+            self.reg.pc = 0x0000;
+            return wake + 5;
+        }
+
         // Consumer an interrupter, the rest is written back to the register
         let n = ii.trailing_zeros();
-        let intf = intf & !(1 << n);
-        self.mem.borrow_mut().set(0xff0f, intf);
+        let intf = mem.get(0xff0f) & !(1 << n);
+        self.write(mem, 0xff0f, intf);
 
-        self.stack_add(self.reg.pc);
         // Set the PC to correspond interrupt process program:
         // V-Blank: 0x40
         // LCD: 0x48
@@ -587,58 +845,95 @@ impl Cpu {
         // JOYPAD: 0x60
         // Serial: 0x58
         self.reg.pc = 0x0040 | ((n as u16) << 3);
-        4
+        wake + 5
     }
 
-    fn ex(&mut self) -> u32 {
-        let opcode = self.imm();
+    // One line of the log of Gameboy Doctor, before the instruction at PC is executed: the registers and the 4 bytes
+    // from PC, e.g.
+    //   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+    // The log can be diffed line by line with the logs of other emulators, the first line which differs is the first
+    // instruction which went wrong. A log which can't be written is dropped.
+    fn trace(&mut self, mem: &dyn Memory) {
+        let Some(w) = self.trace.as_mut() else {
+            return;
+        };
+        let r = &self.reg;
+        let pcmem: Vec<String> = (0..4).map(|i| format!("{:02X}", mem.get(r.pc.wrapping_add(i)))).collect();
+        let line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+            r.a,
+            r.f,
+            r.b,
+            r.c,
+            r.d,
+            r.e,
+            r.h,
+            r.l,
+            r.sp,
+            r.pc,
+            pcmem.join(",")
+        );
+        if let Err(e) = writeln!(w, "{}", line) {
+            rog::debugln!("CPU: trace stopped: {}", e);
+            self.trace = None;
+        }
+    }
+
+    fn ex(&mut self, mem: &mut dyn Memory) -> u32 {
+        self.trace(mem);
+        self.prefetch(mem);
+        let opcode = self.imm(mem);
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.reg.pc = self.reg.pc.wrapping_sub(1);
+        }
         let mut cbcode: u8 = 0;
         match opcode {
             // LD r8, d8
-            0x06 => self.reg.b = self.imm(),
-            0x0e => self.reg.c = self.imm(),
-            0x16 => self.reg.d = self.imm(),
-            0x1e => self.reg.e = self.imm(),
-            0x26 => self.reg.h = self.imm(),
-            0x2e => self.reg.l = self.imm(),
+            0x06 => self.reg.b = self.imm(mem),
+            0x0e => self.reg.c = self.imm(mem),
+            0x16 => self.reg.d = self.imm(mem),
+            0x1e => self.reg.e = self.imm(mem),
+            0x26 => self.reg.h = self.imm(mem),
+            0x2e => self.reg.l = self.imm(mem),
             0x36 => {
                 let a = self.reg.get_hl();
-                let v = self.imm();
-                self.mem.borrow_mut().set(a, v);
+                let v = self.imm(mem);
+                self.write(mem, a, v);
             }
-            0x3e => self.reg.a = self.imm(),
+            0x3e => self.reg.a = self.imm(mem),
 
             // LD (r16), A
-            0x02 => self.mem.borrow_mut().set(self.reg.get_bc(), self.reg.a),
-            0x12 => self.mem.borrow_mut().set(self.reg.get_de(), self.reg.a),
+            0x02 => self.write(mem, self.reg.get_bc(), self.reg.a),
+            0x12 => self.write(mem, self.reg.get_de(), self.reg.a),
 
             // LD A, (r16)
-            0x0a => self.reg.a = self.mem.borrow().get(self.reg.get_bc()),
-            0x1a => self.reg.a = self.mem.borrow().get(self.reg.get_de()),
+            0x0a => self.reg.a = mem.get(self.reg.get_bc()),
+            0x1a => self.reg.a = mem.get(self.reg.get_de()),
 
             // LD (HL+), A
             0x22 => {
                 let a = self.reg.get_hl();
-                self.mem.borrow_mut().set(a, self.reg.a);
-                self.reg.set_hl(a + 1);
+                self.write(mem, a, self.reg.a);
+                self.reg.set_hl(a.wrapping_add(1));
             }
             // LD (HL-), A
             0x32 => {
                 let a = self.reg.get_hl();
-                self.mem.borrow_mut().set(a, self.reg.a);
-                self.reg.set_hl(a - 1);
+                self.write(mem, a, self.reg.a);
+                self.reg.set_hl(a.wrapping_sub(1));
             }
             // LD A, (HL+)
             0x2a => {
                 let v = self.reg.get_hl();
-                self.reg.a = self.mem.borrow().get(v);
-                self.reg.set_hl(v + 1);
+                self.reg.a = mem.get(v);
+                self.reg.set_hl(v.wrapping_add(1));
             }
             // LD A, (HL-)
             0x3a => {
                 let v = self.reg.get_hl();
-                self.reg.a = self.mem.borrow().get(v);
-                self.reg.set_hl(v - 1);
+                self.reg.a = mem.get(v);
+                self.reg.set_hl(v.wrapping_sub(1));
             }
 
             // LD r8, r8
@@ -648,7 +943,7 @@ impl Cpu {
             0x43 => self.reg.b = self.reg.e,
             0x44 => self.reg.b = self.reg.h,
             0x45 => self.reg.b = self.reg.l,
-            0x46 => self.reg.b = self.mem.borrow().get(self.reg.get_hl()),
+            0x46 => self.reg.b = mem.get(self.reg.get_hl()),
             0x47 => self.reg.b = self.reg.a,
             0x48 => self.reg.c = self.reg.b,
             0x49 => {}
@@ -656,7 +951,7 @@ impl Cpu {
             0x4b => self.reg.c = self.reg.e,
             0x4c => self.reg.c = self.reg.h,
             0x4d => self.reg.c = self.reg.l,
-            0x4e => self.reg.c = self.mem.borrow().get(self.reg.get_hl()),
+            0x4e => self.reg.c = mem.get(self.reg.get_hl()),
             0x4f => self.reg.c = self.reg.a,
             0x50 => self.reg.d = self.reg.b,
             0x51 => self.reg.d = self.reg.c,
@@ -664,7 +959,7 @@ impl Cpu {
             0x53 => self.reg.d = self.reg.e,
             0x54 => self.reg.d = self.reg.h,
             0x55 => self.reg.d = self.reg.l,
-            0x56 => self.reg.d = self.mem.borrow().get(self.reg.get_hl()),
+            0x56 => self.reg.d = mem.get(self.reg.get_hl()),
             0x57 => self.reg.d = self.reg.a,
             0x58 => self.reg.e = self.reg.b,
             0x59 => self.reg.e = self.reg.c,
@@ -672,7 +967,7 @@ impl Cpu {
             0x5b => {}
             0x5c => self.reg.e = self.reg.h,
             0x5d => self.reg.e = self.reg.l,
-            0x5e => self.reg.e = self.mem.borrow().get(self.reg.get_hl()),
+            0x5e => self.reg.e = mem.get(self.reg.get_hl()),
             0x5f => self.reg.e = self.reg.a,
             0x60 => self.reg.h = self.reg.b,
             0x61 => self.reg.h = self.reg.c,
@@ -680,7 +975,7 @@ impl Cpu {
             0x63 => self.reg.h = self.reg.e,
             0x64 => {}
             0x65 => self.reg.h = self.reg.l,
-            0x66 => self.reg.h = self.mem.borrow().get(self.reg.get_hl()),
+            0x66 => self.reg.h = mem.get(self.reg.get_hl()),
             0x67 => self.reg.h = self.reg.a,
             0x68 => self.reg.l = self.reg.b,
             0x69 => self.reg.l = self.reg.c,
@@ -688,54 +983,54 @@ impl Cpu {
             0x6b => self.reg.l = self.reg.e,
             0x6c => self.reg.l = self.reg.h,
             0x6d => {}
-            0x6e => self.reg.l = self.mem.borrow().get(self.reg.get_hl()),
+            0x6e => self.reg.l = mem.get(self.reg.get_hl()),
             0x6f => self.reg.l = self.reg.a,
-            0x70 => self.mem.borrow_mut().set(self.reg.get_hl(), self.reg.b),
-            0x71 => self.mem.borrow_mut().set(self.reg.get_hl(), self.reg.c),
-            0x72 => self.mem.borrow_mut().set(self.reg.get_hl(), self.reg.d),
-            0x73 => self.mem.borrow_mut().set(self.reg.get_hl(), self.reg.e),
-            0x74 => self.mem.borrow_mut().set(self.reg.get_hl(), self.reg.h),
-            0x75 => self.mem.borrow_mut().set(self.reg.get_hl(), self.reg.l),
-            0x77 => self.mem.borrow_mut().set(self.reg.get_hl(), self.reg.a),
+            0x70 => self.write(mem, self.reg.get_hl(), self.reg.b),
+            0x71 => self.write(mem, self.reg.get_hl(), self.reg.c),
+            0x72 => self.write(mem, self.reg.get_hl(), self.reg.d),
+            0x73 => self.write(mem, self.reg.get_hl(), self.reg.e),
+            0x74 => self.write(mem, self.reg.get_hl(), self.reg.h),
+            0x75 => self.write(mem, self.reg.get_hl(), self.reg.l),
+            0x77 => self.write(mem, self.reg.get_hl(), self.reg.a),
             0x78 => self.reg.a = self.reg.b,
             0x79 => self.reg.a = self.reg.c,
             0x7a => self.reg.a = self.reg.d,
             0x7b => self.reg.a = self.reg.e,
             0x7c => self.reg.a = self.reg.h,
             0x7d => self.reg.a = self.reg.l,
-            0x7e => self.reg.a = self.mem.borrow().get(self.reg.get_hl()),
+            0x7e => self.reg.a = mem.get(self.reg.get_hl()),
             0x7f => {}
 
             // LDH (a8), A
             0xe0 => {
-                let a = 0xff00 | u16::from(self.imm());
-                self.mem.borrow_mut().set(a, self.reg.a);
+                let a = 0xff00 | u16::from(self.imm(mem));
+                self.write(mem, a, self.reg.a);
             }
             // LDH A, (a8)
             0xf0 => {
-                let a = 0xff00 | u16::from(self.imm());
-                self.reg.a = self.mem.borrow().get(a);
+                let a = 0xff00 | u16::from(self.imm(mem));
+                self.reg.a = mem.get(a);
             }
 
             // LD (C), A
-            0xe2 => self.mem.borrow_mut().set(0xff00 | u16::from(self.reg.c), self.reg.a),
+            0xe2 => self.write(mem, 0xff00 | u16::from(self.reg.c), self.reg.a),
             // LD A, (C)
-            0xf2 => self.reg.a = self.mem.borrow().get(0xff00 | u16::from(self.reg.c)),
+            0xf2 => self.reg.a = mem.get(0xff00 | u16::from(self.reg.c)),
 
             // LD (a16), A
             0xea => {
-                let a = self.imm_word();
-                self.mem.borrow_mut().set(a, self.reg.a);
+                let a = self.imm_word(mem);
+                self.write(mem, a, self.reg.a);
             }
             // LD A, (a16)
             0xfa => {
-                let a = self.imm_word();
-                self.reg.a = self.mem.borrow().get(a);
+                let a = self.imm_word(mem);
+                self.reg.a = mem.get(a);
             }
 
             // LD r16, d16
             0x01 | 0x11 | 0x21 | 0x31 => {
-                let v = self.imm_word();
+                let v = self.imm_word(mem);
                 match opcode {
                     0x01 => self.reg.set_bc(v),
                     0x11 => self.reg.set_de(v),
@@ -750,7 +1045,7 @@ impl Cpu {
             // LD SP, d8
             0xf8 => {
                 let a = self.reg.sp;
-                let b = i16::from(self.imm() as i8) as u16;
+                let b = i16::from(self.imm(mem) as i8) as u16;
                 self.reg.set_flag(C, (a & 0x00ff) + (b & 0x00ff) > 0x00ff);
                 self.reg.set_flag(H, (a & 0x000f) + (b & 0x000f) > 0x000f);
                 self.reg.set_flag(N, false);
@@ -759,19 +1054,19 @@ impl Cpu {
             }
             // LD (d16), SP
             0x08 => {
-                let a = self.imm_word();
-                self.mem.borrow_mut().set_word(a, self.reg.sp);
+                let a = self.imm_word(mem);
+                self.write_word(mem, a, self.reg.sp);
             }
 
             // PUSH
-            0xc5 => self.stack_add(self.reg.get_bc()),
-            0xd5 => self.stack_add(self.reg.get_de()),
-            0xe5 => self.stack_add(self.reg.get_hl()),
-            0xf5 => self.stack_add(self.reg.get_af()),
+            0xc5 => self.stack_add(mem, self.reg.get_bc()),
+            0xd5 => self.stack_add(mem, self.reg.get_de()),
+            0xe5 => self.stack_add(mem, self.reg.get_hl()),
+            0xf5 => self.stack_add(mem, self.reg.get_af()),
 
             // POP
             0xc1 | 0xf1 | 0xd1 | 0xe1 => {
-                let v = self.stack_pop();
+                let v = self.stack_pop(mem);
                 match opcode {
                     0xc1 => self.reg.set_bc(v),
                     0xd1 => self.reg.set_de(v),
@@ -789,12 +1084,12 @@ impl Cpu {
             0x84 => self.alu_add(self.reg.h),
             0x85 => self.alu_add(self.reg.l),
             0x86 => {
-                let v = self.mem.borrow().get(self.reg.get_hl());
+                let v = mem.get(self.reg.get_hl());
                 self.alu_add(v);
             }
             0x87 => self.alu_add(self.reg.a),
             0xc6 => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_add(v);
             }
 
@@ -806,12 +1101,12 @@ impl Cpu {
             0x8c => self.alu_adc(self.reg.h),
             0x8d => self.alu_adc(self.reg.l),
             0x8e => {
-                let a = self.mem.borrow().get(self.reg.get_hl());
+                let a = mem.get(self.reg.get_hl());
                 self.alu_adc(a);
             }
             0x8f => self.alu_adc(self.reg.a),
             0xce => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_adc(v);
             }
 
@@ -823,12 +1118,12 @@ impl Cpu {
             0x94 => self.alu_sub(self.reg.h),
             0x95 => self.alu_sub(self.reg.l),
             0x96 => {
-                let a = self.mem.borrow().get(self.reg.get_hl());
+                let a = mem.get(self.reg.get_hl());
                 self.alu_sub(a);
             }
             0x97 => self.alu_sub(self.reg.a),
             0xd6 => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_sub(v);
             }
 
@@ -840,12 +1135,12 @@ impl Cpu {
             0x9c => self.alu_sbc(self.reg.h),
             0x9d => self.alu_sbc(self.reg.l),
             0x9e => {
-                let a = self.mem.borrow().get(self.reg.get_hl());
+                let a = mem.get(self.reg.get_hl());
                 self.alu_sbc(a);
             }
             0x9f => self.alu_sbc(self.reg.a),
             0xde => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_sbc(v);
             }
 
@@ -857,12 +1152,12 @@ impl Cpu {
             0xa4 => self.alu_and(self.reg.h),
             0xa5 => self.alu_and(self.reg.l),
             0xa6 => {
-                let a = self.mem.borrow().get(self.reg.get_hl());
+                let a = mem.get(self.reg.get_hl());
                 self.alu_and(a);
             }
             0xa7 => self.alu_and(self.reg.a),
             0xe6 => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_and(v);
             }
 
@@ -874,12 +1169,12 @@ impl Cpu {
             0xb4 => self.alu_or(self.reg.h),
             0xb5 => self.alu_or(self.reg.l),
             0xb6 => {
-                let a = self.mem.borrow().get(self.reg.get_hl());
+                let a = mem.get(self.reg.get_hl());
                 self.alu_or(a);
             }
             0xb7 => self.alu_or(self.reg.a),
             0xf6 => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_or(v);
             }
 
@@ -891,12 +1186,12 @@ impl Cpu {
             0xac => self.alu_xor(self.reg.h),
             0xad => self.alu_xor(self.reg.l),
             0xae => {
-                let a = self.mem.borrow().get(self.reg.get_hl());
+                let a = mem.get(self.reg.get_hl());
                 self.alu_xor(a);
             }
             0xaf => self.alu_xor(self.reg.a),
             0xee => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_xor(v);
             }
 
@@ -908,12 +1203,12 @@ impl Cpu {
             0xbc => self.alu_cp(self.reg.h),
             0xbd => self.alu_cp(self.reg.l),
             0xbe => {
-                let a = self.mem.borrow().get(self.reg.get_hl());
+                let a = mem.get(self.reg.get_hl());
                 self.alu_cp(a);
             }
             0xbf => self.alu_cp(self.reg.a),
             0xfe => {
-                let v = self.imm();
+                let v = self.imm(mem);
                 self.alu_cp(v);
             }
 
@@ -926,9 +1221,9 @@ impl Cpu {
             0x2c => self.reg.l = self.alu_inc(self.reg.l),
             0x34 => {
                 let a = self.reg.get_hl();
-                let v = self.mem.borrow().get(a);
+                let v = mem.get(a);
                 let h = self.alu_inc(v);
-                self.mem.borrow_mut().set(a, h);
+                self.write(mem, a, h);
             }
             0x3c => self.reg.a = self.alu_inc(self.reg.a),
 
@@ -941,9 +1236,9 @@ impl Cpu {
             0x2d => self.reg.l = self.alu_dec(self.reg.l),
             0x35 => {
                 let a = self.reg.get_hl();
-                let v = self.mem.borrow().get(a);
+                let v = mem.get(a);
                 let h = self.alu_dec(v);
-                self.mem.borrow_mut().set(a, h);
+                self.write(mem, a, h);
             }
             0x3d => self.reg.a = self.alu_dec(self.reg.a),
 
@@ -954,7 +1249,7 @@ impl Cpu {
             0x39 => self.alu_add_hl(self.reg.sp),
 
             // ADD SP, d8
-            0xe8 => self.alu_add_sp(),
+            0xe8 => self.alu_add_sp(mem),
 
             // INC r16
             0x03 => {
@@ -1008,14 +1303,23 @@ impl Cpu {
             0x00 => {}
 
             // HALT
-            0x76 => self.halted = true,
+            0x76 => {
+                if !self.ei && self.pending(mem) != 0x00 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            }
 
             // STOP
-            0x10 => {}
+            0x10 => self.stop(mem),
 
             // DI/EI
-            0xf3 => self.ei = false,
-            0xfb => self.ei = true,
+            0xf3 => {
+                self.ei = false;
+                self.ei_next = false;
+            }
+            0xfb => self.ei_next = true,
 
             // RLCA
             0x07 => {
@@ -1042,12 +1346,12 @@ impl Cpu {
             }
 
             // JUMP
-            0xc3 => self.reg.pc = self.imm_word(),
+            0xc3 => self.reg.pc = self.imm_word(mem),
             0xe9 => self.reg.pc = self.reg.get_hl(),
 
             // JUMP IF
             0xc2 | 0xca | 0xd2 | 0xda => {
-                let pc = self.imm_word();
+                let pc = self.imm_word(mem);
                 let cond = match opcode {
                     0xc2 => !self.reg.get_flag(Z),
                     0xca => self.reg.get_flag(Z),
@@ -1062,7 +1366,7 @@ impl Cpu {
 
             // JR
             0x18 => {
-                let n = self.imm();
+                let n = self.imm(mem);
                 self.alu_jr(n);
             }
 
@@ -1075,7 +1379,7 @@ impl Cpu {
                     0x38 => self.reg.get_flag(C),
                     _ => panic!(""),
                 };
-                let n = self.imm();
+                let n = self.imm(mem);
                 if cond {
                     self.alu_jr(n);
                 }
@@ -1083,8 +1387,8 @@ impl Cpu {
 
             // CALL
             0xcd => {
-                let nn = self.imm_word();
-                self.stack_add(self.reg.pc);
+                let nn = self.imm_word(mem);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = nn;
             }
 
@@ -1097,49 +1401,49 @@ impl Cpu {
                     0xdc => self.reg.get_flag(C),
                     _ => panic!(""),
                 };
-                let nn = self.imm_word();
+                let nn = self.imm_word(mem);
                 if cond {
-                    self.stack_add(self.reg.pc);
+                    self.stack_add(mem, self.reg.pc);
                     self.reg.pc = nn;
                 }
             }
 
             // RST
             0xc7 => {
-                self.stack_add(self.reg.pc);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = 0x00;
             }
             0xcf => {
-                self.stack_add(self.reg.pc);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = 0x08;
             }
             0xd7 => {
-                self.stack_add(self.reg.pc);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = 0x10;
             }
             0xdf => {
-                self.stack_add(self.reg.pc);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = 0x18;
             }
             0xe7 => {
-                self.stack_add(self.reg.pc);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = 0x20;
             }
             0xef => {
-                self.stack_add(self.reg.pc);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = 0x28;
             }
             0xf7 => {
-                self.stack_add(self.reg.pc);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = 0x30;
             }
             0xff => {
-                self.stack_add(self.reg.pc);
+                self.stack_add(mem, self.reg.pc);
                 self.reg.pc = 0x38;
             }
 
             // RET
-            0xc9 => self.reg.pc = self.stack_pop(),
+            0xc9 => self.reg.pc = self.stack_pop(mem),
 
             // RET IF
             0xc0 | 0xc8 | 0xd0 | 0xd8 => {
@@ -1151,20 +1455,19 @@ impl Cpu {
                     _ => panic!(""),
                 };
                 if cond {
-                    self.reg.pc = self.stack_pop();
+                    self.reg.pc = self.stack_pop(mem);
                 }
             }
 
             // RETI
             0xd9 => {
-                self.reg.pc = self.stack_pop();
+                self.reg.pc = self.stack_pop(mem);
                 self.ei = true;
             }
 
             // Extended Bit Operations
             0xcb => {
-                cbcode = self.mem.borrow().get(self.reg.pc);
-                self.reg.pc += 1;
+                cbcode = self.imm(mem);
                 match cbcode {
                     // RLC r8
                     0x00 => self.reg.b = self.alu_rlc(self.reg.b),
@@ -1175,9 +1478,9 @@ impl Cpu {
                     0x05 => self.reg.l = self.alu_rlc(self.reg.l),
                     0x06 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_rlc(v);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x07 => self.reg.a = self.alu_rlc(self.reg.a),
 
@@ -1190,9 +1493,9 @@ impl Cpu {
                     0x0d => self.reg.l = self.alu_rrc(self.reg.l),
                     0x0e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_rrc(v);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x0f => self.reg.a = self.alu_rrc(self.reg.a),
 
@@ -1205,9 +1508,9 @@ impl Cpu {
                     0x15 => self.reg.l = self.alu_rl(self.reg.l),
                     0x16 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_rl(v);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x17 => self.reg.a = self.alu_rl(self.reg.a),
 
@@ -1220,9 +1523,9 @@ impl Cpu {
                     0x1d => self.reg.l = self.alu_rr(self.reg.l),
                     0x1e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_rr(v);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x1f => self.reg.a = self.alu_rr(self.reg.a),
 
@@ -1235,9 +1538,9 @@ impl Cpu {
                     0x25 => self.reg.l = self.alu_sla(self.reg.l),
                     0x26 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_sla(v);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x27 => self.reg.a = self.alu_sla(self.reg.a),
 
@@ -1250,9 +1553,9 @@ impl Cpu {
                     0x2d => self.reg.l = self.alu_sra(self.reg.l),
                     0x2e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_sra(v);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x2f => self.reg.a = self.alu_sra(self.reg.a),
 
@@ -1265,9 +1568,9 @@ impl Cpu {
                     0x35 => self.reg.l = self.alu_swap(self.reg.l),
                     0x36 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_swap(v);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x37 => self.reg.a = self.alu_swap(self.reg.a),
 
@@ -1280,9 +1583,9 @@ impl Cpu {
                     0x3d => self.reg.l = self.alu_srl(self.reg.l),
                     0x3e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_srl(v);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x3f => self.reg.a = self.alu_srl(self.reg.a),
 
@@ -1295,7 +1598,7 @@ impl Cpu {
                     0x45 => self.alu_bit(self.reg.l, 0),
                     0x46 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 0);
                     }
                     0x47 => self.alu_bit(self.reg.a, 0),
@@ -1307,7 +1610,7 @@ impl Cpu {
                     0x4d => self.alu_bit(self.reg.l, 1),
                     0x4e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 1);
                     }
                     0x4f => self.alu_bit(self.reg.a, 1),
@@ -1319,7 +1622,7 @@ impl Cpu {
                     0x55 => self.alu_bit(self.reg.l, 2),
                     0x56 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 2);
                     }
                     0x57 => self.alu_bit(self.reg.a, 2),
@@ -1331,7 +1634,7 @@ impl Cpu {
                     0x5d => self.alu_bit(self.reg.l, 3),
                     0x5e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 3);
                     }
                     0x5f => self.alu_bit(self.reg.a, 3),
@@ -1343,7 +1646,7 @@ impl Cpu {
                     0x65 => self.alu_bit(self.reg.l, 4),
                     0x66 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 4);
                     }
                     0x67 => self.alu_bit(self.reg.a, 4),
@@ -1355,7 +1658,7 @@ impl Cpu {
                     0x6d => self.alu_bit(self.reg.l, 5),
                     0x6e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 5);
                     }
                     0x6f => self.alu_bit(self.reg.a, 5),
@@ -1367,7 +1670,7 @@ impl Cpu {
                     0x75 => self.alu_bit(self.reg.l, 6),
                     0x76 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 6);
                     }
                     0x77 => self.alu_bit(self.reg.a, 6),
@@ -1379,7 +1682,7 @@ impl Cpu {
                     0x7d => self.alu_bit(self.reg.l, 7),
                     0x7e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         self.alu_bit(v, 7);
                     }
                     0x7f => self.alu_bit(self.reg.a, 7),
@@ -1393,9 +1696,9 @@ impl Cpu {
                     0x85 => self.reg.l = self.alu_res(self.reg.l, 0),
                     0x86 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 0);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x87 => self.reg.a = self.alu_res(self.reg.a, 0),
                     0x88 => self.reg.b = self.alu_res(self.reg.b, 1),
@@ -1406,9 +1709,9 @@ impl Cpu {
                     0x8d => self.reg.l = self.alu_res(self.reg.l, 1),
                     0x8e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 1);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x8f => self.reg.a = self.alu_res(self.reg.a, 1),
                     0x90 => self.reg.b = self.alu_res(self.reg.b, 2),
@@ -1419,9 +1722,9 @@ impl Cpu {
                     0x95 => self.reg.l = self.alu_res(self.reg.l, 2),
                     0x96 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 2);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x97 => self.reg.a = self.alu_res(self.reg.a, 2),
                     0x98 => self.reg.b = self.alu_res(self.reg.b, 3),
@@ -1432,9 +1735,9 @@ impl Cpu {
                     0x9d => self.reg.l = self.alu_res(self.reg.l, 3),
                     0x9e => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 3);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0x9f => self.reg.a = self.alu_res(self.reg.a, 3),
                     0xa0 => self.reg.b = self.alu_res(self.reg.b, 4),
@@ -1445,9 +1748,9 @@ impl Cpu {
                     0xa5 => self.reg.l = self.alu_res(self.reg.l, 4),
                     0xa6 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 4);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xa7 => self.reg.a = self.alu_res(self.reg.a, 4),
                     0xa8 => self.reg.b = self.alu_res(self.reg.b, 5),
@@ -1458,9 +1761,9 @@ impl Cpu {
                     0xad => self.reg.l = self.alu_res(self.reg.l, 5),
                     0xae => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 5);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xaf => self.reg.a = self.alu_res(self.reg.a, 5),
                     0xb0 => self.reg.b = self.alu_res(self.reg.b, 6),
@@ -1471,9 +1774,9 @@ impl Cpu {
                     0xb5 => self.reg.l = self.alu_res(self.reg.l, 6),
                     0xb6 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 6);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xb7 => self.reg.a = self.alu_res(self.reg.a, 6),
                     0xb8 => self.reg.b = self.alu_res(self.reg.b, 7),
@@ -1484,9 +1787,9 @@ impl Cpu {
                     0xbd => self.reg.l = self.alu_res(self.reg.l, 7),
                     0xbe => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_res(v, 7);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xbf => self.reg.a = self.alu_res(self.reg.a, 7),
 
@@ -1499,9 +1802,9 @@ impl Cpu {
                     0xc5 => self.reg.l = self.alu_set(self.reg.l, 0),
                     0xc6 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 0);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xc7 => self.reg.a = self.alu_set(self.reg.a, 0),
                     0xc8 => self.reg.b = self.alu_set(self.reg.b, 1),
@@ -1512,9 +1815,9 @@ impl Cpu {
                     0xcd => self.reg.l = self.alu_set(self.reg.l, 1),
                     0xce => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 1);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xcf => self.reg.a = self.alu_set(self.reg.a, 1),
                     0xd0 => self.reg.b = self.alu_set(self.reg.b, 2),
@@ -1525,9 +1828,9 @@ impl Cpu {
                     0xd5 => self.reg.l = self.alu_set(self.reg.l, 2),
                     0xd6 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 2);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xd7 => self.reg.a = self.alu_set(self.reg.a, 2),
                     0xd8 => self.reg.b = self.alu_set(self.reg.b, 3),
@@ -1538,9 +1841,9 @@ impl Cpu {
                     0xdd => self.reg.l = self.alu_set(self.reg.l, 3),
                     0xde => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 3);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xdf => self.reg.a = self.alu_set(self.reg.a, 3),
                     0xe0 => self.reg.b = self.alu_set(self.reg.b, 4),
@@ -1551,9 +1854,9 @@ impl Cpu {
                     0xe5 => self.reg.l = self.alu_set(self.reg.l, 4),
                     0xe6 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 4);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xe7 => self.reg.a = self.alu_set(self.reg.a, 4),
                     0xe8 => self.reg.b = self.alu_set(self.reg.b, 5),
@@ -1564,9 +1867,9 @@ impl Cpu {
                     0xed => self.reg.l = self.alu_set(self.reg.l, 5),
                     0xee => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 5);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xef => self.reg.a = self.alu_set(self.reg.a, 5),
                     0xf0 => self.reg.b = self.alu_set(self.reg.b, 6),
@@ -1577,9 +1880,9 @@ impl Cpu {
                     0xf5 => self.reg.l = self.alu_set(self.reg.l, 6),
                     0xf6 => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 6);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xf7 => self.reg.a = self.alu_set(self.reg.a, 6),
                     0xf8 => self.reg.b = self.alu_set(self.reg.b, 7),
@@ -1590,76 +1893,32 @@ impl Cpu {
                     0xfd => self.reg.l = self.alu_set(self.reg.l, 7),
                     0xfe => {
                         let a = self.reg.get_hl();
-                        let v = self.mem.borrow().get(a);
+                        let v = mem.get(a);
                         let h = self.alu_set(v, 7);
-                        self.mem.borrow_mut().set(a, h);
+                        self.write(mem, a, h);
                     }
                     0xff => self.reg.a = self.alu_set(self.reg.a, 7),
                 }
             }
-            0xd3 => panic!("Opcode 0xd3 is not implemented"),
-            0xdb => panic!("Opcode 0xdb is not implemented"),
-            0xdd => panic!("Opcode 0xdd is not implemented"),
-            0xe3 => panic!("Opcode 0xe3 is not implemented"),
-            0xe4 => panic!("Opcode 0xd4 is not implemented"),
-            0xeb => panic!("Opcode 0xeb is not implemented"),
-            0xec => panic!("Opcode 0xec is not implemented"),
-            0xed => panic!("Opcode 0xed is not implemented"),
-            0xf4 => panic!("Opcode 0xf4 is not implemented"),
-            0xfc => panic!("Opcode 0xfc is not implemented"),
-            0xfd => panic!("Opcode 0xfd is not implemented"),
+            // The opcodes which don't exist lock the CPU up, only a reset gets it out.
+            0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                rog::debugln!(
+                    "CPU: illegal opcode 0x{:02x} at 0x{:04x}, locked up",
+                    opcode,
+                    self.reg.pc.wrapping_sub(1)
+                );
+                self.locked = true;
+            }
         };
 
         let ecycle = match opcode {
-            0x20 | 0x30 => {
-                if self.reg.get_flag(Z) {
-                    0x00
-                } else {
-                    0x01
-                }
-            }
-            0x28 | 0x38 => {
-                if self.reg.get_flag(Z) {
-                    0x01
-                } else {
-                    0x00
-                }
-            }
-            0xc0 | 0xd0 => {
-                if self.reg.get_flag(Z) {
-                    0x00
-                } else {
-                    0x03
-                }
-            }
-            0xc8 | 0xcc | 0xd8 | 0xdc => {
-                if self.reg.get_flag(Z) {
-                    0x03
-                } else {
-                    0x00
-                }
-            }
-            0xc2 | 0xd2 => {
-                if self.reg.get_flag(Z) {
-                    0x00
-                } else {
-                    0x01
-                }
-            }
-            0xca | 0xda => {
-                if self.reg.get_flag(Z) {
-                    0x01
-                } else {
-                    0x00
-                }
-            }
-            0xc4 | 0xd4 => {
-                if self.reg.get_flag(Z) {
-                    0x00
-                } else {
-                    0x03
-                }
-            }
+            0x20 | 0x30 if !self.reg.get_flag(Z) => 0x01,
+            0x28 | 0x38 if self.reg.get_flag(Z) => 0x01,
+            0xc0 | 0xd0 if !self.reg.get_flag(Z) => 0x03,
+            0xc8 | 0xcc | 0xd8 | 0xdc if self.reg.get_flag(Z) => 0x03,
+            0xc2 | 0xd2 if !self.reg.get_flag(Z) => 0x01,
+            0xca | 0xda if self.reg.get_flag(Z) => 0x01,
+            0xc4 | 0xd4 if !self.reg.get_flag(Z) => 0x03,
             _ => 0x00,
         };
         if opcode == 0xcb {
@@ -1669,15 +1928,65 @@ impl Cpu {
         }
     }
 
-    pub fn next(&mut self) -> u32 {
-        let mac = {
-            let c = self.hi();
+    fn pc_hook(&mut self) {
+        let mut hooks = std::mem::take(&mut self.pc_hooks);
+        for (a, f) in hooks.iter_mut() {
+            if *a == self.reg.pc {
+                f(self);
+            }
+        }
+        self.pc_hooks = hooks;
+    }
+
+    fn ex_watched(&mut self, mem: &mut dyn Memory) -> u32 {
+        let points = std::mem::take(&mut self.watchpoints);
+        // Decoding a block would read the instructions after this one, the watched one is read from the bus.
+        let cache = self.cache.take();
+        let mut bus = Watched::new(mem, &points, self.reg.pc);
+        let cycles = self.ex(&mut bus);
+        self.cache = cache;
+        if let Some(hit) = bus.hit.get() {
+            self.watch_hit.get_or_insert(hit);
+        }
+        self.watchpoints = points;
+        cycles
+    }
+
+    // Run one instruction on the bus given, or wait an M-cycle. The CPU doesn't own the bus, the motherboard lends it
+    // for each step, so no access needs a runtime borrow check.
+    pub fn next(&mut self, mem: &mut dyn Memory) -> u32 {
+        let mac = if self.locked {
+            OP_CYCLES[0]
+        } else if self.stopped {
+            // Interrupts can't wake it, only a key.
+            self.stopped = mem.get(0xff00) & 0x0f == 0x0f;
+            OP_CYCLES[0]
+        } else if self.stall != 0 {
+            self.stall -= 1;
+            OP_CYCLES[0]
+        } else {
+            let c = self.hi(mem);
             if c != 0 {
                 c
             } else if self.halted {
                 OP_CYCLES[0]
             } else {
-                self.ex()
+                if let Some(mut hook) = self.hook.take() {
+                    hook(self, mem);
+                    self.hook = Some(hook);
+                }
+                if !self.pc_hooks.is_empty() {
+                    self.pc_hook();
+                }
+                if self.ei_next {
+                    self.ei_next = false;
+                    self.ei = true;
+                }
+                if self.watchpoints.is_empty() {
+                    self.ex(mem)
+                } else {
+                    self.ex_watched(mem)
+                }
             }
         };
         mac * 4
@@ -1687,40 +1996,103 @@ impl Cpu {
 // Real time cpu provided to simulate real hardware speed.
 pub struct Rtc {
     pub cpu: Cpu,
+    // Sleep to match the real hardware speed. When disabled, the emulation runs as fast as the host allows.
+    pub throttle: bool,
+    // Clock cycles at normal speed between two sleeps. A shorter step paces the emulation more evenly, at the cost of
+    // more sleeps, which the host may round up.
+    pub step: u32,
+    // Times faster than real time the emulation runs while throttled, e.g. while fast-forwarding.
+    pub rate: u32,
     step_cycles: u32,
-    step_zero: time::Instant,
+    // When the pacing started and the clock cycles run since, set on the first sleep so nothing reads the host clock
+    // while not throttled. Each sleep targets the exact time of its step from there, so rounding never accumulates.
+    step_zero: Option<time::Instant>,
+    step_total: u64,
     step_flip: bool,
+    // The host clocks at the last sleep, and the time the host was suspended since, until taken by suspended().
+    step_last: Option<(time::Instant, time::SystemTime)>,
+    step_gap: Option<time::Duration>,
+    // The time slept since power up, for the performance statistics.
+    pub slept: time::Duration,
 }
 
 impl Rtc {
-    pub fn power_up(term: Term, mem: Rc<RefCell<dyn Memory>>) -> Self {
-        let cpu = Cpu::power_up(term, mem);
-        Self { cpu, step_cycles: 0, step_zero: time::Instant::now(), step_flip: false }
+    pub fn power_up(term: Term) -> Self {
+        let cpu = Cpu::power_up(term);
+        // The browser has no way to block, the page paces the frames with requestAnimationFrame instead.
+        let throttle = cfg!(not(target_arch = "wasm32"));
+        Self {
+            cpu,
+            throttle,
+            step: STEP_CYCLES,
+            rate: 1,
+            step_cycles: 0,
+            step_zero: None,
+            step_total: 0,
+            step_flip: false,
+            step_last: None,
+            step_gap: None,
+            slept: time::Duration::ZERO,
+        }
     }
 
     // Function next simulates real hardware execution speed, by limiting the frequency of the function cpu.next().
-    pub fn next(&mut self) -> u32 {
-        if self.step_cycles > STEP_CYCLES {
+    // The CPU runs speed times faster than at normal speed, 2 in CGB double speed mode.
+    // A sleep covers rate steps of emulation, so the rate can change at any time without upsetting the pacing.
+    pub fn next(&mut self, mem: &mut dyn Memory, speed: u32) -> u32 {
+        let step = self.step * self.rate.max(1);
+        if self.step_cycles >= step {
             self.step_flip = true;
-            self.step_cycles -= STEP_CYCLES;
-            let now = time::Instant::now();
-            let d = now.duration_since(self.step_zero);
-            let s = u64::from(STEP_TIME.saturating_sub(d.as_millis() as u32));
-            rog::debugln!("CPU: sleep {} millis", s);
-            thread::sleep(time::Duration::from_millis(s));
-            self.step_zero = self.step_zero.checked_add(time::Duration::from_millis(u64::from(STEP_TIME))).unwrap();
-
-            // If now is after the just updated target frame time, reset to
-            // avoid drift.
-            if now.checked_duration_since(self.step_zero).is_some() {
-                self.step_zero = now;
+            self.step_cycles -= step;
+            if self.throttle {
+                self.sleep();
             }
         }
-        let cycles = self.cpu.next();
-        self.step_cycles += cycles;
+        let cycles = self.cpu.next(mem);
+        self.step_cycles += cycles / speed;
         cycles
     }
 
+    // A suspended host, e.g. a laptop with its lid closed, is found by its two clocks: the monotonic clock stands still
+    // while suspended on Linux and Mac, the wall clock doesn't. When the wall clock jumps ahead of the monotonic one by
+    // seconds, the pacing starts again from now, and the frontend learns about it from suspended().
+    #[cfg(not(target_arch = "wasm32"))]
+    fn sleep(&mut self) {
+        let now = time::Instant::now();
+        let wall = time::SystemTime::now();
+        if let Some((last, last_wall)) = self.step_last.replace((now, wall)) {
+            // The wall clock may also go back, when set by the user or NTP.
+            let gap = wall.duration_since(last_wall).unwrap_or_default().saturating_sub(now - last);
+            if gap >= time::Duration::from_secs(2) {
+                rog::debugln!("CPU: host suspended for {} ms", gap.as_millis());
+                self.step_gap = Some(gap);
+                self.step_zero = Some(now);
+                self.step_total = 0;
+                return;
+            }
+        }
+        let zero = *self.step_zero.get_or_insert(now);
+        self.step_total += u64::from(self.step);
+        let nanos = self.step_total * 1_000_000_000 / u64::from(CLOCK_FREQUENCY);
+        let target = zero + time::Duration::from_nanos(nanos);
+        match target.checked_duration_since(now) {
+            Some(d) => {
+                rog::debugln!("CPU: sleep {} micros", d.as_micros());
+                std::thread::sleep(d);
+                self.slept += now.elapsed();
+            }
+            // Behind the target, e.g. after a pause or a slow host: start again from now rather than run fast to
+            // catch up.
+            None => {
+                self.step_zero = Some(now);
+                self.step_total = 0;
+            }
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn sleep(&mut self) {}
+
     pub fn flip(&mut self) -> bool {
         let r = self.step_flip;
         if r {
@@ -1728,4 +2100,308 @@ impl Rtc {
         }
         r
     }
+
+    // How long the host was suspended, once after it resumes.
+    pub fn suspended(&mut self) -> Option<time::Duration> {
+        self.step_gap.take()
+    }
+}
+
+impl Snapshot for Cpu {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.reg.a);
+        w.u8(self.reg.f);
+        w.u8(self.reg.b);
+        w.u8(self.reg.c);
+        w.u8(self.reg.d);
+        w.u8(self.reg.e);
+        w.u8(self.reg.h);
+        w.u8(self.reg.l);
+        w.u16(self.reg.sp);
+        w.u16(self.reg.pc);
+        w.bool(self.halted);
+        // IME, the pending EI, the HALT bug and the lock up are the bits 0 to 3 of a single byte. States of an older
+        // version aren't loaded, see the state module.
+        w.u8(u8::from(self.ei)
+            | u8::from(self.ei_next) << 1
+            | u8::from(self.halt_bug) << 2
+            | u8::from(self.locked) << 3);
+        w.bool(self.stopped);
+        w.u32(self.stall);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.reg.a = r.u8()?;
+        self.reg.f = r.u8()?;
+        self.reg.b = r.u8()?;
+        self.reg.c = r.u8()?;
+        self.reg.d = r.u8()?;
+        self.reg.e = r.u8()?;
+        self.reg.h = r.u8()?;
+        self.reg.l = r.u8()?;
+        self.reg.sp = r.u16()?;
+        self.reg.pc = r.u16()?;
+        self.halted = r.bool()?;
+        let ime = r.u8()?;
+        self.ei = ime & 0x01 != 0x00;
+        self.ei_next = ime & 0x02 != 0x00;
+        self.halt_bug = ime & 0x04 != 0x00;
+        self.locked = ime & 0x08 != 0x00;
+        self.stopped = r.bool()?;
+        self.stall = r.u32()?;
+        self.speed_switch = false;
+        // The banks of the cartridge come back with the state, they were never written by the CPU.
+        self.flush();
+        Ok(())
+    }
+}
+
+impl Snapshot for Rtc {
+    fn save(&self, w: &mut Writer) {
+        self.cpu.save(w);
+        w.u32(self.step_cycles);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.cpu.load(r)?;
+        // Saved with another step, maybe.
+        self.step_cycles = r.u32()?.min(self.step);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // How HALT, STOP and EI interact with pending interrupts and the joypad, on a CPU wired to a flat 64KB memory. Each
+    // case loads a few instructions at 0x0100, runs them and looks at where the CPU went. In the flat memory, JOYP
+    // reads back what the case wrote to it, so a key is held by clearing one of its low bits.
+    use super::*;
+    use std::ops::{Deref, DerefMut};
+
+    struct Flat {
+        data: Vec<u8>,
+    }
+
+    impl Memory for Flat {
+        fn get(&self, a: u16) -> u8 {
+            self.data[a as usize]
+        }
+
+        fn set(&mut self, a: u16, v: u8) {
+            self.data[a as usize] = v;
+        }
+    }
+
+    const NOP: u8 = 0x00;
+    const STOP: u8 = 0x10;
+    const INC_A: u8 = 0x3c;
+    const HALT: u8 = 0x76;
+    const DI: u8 = 0xf3;
+    const EI: u8 = 0xfb;
+    const RETI: u8 = 0xd9;
+
+    // The CPU and the memory it runs on. The registers and flags of the CPU are reached through it.
+    struct Machine {
+        cpu: Cpu,
+        mem: Flat,
+    }
+
+    impl Machine {
+        fn next(&mut self) -> u32 {
+            self.cpu.next(&mut self.mem)
+        }
+
+        fn steps(&mut self, n: usize) {
+            for _ in 0..n {
+                self.next();
+            }
+        }
+
+        fn intf(&self) -> u8 {
+            self.mem.get(0xff0f)
+        }
+    }
+
+    impl Deref for Machine {
+        type Target = Cpu;
+
+        fn deref(&self) -> &Cpu {
+            &self.cpu
+        }
+    }
+
+    impl DerefMut for Machine {
+        fn deref_mut(&mut self) -> &mut Cpu {
+            &mut self.cpu
+        }
+    }
+
+    fn cpu(program: &[u8], intf: u8, inte: u8) -> Machine {
+        let mut mem = Flat { data: vec![NOP; 0x10000] };
+        mem.data[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        mem.data[0xff0f] = intf;
+        mem.data[0xffff] = inte;
+        // No key held.
+        mem.data[0xff00] = 0xff;
+        let mut cpu = Cpu::power_up(Term::GB);
+        cpu.reg.a = 0x00;
+        // Start with IME=0, as after a DI.
+        cpu.ei = false;
+        Machine { cpu, mem }
+    }
+
+    #[test]
+    fn halt_ime_0() {
+        // The timer interrupt ends HALT: after the M-cycle of the wake up, the CPU runs the next instruction, nothing
+        // is serviced and IF keeps the request.
+        let mut c = cpu(&[HALT, INC_A], 0x00, 0x04);
+        c.steps(4);
+        assert!(c.halted && c.reg.pc == 0x0101);
+        c.mem.set(0xff0f, 0x04);
+        assert_eq!(c.next(), 4);
+        assert!(!c.halted && c.reg.pc == 0x0101);
+        c.steps(1);
+        assert_eq!((c.reg.pc, c.reg.a), (0x0102, 0x01));
+        assert_eq!(c.reg.sp, 0xfffe);
+        assert_eq!(c.intf(), 0x04);
+    }
+
+    #[test]
+    fn halt_ime_1() {
+        // The same interrupt is serviced, and its return address is the instruction after HALT.
+        let mut c = cpu(&[HALT, INC_A], 0x00, 0x04);
+        c.ei = true;
+        c.steps(2);
+        c.mem.set(0xff0f, 0x04);
+        assert_eq!(c.next(), 24);
+        assert!(c.reg.pc == 0x0050 && !c.ei);
+        assert_eq!(c.mem.get_word(c.reg.sp), 0x0101);
+        assert_eq!(c.intf(), 0x00);
+    }
+
+    #[test]
+    fn halt_bug() {
+        // HALT with IME=0 and an interrupt already pending doesn't halt, and the byte after it is executed twice.
+        let mut c = cpu(&[HALT, INC_A, NOP], 0x04, 0x04);
+        c.steps(3);
+        assert!(!c.halted);
+        assert_eq!((c.reg.pc, c.reg.a), (0x0102, 0x02));
+        assert_eq!(c.intf(), 0x04);
+    }
+
+    #[test]
+    fn halt_unused_if_bits() {
+        // The upper 3 bits of IF and IE are no interrupts.
+        let mut c = cpu(&[HALT, INC_A], 0xe0, 0xff);
+        c.ei = true;
+        c.steps(4);
+        assert!(c.halted && c.reg.pc == 0x0101);
+    }
+
+    #[test]
+    fn ei_di() {
+        // EI takes effect after the next instruction: EI; DI never lets an interrupt through.
+        let mut c = cpu(&[EI, DI, INC_A], 0x04, 0x04);
+        c.steps(3);
+        assert_eq!((c.reg.pc, c.reg.sp), (0x0103, 0xfffe));
+    }
+
+    #[test]
+    fn ei_delay() {
+        // The instruction after EI runs before a pending interrupt is serviced.
+        let mut c = cpu(&[EI, INC_A, INC_A], 0x04, 0x04);
+        c.steps(3);
+        assert_eq!((c.reg.pc, c.reg.a), (0x0050, 0x01));
+        assert_eq!(c.mem.get_word(c.reg.sp), 0x0102);
+    }
+
+    #[test]
+    fn ei_halt() {
+        // With a pending interrupt, it is serviced once HALT has executed, not before.
+        let mut c = cpu(&[EI, HALT, INC_A], 0x04, 0x04);
+        c.steps(3);
+        assert_eq!(c.reg.pc, 0x0050);
+        assert_eq!(c.mem.get_word(c.reg.sp), 0x0102);
+    }
+
+    #[test]
+    fn reti() {
+        // RETI enables the interrupts at once: a pending one is serviced right after the return.
+        let mut c = cpu(&[RETI], 0x04, 0x04);
+        c.reg.sp = 0xfffc;
+        c.mem.set_word(0xfffc, 0x0200);
+        c.steps(2);
+        assert_eq!(c.reg.pc, 0x0050);
+        assert_eq!(c.mem.get_word(c.reg.sp), 0x0200);
+    }
+
+    #[test]
+    fn dispatch() {
+        // Servicing an interrupt outside of HALT takes 20 cycles.
+        let mut c = cpu(&[NOP], 0x04, 0x04);
+        c.ei = true;
+        assert_eq!(c.next(), 20);
+        assert_eq!(c.reg.pc, 0x0050);
+    }
+
+    #[test]
+    fn dispatch_ie_push() {
+        // The push of the high byte of PC onto IE cancels the interrupt: the CPU jumps to 0000h and IF keeps it.
+        let mut c = cpu(&[NOP], 0x04, 0x04);
+        c.ei = true;
+        c.reg.sp = 0x0000;
+        c.steps(1);
+        assert_eq!(c.reg.pc, 0x0000);
+        assert_eq!(c.intf(), 0x04);
+        assert_eq!(c.mem.get(0xffff), 0x01);
+    }
+
+    #[test]
+    fn stop() {
+        // STOP waits for a key whatever the interrupts, and skips the byte after it.
+        let mut c = cpu(&[STOP, INC_A, INC_A], 0x00, 0x04);
+        c.mem.set(0xff04, 0xab);
+        c.steps(4);
+        assert!(c.stopped);
+        assert_eq!((c.reg.pc, c.reg.a), (0x0102, 0x00));
+        assert_eq!(c.mem.get(0xff04), 0x00);
+        c.mem.set(0xff0f, 0x04);
+        c.steps(2);
+        assert!(c.stopped);
+        c.mem.set(0xff00, 0xfe);
+        c.steps(2);
+        assert!(!c.stopped && c.reg.a == 0x01);
+    }
+
+    #[test]
+    fn stop_interrupt_pending() {
+        // With an interrupt pending, STOP is a single byte.
+        let mut c = cpu(&[STOP, INC_A], 0x04, 0x04);
+        c.steps(1);
+        assert!(c.stopped && c.reg.pc == 0x0101);
+    }
+
+    #[test]
+    fn stop_key_held() {
+        // A key held makes STOP a HALT, and DIV isn't reset.
+        let mut c = cpu(&[STOP, INC_A, INC_A], 0x00, 0x04);
+        c.mem.set(0xff00, 0xfe);
+        c.mem.set(0xff04, 0xab);
+        c.steps(3);
+        assert!(!c.stopped && c.halted && c.reg.pc == 0x0102);
+        assert_eq!(c.mem.get(0xff04), 0xab);
+    }
+
+    #[test]
+    fn stop_speed_switch() {
+        // An armed speed switch doesn't stop, the CPU pauses while the speed changes.
+        let mut c = cpu(&[STOP, INC_A, INC_A], 0x00, 0x00);
+        c.mem.set(0xff4d, 0x01);
+        c.steps(1);
+        assert!(!c.stopped && c.speed_switch);
+        c.steps(2050);
+        assert_eq!((c.reg.pc, c.reg.a), (0x0102, 0x00));
+        c.steps(1);
+        assert_eq!(c.reg.a, 0x01);
+    }
 }
@@ -0,0 +1,239 @@
+// A command line debugger. It stops the emulation before an instruction at a breakpoint is executed, after a single
+// step, or after an instruction which hit a watchpoint, and reads commands from the standard input:
+//   c            Continue until the next breakpoint
+//   s            Execute a single instruction
+//   b <addr>     Set a breakpoint at address, e.g. b 0150
+//   d <addr>     Delete a breakpoint
+//   w <watch>    Set a watchpoint, e.g. w a000-bfff for the writes to the cartridge RAM, see the watch module
+//   dw <addr>    Delete the watchpoints of an address
+//   l            List breakpoints and watchpoints
+//   r            Dump the registers
+//   m <addr> [n] Dump n bytes of memory starting at address, 16 by default
+//   g            Dump the LCD registers
+//   export <region> <file>
+//                Write a RAM region to a file: wram, vram, hram or oam. The banks of the CGB are included
+//   import <region> <file>
+//                Read a RAM region back from a file written by export, e.g. by another emulator
+//   q            Quit
+use super::cpu::Cpu;
+use super::disasm;
+use super::gpu;
+use super::memory::Memory;
+use super::watch::Watchpoint;
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    // Stop before the next instruction, whatever its address.
+    step: bool,
+}
+
+impl Debugger {
+    // The debugger stops before the first instruction.
+    pub fn power_up() -> Self {
+        Self { breakpoints: BTreeSet::new(), step: true }
+    }
+
+    // Attach the debugger to the pre-execution hook of the CPU.
+    pub fn attach(mut self, cpu: &mut Cpu) {
+        cpu.hook = Some(Box::new(move |cpu, mem| self.hook(cpu, mem)));
+    }
+
+    fn hook(&mut self, cpu: &mut Cpu, mem: &mut dyn Memory) {
+        let hit = cpu.watch_hit.take();
+        if !self.step && !self.breakpoints.contains(&cpu.reg.pc) && hit.is_none() {
+            return;
+        }
+        if let Some(hit) = hit {
+            println!("Watchpoint: {}", hit);
+        }
+        self.step = false;
+        self.dump_next(cpu, mem);
+        let stdin = std::io::stdin();
+        loop {
+            print!("(gbdb) ");
+            std::io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap() == 0 {
+                // End of input, let the game run.
+                return;
+            }
+            let args: Vec<&str> = line.split_whitespace().collect();
+            let addr = |i: usize| args.get(i).and_then(|s| u16::from_str_radix(s.trim_start_matches("0x"), 16).ok());
+            match args.first().copied() {
+                Some("c") => return,
+                Some("s") => {
+                    self.step = true;
+                    return;
+                }
+                Some("b") => match addr(1) {
+                    Some(a) => {
+                        self.breakpoints.insert(a);
+                    }
+                    None => println!("Usage: b <addr>"),
+                },
+                Some("d") => match addr(1) {
+                    Some(a) => {
+                        self.breakpoints.remove(&a);
+                    }
+                    None => println!("Usage: d <addr>"),
+                },
+                Some("w") => match args.get(1).map(|s| s.parse::<Watchpoint>()) {
+                    Some(Ok(w)) => cpu.watchpoints.push(w),
+                    Some(Err(e)) => println!("{}", e),
+                    None => println!("Usage: w <start>[-<end>][:r|w|rw]"),
+                },
+                Some("dw") => match addr(1) {
+                    Some(a) => cpu.watchpoints.retain(|w| !w.range.contains(&a)),
+                    None => println!("Usage: dw <addr>"),
+                },
+                Some("l") => {
+                    for a in &self.breakpoints {
+                        println!("{:04x}", a);
+                    }
+                    for w in &cpu.watchpoints {
+                        println!("{}", w);
+                    }
+                }
+                Some("r") => self.dump_registers(cpu),
+                Some("m") => match addr(1) {
+                    Some(a) => {
+                        let n = args.get(2).and_then(|s| s.parse::<u16>().ok()).unwrap_or(16);
+                        self.dump_memory(mem, a, n);
+                    }
+                    None => println!("Usage: m <addr> [n]"),
+                },
+                Some("g") => println!("{}", gpu::report(mem, is_cgb(mem))),
+                Some(c @ ("export" | "import")) => match (args.get(1).and_then(|r| region(r)), args.get(2)) {
+                    (Some(segments), Some(path)) => {
+                        let r = if c == "export" {
+                            self.export(mem, &segments, path)
+                        } else {
+                            self.import(mem, &segments, path)
+                        };
+                        if let Err(e) = r {
+                            println!("{}", e);
+                        }
+                    }
+                    _ => println!("Usage: {} <wram|vram|hram|oam> <file>", c),
+                },
+                Some("q") => std::process::exit(0),
+                Some(_) => println!(
+                    "Commands: c, s, b <addr>, d <addr>, w <watch>, dw <addr>, l, r, m <addr> [n], g, \
+                     export <region> <file>, import <region> <file>, q"
+                ),
+                None => {}
+            }
+        }
+    }
+
+    fn dump_next(&self, cpu: &Cpu, mem: &dyn Memory) {
+        println!("{}", disasm::decode(mem, cpu.reg.pc));
+    }
+
+    fn dump_registers(&self, cpu: &Cpu) {
+        let r = &cpu.reg;
+        println!(
+            "af={:04x} bc={:04x} de={:04x} hl={:04x} sp={:04x} pc={:04x} ime={} halted={}",
+            r.get_af(),
+            r.get_bc(),
+            r.get_de(),
+            r.get_hl(),
+            r.sp,
+            r.pc,
+            cpu.ei,
+            cpu.halted
+        );
+    }
+
+    // The region is read through the bus, the banks are switched with their registers and switched back afterwards.
+    fn export(&self, mem: &mut dyn Memory, segments: &[Segment], path: &str) -> std::io::Result<()> {
+        let cgb = is_cgb(mem);
+        let mut data = vec![];
+        for s in segments.iter().filter(|s| cgb || !s.cgb) {
+            let bank = s.bank.map(|(a, v)| (a, mem.get(a), v));
+            if let Some((a, _, v)) = bank {
+                mem.set(a, v);
+            }
+            data.extend((s.start..s.start + s.len).map(|a| mem.get(a)));
+            if let Some((a, old, _)) = bank {
+                mem.set(a, old);
+            }
+        }
+        std::fs::write(path, &data)?;
+        println!("{} bytes written to {}", data.len(), path);
+        Ok(())
+    }
+
+    fn import(&self, mem: &mut dyn Memory, segments: &[Segment], path: &str) -> std::io::Result<()> {
+        let cgb = is_cgb(mem);
+        let data = std::fs::read(path)?;
+        let size: usize = segments.iter().filter(|s| cgb || !s.cgb).map(|s| usize::from(s.len)).sum();
+        if data.len() != size {
+            let e = format!("Debugger: {} has {} bytes, the region has {}", path, data.len(), size);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+        }
+        let mut i = 0;
+        for s in segments.iter().filter(|s| cgb || !s.cgb) {
+            let bank = s.bank.map(|(a, v)| (a, mem.get(a), v));
+            if let Some((a, _, v)) = bank {
+                mem.set(a, v);
+            }
+            for a in s.start..s.start + s.len {
+                mem.set(a, data[i]);
+                i += 1;
+            }
+            if let Some((a, old, _)) = bank {
+                mem.set(a, old);
+            }
+        }
+        println!("{} bytes read from {}", size, path);
+        Ok(())
+    }
+
+    fn dump_memory(&self, mem: &dyn Memory, a: u16, n: u16) {
+        for row in (0..n).step_by(16) {
+            let base = a.wrapping_add(row);
+            let line: Vec<String> =
+                (0..16.min(n - row)).map(|i| format!("{:02x}", mem.get(base.wrapping_add(i)))).collect();
+            println!("{:04x}: {}", base, line.join(" "));
+        }
+    }
+}
+
+// The CGB flag of the cartridge header, the same test the Mmunit uses to pick the term.
+fn is_cgb(mem: &dyn Memory) -> bool {
+    mem.get(0x0143) & 0x80 != 0x00
+}
+
+// A part of a RAM region as seen on the bus.
+struct Segment {
+    start: u16,
+    len: u16,
+    // Register and value which select the bank before the access.
+    bank: Option<(u16, u8)>,
+    // The bank only exists on the CGB.
+    cgb: bool,
+}
+
+fn region(name: &str) -> Option<Vec<Segment>> {
+    let plain = |start, len| Segment { start, len, bank: None, cgb: false };
+    match name {
+        // Bank 0 at C000, then banks 1 to 7 at D000 selected by SVBK. The DMG only has bank 1.
+        "wram" => {
+            let mut r = vec![plain(0xc000, 0x1000)];
+            for n in 1..8 {
+                r.push(Segment { start: 0xd000, len: 0x1000, bank: Some((0xff70, n)), cgb: n != 1 });
+            }
+            Some(r)
+        }
+        // Banks 0 and 1 selected by VBK.
+        "vram" => {
+            Some((0..2).map(|n| Segment { start: 0x8000, len: 0x2000, bank: Some((0xff4f, n)), cgb: n != 0 }).collect())
+        }
+        "hram" => Some(vec![plain(0xff80, 0x7f)]),
+        "oam" => Some(vec![plain(0xfe00, 0xa0)]),
+        _ => None,
+    }
+}
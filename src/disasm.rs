@@ -0,0 +1,150 @@
+// Disassembler for the LR35902 instruction set. The opcodes are decoded by their bit fields rather than by a table of
+// 512 entries, as most instructions are laid out in a regular grid:
+//
+//   7 6 | 5 4 3 | 2 1 0
+//    x  |   y   |   z       y = p q, p = bits 5-4, q = bit 3
+//
+// The lengths are those of OP_LEN, the table the block cache of the CPU decodes the rom with.
+//
+// See: https://gb-archive.github.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
+use super::cpu::{CB_CYCLES, OP_CYCLES, OP_LEN};
+use super::memory::Memory;
+use std::fmt;
+
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const R16_STACK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const R16_MEM: [&str; 4] = ["(BC)", "(DE)", "(HL+)", "(HL-)"];
+const COND: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const MISC: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+pub struct Instruction {
+    pub addr: u16,
+    // The opcode and its operands.
+    pub bytes: Vec<u8>,
+    pub text: String,
+    // The text split in its mnemonic and operands, e.g. "LD" and ["A", "($FF44)"].
+    pub mnemonic: String,
+    pub operands: Vec<String>,
+    // Clock cycles at normal speed. A conditional jump, call or return takes cycles_taken when the condition holds.
+    // An opcode that doesn't exist takes none, the CPU locks up.
+    pub cycles: u32,
+    pub cycles_taken: Option<u32>,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        write!(f, "{:04x}: {:<9} {}", self.addr, bytes.join(" "), self.text)
+    }
+}
+
+// Decode the instruction at address a.
+pub fn decode(mem: &dyn Memory, a: u16) -> Instruction {
+    let opcode = mem.get(a);
+    let d8 = mem.get(a.wrapping_add(1));
+    let d16 = u16::from(d8) | (u16::from(mem.get(a.wrapping_add(2))) << 8);
+    // Target of a relative jump.
+    let r8 = a.wrapping_add(2).wrapping_add(d8 as i8 as u16);
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let z = opcode & 0x07;
+    let p = y >> 1;
+    let q = y & 0x01;
+
+    let text = match (x, z) {
+        (0, 0) => match y {
+            0 => String::from("NOP"),
+            1 => format!("LD (${:04X}),SP", d16),
+            2 => String::from("STOP"),
+            3 => format!("JR ${:04X}", r8),
+            _ => format!("JR {},${:04X}", COND[y - 4], r8),
+        },
+        (0, 1) if q == 0 => format!("LD {},${:04X}", R16[p], d16),
+        (0, 1) => format!("ADD HL,{}", R16[p]),
+        (0, 2) if q == 0 => format!("LD {},A", R16_MEM[p]),
+        (0, 2) => format!("LD A,{}", R16_MEM[p]),
+        (0, 3) if q == 0 => format!("INC {}", R16[p]),
+        (0, 3) => format!("DEC {}", R16[p]),
+        (0, 4) => format!("INC {}", R8[y]),
+        (0, 5) => format!("DEC {}", R8[y]),
+        (0, 6) => format!("LD {},${:02X}", R8[y], d8),
+        (0, _) => String::from(MISC[y]),
+        (1, 6) if y == 6 => String::from("HALT"),
+        (1, _) => format!("LD {},{}", R8[y], R8[z as usize]),
+        (2, _) => format!("{}{}", ALU[y], R8[z as usize]),
+        (_, 0) => match y {
+            0..=3 => format!("RET {}", COND[y]),
+            4 => format!("LDH ($FF{:02X}),A", d8),
+            5 => format!("ADD SP,{}", d8 as i8),
+            6 => format!("LDH A,($FF{:02X})", d8),
+            _ => format!("LD HL,SP{:+}", d8 as i8),
+        },
+        (_, 1) if q == 0 => format!("POP {}", R16_STACK[p]),
+        (_, 1) => String::from(["RET", "RETI", "JP HL", "LD SP,HL"][p]),
+        (_, 2) => match y {
+            0..=3 => format!("JP {},${:04X}", COND[y], d16),
+            4 => String::from("LD ($FF00+C),A"),
+            5 => format!("LD (${:04X}),A", d16),
+            6 => String::from("LD A,($FF00+C)"),
+            _ => format!("LD A,(${:04X})", d16),
+        },
+        (_, 3) => match y {
+            0 => format!("JP ${:04X}", d16),
+            1 => {
+                let x = d8 >> 6;
+                let y = ((d8 >> 3) & 0x07) as usize;
+                let r = R8[(d8 & 0x07) as usize];
+                match x {
+                    0 => format!("{} {}", ROT[y], r),
+                    1 => format!("BIT {},{}", y, r),
+                    2 => format!("RES {},{}", y, r),
+                    _ => format!("SET {},{}", y, r),
+                }
+            }
+            6 => String::from("DI"),
+            7 => String::from("EI"),
+            _ => format!("DB ${:02X}", opcode),
+        },
+        (_, 4) if y < 4 => format!("CALL {},${:04X}", COND[y], d16),
+        (_, 5) if q == 0 => format!("PUSH {}", R16_STACK[p]),
+        (_, 5) if y == 1 => format!("CALL ${:04X}", d16),
+        (_, 6) => format!("{}${:02X}", ALU[y], d8),
+        (_, 7) => format!("RST ${:02X}", y * 8),
+        // The remaining opcodes don't exist and lock up the CPU.
+        _ => format!("DB ${:02X}", opcode),
+    };
+    let bytes = (0..u16::from(OP_LEN[opcode as usize])).map(|i| mem.get(a.wrapping_add(i))).collect();
+    let (mnemonic, operands) = match text.split_once(' ') {
+        Some((m, o)) => (m.to_string(), o.split(',').map(String::from).collect()),
+        None => (text.clone(), vec![]),
+    };
+    let cycles = match opcode {
+        0xcb => CB_CYCLES[d8 as usize],
+        // Both are counted by the CPU as the time it sleeps.
+        0x10 | 0x76 => 1,
+        _ => OP_CYCLES[opcode as usize],
+    } * 4;
+    let extra = match opcode {
+        0x20 | 0x28 | 0x30 | 0x38 | 0xc2 | 0xca | 0xd2 | 0xda => Some(4),
+        0xc0 | 0xc8 | 0xd0 | 0xd8 | 0xc4 | 0xcc | 0xd4 | 0xdc => Some(12),
+        _ => None,
+    };
+    Instruction { addr: a, bytes, text, mnemonic, operands, cycles, cycles_taken: extra.map(|e| cycles + e) }
+}
+
+// Decode count instructions one after the other from address a, e.g. for a view of the code at PC. Instructions have
+// different lengths, so decoding can't go backwards from PC: start from an address known to be an instruction, such
+// as one PC held earlier, to show the code before it.
+pub fn disassemble(mem: &dyn Memory, a: u16, count: usize) -> Vec<Instruction> {
+    let mut r = Vec::with_capacity(count);
+    let mut a = a;
+    for _ in 0..count {
+        let i = decode(mem, a);
+        a = a.wrapping_add(i.bytes.len() as u16);
+        r.push(i);
+    }
+    r
+}
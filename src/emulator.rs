@@ -0,0 +1,90 @@
+// A headless emulator for library users: bots, test harnesses and other frontends. It runs frame by frame as fast as
+// the host calls it, never sleeps, and the host does the pacing, the drawing and the sound output.
+//
+//   let mut emulator = Emulator::power_up("./res/sml.gb")?;
+//   loop {
+//       emulator.push_input(JoypadKey::Start, pressed);
+//       let frame = emulator.run_frame();
+//       let samples = emulator.audio_samples();
+//   }
+//
+// The machine owns all of its state, the devices plugged into it must be Send too: it can be moved to a worker thread,
+// or shared behind a Mutex.
+use super::cartridge::{self, Cartridge};
+use super::disasm::{self, Instruction};
+use super::error::Result;
+use super::gpu::{Framebuffer, SCREEN_H, SCREEN_W};
+use super::joypad::JoypadKey;
+use super::motherboard::MotherBoard;
+use super::verify::Digest;
+use std::path::Path;
+
+pub struct Emulator {
+    // The machine, for everything the facade doesn't cover.
+    pub mbrd: MotherBoard,
+    frame: Box<Framebuffer>,
+}
+
+impl Emulator {
+    pub fn power_up(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::power_up_cartridge(cartridge::power_up(path)?))
+    }
+
+    pub fn power_up_cartridge(cart: Box<dyn Cartridge>) -> Self {
+        let mut mbrd = MotherBoard::power_up_cartridge(cart);
+        mbrd.cpu.throttle = false;
+        Self { mbrd, frame: Box::new([[[0xff; 3]; SCREEN_W]; SCREEN_H]) }
+    }
+
+    // Run until the next VBlank and return the screen. While the LCD is off there is no VBlank, it then returns after
+    // the length of a frame with the last screen, which is what the LCD shows too.
+    pub fn run_frame(&mut self) -> &Framebuffer {
+        self.mbrd.finish_frame();
+        if self.mbrd.check_and_reset_gpu_updated() {
+            *self.frame = self.mbrd.mmu.gpu.data;
+        }
+        &self.frame
+    }
+
+    pub fn push_input(&mut self, key: JoypadKey, pressed: bool) {
+        let mmu = &mut self.mbrd.mmu;
+        if pressed {
+            mmu.joypad.keydown(key);
+        } else {
+            mmu.joypad.keyup(key);
+        }
+    }
+
+    // The stereo samples generated since the last call, at sample_rate. At most one second is kept, call it at
+    // least that often.
+    pub fn audio_samples(&mut self) -> Vec<(f32, f32)> {
+        self.mbrd.mmu.apu.buffer.drain()
+    }
+
+    // Leave a channel out of the sound: 0 and 1 are the square channels, 2 the wave channel and 3 the noise channel.
+    // Other numbers are ignored.
+    pub fn mute_channel(&mut self, channel: usize, muted: bool) {
+        if let Some(m) = self.mbrd.mmu.apu.muted.get_mut(channel) {
+            *m = muted;
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.mbrd.mmu.apu.sample_rate()
+    }
+
+    // The instructions from address a, as the CPU sees the memory now. Use the program counter for a live code view:
+    // disassemble(emulator.pc(), 16).
+    pub fn disassemble(&self, a: u16, count: usize) -> Vec<Instruction> {
+        disasm::disassemble(&self.mbrd.mmu, a, count)
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.mbrd.cpu.cpu.reg.pc
+    }
+
+    // The CRC32 and SHA-1 of the rom, to look it up in a No-Intro DAT with verify::find.
+    pub fn digest(&self) -> Digest {
+        Digest::of(self.mbrd.mmu.cartridge.rom())
+    }
+}
@@ -0,0 +1,50 @@
+// The errors of the public API. Loading a rom, a save state or a boot ROM, or writing a save, can fail for reasons
+// outside of the emulator's hands, a missing file or a rom it can't run, and a frontend embedding the emulator gets
+// them as values rather than a panic that takes the whole application down. Panics are left for broken invariants of
+// the emulator itself.
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    // Reading the rom, or reading or writing the battery backed RAM or clock next to it.
+    Io(io::Error),
+    // The rom can't be run: its header is missing or corrupt, or describes hardware that isn't emulated.
+    Rom(String),
+    // A save state can't be restored: it is cut short, or saved by another version or for another cartridge. Each
+    // part of the machine tells what it found wrong in its snapshot.
+    State(io::Error),
+    // The boot ROM doesn't fit the model.
+    BootRom(String),
+    // A palette file which isn't one, see the palette module.
+    Palette(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Rom(s) => write!(f, "Cartridge: {}", s),
+            Error::State(e) => write!(f, "{}", e),
+            Error::BootRom(s) => write!(f, "Boot ROM: {}", s),
+            Error::Palette(s) => write!(f, "Palette: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::State(e) => Some(e),
+            Error::Rom(_) | Error::BootRom(_) | Error::Palette(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,8 +1,11 @@
+use super::clock::{Domain, Scheduler, Tick};
 use super::convention::Term;
 use super::intf::{Flag, Intf};
 use super::memory::Memory;
-use std::cell::RefCell;
-use std::rc::Rc;
+use super::palette::{self, DmgPalette};
+use super::state::{Reader, Snapshot, Writer};
+use std::str::FromStr;
+use std::sync::OnceLock;
 
 #[derive(Eq, PartialEq)]
 pub enum HdmaMode {
@@ -155,6 +158,9 @@ pub struct Stat {
     //    2: During Searching OAM
     //    3: During Transferring Data to LCD Driver
     mode: u8,
+    // The four sources share a single interrupt line, the OR of the enabled conditions. LCDStat is only requested when
+    // the line goes from low to high, so a condition that starts while another one holds the line high is lost.
+    line: bool,
 }
 
 impl Stat {
@@ -165,6 +171,7 @@ impl Stat {
             enable_m1_interrupt: false,
             enable_m0_interrupt: false,
             mode: 0x00,
+            line: false,
         }
     }
 }
@@ -238,6 +245,83 @@ impl From<u8> for Attr {
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
 
+// The screen in RGB, line by line.
+pub type Framebuffer = [[[u8; 3]; SCREEN_W]; SCREEN_H];
+
+// The registers a scanline is drawn with. The LCD controller reads them when the line enters mode 3, so a game changes
+// them for the next line during H-Blank or mode 2, and a write during mode 3 waits for the next line. The real
+// hardware reads SCX and SCY again for each tile, which this renderer of whole lines doesn't do.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Scanline {
+    pub scx: u8,
+    pub scy: u8,
+    pub wx: u8,
+    // The row of the window drawn on the line, None when the window isn't on it.
+    pub window: Option<u8>,
+}
+
+// How the 15-bit colors of a GBC game are turned into the RGB of the screen. The LCD of the GBC doesn't show them as
+// a monitor does, see set_rgb.
+//
+//   Raw  The colors as written by the game, scaled to 8 bits. What a developer picked in a paint program.
+//   Cgb  The colors mixed and washed out as on the GBC.
+//   Gba  The colors as on the darker LCD of the GBA, which runs GBC games with a higher contrast.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorCorrection {
+    Raw,
+    Cgb,
+    Gba,
+}
+
+impl FromStr for ColorCorrection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "raw" => Ok(ColorCorrection::Raw),
+            "cgb" => Ok(ColorCorrection::Cgb),
+            "gba" => Ok(ColorCorrection::Gba),
+            _ => Err(format!("Unsupported color correction: {}", s)),
+        }
+    }
+}
+
+impl ColorCorrection {
+    // The RGB of a 15-bit color, each component in 0-1Fh.
+    pub fn rgb(self, r: u8, g: u8, b: u8) -> [u8; 3] {
+        assert!(r <= 0x1f);
+        assert!(g <= 0x1f);
+        assert!(b <= 0x1f);
+        match self {
+            ColorCorrection::Raw => [r << 3 | r >> 2, g << 3 | g >> 2, b << 3 | b >> 2],
+            ColorCorrection::Cgb => {
+                let r = u32::from(r);
+                let g = u32::from(g);
+                let b = u32::from(b);
+                let lr = ((r * 13 + g * 2 + b) >> 1) as u8;
+                let lg = ((g * 3 + b) << 1) as u8;
+                let lb = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
+                [lr, lg, lb]
+            }
+            ColorCorrection::Gba => {
+                // The mixing needs a power per pixel, so all 32768 colors are computed once.
+                static TABLE: OnceLock<Vec<[u8; 3]>> = OnceLock::new();
+                let table = TABLE.get_or_init(|| (0..0x8000).map(gba_rgb).collect());
+                table[usize::from(b) << 10 | usize::from(g) << 5 | usize::from(r)]
+            }
+        }
+    }
+}
+
+// The GBA LCD has a gamma of about 4 and its pigments bleed into each other: the components are made linear, mixed,
+// and brought back to the 2.2 gamma of a monitor. The brightest white stays a little below full white, as on the GBA.
+fn gba_rgb(c: usize) -> [u8; 3] {
+    let lin = |v: usize| ((v & 0x1f) as f64 / 31.0).powf(4.0);
+    let (r, g, b) = (lin(c), lin(c >> 5), lin(c >> 10));
+    let out = |v: f64| ((v / 255.0).powf(1.0 / 2.2) * 255.0 * 255.0 / 280.0).round() as u8;
+    [out(255.0 * r + 50.0 * g), out(10.0 * r + 230.0 * g + 30.0 * b), out(50.0 * r + 10.0 * g + 220.0 * b)]
+}
+
 pub struct Gpu {
     // Digital image with mode RGB. Size = 144 * 160 * 3.
     // 3---------
@@ -245,11 +329,24 @@ pub struct Gpu {
     // ----------
     // ---------- 160
     //        144
-    pub data: [[[u8; 3]; SCREEN_W]; SCREEN_H],
-    pub intf: Rc<RefCell<Intf>>,
+    pub data: Framebuffer,
+    pub intf: Intf,
     pub term: Term,
     pub h_blank: bool,
     pub v_blank: bool,
+    // Debug overlay: tint the scanlines with more than 10 sprites in red. The hardware only draws the first 10 sprites
+    // of a line, so these are the lines where sprites go missing or flicker.
+    pub overflow_tint: bool,
+    // The colors of the gray shades in DMG mode. In the compatibility mode of the CGB they are those its boot ROM
+    // picks, see palette::cgb, to be picked again by a frontend which changes the color correction.
+    pub dmg_palette: DmgPalette,
+    // The colors of GBC games.
+    pub color_correction: ColorCorrection,
+    // LY always reads 0x90, the first line of the vertical blank. The logs of Gameboy Doctor are made this way, so that
+    // a trace matches them whatever the timing of the GPU, see Cpu::trace.
+    pub ly_stub: bool,
+    // The registers of each visible line of the frame, e.g. for tests of raster effects.
+    pub scanlines: [Scanline; SCREEN_H],
 
     lcdc: Lcdc,
     stat: Stat,
@@ -262,6 +359,10 @@ pub struct Gpu {
     // Window Y Position (R/W), Window X Position minus 7 (R/W)
     wy: u8,
     wx: u8,
+    // The window starts on the first line where LY equals WY, and stays on for the rest of the frame. Its own line
+    // counter only counts the lines it was drawn on: hidden for a few lines, it resumes where it stopped.
+    window_y: bool,
+    window_line: u8,
     // The LY indicates the vertical line to which the present data is transferred to the LCD Driver. The LY can take
     // on any value between 0 through 153. The values between 144 and 153 indicate the V-Blank period. Writing will
     // reset the counter.
@@ -298,6 +399,10 @@ pub struct Gpu {
 
     ram: [u8; 0x4000],
     ram_bank: usize,
+    // Decoded tile rows of the background, the window and the sprites, indexed by the offset of their first byte in
+    // ram divided by 2. The 2 bits of a pixel are spread over two bytes, decoding them again for every scanline is
+    // wasted work in static scenes: a row is decoded once, and dropped when one of its bytes is written.
+    tile_rows: Vec<Option<[u8; 8]>>,
     // VRAM Sprite Attribute Table (OAM)
     // Gameboy video controller can display up to 40 sprites either in 8x8 or in 8x16 pixels. Because of a limitation of
     // hardware, only ten sprites can be displayed per scan line. Sprite patterns have the same format as BG tiles, but
@@ -337,13 +442,19 @@ pub struct Gpu {
 }
 
 impl Gpu {
-    pub fn power_up(term: Term, intf: Rc<RefCell<Intf>>) -> Self {
+    pub fn power_up(term: Term, clock: &mut Scheduler) -> Self {
+        clock.register(Tick::Dot, Domain::Fixed, 1);
         Self {
             data: [[[0xffu8; 3]; SCREEN_W]; SCREEN_H],
-            intf,
+            intf: Intf::power_up(),
             term,
             h_blank: false,
             v_blank: false,
+            overflow_tint: false,
+            dmg_palette: palette::GRAY,
+            color_correction: ColorCorrection::Cgb,
+            ly_stub: false,
+            scanlines: [Scanline::default(); SCREEN_H],
 
             lcdc: Lcdc::power_up(),
             stat: Stat::power_up(),
@@ -351,6 +462,8 @@ impl Gpu {
             sx: 0x00,
             wx: 0x00,
             wy: 0x00,
+            window_y: false,
+            window_line: 0x00,
             ly: 0x00,
             lc: 0x00,
             bgp: 0x00,
@@ -362,12 +475,49 @@ impl Gpu {
             cobpd: [[[0u8; 3]; 4]; 8],
             ram: [0x00; 0x4000],
             ram_bank: 0x00,
+            tile_rows: vec![None; 0x2000],
             oam: [0x00; 0xa0],
             prio: [(true, 0); SCREEN_W],
             dots: 0,
         }
     }
 
+    // OAM bug: during mode 2 the PPU reads OAM one row of 8 bytes every 4 dots. A write to FE00-FEFF at that time
+    // corrupts the row being read: its first word is mixed with the first and third words of the preceding row, and
+    // the other three words are copied from the preceding row. The first row is never corrupted.
+    //
+    // See: https://gbdev.io/pandocs/OAM_Corruption_Bug.html
+    pub fn oam_bug_write(&mut self) {
+        if !self.lcdc.bit7() || self.stat.mode != 2 {
+            return;
+        }
+        let row = (self.dots / 4) as usize;
+        if row == 0 || row >= 20 {
+            return;
+        }
+        let i = row * 8;
+        let word = |j: usize| u16::from(self.oam[j]) | (u16::from(self.oam[j + 1]) << 8);
+        let a = word(i);
+        let b = word(i - 8);
+        let c = word(i - 4);
+        let v = ((a ^ c) & (b ^ c)) ^ c;
+        self.oam[i] = v as u8;
+        self.oam[i + 1] = (v >> 8) as u8;
+        for j in 2..8 {
+            self.oam[i + j] = self.oam[i - 8 + j];
+        }
+    }
+
+    // Color numbers of the 8 pixels of a tile row, from left to right. i is the offset of the row in ram.
+    fn tile_row(&mut self, i: usize) -> [u8; 8] {
+        if let Some(row) = self.tile_rows[i / 2] {
+            return row;
+        }
+        let row = decode_row(self.ram[i], self.ram[i + 1]);
+        self.tile_rows[i / 2] = Some(row);
+        row
+    }
+
     fn get_ram0(&self, a: u16) -> u8 {
         self.ram[a as usize - 0x8000]
     }
@@ -386,18 +536,13 @@ impl Gpu {
     // 1  Light gray
     // 2  Dark gray
     // 3  Black
-    fn get_gray_shades(v: u8, i: usize) -> GrayShades {
-        match v >> (2 * i) & 0x03 {
-            0x00 => GrayShades::White,
-            0x01 => GrayShades::Light,
-            0x02 => GrayShades::Dark,
-            _ => GrayShades::Black,
-        }
+    fn get_gray_shades(v: u8, i: usize) -> usize {
+        (v >> (2 * i) & 0x03) as usize
     }
 
-    // Grey scale.
-    fn set_gre(&mut self, x: usize, g: u8) {
-        self.data[self.ly as usize][x] = [g, g, g];
+    // A gray shade of the background (0), OBP0 (1) or OBP1 (2), in the colors of the DMG palette.
+    fn set_gre(&mut self, x: usize, palette: usize, shade: usize) {
+        self.data[self.ly as usize][x] = self.dmg_palette[palette][shade];
     }
 
     // When developing graphics on PCs, note that the RGB values will have different appearance on CGB displays as on
@@ -408,20 +553,14 @@ impl Gpu {
     // intensity of only one R,G,B color will also influence the other two R,G,B colors. For example, a color setting
     // of 03EFh (Blue=0, Green=1Fh, Red=0Fh) will appear as Neon Green on VGA displays, but on the CGB it'll produce a
     // decently washed out Yellow. See image on the right.
+    //
+    // The color_correction picks how much of this is emulated.
     fn set_rgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
-        assert!(r <= 0x1f);
-        assert!(g <= 0x1f);
-        assert!(b <= 0x1f);
-        let r = u32::from(r);
-        let g = u32::from(g);
-        let b = u32::from(b);
-        let lr = ((r * 13 + g * 2 + b) >> 1) as u8;
-        let lg = ((g * 3 + b) << 1) as u8;
-        let lb = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
-        self.data[self.ly as usize][x] = [lr, lg, lb];
+        self.data[self.ly as usize][x] = self.color_correction.rgb(r, g, b);
     }
 
-    pub fn next(&mut self, cycles: u32) {
+    pub fn next(&mut self, clock: &mut Scheduler) {
+        let cycles = clock.take(Tick::Dot);
         if !self.lcdc.bit7() {
             return;
         }
@@ -452,9 +591,7 @@ impl Gpu {
             self.dots %= 456;
             if d != self.dots {
                 self.ly = (self.ly + 1) % 154;
-                if self.stat.enable_ly_interrupt && self.ly == self.lc {
-                    self.intf.borrow_mut().hi(Flag::LCDStat);
-                }
+                self.update_stat();
             }
             if self.ly >= 144 {
                 if self.stat.mode == 1 {
@@ -462,29 +599,30 @@ impl Gpu {
                 }
                 self.stat.mode = 1;
                 self.v_blank = true;
-                self.intf.borrow_mut().hi(Flag::VBlank);
-                if self.stat.enable_m1_interrupt {
-                    self.intf.borrow_mut().hi(Flag::LCDStat);
-                }
+                self.window_y = false;
+                self.window_line = 0;
+                self.intf.hi(Flag::VBlank);
+                self.update_stat();
             } else if self.dots <= 80 {
                 if self.stat.mode == 2 {
                     continue;
                 }
                 self.stat.mode = 2;
-                if self.stat.enable_m2_interrupt {
-                    self.intf.borrow_mut().hi(Flag::LCDStat);
-                }
+                self.update_stat();
             } else if self.dots <= (80 + 172) {
+                if self.stat.mode == 3 {
+                    continue;
+                }
                 self.stat.mode = 3;
+                self.update_stat();
+                self.latch();
             } else {
                 if self.stat.mode == 0 {
                     continue;
                 }
                 self.stat.mode = 0;
                 self.h_blank = true;
-                if self.stat.enable_m0_interrupt {
-                    self.intf.borrow_mut().hi(Flag::LCDStat);
-                }
+                self.update_stat();
                 // Render scanline
                 if self.term == Term::GBC || self.lcdc.bit0() {
                     self.draw_bg();
@@ -496,73 +634,106 @@ impl Gpu {
         }
     }
 
+    // Read the registers of the line entering mode 3.
+    fn latch(&mut self) {
+        if self.ly == self.wy {
+            self.window_y = true;
+        }
+        let window = self.lcdc.bit5() && self.window_y && self.wx <= 166;
+        self.scanlines[usize::from(self.ly)] =
+            Scanline { scx: self.sx, scy: self.sy, wx: self.wx, window: window.then_some(self.window_line) };
+        if window {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+    }
+
+    // The STAT interrupt line, low while the LCD is off.
+    fn stat_line(&self) -> bool {
+        if !self.lcdc.bit7() {
+            return false;
+        }
+        (self.stat.enable_ly_interrupt && self.ly == self.lc)
+            || (self.stat.enable_m2_interrupt && self.stat.mode == 2)
+            || (self.stat.enable_m1_interrupt && self.stat.mode == 1)
+            || (self.stat.enable_m0_interrupt && self.stat.mode == 0)
+    }
+
+    fn update_stat(&mut self) {
+        let line = self.stat_line();
+        if line && !self.stat.line {
+            self.intf.hi(Flag::LCDStat);
+        }
+        self.stat.line = line;
+    }
+
+    // The background and the window are drawn a tile at a time. When x enters a tile, its number and attributes are
+    // read from the tile map and its row is taken decoded from tile_rows, turned around for X flip. The pixels of the
+    // tile are then a lookup in the row and in the colors of the line.
     fn draw_bg(&mut self) {
-        let show_window = self.lcdc.bit5() && self.wy <= self.ly;
+        let line = self.scanlines[usize::from(self.ly)];
         let tile_base = if self.lcdc.bit4() { 0x8000 } else { 0x8800 };
 
-        let wx = self.wx.wrapping_sub(7);
-        let py = if show_window { self.ly.wrapping_sub(self.wy) } else { self.sy.wrapping_add(self.ly) };
-        let ty = (u16::from(py) >> 3) & 31;
+        // The window starts at WX - 7, left of the screen for WX below 7.
+        let wx = i32::from(line.wx) - 7;
+        // The colors of the 8 BG palettes, the same BGP colors in all of them on the DMG.
+        let colors: [[[u8; 3]; 4]; 8] = std::array::from_fn(|p| self.bg_colors(p));
 
+        let mut tile_row = [0x00; 8];
+        let mut tile_attr = Attr::from(0x00);
         for x in 0..SCREEN_W {
-            let px = if show_window && x as u8 >= wx { x as u8 - wx } else { self.sx.wrapping_add(x as u8) };
-            let tx = (u16::from(px) >> 3) & 31;
+            let in_window = line.window.is_some() && x as i32 >= wx;
+            let (px, py) = match line.window {
+                Some(row) if in_window => ((x as i32 - wx) as u8, row),
+                _ => (line.scx.wrapping_add(x as u8), line.scy.wrapping_add(self.ly)),
+            };
+            // The window always starts at the left of a tile, the background at x 0 may not.
+            if x == 0 || px % 8 == 0 {
+                let tx = (u16::from(px) >> 3) & 31;
+                let ty = (u16::from(py) >> 3) & 31;
 
-            // Background memory base addr.
-            let bg_base = if show_window && x as u8 >= wx {
-                if self.lcdc.bit6() {
+                // Background memory base addr.
+                let bg_base = if in_window {
+                    if self.lcdc.bit6() {
+                        0x9c00
+                    } else {
+                        0x9800
+                    }
+                } else if self.lcdc.bit3() {
                     0x9c00
                 } else {
                     0x9800
-                }
-            } else if self.lcdc.bit3() {
-                0x9c00
-            } else {
-                0x9800
-            };
+                };
 
-            // Tile data
-            // Each tile is sized 8x8 pixels and has a color depth of 4 colors/gray shades.
-            // Each tile occupies 16 bytes, where each 2 bytes represent a line:
-            // Byte 0-1  First Line (Upper 8 pixels)
-            // Byte 2-3  Next Line
-            // etc.
-            let tile_addr = bg_base + ty * 32 + tx;
-            let tile_number = self.get_ram0(tile_addr);
-            let tile_offset =
-                if self.lcdc.bit4() { i16::from(tile_number) } else { i16::from(tile_number as i8) + 128 } as u16 * 16;
-            let tile_location = tile_base + tile_offset;
-            let tile_attr = Attr::from(self.get_ram1(tile_addr));
-
-            let tile_y = if tile_attr.yflip { 7 - py % 8 } else { py % 8 };
-            let tile_y_data: [u8; 2] = if self.term == Term::GBC && tile_attr.bank {
-                let a = self.get_ram1(tile_location + u16::from(tile_y * 2));
-                let b = self.get_ram1(tile_location + u16::from(tile_y * 2) + 1);
-                [a, b]
-            } else {
-                let a = self.get_ram0(tile_location + u16::from(tile_y * 2));
-                let b = self.get_ram0(tile_location + u16::from(tile_y * 2) + 1);
-                [a, b]
-            };
-            let tile_x = if tile_attr.xflip { 7 - px % 8 } else { px % 8 };
+                // Tile data
+                // Each tile is sized 8x8 pixels and has a color depth of 4 colors/gray shades.
+                // Each tile occupies 16 bytes, where each 2 bytes represent a line:
+                // Byte 0-1  First Line (Upper 8 pixels)
+                // Byte 2-3  Next Line
+                // etc.
+                let tile_addr = bg_base + ty * 32 + tx;
+                let tile_number = self.get_ram0(tile_addr);
+                let tile_offset =
+                    if self.lcdc.bit4() { i16::from(tile_number) } else { i16::from(tile_number as i8) + 128 } as u16
+                        * 16;
+                let tile_location = tile_base + tile_offset;
+                tile_attr = Attr::from(self.get_ram1(tile_addr));
+
+                let tile_y = if tile_attr.yflip { 7 - py % 8 } else { py % 8 };
+                let bank = if self.term == Term::GBC && tile_attr.bank { 0x2000 } else { 0x0000 };
+                tile_row = self.tile_row(bank + (tile_location - 0x8000) as usize + tile_y as usize * 2);
+                if tile_attr.xflip {
+                    tile_row.reverse();
+                }
+            }
 
             // Palettes
-            let color_l = if tile_y_data[0] & (0x80 >> tile_x) != 0 { 1 } else { 0 };
-            let color_h = if tile_y_data[1] & (0x80 >> tile_x) != 0 { 2 } else { 0 };
-            let color = color_h | color_l;
+            let color = tile_row[usize::from(px % 8)] as usize;
 
             // Priority
             self.prio[x] = (tile_attr.priority, color);
 
-            if self.term == Term::GBC {
-                let r = self.cbgpd[tile_attr.palette_number_1][color][0];
-                let g = self.cbgpd[tile_attr.palette_number_1][color][1];
-                let b = self.cbgpd[tile_attr.palette_number_1][color][2];
-                self.set_rgb(x as usize, r, g, b);
-            } else {
-                let color = Self::get_gray_shades(self.bgp, color) as u8;
-                self.set_gre(x, color);
-            }
+            let palette = if self.term == Term::GBC { tile_attr.palette_number_1 } else { 0 };
+            self.data[usize::from(self.ly)][x] = colors[palette][color];
         }
     }
 
@@ -595,16 +766,21 @@ impl Gpu {
     //     Bit4   Palette number  **Non CGB Mode Only** (0=OBP0, 1=OBP1)
     //     Bit3   Tile VRAM-Bank  **CGB Mode Only**     (0=Bank 0, 1=Bank 1)
     //     Bit2-0 Palette number  **CGB Mode Only**     (OBP0-7)
+    //
+    // Sprite selection
+    // During mode 2 the PPU scans OAM in order and keeps the first ten sprites whose rows cover the line. Their X
+    // coordinate plays no part, a sprite off the left or right edge still takes one of the ten places. Where the
+    // opaque pixels of the selected sprites overlap, the one with the smaller X wins on the DMG, the first in OAM when
+    // their X is the same. In CGB mode the first in OAM always wins. The winning pixel alone is then checked against
+    // the background: if it is behind a background color 1-3, no other sprite shows through it.
     fn draw_sprites(&mut self) {
         // Sprite tile size 8x8 or 8x16(2 stacked vertically).
         let sprite_size = if self.lcdc.bit2() { 16 } else { 8 };
+        // Sprites on this line, whatever their X coordinate, and the first ten of them.
+        let mut count = 0;
+        let mut sprites = [0u16; 10];
         for i in 0..40 {
-            let sprite_addr = 0xfe00 + (i as u16) * 4;
-            let py = self.get(sprite_addr).wrapping_sub(16);
-            let px = self.get(sprite_addr + 1).wrapping_sub(8);
-            let tile_number = self.get(sprite_addr + 2) & if self.lcdc.bit2() { 0xfe } else { 0xff };
-            let tile_attr = Attr::from(self.get(sprite_addr + 3));
-
+            let py = self.get(0xfe00 + i * 4).wrapping_sub(16);
             // If this is true the scanline is out of the area we care about
             if py <= 0xff - sprite_size + 1 {
                 if self.ly < py || self.ly > py + sprite_size - 1 {
@@ -615,36 +791,47 @@ impl Gpu {
                     continue;
                 }
             }
+            if count < sprites.len() {
+                sprites[count] = i;
+            }
+            count += 1;
+        }
+        let sprites = &mut sprites[..count.min(10)];
+        if self.term != Term::GBC {
+            // A stable sort, sprites with the same X stay in OAM order.
+            sprites.sort_by_key(|&i| self.get(0xfe00 + i * 4 + 1));
+        }
+        // The pixels already taken by a sprite of higher priority.
+        let mut taken = [false; SCREEN_W];
+        for &i in sprites.iter() {
+            let sprite_addr = 0xfe00 + i * 4;
+            let py = self.get(sprite_addr).wrapping_sub(16);
+            let px = self.get(sprite_addr + 1).wrapping_sub(8);
+            let tile_number = self.get(sprite_addr + 2) & if self.lcdc.bit2() { 0xfe } else { 0xff };
+            let tile_attr = Attr::from(self.get(sprite_addr + 3));
             if px >= (SCREEN_W as u8) && px <= (0xff - 7) {
                 continue;
             }
 
             let tile_y =
                 if tile_attr.yflip { sprite_size - 1 - self.ly.wrapping_sub(py) } else { self.ly.wrapping_sub(py) };
-            let tile_y_addr = 0x8000u16 + u16::from(tile_number) * 16 + u16::from(tile_y) * 2;
-            let tile_y_data: [u8; 2] = if self.term == Term::GBC && tile_attr.bank {
-                let b1 = self.get_ram1(tile_y_addr);
-                let b2 = self.get_ram1(tile_y_addr + 1);
-                [b1, b2]
-            } else {
-                let b1 = self.get_ram0(tile_y_addr);
-                let b2 = self.get_ram0(tile_y_addr + 1);
-                [b1, b2]
-            };
+            let bank = if self.term == Term::GBC && tile_attr.bank { 0x2000 } else { 0x0000 };
+            let mut tile_row = self.tile_row(bank + usize::from(tile_number) * 16 + usize::from(tile_y) * 2);
+            if tile_attr.xflip {
+                tile_row.reverse();
+            }
 
             for x in 0..8 {
                 if px.wrapping_add(x) >= (SCREEN_W as u8) {
                     continue;
                 }
-                let tile_x = if tile_attr.xflip { 7 - x } else { x };
 
                 // Palettes
-                let color_l = if tile_y_data[0] & (0x80 >> tile_x) != 0 { 1 } else { 0 };
-                let color_h = if tile_y_data[1] & (0x80 >> tile_x) != 0 { 2 } else { 0 };
-                let color = color_h | color_l;
-                if color == 0 {
+                let color = tile_row[usize::from(x)] as usize;
+                if color == 0 || taken[px.wrapping_add(x) as usize] {
                     continue;
                 }
+                taken[px.wrapping_add(x) as usize] = true;
 
                 // Confirm the priority of background and sprite.
                 let prio = self.prio[px.wrapping_add(x) as usize];
@@ -665,18 +852,158 @@ impl Gpu {
                     let b = self.cobpd[tile_attr.palette_number_1][color][2];
                     self.set_rgb(px.wrapping_add(x) as usize, r, g, b);
                 } else {
-                    let color = if tile_attr.palette_number_0 == 1 {
-                        Self::get_gray_shades(self.op1, color) as u8
+                    let (palette, shade) = if tile_attr.palette_number_0 == 1 {
+                        (2, Self::get_gray_shades(self.op1, color))
                     } else {
-                        Self::get_gray_shades(self.op0, color) as u8
+                        (1, Self::get_gray_shades(self.op0, color))
                     };
-                    self.set_gre(px.wrapping_add(x) as usize, color);
+                    self.set_gre(px.wrapping_add(x) as usize, palette, shade);
+                }
+            }
+        }
+        if self.overflow_tint && count > 10 {
+            for pixel in self.data[self.ly as usize].iter_mut() {
+                *pixel = [pixel[0] / 2 + 0x80, pixel[1] / 2, pixel[2] / 2];
+            }
+        }
+    }
+
+    // The LCD registers with the meaning of their bits, see report.
+    pub fn report(&self) -> String {
+        report(self, self.term == Term::GBC)
+    }
+
+    // The colors of the 4 color numbers of a background tile: through BGP on the DMG, in BG palette p on the GBC.
+    fn bg_colors(&self, p: usize) -> [[u8; 3]; 4] {
+        [0, 1, 2, 3].map(|i| {
+            if self.term == Term::GBC {
+                let c = self.cbgpd[p][i];
+                self.color_correction.rgb(c[0], c[1], c[2])
+            } else {
+                self.dmg_palette[0][Self::get_gray_shades(self.bgp, i)]
+            }
+        })
+    }
+
+    // All the tiles in VRAM for a tile viewer, 16 per row in the order of their addresses from 8000h: the 384 tiles
+    // of bank 0, and on the GBC the 384 of bank 1 to the right of them. A tile has no colors of its own, they are
+    // shown in the colors of the background, BG palette 0 on the GBC.
+    pub fn tiles(&self) -> Picture {
+        let banks = if self.term == Term::GBC { 2 } else { 1 };
+        let mut pic = Picture::new(128 * banks, 192);
+        let colors = self.bg_colors(0);
+        for bank in 0..banks {
+            for tile in 0..384 {
+                for y in 0..8 {
+                    let i = bank * 0x2000 + tile * 16 + y * 2;
+                    let row = decode_row(self.ram[i], self.ram[i + 1]);
+                    for (x, &color) in row.iter().enumerate() {
+                        pic.set(bank * 128 + tile % 16 * 8 + x, tile / 16 * 8 + y, colors[color as usize]);
+                    }
+                }
+            }
+        }
+        pic
+    }
+
+    // The 256x256 pixels of the tile map at 9800h or 9C00h, drawn whole with the tile data and, on the GBC, the
+    // attributes the background is drawn with now.
+    pub fn tile_map(&self, base: u16) -> Picture {
+        let mut pic = Picture::new(256, 256);
+        let tile_base = if self.lcdc.bit4() { 0x8000 } else { 0x8800 };
+        for ty in 0..32 {
+            for tx in 0..32 {
+                let tile_addr = base + ty * 32 + tx;
+                let tile_number = self.get_ram0(tile_addr);
+                let tile_offset =
+                    if self.lcdc.bit4() { i16::from(tile_number) } else { i16::from(tile_number as i8) + 128 } as u16
+                        * 16;
+                let tile_attr = Attr::from(self.get_ram1(tile_addr));
+                let bank = if self.term == Term::GBC && tile_attr.bank { 0x2000 } else { 0x0000 };
+                let colors = self.bg_colors(if self.term == Term::GBC { tile_attr.palette_number_1 } else { 0 });
+                for y in 0..8 {
+                    let tile_y = if tile_attr.yflip { 7 - y } else { y };
+                    let i = bank + (tile_base + tile_offset - 0x8000) as usize + tile_y * 2;
+                    let row = decode_row(self.ram[i], self.ram[i + 1]);
+                    for x in 0..8 {
+                        let tile_x = if tile_attr.xflip { 7 - x } else { x };
+                        let color = colors[row[tile_x] as usize];
+                        pic.set(usize::from(tx) * 8 + x, usize::from(ty) * 8 + y, color);
+                    }
+                }
+            }
+        }
+        pic
+    }
+
+    // Both tile maps side by side for a map viewer, 9800h on the left and 9C00h on the right. The part of the
+    // background on the screen is outlined in red, it wraps around the edges of its map like the screen does. The
+    // part of the window on the screen, when it is on, is outlined in blue from the top left of its map.
+    pub fn tile_maps(&self) -> Picture {
+        let mut pic = Picture::new(512, 256);
+        for (n, base) in [0x9800, 0x9c00].into_iter().enumerate() {
+            let map = self.tile_map(base);
+            for y in 0..256 {
+                for x in 0..256 {
+                    pic.set(n * 256 + x, y, map.data[y * 256 + x]);
                 }
             }
         }
+        let bg = if self.lcdc.bit3() { 256 } else { 0 };
+        pic.outline(bg, usize::from(self.sx), usize::from(self.sy), SCREEN_W, SCREEN_H, [0xff, 0x00, 0x00]);
+        let (wx, wy) = (i32::from(self.wx) - 7, i32::from(self.wy));
+        if self.lcdc.bit5() && wx < SCREEN_W as i32 && wy < SCREEN_H as i32 {
+            let w = (SCREEN_W as i32 - wx).min(256) as usize;
+            let h = (SCREEN_H as i32 - wy) as usize;
+            let win = if self.lcdc.bit6() { 256 } else { 0 };
+            pic.outline(win, 0, 0, w, h, [0x00, 0x00, 0xff]);
+        }
+        pic
+    }
+}
+
+// A picture made for a debug view, e.g. of the tiles in VRAM, in RGB.
+pub struct Picture {
+    pub w: usize,
+    pub h: usize,
+    pub data: Vec<[u8; 3]>,
+}
+
+impl Picture {
+    pub fn new(w: usize, h: usize) -> Self {
+        Self { w, h, data: vec![[0x00; 3]; w * h] }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        self.data[y * self.w + x] = rgb;
+    }
+
+    // The border of a w x h rectangle at (x, y) in the 256x256 map which starts at column left, wrapped around the
+    // edges of the map.
+    fn outline(&mut self, left: usize, x: usize, y: usize, w: usize, h: usize, rgb: [u8; 3]) {
+        for i in 0..w {
+            self.set(left + (x + i) % 256, y % 256, rgb);
+            self.set(left + (x + i) % 256, (y + h - 1) % 256, rgb);
+        }
+        for j in 0..h {
+            self.set(left + x % 256, (y + j) % 256, rgb);
+            self.set(left + (x + w - 1) % 256, (y + j) % 256, rgb);
+        }
     }
 }
 
+// The color numbers of the 8 pixels of a tile row, from left to right, from its two bytes. The 2 bits of a pixel are
+// spread over the two: the low bit in the first byte, the high bit in the second.
+fn decode_row(lo: u8, hi: u8) -> [u8; 8] {
+    let mut row = [0x00; 8];
+    for (x, color) in row.iter_mut().enumerate() {
+        let color_l = (lo >> (7 - x)) & 0x01;
+        let color_h = (hi >> (7 - x)) & 0x01;
+        *color = (color_h << 1) | color_l;
+    }
+    row
+}
+
 impl Memory for Gpu {
     fn get(&self, a: u16) -> u8 {
         match a {
@@ -693,7 +1020,13 @@ impl Memory for Gpu {
             }
             0xff42 => self.sy,
             0xff43 => self.sx,
-            0xff44 => self.ly,
+            0xff44 => {
+                if self.ly_stub {
+                    0x90
+                } else {
+                    self.ly
+                }
+            }
             0xff45 => self.lc,
             0xff47 => self.bgp,
             0xff48 => self.op0,
@@ -735,7 +1068,11 @@ impl Memory for Gpu {
 
     fn set(&mut self, a: u16, v: u8) {
         match a {
-            0x8000..=0x9fff => self.ram[self.ram_bank * 0x2000 + a as usize - 0x8000] = v,
+            0x8000..=0x9fff => {
+                let i = self.ram_bank * 0x2000 + a as usize - 0x8000;
+                self.ram[i] = v;
+                self.tile_rows[i / 2] = None;
+            }
             0xfe00..=0xfe9f => self.oam[a as usize - 0xfe00] = v,
             0xff40 => {
                 self.lcdc.data = v;
@@ -743,21 +1080,38 @@ impl Memory for Gpu {
                     self.dots = 0;
                     self.ly = 0;
                     self.stat.mode = 0;
+                    self.window_y = false;
+                    self.window_line = 0;
                     // Clean screen.
-                    self.data = [[[0xffu8; 3]; SCREEN_W]; SCREEN_H];
+                    let white = if self.term == Term::GBC { [0xff; 3] } else { self.dmg_palette[0][0] };
+                    self.data = [[white; SCREEN_W]; SCREEN_H];
                     self.v_blank = true;
                 }
+                self.update_stat();
             }
             0xff41 => {
+                // On the DMG, the write enables all four sources for a cycle before the value lands: in H-Blank,
+                // V-Blank or on the LYC line, the line goes high and requests LCDStat whatever the value written.
+                // Road Rash relies on it.
+                if self.term != Term::GBC && self.lcdc.bit7() && (self.stat.mode <= 1 || self.ly == self.lc) {
+                    if !self.stat.line {
+                        self.intf.hi(Flag::LCDStat);
+                    }
+                    self.stat.line = true;
+                }
                 self.stat.enable_ly_interrupt = v & 0x40 != 0x00;
                 self.stat.enable_m2_interrupt = v & 0x20 != 0x00;
                 self.stat.enable_m1_interrupt = v & 0x10 != 0x00;
                 self.stat.enable_m0_interrupt = v & 0x08 != 0x00;
+                self.update_stat();
             }
             0xff42 => self.sy = v,
             0xff43 => self.sx = v,
             0xff44 => {}
-            0xff45 => self.lc = v,
+            0xff45 => {
+                self.lc = v;
+                self.update_stat();
+            }
             0xff47 => self.bgp = v,
             0xff48 => self.op0 = v,
             0xff49 => self.op1 = v,
@@ -800,3 +1154,209 @@ impl Memory for Gpu {
         }
     }
 }
+
+// Format the LCD registers with the meaning of their bits, one register per line, to diagnose rendering problems
+// without a table of the bit layouts at hand. The registers are read through mem, which is either the Gpu or the whole
+// bus. With cgb, the registers of the Game Boy Color are appended.
+pub fn report(mem: &dyn Memory, cgb: bool) -> String {
+    let on = |b: bool| if b { "on" } else { "off" };
+    let shades = |v: u8| (0..4).map(|i| format!("{}={}", i, v >> (i * 2) & 0x03)).collect::<Vec<String>>().join(" ");
+    let mut lines = vec![];
+
+    let v = mem.get(0xff40);
+    lines.push(format!(
+        "LCDC ff40={:02x} lcd={} win_map={} win={} tile_data={} bg_map={} obj_size={} obj={} {}={}",
+        v,
+        on(v & 0x80 != 0x00),
+        if v & 0x40 != 0x00 { "9c00" } else { "9800" },
+        on(v & 0x20 != 0x00),
+        if v & 0x10 != 0x00 { "8000" } else { "8800" },
+        if v & 0x08 != 0x00 { "9c00" } else { "9800" },
+        if v & 0x04 != 0x00 { "8x16" } else { "8x8" },
+        on(v & 0x02 != 0x00),
+        // On the CGB, bit 0 no longer hides the background but takes the priority away from it.
+        if cgb { "bg_priority" } else { "bg" },
+        on(v & 0x01 != 0x00),
+    ));
+    let v = mem.get(0xff41);
+    lines.push(format!(
+        "STAT ff41={:02x} mode={} ({}) coincidence={} int_lyc={} int_oam={} int_vblank={} int_hblank={}",
+        v,
+        v & 0x03,
+        ["hblank", "vblank", "oam scan", "drawing"][usize::from(v & 0x03)],
+        on(v & 0x04 != 0x00),
+        on(v & 0x40 != 0x00),
+        on(v & 0x20 != 0x00),
+        on(v & 0x10 != 0x00),
+        on(v & 0x08 != 0x00),
+    ));
+    lines.push(format!("SCY  ff42={:02x}", mem.get(0xff42)));
+    lines.push(format!("SCX  ff43={:02x}", mem.get(0xff43)));
+    let v = mem.get(0xff44);
+    lines.push(format!("LY   ff44={:02x} line={}{}", v, v, if v >= 144 { " (vblank)" } else { "" }));
+    lines.push(format!("LYC  ff45={:02x}", mem.get(0xff45)));
+    lines.push(format!("WY   ff4a={:02x}", mem.get(0xff4a)));
+    let v = mem.get(0xff4b);
+    lines.push(format!("WX   ff4b={:02x} x={}", v, i16::from(v) - 7));
+    let v = mem.get(0xff47);
+    lines.push(format!("BGP  ff47={:02x} {}", v, shades(v)));
+    // Color 0 of a sprite is transparent, its shade is never used.
+    let v = mem.get(0xff48);
+    lines.push(format!("OBP0 ff48={:02x} {}", v, shades(v)));
+    let v = mem.get(0xff49);
+    lines.push(format!("OBP1 ff49={:02x} {}", v, shades(v)));
+
+    if cgb {
+        let v = mem.get(0xff4f);
+        lines.push(format!("VBK  ff4f={:02x} bank={}", v, v & 0x01));
+        for (name, a) in [("BCPS", 0xff68), ("OCPS", 0xff6a)] {
+            let v = mem.get(a);
+            lines.push(format!(
+                "{} {:04x}={:02x} index={:02x} palette={} color={} auto_increment={}",
+                name,
+                a,
+                v,
+                v & 0x3f,
+                v >> 3 & 0x07,
+                v >> 1 & 0x03,
+                on(v & 0x80 != 0x00)
+            ));
+            lines.push(format!(
+                "{} {:04x}={:02x}",
+                if name == "BCPS" { "BCPD" } else { "OCPD" },
+                a + 1,
+                mem.get(a + 1)
+            ));
+        }
+    }
+    lines.join("\n")
+}
+
+impl Snapshot for Hdma {
+    fn save(&self, w: &mut Writer) {
+        w.u16(self.src);
+        w.u16(self.dst);
+        w.bool(self.active);
+        w.bool(self.mode == HdmaMode::Hdma);
+        w.u8(self.remain);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.src = r.u16()?;
+        self.dst = r.u16()?;
+        self.active = r.bool()?;
+        self.mode = if r.bool()? { HdmaMode::Hdma } else { HdmaMode::Gdma };
+        self.remain = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Gpu {
+    fn save(&self, w: &mut Writer) {
+        for line in self.data.iter() {
+            for pixel in line.iter() {
+                w.bytes(pixel);
+            }
+        }
+        w.bool(self.h_blank);
+        w.bool(self.v_blank);
+        w.u8(self.lcdc.data);
+        w.bool(self.stat.enable_ly_interrupt);
+        w.bool(self.stat.enable_m2_interrupt);
+        w.bool(self.stat.enable_m1_interrupt);
+        w.bool(self.stat.enable_m0_interrupt);
+        w.u8(self.stat.mode);
+        w.u8(self.sy);
+        w.u8(self.sx);
+        w.u8(self.wy);
+        w.u8(self.wx);
+        w.bool(self.window_y);
+        w.u8(self.window_line);
+        // The registers latched for the line being drawn, the lines above are done.
+        let line = self.scanlines[usize::from(self.ly).min(SCREEN_H - 1)];
+        w.u8(line.scx);
+        w.u8(line.scy);
+        w.u8(line.wx);
+        w.bool(line.window.is_some());
+        w.u8(line.window.unwrap_or(0));
+        w.u8(self.ly);
+        w.u8(self.lc);
+        w.u8(self.bgp);
+        w.u8(self.op0);
+        w.u8(self.op1);
+        w.u8(self.cbgpi.get());
+        for palette in self.cbgpd.iter() {
+            for color in palette.iter() {
+                w.bytes(color);
+            }
+        }
+        w.u8(self.cobpi.get());
+        for palette in self.cobpd.iter() {
+            for color in palette.iter() {
+                w.bytes(color);
+            }
+        }
+        w.bytes(&self.ram);
+        w.u8(self.ram_bank as u8);
+        w.bytes(&self.oam);
+        w.u32(self.dots);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        for line in self.data.iter_mut() {
+            for pixel in line.iter_mut() {
+                r.bytes(pixel)?;
+            }
+        }
+        self.h_blank = r.bool()?;
+        self.v_blank = r.bool()?;
+        self.lcdc.data = r.u8()?;
+        self.stat.enable_ly_interrupt = r.bool()?;
+        self.stat.enable_m2_interrupt = r.bool()?;
+        self.stat.enable_m1_interrupt = r.bool()?;
+        self.stat.enable_m0_interrupt = r.bool()?;
+        self.stat.mode = r.u8()?;
+        self.sy = r.u8()?;
+        self.sx = r.u8()?;
+        self.wy = r.u8()?;
+        self.wx = r.u8()?;
+        self.window_y = r.bool()?;
+        self.window_line = r.u8()?;
+        let scx = r.u8()?;
+        let scy = r.u8()?;
+        let wx = r.u8()?;
+        let window = r.bool()?;
+        let row = r.u8()?;
+        self.ly = r.u8()?;
+        self.scanlines[usize::from(self.ly).min(SCREEN_H - 1)] =
+            Scanline { scx, scy, wx, window: window.then_some(row) };
+        self.lc = r.u8()?;
+        self.bgp = r.u8()?;
+        self.op0 = r.u8()?;
+        self.op1 = r.u8()?;
+        self.cbgpi.set(r.u8()?);
+        for palette in self.cbgpd.iter_mut() {
+            for color in palette.iter_mut() {
+                r.bytes(color)?;
+            }
+        }
+        self.cobpi.set(r.u8()?);
+        for palette in self.cobpd.iter_mut() {
+            for color in palette.iter_mut() {
+                r.bytes(color)?;
+            }
+        }
+        r.bytes(&mut self.ram)?;
+        self.tile_rows.fill(None);
+        self.ram_bank = usize::from(r.u8()?);
+        if self.ram_bank > 1 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "State: video RAM bank out of range"));
+        }
+        r.bytes(&mut self.oam)?;
+        self.dots = r.u32()?;
+        // Not saved, it follows from the registers.
+        self.stat.line = self.stat_line();
+
+        Ok(())
+    }
+}
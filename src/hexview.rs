@@ -0,0 +1,163 @@
+// A hex dump of the whole address space, 0000 to FFFF, read and written through the bus like the CPU does: the banks
+// which are switched in, the I/O registers as the game reads them, FF everywhere but HRAM during an OAM DMA. The
+// frontend shows it in a window of its own next to the game, and writes go to the bus at once, so a byte of RAM or an
+// I/O register can be poked while the game runs.
+//
+// The dump shows ROWS lines of 16 bytes, from top. One byte is the cursor: typing two hex digits writes them there and
+// moves the cursor to the next byte. The first digit is only kept until the second one comes, or cancel().
+use super::gpu::Picture;
+use super::memory::Memory;
+
+// The lines of 16 bytes shown at once.
+pub const ROWS: usize = 32;
+
+// The hex digits, 3x5 pixels, one row of 3 bits per byte.
+const FONT: [[u8; 5]; 16] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b111, 0b100, 0b111],
+    [0b111, 0b100, 0b111, 0b100, 0b100],
+];
+
+// A character takes 4x7 pixels, the glyph and the space around it. A line is the address, two spaces, and the 16
+// bytes with a space between them and two in the middle.
+const CHAR_W: usize = 4;
+const CHAR_H: usize = 7;
+const LINE: usize = 4 + 2 + 16 * 3;
+const MARGIN: usize = 2;
+
+const BACK: [u8; 3] = [0x10, 0x10, 0x18];
+const ADDR: [u8; 3] = [0x80, 0x80, 0x90];
+const BYTE: [u8; 3] = [0xe0, 0xe0, 0xe0];
+// The cursor is drawn as a light cell with dark digits, yellow while a digit was typed.
+const CURSOR: [u8; 3] = [0xa0, 0xc0, 0xff];
+const EDITING: [u8; 3] = [0xff, 0xd0, 0x40];
+
+pub struct HexView {
+    // The address of the first line, a multiple of 16.
+    top: u16,
+    cursor: u16,
+    // The high digit typed at the cursor, waiting for the low one.
+    high: Option<u8>,
+}
+
+impl HexView {
+    pub fn power_up() -> Self {
+        Self { top: 0x0000, cursor: 0x0000, high: None }
+    }
+
+    pub fn cursor(&self) -> u16 {
+        self.cursor
+    }
+
+    // Put the cursor on an address, and scroll just enough to show it.
+    pub fn goto(&mut self, a: u16) {
+        self.cursor = a;
+        self.high = None;
+        let line = a & 0xfff0;
+        let span = (ROWS as u16 - 1) * 16;
+        if line.wrapping_sub(self.top) > span {
+            // Above the dump it becomes the first line, below it the last.
+            self.top = if line.wrapping_sub(self.top) > 0x8000 { line } else { line.wrapping_sub(span) };
+        }
+    }
+
+    // Move the cursor by n bytes, e.g. 16 for a line down. It wraps around the address space.
+    pub fn move_cursor(&mut self, n: i32) {
+        self.goto(self.cursor.wrapping_add(n as u16));
+    }
+
+    // Type a hex digit at the cursor. The second digit writes the byte and moves to the next one, and true is returned
+    // then.
+    pub fn type_digit(&mut self, mem: &mut dyn Memory, d: u8) -> bool {
+        assert!(d < 16);
+        match self.high.take() {
+            None => {
+                self.high = Some(d);
+                false
+            }
+            Some(h) => {
+                mem.set(self.cursor, h << 4 | d);
+                self.move_cursor(1);
+                true
+            }
+        }
+    }
+
+    // Forget the digit typed at the cursor.
+    pub fn cancel(&mut self) {
+        self.high = None;
+    }
+
+    // The size of the picture drawn.
+    pub fn size() -> (usize, usize) {
+        (LINE * CHAR_W + MARGIN * 2, ROWS * CHAR_H + MARGIN * 2)
+    }
+
+    pub fn draw(&self, mem: &dyn Memory) -> Picture {
+        let (w, h) = Self::size();
+        let mut pic = Picture::new(w, h);
+        pic.data.fill(BACK);
+        for row in 0..ROWS {
+            let base = self.top.wrapping_add(row as u16 * 16);
+            for (i, shift) in [12, 8, 4, 0].into_iter().enumerate() {
+                glyph(&mut pic, i, row, (base >> shift & 0xf) as u8, ADDR);
+            }
+            for i in 0..16 {
+                let a = base.wrapping_add(i as u16);
+                let col = 6 + i * 3 + usize::from(i >= 8);
+                let v = mem.get(a);
+                let (hi, lo, color) = if a != self.cursor {
+                    (Some(v >> 4), Some(v & 0xf), BYTE)
+                } else {
+                    let back = if self.high.is_some() { EDITING } else { CURSOR };
+                    cell(&mut pic, col, row, 2, back);
+                    match self.high {
+                        Some(h) => (Some(h), None, BACK),
+                        None => (Some(v >> 4), Some(v & 0xf), BACK),
+                    }
+                };
+                if let Some(d) = hi {
+                    glyph(&mut pic, col, row, d, color);
+                }
+                if let Some(d) = lo {
+                    glyph(&mut pic, col + 1, row, d, color);
+                }
+            }
+        }
+        pic
+    }
+}
+
+// Fill n characters from the column and line given, with a pixel more around the glyphs.
+fn cell(pic: &mut Picture, col: usize, row: usize, n: usize, rgb: [u8; 3]) {
+    let (x, y) = (MARGIN + col * CHAR_W, MARGIN + row * CHAR_H);
+    for py in y..y + CHAR_H {
+        for px in x.saturating_sub(1)..x + n * CHAR_W {
+            pic.set(px, py, rgb);
+        }
+    }
+}
+
+fn glyph(pic: &mut Picture, col: usize, row: usize, d: u8, rgb: [u8; 3]) {
+    let (x, y) = (MARGIN + col * CHAR_W, MARGIN + row * CHAR_H + 1);
+    for (j, bits) in FONT[usize::from(d)].iter().enumerate() {
+        for i in 0..3 {
+            if bits >> (2 - i) & 1 != 0 {
+                pic.set(x + i, y + j, rgb);
+            }
+        }
+    }
+}
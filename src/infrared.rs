@@ -0,0 +1,98 @@
+// The CGB has an infrared LED and a sensor on its top, for the games which exchange data between two consoles held
+// face to face, e.g. the Mystery Gift of Pokemon Gold and Silver. There is no serial logic behind it: the game turns
+// the LED on and off and polls the sensor, and times the pulses by counting the iterations of its own loops.
+//
+// FF56 - RP - CGB Mode Only - Infrared Communications Port
+// Bit 0:   Write Data   (0=LED Off, 1=LED On)             (Read/Write)
+// Bit 1:   Read Data    (0=Receiving IR Signal, 1=Normal) (Read Only)
+// Bit 2-5: Unused, read 1
+// Bit 6-7: Data Read Enable (0=Disable, 3=Enable)        (Read/Write)
+// While reading is disabled, Read Data is 1 as if nothing was received.
+//
+// What faces the sensor is an InfraredDevice, e.g. another emulator in the same process. The pulses of the games are
+// a few hundred clock cycles long, so both sides must run close together: a LocalInfrared pair works for two machines
+// stepped in turns of a few instructions, like the LocalLink of the serial port.
+//
+// See: https://gbdev.io/pandocs/IR.html
+use super::state::{Reader, Snapshot, Writer};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// The other side of the infrared port.
+pub trait InfraredDevice: Send {
+    // The LED of the port was turned on or off.
+    fn emit(&mut self, on: bool);
+
+    // Whether light reaches the sensor of the port now.
+    fn receive(&self) -> bool;
+}
+
+pub struct Infrared {
+    // Bits 0, 6 and 7 of RP, as written.
+    data: u8,
+    // The other side, if any. Without one the sensor never receives anything.
+    pub device: Option<Box<dyn InfraredDevice>>,
+}
+
+impl Infrared {
+    pub fn power_up() -> Self {
+        Self { data: 0x00, device: None }
+    }
+
+    pub fn get(&self, a: u16) -> u8 {
+        assert_eq!(a, 0xff56);
+        let receiving = self.data & 0xc0 == 0xc0 && self.device.as_ref().is_some_and(|d| d.receive());
+        let bit1 = if receiving { 0x00 } else { 0x02 };
+        0x3c | self.data | bit1
+    }
+
+    pub fn set(&mut self, a: u16, v: u8) {
+        assert_eq!(a, 0xff56);
+        let led = self.data & 0x01;
+        self.data = v & 0xc1;
+        if let Some(d) = &mut self.device {
+            if self.data & 0x01 != led {
+                d.emit(self.data & 0x01 == 0x01);
+            }
+        }
+    }
+}
+
+// The device is not part of the state, a restored LED is shown to it again.
+impl Snapshot for Infrared {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.data);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.data = r.u8()? & 0xc1;
+        if let Some(d) = &mut self.device {
+            d.emit(self.data & 0x01 == 0x01);
+        }
+        Ok(())
+    }
+}
+
+// Two ports in the same process facing each other: each sensor sees the LED of the other side.
+pub struct LocalInfrared {
+    led: Arc<AtomicBool>,
+    peer: Arc<AtomicBool>,
+}
+
+impl LocalInfrared {
+    pub fn pair() -> (Self, Self) {
+        let a = Arc::new(AtomicBool::new(false));
+        let b = Arc::new(AtomicBool::new(false));
+        (Self { led: a.clone(), peer: b.clone() }, Self { led: b, peer: a })
+    }
+}
+
+impl InfraredDevice for LocalInfrared {
+    fn emit(&mut self, on: bool) {
+        self.led.store(on, Ordering::Relaxed);
+    }
+
+    fn receive(&self) -> bool {
+        self.peer.load(Ordering::Relaxed)
+    }
+}
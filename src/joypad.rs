@@ -14,13 +14,20 @@
 //
 // Note: Most programs are repeatedly reading from this port several times (the first reads used as short delay,
 // allowing the inputs to stabilize, and only the value from the last read actually used).
+//
+// A key pressed connects its input line to its select line. With both select lines low, an input line is low when
+// either of its two keys is pressed. The joypad interrupt is requested when an input line goes from high to low, so a
+// key whose row isn't selected doesn't request it.
+//
+// On a Super Game Boy up to four joypads can be read, see the sgb module. The methods without a player work on the
+// first one.
 use super::intf::{Flag, Intf};
 use super::memory::Memory;
-use std::cell::RefCell;
-use std::rc::Rc;
+use super::sgb::Sgb;
+use super::state::{Reader, Snapshot, Writer};
 
 #[rustfmt::skip]
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum JoypadKey {
     Right  = 0b0000_0001,
     Left   = 0b0000_0010,
@@ -32,43 +39,159 @@ pub enum JoypadKey {
     Start  = 0b1000_0000,
 }
 
+impl std::str::FromStr for JoypadKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "right" => Ok(JoypadKey::Right),
+            "left" => Ok(JoypadKey::Left),
+            "up" => Ok(JoypadKey::Up),
+            "down" => Ok(JoypadKey::Down),
+            "a" => Ok(JoypadKey::A),
+            "b" => Ok(JoypadKey::B),
+            "select" => Ok(JoypadKey::Select),
+            "start" => Ok(JoypadKey::Start),
+            _ => Err(format!("Unsupported joypad key: {}", s)),
+        }
+    }
+}
+
 pub struct Joypad {
-    intf: Rc<RefCell<Intf>>,
-    matrix: u8,
+    // The interrupts requested by the keypad, the MMU folds them into IF.
+    pub intf: Intf,
+    // One per player, a bit is cleared while its key is pressed.
+    matrix: [u8; 4],
     select: u8,
+    // P10-P13 held low from outside the matrix, e.g. by a test or a peripheral wired to the port.
+    pulled: u8,
+    // The input lines as last seen, to find their falling edges.
+    lines: u8,
+    // The command packets of the Super Game Boy, when emulating its multiplayer support.
+    pub sgb: Option<Sgb>,
 }
 
 impl Joypad {
-    pub fn power_up(intf: Rc<RefCell<Intf>>) -> Self {
-        Self { intf, matrix: 0xff, select: 0x00 }
+    pub fn power_up() -> Self {
+        Self { intf: Intf::power_up(), matrix: [0xff; 4], select: 0x00, pulled: 0x00, lines: 0x0f, sgb: None }
     }
 }
 
 impl Joypad {
     pub fn keydown(&mut self, key: JoypadKey) {
-        self.matrix &= !(key as u8);
-        self.intf.borrow_mut().hi(Flag::Joypad);
+        self.matrix[0] &= !(key as u8);
+        self.update();
     }
 
     pub fn keyup(&mut self, key: JoypadKey) {
-        self.matrix |= key as u8;
+        self.matrix[0] |= key as u8;
+        self.update();
+    }
+
+    pub fn is_pressed(&self, key: JoypadKey) -> bool {
+        self.matrix[0] & key as u8 == 0x00
+    }
+
+    // The pressed keys, one bit per key as in JoypadKey.
+    pub fn state(&self) -> u8 {
+        self.player_state(0)
+    }
+
+    // Press exactly the given keys and release the others.
+    pub fn set_state(&mut self, keys: u8) {
+        self.set_player_state(0, keys)
+    }
+
+    pub fn player_state(&self, player: usize) -> u8 {
+        !self.matrix[player]
+    }
+
+    pub fn set_player_state(&mut self, player: usize, keys: u8) {
+        self.matrix[player] = !keys;
+        self.update();
+    }
+
+    // The P14 and P15 select lines as last written, in bits 4 and 5. 0 selects.
+    pub fn select_lines(&self) -> u8 {
+        self.select & 0x30
+    }
+
+    // The P10-P13 input lines as the CPU reads them, in bits 0 to 3. 0 is low.
+    pub fn input_lines(&self) -> u8 {
+        let matrix = self.matrix[self.player()];
+        let mut lines = 0x0f & !self.pulled;
+        if self.select & 0x10 == 0x00 {
+            lines &= matrix & 0x0f;
+        }
+        if self.select & 0x20 == 0x00 {
+            lines &= matrix >> 4;
+        }
+        if self.select & 0x30 == 0x30 {
+            if let Some(sgb) = self.sgb.as_ref().filter(|sgb| sgb.players > 1) {
+                lines &= sgb.id();
+            }
+        }
+        lines
+    }
+
+    // Hold the given P10-P13 input lines low, whatever the keys and the select lines, and release the others. This is
+    // the level of the pins rather than keys, for tests of the port and hardware other than a joypad.
+    pub fn pull_lines(&mut self, lines: u8) {
+        self.pulled = lines & 0x0f;
+        self.update();
+    }
+
+    // The joypad being read, only the SGB reads another one than the first.
+    fn player(&self) -> usize {
+        self.sgb.as_ref().map_or(0, |sgb| usize::from(sgb.player))
+    }
+
+    // Request the interrupt on a falling edge of the input lines.
+    fn update(&mut self) {
+        let lines = self.input_lines();
+        if self.lines & !lines != 0x00 {
+            self.intf.hi(Flag::Joypad);
+        }
+        self.lines = lines;
     }
 }
 
 impl Memory for Joypad {
     fn get(&self, a: u16) -> u8 {
         assert_eq!(a, 0xff00);
-        if (self.select & 0b0001_0000) == 0x00 {
-            return self.select | (self.matrix & 0x0f);
-        }
-        if (self.select & 0b0010_0000) == 0x00 {
-            return self.select | (self.matrix >> 4);
-        }
-        self.select
+        0xc0 | self.select_lines() | self.input_lines()
     }
 
     fn set(&mut self, a: u16, v: u8) {
         assert_eq!(a, 0xff00);
         self.select = v;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.set(v);
+        }
+        self.update();
+    }
+}
+
+// The pressed keys come from the host, only the selected lines are restored.
+impl Snapshot for Joypad {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.select);
+        w.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save(w);
+        }
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.select = r.u8()?;
+        self.sgb = if r.bool()? {
+            let mut sgb = Sgb::power_up();
+            sgb.load(r)?;
+            Some(sgb)
+        } else {
+            None
+        };
+        self.lines = self.input_lines();
+        Ok(())
     }
 }
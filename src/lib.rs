@@ -3,12 +3,39 @@ pub mod cartridge;
 pub mod clock;
 pub mod convention;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod emulator;
+pub mod error;
 pub mod gpu;
+pub mod hexview;
+pub mod infrared;
 pub mod intf;
 pub mod joypad;
+pub mod library;
+pub mod link;
 pub mod memory;
 pub mod mmunit;
 pub mod motherboard;
+pub mod movie;
+pub mod osd;
+pub mod palette;
+pub mod peek;
+pub mod recorder;
 pub mod register;
+pub mod ring;
+pub mod rom;
+#[cfg(feature = "script")]
+pub mod script;
 pub mod serial;
+pub mod sgb;
+pub mod state;
+pub mod stats;
+pub mod stretch;
 pub mod timer;
+pub mod verify;
+pub mod video;
+pub mod watch;
+pub mod wav;
+
+pub use emulator::Emulator;
@@ -0,0 +1,128 @@
+// The roms of a directory, for a picker which lists the games without opening every file each time. The header of
+// each rom is read once and kept in a cache file in the same directory, together with a hash of the whole rom that
+// identifies a game independently of its file name, e.g. for settings per game. An entry is read again when the
+// size or the modification time of its file changed.
+//
+// Cache file format
+// Plain text, one rom per line, the fields separated by tabs:
+//   file name, size, modification time in seconds, hash, CGB flag (0 or 1), mapper, title
+use super::cartridge::{self, Cartridge, RomOnly};
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const CACHE: &str = "gameboy.db";
+
+#[derive(Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: u64,
+    // FNV-1a 64 of the whole rom.
+    pub hash: u64,
+    // The game uses the Game Boy Color features.
+    pub cgb: bool,
+    pub mapper: String,
+    pub title: String,
+}
+
+impl Entry {
+    fn read(path: PathBuf, size: u64, mtime: u64) -> Result<Option<Self>> {
+        let rom = fs::read(&path)?;
+        if rom.len() < 0x150 {
+            return Ok(None);
+        }
+        let header = RomOnly::power_up(rom[..0x150].to_vec());
+        Ok(Some(Self {
+            path,
+            size,
+            mtime,
+            hash: hash(&rom),
+            cgb: rom[0x0143] & 0x80 != 0x00,
+            mapper: cartridge::mbc_info(rom[0x0147]),
+            title: header.title().chars().map(|c| if c.is_ascii_graphic() { c } else { ' ' }).collect(),
+        }))
+    }
+
+    fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{:016x}\t{}\t{}\t{}",
+            self.path.file_name().unwrap().to_string_lossy(),
+            self.size,
+            self.mtime,
+            self.hash,
+            u8::from(self.cgb),
+            self.mapper,
+            self.title
+        )
+    }
+
+    fn parse(dir: &Path, line: &str) -> Option<Self> {
+        let f: Vec<&str> = line.splitn(7, '\t').collect();
+        if f.len() != 7 {
+            return None;
+        }
+        Some(Self {
+            path: dir.join(f[0]),
+            size: f[1].parse().ok()?,
+            mtime: f[2].parse().ok()?,
+            hash: u64::from_str_radix(f[3], 16).ok()?,
+            cgb: f[4] == "1",
+            mapper: String::from(f[5]),
+            title: String::from(f[6]),
+        })
+    }
+}
+
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+fn is_rom(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(e) => e.eq_ignore_ascii_case("gb") || e.eq_ignore_ascii_case("gbc"),
+        None => false,
+    }
+}
+
+// List the roms of a directory, sorted by title. Only the new and changed files are read, and the cache is rewritten
+// when anything changed. A cache that can't be written, e.g. on a read only medium, is not an error.
+pub fn scan(dir: impl AsRef<Path>) -> Result<Vec<Entry>> {
+    let dir = dir.as_ref();
+    let cache_path = dir.join(CACHE);
+    let cached: Vec<Entry> = match fs::read_to_string(&cache_path) {
+        Ok(ok) => ok.lines().filter_map(|l| Entry::parse(dir, l)).collect(),
+        Err(_) => vec![],
+    };
+    let mut entries = vec![];
+    let mut dirty = false;
+    for e in fs::read_dir(dir)? {
+        let path = e?.path();
+        if !path.is_file() || !is_rom(&path) {
+            continue;
+        }
+        let meta = fs::metadata(&path)?;
+        let mtime =
+            meta.modified().ok().and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+        match cached.iter().find(|c| c.path == path && c.size == meta.len() && c.mtime == mtime) {
+            Some(c) => entries.push(c.clone()),
+            None => {
+                rog::debugln!("Library: reading {:?}", path);
+                // Files too small to be a rom are not cached, they are cheap to check again.
+                if let Some(e) = Entry::read(path, meta.len(), mtime)? {
+                    dirty = true;
+                    entries.push(e);
+                }
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.title.cmp(&b.title).then_with(|| a.path.cmp(&b.path)));
+    if dirty || entries.len() != cached.len() {
+        let data: String = entries.iter().map(|e| e.line() + "\n").collect();
+        if let Err(e) = fs::write(&cache_path, data) {
+            rog::debugln!("Library: cache not written {}", e);
+        }
+    }
+    Ok(entries)
+}
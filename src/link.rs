@@ -0,0 +1,297 @@
+// The link cable can be stretched over a TCP connection, so that two emulators running on different machines can
+// trade with each other. Unlike a real cable, a network adds latency and jitter that the games on both ends are not
+// prepared for: a master that completes its 8 bit shift before the reply of the other side arrived would read a
+// garbage byte, and the trade protocol desyncs immediately.
+//
+// To hide this, the transfer started by the master is allowed to overrun its hardware duration by a configurable
+// number of frames while waiting for the reply, the emulation keeps running in the meantime. Only when this budget is
+// exhausted the emulation blocks on the socket.
+//
+// Protocol
+// After the connection is established, both sides send a 6 bytes hello: the magic "GBLK", the protocol version and
+// the latency in frames they were configured with. Both sides then use the bigger one of the two latencies. Each
+// following message is 2 bytes long, a kind and a data byte:
+//   01h  DATA   The byte shifted out by a side which uses its internal clock (the master)
+//   02h  REPLY  The byte shifted out by a side which received a DATA (the slave)
+//
+// Clock negotiation
+// Which side is the master is decided transfer by transfer, by the game writing the Shift Clock bit of SC. A side
+// that receives a DATA while its own game waits on the external clock completes the transfer and answers with a
+// REPLY. When both games start an internal clock transfer at the same time, both DATA cross on the wire and each
+// side simply takes the byte of the other one, which is what happens with two masters on a real cable.
+use super::serial::SerialDevice;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time;
+
+const MAGIC: &[u8; 4] = b"GBLK";
+const VERSION: u8 = 0x01;
+
+const KIND_DATA: u8 = 0x01;
+const KIND_REPLY: u8 = 0x02;
+
+// Number of clock cycles in a frame: 154 scanlines of 456 dots.
+pub const FRAME_CYCLES: u32 = 70224;
+
+pub enum Message {
+    Data(u8),
+    Reply(u8),
+}
+
+pub struct TcpLink {
+    stream: TcpStream,
+    // Bytes received but not yet forming a complete message.
+    rbuf: Vec<u8>,
+    // Number of frames a master transfer can overrun while waiting for the reply.
+    latency: u32,
+    // How long to block on the socket once the latency budget is exhausted. Without an answer after this duration
+    // the peer is considered gone.
+    timeout: time::Duration,
+    alive: bool,
+}
+
+impl TcpLink {
+    // Wait for the other side to connect.
+    pub fn listen(addr: impl ToSocketAddrs, latency: u32) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        rog::debugln!("Link: listening on {}", listener.local_addr()?);
+        Self::accept(&listener, latency)
+    }
+
+    // Wait for the other side to connect to a listener bound beforehand, e.g. to port 0 to learn the port it got.
+    pub fn accept(listener: &TcpListener, latency: u32) -> std::io::Result<Self> {
+        let (stream, peer) = listener.accept()?;
+        rog::debugln!("Link: accepted {}", peer);
+        Self::handshake(stream, latency)
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs, latency: u32) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        rog::debugln!("Link: connected to {}", stream.peer_addr()?);
+        Self::handshake(stream, latency)
+    }
+
+    fn handshake(mut stream: TcpStream, latency: u32) -> std::io::Result<Self> {
+        let latency = latency.min(0xff) as u8;
+        stream.set_nodelay(true)?;
+        let mut hello = [0x00; 6];
+        hello[..4].copy_from_slice(MAGIC);
+        hello[4] = VERSION;
+        hello[5] = latency;
+        stream.write_all(&hello)?;
+        stream.read_exact(&mut hello)?;
+        if &hello[..4] != MAGIC || hello[4] != VERSION {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "Link: unsupported peer"));
+        }
+        let latency = u32::from(latency.max(hello[5]));
+        rog::debugln!("Link: negotiated latency {} frames", latency);
+        stream.set_nonblocking(true)?;
+        Ok(Self { stream, rbuf: Vec::new(), latency, timeout: time::Duration::from_secs(1), alive: true })
+    }
+
+    // A peer which breaks the protocol is dropped, the cable is then unplugged.
+    fn message(&mut self) -> Option<Message> {
+        if self.rbuf.len() < 2 {
+            return None;
+        }
+        let m = match self.rbuf[0] {
+            KIND_DATA => Message::Data(self.rbuf[1]),
+            KIND_REPLY => Message::Reply(self.rbuf[1]),
+            n => {
+                let msg = format!("Link: unsupported message 0x{:02x}", n);
+                self.disconnect(std::io::Error::new(ErrorKind::InvalidData, msg));
+                return None;
+            }
+        };
+        self.rbuf.drain(..2);
+        Some(m)
+    }
+
+    fn disconnect(&mut self, e: std::io::Error) {
+        rog::debugln!("Link: disconnected {}", e);
+        self.alive = false;
+        self.rbuf.clear();
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn send(&mut self, m: Message) {
+        if !self.alive {
+            return;
+        }
+        let b = match m {
+            Message::Data(v) => [KIND_DATA, v],
+            Message::Reply(v) => [KIND_REPLY, v],
+        };
+        // The socket is non-blocking, a full send buffer is simply retried.
+        let mut i = 0;
+        while i < b.len() {
+            match self.stream.write(&b[i..]) {
+                Ok(n) => i += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(time::Duration::from_micros(100)),
+                Err(e) => return self.disconnect(e),
+            }
+        }
+    }
+
+    fn poll(&mut self) -> Option<Message> {
+        if let Some(m) = self.message() {
+            return Some(m);
+        }
+        if !self.alive {
+            return None;
+        }
+        let mut b = [0x00; 64];
+        match self.stream.read(&mut b) {
+            Ok(0) => self.disconnect(std::io::Error::from(ErrorKind::UnexpectedEof)),
+            Ok(n) => self.rbuf.extend_from_slice(&b[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => self.disconnect(e),
+        }
+        self.message()
+    }
+
+    // Block until a message arrives, or the timeout expires.
+    fn wait(&mut self) -> Option<Message> {
+        let zero = time::Instant::now();
+        loop {
+            if let Some(m) = self.poll() {
+                return Some(m);
+            }
+            if !self.alive || zero.elapsed() > self.timeout {
+                return None;
+            }
+            std::thread::sleep(time::Duration::from_micros(100));
+        }
+    }
+
+    fn budget(&self) -> u32 {
+        self.latency * FRAME_CYCLES
+    }
+}
+
+type Queue = Arc<Mutex<VecDeque<Message>>>;
+
+// A cable between two emulators in the same process, e.g. a host program which runs two MotherBoards side by side.
+// The messages are queued in memory instead of going over a socket. The host steps the two machines in turns, so
+// nothing can arrive while one of them waits: a master transfer can overrun by latency frames of its own clock, which
+// must cover the time the host spends running the other machine, and reads FFh after that.
+pub struct LocalLink {
+    tx: Queue,
+    rx: Queue,
+    latency: u32,
+}
+
+impl LocalLink {
+    // Both ends of the cable.
+    pub fn pair(latency: u32) -> (Self, Self) {
+        let a: Queue = Arc::new(Mutex::new(VecDeque::new()));
+        let b: Queue = Arc::new(Mutex::new(VecDeque::new()));
+        (Self { tx: a.clone(), rx: b.clone(), latency }, Self { tx: b, rx: a, latency })
+    }
+}
+
+impl SerialDevice for LocalLink {
+    fn send(&mut self, m: Message) {
+        self.tx.lock().unwrap().push_back(m);
+    }
+
+    fn poll(&mut self) -> Option<Message> {
+        self.rx.lock().unwrap().pop_front()
+    }
+
+    fn wait(&mut self) -> Option<Message> {
+        self.poll()
+    }
+
+    fn budget(&self) -> u32 {
+        self.latency * FRAME_CYCLES
+    }
+}
+
+// The cable plugged into the standard streams, for scripts that test the link protocol of a game. The bytes the game
+// shifts out are written to stdout, and the bytes piped into stdin are shifted in, the script plays the other side:
+// - The game is master: each transfer takes the next byte of stdin as the reply. It blocks until the byte arrives,
+//   and reads FFh once stdin is closed.
+// - The game is slave: the next byte of stdin is clocked in as soon as the game waits for it on the external clock,
+//   never before, so a byte is not lost because the game wasn't ready. The reply of the game goes to stdout.
+pub struct StdioLink {
+    output: bool,
+    // Read by a thread, so that the emulation only blocks when a reply is due.
+    input: Option<mpsc::Receiver<u8>>,
+    // Messages for the game.
+    queue: VecDeque<Message>,
+    // A byte was clocked in to the game, its reply has not come yet.
+    busy: bool,
+}
+
+impl StdioLink {
+    // Write the bytes of the game to stdout, shift the bytes of stdin in, or both. Without input, the game reads FFh
+    // as with no cable.
+    pub fn power_up(output: bool, input: bool) -> Self {
+        let input = input.then(|| {
+            let (tx, rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                for b in std::io::stdin().lock().bytes() {
+                    match b {
+                        Ok(b) if tx.send(b).is_ok() => {}
+                        _ => break,
+                    }
+                }
+                rog::debugln!("Link: end of stdin");
+            });
+            rx
+        });
+        Self { output, input, queue: VecDeque::new(), busy: false }
+    }
+
+    fn write(&self, v: u8) {
+        if self.output {
+            // The reader of stdout may be gone, the game keeps running without it.
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(&[v]).and_then(|_| stdout.flush());
+        }
+    }
+}
+
+impl SerialDevice for StdioLink {
+    fn send(&mut self, m: Message) {
+        match m {
+            Message::Data(v) => {
+                self.write(v);
+                let reply = self.input.as_ref().and_then(|rx| rx.recv().ok()).unwrap_or(0xff);
+                self.queue.push_back(Message::Reply(reply));
+            }
+            Message::Reply(v) => {
+                self.write(v);
+                self.busy = false;
+            }
+        }
+    }
+
+    fn poll(&mut self) -> Option<Message> {
+        self.queue.pop_front()
+    }
+
+    fn wait(&mut self) -> Option<Message> {
+        self.poll()
+    }
+
+    // The reply is queued as soon as the byte is sent.
+    fn budget(&self) -> u32 {
+        0
+    }
+
+    fn ready(&mut self) {
+        if self.busy || !self.queue.is_empty() {
+            return;
+        }
+        if let Some(v) = self.input.as_ref().and_then(|rx| rx.try_recv().ok()) {
+            self.queue.push_back(Message::Data(v));
+            self.busy = true;
+        }
+    }
+}
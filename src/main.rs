@@ -3,70 +3,1188 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::Sample;
 use gameboy::apu::Apu;
-use gameboy::gpu::{SCREEN_H, SCREEN_W};
-use gameboy::motherboard::MotherBoard;
+use gameboy::cartridge::RtcSync;
+use gameboy::convention::{Revision, Term};
+use gameboy::cpu::{BlockCache, CLOCK_FREQUENCY};
+use gameboy::debugger::Debugger;
+use gameboy::gpu::{ColorCorrection, Gpu, Picture, SCREEN_H, SCREEN_W};
+use gameboy::hexview::{HexView, ROWS};
+use gameboy::joypad::JoypadKey;
+use gameboy::library;
+use gameboy::link::{StdioLink, TcpLink};
+use gameboy::mmunit::Mmunit;
+use gameboy::motherboard::{MotherBoard, Rewind};
+use gameboy::movie::Movie;
+use gameboy::osd::Osd;
+use gameboy::recorder::Recorder;
+use gameboy::ring::Ring;
+use gameboy::script::Script;
+use gameboy::state::{Slots, SLOTS};
+use gameboy::stretch;
+use gameboy::verify::{self, Digest};
+use gameboy::video::{self, Blend, Filter};
+use gameboy::watch::Watchpoint;
+use gameboy::wav::WavWriter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Every keyboard key, to look them up by name.
+#[rustfmt::skip]
+const KEYS: &[minifb::Key] = {
+    use minifb::Key::*;
+    &[
+        Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, F13, F14, F15,
+        Down, Left, Right, Up, Apostrophe, Backquote, Backslash, Comma, Equal, LeftBracket, Minus, Period, RightBracket,
+        Semicolon, Slash, Backspace, Delete, End, Enter, Escape, Home, Insert, Menu, PageDown, PageUp, Pause, Space,
+        Tab, NumLock, CapsLock, ScrollLock, LeftShift, RightShift, LeftCtrl, RightCtrl,
+        NumPad0, NumPad1, NumPad2, NumPad3, NumPad4, NumPad5, NumPad6, NumPad7, NumPad8, NumPad9,
+        NumPadDot, NumPadSlash, NumPadAsterisk, NumPadMinus, NumPadPlus, NumPadEnter,
+        LeftAlt, RightAlt, LeftSuper, RightSuper,
+    ]
+};
+
+// The keyboard keys of the joypad. A joypad key can have several keyboard keys.
+type Keymap = Vec<(minifb::Key, JoypadKey)>;
+
+fn default_keymap() -> Keymap {
+    vec![
+        (minifb::Key::Right, JoypadKey::Right),
+        (minifb::Key::Up, JoypadKey::Up),
+        (minifb::Key::Left, JoypadKey::Left),
+        (minifb::Key::Down, JoypadKey::Down),
+        (minifb::Key::Z, JoypadKey::A),
+        (minifb::Key::X, JoypadKey::B),
+        (minifb::Key::Space, JoypadKey::Select),
+        (minifb::Key::Enter, JoypadKey::Start),
+    ]
+}
+
+// Parse a binding of the form <joypad key>=<keyboard key>, e.g. a=K. Keyboard keys are named as in minifb::Key.
+fn parse_binding(s: &str) -> Result<(minifb::Key, JoypadKey), String> {
+    let (vk, rk) = s.split_once('=').ok_or(format!("Binding is not of the form <joypad key>=<keyboard key>: {}", s))?;
+    let vk: JoypadKey = vk.trim().parse()?;
+    let rk = KEYS
+        .iter()
+        .find(|k| format!("{:?}", k).eq_ignore_ascii_case(rk.trim()))
+        .ok_or(format!("Unsupported keyboard key: {}", rk.trim()))?;
+    Ok((*rk, vk))
+}
+
+// The bindings replace the default keys of the joypad keys they name, the other joypad keys keep theirs.
+fn keymap(defaults: Keymap, bindings: &[String]) -> Keymap {
+    let bindings: Vec<(minifb::Key, JoypadKey)> =
+        bindings.iter().map(|b| parse_binding(b).unwrap_or_else(|e| fatal(e))).collect();
+    let mut keymap = defaults;
+    keymap.retain(|(_, vk)| !bindings.iter().any(|(_, b)| b == vk));
+    keymap.extend(bindings);
+    keymap
+}
+
+// The bindings of a key file followed by the ones of the command line.
+fn bindings(path: &Option<String>, bind: Vec<String>) -> Vec<String> {
+    let mut bindings: Vec<String> = vec![];
+    if let Some(path) = path {
+        let text = std::fs::read_to_string(path).unwrap_or_else(|e| fatal(format!("Keys not loaded: {}", e)));
+        let lines = text.lines().map(|l| l.split('#').next().unwrap().trim()).filter(|l| !l.is_empty());
+        bindings.extend(lines.map(String::from));
+    }
+    bindings.extend(bind);
+    bindings
+}
+
+fn joypad_key(keymap: &Keymap, k: minifb::Key) -> Option<JoypadKey> {
+    keymap.iter().find(|(rk, _)| *rk == k).map(|(_, vk)| vk.clone())
+}
+
+// The keys held on the keyboard.
+fn keyboard_keys(window: &minifb::Window, keymap: &Keymap) -> u8 {
+    window.get_keys().into_iter().filter_map(|rk| joypad_key(keymap, rk)).fold(0x00, |k, vk| k | vk as u8)
+}
+
+// The keys of the save state slots, slot 1 first.
+const SLOT_KEYS: [minifb::Key; SLOTS] = [
+    minifb::Key::F1,
+    minifb::Key::F2,
+    minifb::Key::F3,
+    minifb::Key::F4,
+    minifb::Key::F5,
+    minifb::Key::F6,
+    minifb::Key::F7,
+    minifb::Key::F8,
+    minifb::Key::F9,
+    minifb::Key::F10,
+];
+
+// Buttons are mapped by position, as on the Game Boy A is on the right of B.
+const PAD_BUTTONS: [(gilrs::Button, JoypadKey); 8] = [
+    (gilrs::Button::DPadRight, JoypadKey::Right),
+    (gilrs::Button::DPadLeft, JoypadKey::Left),
+    (gilrs::Button::DPadUp, JoypadKey::Up),
+    (gilrs::Button::DPadDown, JoypadKey::Down),
+    (gilrs::Button::East, JoypadKey::A),
+    (gilrs::Button::South, JoypadKey::B),
+    (gilrs::Button::Select, JoypadKey::Select),
+    (gilrs::Button::Start, JoypadKey::Start),
+];
+
+// The keys held on the gamepads of each player. With a second player, the first gamepad is the one of the first
+// player and the others are of the second. The left stick gives the directions too, once pushed past half way.
+fn gamepad_keys(gilrs: &mut gilrs::Gilrs, players: usize) -> [u8; 2] {
+    // Events must be drained for the state of the gamepads to be updated.
+    while gilrs.next_event().is_some() {}
+    let mut keys = [0x00; 2];
+    for (i, (_, pad)) in gilrs.gamepads().enumerate() {
+        let keys = &mut keys[i.min(players - 1)];
+        for (button, vk) in PAD_BUTTONS.iter() {
+            if pad.is_pressed(*button) {
+                *keys |= vk.clone() as u8;
+            }
+        }
+        let x = pad.value(gilrs::Axis::LeftStickX);
+        let y = pad.value(gilrs::Axis::LeftStickY);
+        let stick = [
+            (x > 0.5, JoypadKey::Right),
+            (x < -0.5, JoypadKey::Left),
+            (y > 0.5, JoypadKey::Up),
+            (y < -0.5, JoypadKey::Down),
+        ];
+        for (on, vk) in stick {
+            if on {
+                *keys |= vk as u8;
+            }
+        }
+    }
+    keys
+}
+
+// The left stick of the first gamepad pushed out of its dead zone, with y growing downwards.
+fn gamepad_stick(gilrs: &gilrs::Gilrs) -> Option<(f32, f32)> {
+    gilrs
+        .gamepads()
+        .map(|(_, pad)| (pad.value(gilrs::Axis::LeftStickX), -pad.value(gilrs::Axis::LeftStickY)))
+        .find(|(x, y)| x.abs() > 0.1 || y.abs() > 0.1)
+}
+
+// The tilt of the console for tilt cartridges, from the left stick of a gamepad or from the direction keys. The stick
+// tilts it as far as it is pushed. A direction key tilts it gradually while held, the way a hand would, and the console
+// levels out again once it is released. Either way the tilt at full is sensitivity g.
+struct Tilt {
+    x: f32,
+    y: f32,
+    sensitivity: f32,
+}
+
+impl Tilt {
+    // How far a direction key tilts the console in a frame: it takes 8 frames to tilt fully, or to level out.
+    const STEP: f32 = 0.125;
+
+    fn power_up(sensitivity: f32) -> Self {
+        Self { x: 0.0, y: 0.0, sensitivity }
+    }
+
+    // Called once a frame, returns the tilt in g.
+    fn update(&mut self, keys: u8, stick: Option<(f32, f32)>) -> (f32, f32) {
+        match stick {
+            Some((x, y)) => (self.x, self.y) = (x, y),
+            None => {
+                let axis = |v: f32, plus: JoypadKey, minus: JoypadKey| {
+                    let to = f32::from(keys & plus as u8 != 0x00) - f32::from(keys & minus as u8 != 0x00);
+                    v + (to - v).clamp(-Self::STEP, Self::STEP)
+                };
+                self.x = axis(self.x, JoypadKey::Right, JoypadKey::Left);
+                self.y = axis(self.y, JoypadKey::Down, JoypadKey::Up);
+            }
+        }
+        (self.x * self.sensitivity, self.y * self.sensitivity)
+    }
+}
+
+// The rumble motor of the cartridge, played as a strong rumble on the gamepads with force feedback. Only the gamepads
+// connected at start rumble.
+struct Rumble {
+    effect: gilrs::ff::Effect,
+    on: bool,
+}
+
+impl Rumble {
+    fn power_up(gilrs: &mut gilrs::Gilrs) -> Option<Self> {
+        let pads: Vec<_> = gilrs.gamepads().filter(|(_, pad)| pad.is_ff_supported()).map(|(id, _)| id).collect();
+        if pads.is_empty() {
+            return None;
+        }
+        let kind = gilrs::ff::BaseEffectType::Strong { magnitude: 0xc000 };
+        let effect = gilrs::ff::EffectBuilder::new()
+            .add_effect(gilrs::ff::BaseEffect { kind, ..Default::default() })
+            .gamepads(&pads)
+            .finish(gilrs);
+        match effect {
+            Ok(effect) => Some(Self { effect, on: false }),
+            Err(e) => {
+                rog::debugln!("Rumble not available: {}", e);
+                None
+            }
+        }
+    }
+
+    fn set(&mut self, on: bool) {
+        if on == self.on {
+            return;
+        }
+        self.on = on;
+        let r = if on { self.effect.play() } else { self.effect.stop() };
+        if let Err(e) = r {
+            rog::debugln!("Rumble: {}", e);
+        }
+    }
+}
+
+// Copy the screen to the window buffer through the filter, blended with the last frame and with the keys held, the
+// performance statistics and the text of the script on top if asked.
+fn draw(
+    mbrd: &MotherBoard,
+    screen: &mut [u32],
+    show_input: bool,
+    script: &Option<Script>,
+    blend: &mut Option<Blend>,
+    filter: Filter,
+    buffer: &mut [u32],
+) {
+    let mut i: usize = 0;
+    for l in mbrd.mmu.gpu.data.iter() {
+        for w in l.iter() {
+            let b = u32::from(w[0]) << 16;
+            let g = u32::from(w[1]) << 8;
+            let r = u32::from(w[2]);
+            let a = 0xff00_0000;
+
+            screen[i] = a | b | g | r;
+            i += 1;
+        }
+    }
+    if let Some(blend) = blend {
+        blend.apply(screen);
+    }
+    if show_input {
+        Osd::new(screen).input(&mbrd.mmu.joypad);
+    }
+    if let Some(s) = mbrd.stats() {
+        Osd::new(screen).stats(&s);
+    }
+    if let Some(script) = script {
+        script.draw(&mut Osd::new(screen));
+    }
+    filter.apply(screen, SCREEN_W, SCREEN_H, buffer);
+}
+
+// Call the script after a frame. A script which fails is stopped, the game goes on without it.
+fn run_script(script: &mut Option<Script>, mbrd: &mut MotherBoard) {
+    if let Some(Err(e)) = script.as_mut().map(|s| s.frame(mbrd)) {
+        rog::println!("Script stopped: {}", e);
+        *script = None;
+    }
+}
+
+// Show the buffer of w x h pixels in the window, scaled by zoom in fullscreen.
+fn present(window: &mut minifb::Window, buffer: &[u32], w: usize, h: usize, zoom: usize, zoomed: &mut Vec<u32>) {
+    if zoom == 1 {
+        window.update_with_buffer(buffer, w, h).unwrap();
+        return;
+    }
+    zoomed.resize(w * h * zoom * zoom, 0);
+    video::zoom(buffer, w, h, zoom, zoomed);
+    window.update_with_buffer(zoomed, w * zoom, h * zoom).unwrap();
+}
+
+// The size of the monitor in pixels. On X11 it is the size of the whole screen, which spans all the monitors of a
+// desktop made of several.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn screen_size() -> Option<(usize, usize)> {
+    let xlib = x11_dl::xlib::Xlib::open().ok()?;
+    unsafe {
+        let display = (xlib.XOpenDisplay)(std::ptr::null());
+        if display.is_null() {
+            return None;
+        }
+        let screen = (xlib.XDefaultScreen)(display);
+        let size = ((xlib.XDisplayWidth)(display, screen), (xlib.XDisplayHeight)(display, screen));
+        (xlib.XCloseDisplay)(display);
+        Some((size.0 as usize, size.1 as usize))
+    }
+}
+
+#[cfg(windows)]
+fn screen_size() -> Option<(usize, usize)> {
+    use winapi::um::winuser::{GetSystemMetrics, SM_CXSCREEN, SM_CYSCREEN};
+    let size = unsafe { (GetSystemMetrics(SM_CXSCREEN), GetSystemMetrics(SM_CYSCREEN)) };
+    Some((size.0 as usize, size.1 as usize))
+}
+
+#[cfg(not(any(windows, all(unix, not(target_os = "macos")))))]
+fn screen_size() -> Option<(usize, usize)> {
+    None
+}
+
+// A borderless window over the whole monitor, and the largest whole scale of a w x h picture which fits in it. The
+// picture is centered, on black.
+fn fullscreen(title: &str, w: usize, h: usize) -> Option<(minifb::Window, usize)> {
+    let (sw, sh) = screen_size()?;
+    let option = minifb::WindowOptions {
+        borderless: true,
+        title: false,
+        topmost: true,
+        scale_mode: minifb::ScaleMode::Center,
+        ..Default::default()
+    };
+    let mut window = match minifb::Window::new(title, sw, sh, option) {
+        Ok(ok) => ok,
+        Err(e) => {
+            rog::debugln!("Fullscreen window not opened: {}", e);
+            return None;
+        }
+    };
+    window.set_position(0, 0);
+    Some((window, (sw / w).min(sh / h).max(1)))
+}
+
+// The next n samples to play, taken from the APU into scratch. When the APU is late, the missing samples are silent.
+fn pop_samples<'a>(ring: &Ring, scratch: &'a mut Vec<(f32, f32)>, n: usize) -> &'a [(f32, f32)] {
+    scratch.resize(n, (0.0, 0.0));
+    let got = ring.pop(scratch);
+    scratch[got..].fill((0.0, 0.0));
+    scratch
+}
+
+// A debug view in a window of its own, e.g. the tiles in VRAM, opened and closed with a key.
+struct View {
+    title: &'static str,
+    key: minifb::Key,
+    make: fn(&Gpu) -> Picture,
+    window: Option<minifb::Window>,
+    buffer: Vec<u32>,
+}
+
+impl View {
+    fn new(title: &'static str, key: minifb::Key, make: fn(&Gpu) -> Picture) -> Self {
+        Self { title, key, make, window: None, buffer: vec![] }
+    }
+
+    fn toggle(&mut self, gpu: &Gpu) {
+        if self.window.take().is_some() {
+            return;
+        }
+        let pic = (self.make)(gpu);
+        let option = minifb::WindowOptions { resize: true, scale: minifb::Scale::X2, ..Default::default() };
+        match minifb::Window::new(self.title, pic.w, pic.h, option) {
+            Ok(w) => self.window = Some(w),
+            Err(e) => rog::println!("{} not opened: {}", self.title, e),
+        }
+        self.update(gpu);
+    }
+
+    // Draw the view again, after a frame. A view closed from its window is dropped.
+    fn update(&mut self, gpu: &Gpu) {
+        let Some(window) = self.window.as_mut() else {
+            return;
+        };
+        if !window.is_open() {
+            self.window = None;
+            return;
+        }
+        let pic = (self.make)(gpu);
+        self.buffer.clear();
+        self.buffer.extend(pic.data.iter().map(|p| u32::from(p[0]) << 16 | u32::from(p[1]) << 8 | u32::from(p[2])));
+        window.update_with_buffer(&self.buffer, pic.w, pic.h).unwrap();
+    }
+}
+
+// The memory of the game as a hex dump, in a window of its own opened and closed with Ctrl+F4. The arrows and Page Up
+// and Down move the cursor, with Shift Page Up and Down by 4 KB, and two hex digits typed write a byte at the cursor.
+struct MemoryView {
+    window: Option<minifb::Window>,
+    hex: HexView,
+    buffer: Vec<u32>,
+}
+
+impl MemoryView {
+    fn power_up() -> Self {
+        Self { window: None, hex: HexView::power_up(), buffer: vec![] }
+    }
+
+    fn toggle(&mut self, mmu: &mut Mmunit) {
+        if self.window.take().is_some() {
+            return;
+        }
+        let (w, h) = HexView::size();
+        let option = minifb::WindowOptions { resize: true, scale: minifb::Scale::X2, ..Default::default() };
+        match minifb::Window::new("Gameboy - Memory", w, h, option) {
+            Ok(w) => self.window = Some(w),
+            Err(e) => rog::println!("Gameboy - Memory not opened: {}", e),
+        }
+        self.update(mmu);
+    }
+
+    // Apply the keys pressed in the window and draw the dump again, true when a byte was written. A view closed from
+    // its window is dropped.
+    fn update(&mut self, mmu: &mut Mmunit) -> bool {
+        let Some(window) = self.window.as_mut() else {
+            return false;
+        };
+        if !window.is_open() {
+            self.window = None;
+            return false;
+        }
+        let mut wrote = false;
+        let shift = window.is_key_down(minifb::Key::LeftShift) || window.is_key_down(minifb::Key::RightShift);
+        let page = if shift { 0x1000 } else { ROWS as i32 * 16 };
+        for k in window.get_keys_pressed(minifb::KeyRepeat::Yes) {
+            match k {
+                minifb::Key::Left => self.hex.move_cursor(-1),
+                minifb::Key::Right => self.hex.move_cursor(1),
+                minifb::Key::Up => self.hex.move_cursor(-16),
+                minifb::Key::Down => self.hex.move_cursor(16),
+                minifb::Key::PageUp => self.hex.move_cursor(-page),
+                minifb::Key::PageDown => self.hex.move_cursor(page),
+                minifb::Key::Escape => self.hex.cancel(),
+                _ => {
+                    if let Some(d) = hex_digit(k) {
+                        wrote |= self.hex.type_digit(mmu, d);
+                    }
+                }
+            }
+        }
+        let pic = self.hex.draw(mmu);
+        self.buffer.clear();
+        self.buffer.extend(pic.data.iter().map(|p| u32::from(p[0]) << 16 | u32::from(p[1]) << 8 | u32::from(p[2])));
+        window.update_with_buffer(&self.buffer, pic.w, pic.h).unwrap();
+        wrote
+    }
+}
+
+fn hex_digit(k: minifb::Key) -> Option<u8> {
+    use minifb::Key::*;
+    let digits = [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, A, B, C, D, E, F];
+    let pad = [NumPad0, NumPad1, NumPad2, NumPad3, NumPad4, NumPad5, NumPad6, NumPad7, NumPad8, NumPad9];
+    digits.iter().position(|&d| d == k).or_else(|| pad.iter().position(|&d| d == k)).map(|d| d as u8)
+}
+
+// The first free file name of the form <rom>-<n>.<ext>, next to the rom.
+fn free_path(rom: &str, ext: &str) -> std::path::PathBuf {
+    let stem = std::path::Path::new(rom).with_extension("");
+    (1..).map(|n| std::path::PathBuf::from(format!("{}-{}.{}", stem.display(), n, ext))).find(|p| !p.exists()).unwrap()
+}
+
+// Why a run ended before the window was closed.
+enum Exit {
+    Frames(u64),
+    Seconds(f64),
+    // The CPU stayed at this address for the length of the watchdog.
+    Stuck(u16),
+}
+
+// Ends runs nobody watches, e.g. tests in CI: after a number of frames or seconds, or when the CPU is stuck at the same
+// address, which a broken rom or emulator would otherwise do forever. All time is emulated time, so a run ends at the
+// same point whatever the speed of the host. Waiting in a HALT or a loop for an interrupt is not stuck, as long as the
+// interrupt comes: its handler moves the CPU away.
+struct Limits {
+    frames: Option<u64>,
+    // In clock cycles.
+    cycles: Option<u64>,
+    watchdog: Option<u64>,
+    frame: u64,
+    pc: u16,
+    since: u64,
+}
+
+impl Limits {
+    fn power_up(frames: Option<u64>, seconds: Option<f64>, watchdog: Option<f64>) -> Self {
+        let cycles = |s: f64| (s * f64::from(CLOCK_FREQUENCY)) as u64;
+        Self { frames, cycles: seconds.map(cycles), watchdog: watchdog.map(cycles), frame: 0, pc: 0, since: 0 }
+    }
+
+    // Call it after every instruction, with whether it completed a frame.
+    fn check(&mut self, mbrd: &MotherBoard, frame: bool) -> Option<Exit> {
+        let now = mbrd.mmu.clock.now;
+        if frame {
+            self.frame += 1;
+        }
+        if self.frames.is_some_and(|n| self.frame >= n) {
+            return Some(Exit::Frames(self.frame));
+        }
+        if self.cycles.is_some_and(|n| now >= n) {
+            return Some(Exit::Seconds(now as f64 / f64::from(CLOCK_FREQUENCY)));
+        }
+        let pc = mbrd.cpu.cpu.reg.pc;
+        if pc != self.pc {
+            self.pc = pc;
+            self.since = now;
+        } else if self.watchdog.is_some_and(|n| now - self.since >= n) {
+            return Some(Exit::Stuck(pc));
+        }
+        None
+    }
+}
+
+// Save what the run produced: recordings, movie, sound and the battery RAM.
+fn shutdown(mbrd: &mut MotherBoard) {
+    if let Some(r) = mbrd.recorder.take() {
+        if let Err(e) = r.finish() {
+            rog::println!("Recording not saved: {}", e);
+        }
+    }
+    if let Some(m) = mbrd.movie.take() {
+        if let Err(e) = m.finish() {
+            rog::println!("Movie not saved: {}", e);
+        }
+    }
+    for mut sink in mbrd.mmu.apu.sinks.drain(..) {
+        if let Err(e) = sink.finish() {
+            rog::println!("Sound not saved: {}", e);
+        }
+    }
+    if let Err(e) = mbrd.finish_trace() {
+        rog::println!("Trace not written: {}", e);
+    }
+    if let Err(e) = mbrd.mmu.cartridge.sav() {
+        rog::println!("Save not written: {}", e);
+    }
+    if let Some(s) = mbrd.mmu.stats.as_ref().and_then(|s| s.average()) {
+        rog::println!("Average of {} frames: {}", s.frame, s);
+    }
+}
+
+// Report why the run ended and exit, with status 2 if the CPU got stuck.
+fn exit(exit: Option<Exit>) {
+    match exit {
+        None => {}
+        Some(Exit::Frames(n)) => rog::println!("Exit after {} frames", n),
+        Some(Exit::Seconds(s)) => rog::println!("Exit after {:.2} seconds", s),
+        Some(Exit::Stuck(pc)) => {
+            rog::println!("Watchdog: the CPU is stuck at {:04x}", pc);
+            std::process::exit(2);
+        }
+    }
+}
+
+// List the roms of a directory and ask which one to start.
+fn pick(dir: &str) -> String {
+    let entries = library::scan(dir).unwrap_or_else(|e| fatal(format!("Roms not listed: {}", e)));
+    if entries.is_empty() {
+        fatal(format!("No rom found in {}", dir));
+    }
+    for (i, e) in entries.iter().enumerate() {
+        let cgb = if e.cgb { "CGB" } else { "" };
+        println!("{:>4}  {:<16} {:<3}  {:<24} {:016x}", i + 1, e.title, cgb, e.mapper, e.hash);
+    }
+    let stdin = std::io::stdin();
+    loop {
+        print!("Rom number: ");
+        std::io::Write::flush(&mut std::io::stdout()).unwrap();
+        let mut line = String::new();
+        if stdin.read_line(&mut line).unwrap() == 0 {
+            std::process::exit(0);
+        }
+        match line.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= entries.len() => return entries[n - 1].path.to_string_lossy().into_owned(),
+            _ => println!("Enter a number from 1 to {}", entries.len()),
+        }
+    }
+}
+
+// The options of the command line, see the README.
+struct Config {
+    rom: String,
+    audio: bool,
+    scale: usize,
+    filter: Filter,
+    blend: bool,
+    no_throttle: bool,
+    limiter_step: Option<f64>,
+    fast_forward: u32,
+    fast_audio: stretch::Mode,
+    wav: Option<String>,
+    model: Option<Term>,
+    revision: Option<Revision>,
+    raw: bool,
+    mmap: bool,
+    boot_rom: Option<String>,
+    rtc_sync: RtcSync,
+    overflow: bool,
+    color: ColorCorrection,
+    palette: Option<String>,
+    palette_name: Option<String>,
+    input: bool,
+    stats: bool,
+    ffmpeg: bool,
+    rewind: bool,
+    keys: Option<String>,
+    bind: Vec<String>,
+    keys2: Option<String>,
+    bind2: Vec<String>,
+    tilt: f32,
+    sgb: bool,
+    record_movie: Option<String>,
+    play_movie: Option<String>,
+    paused: bool,
+    pause_on_suspend: bool,
+    debug: bool,
+    trace: Option<String>,
+    ly_stub: bool,
+    disassemble: bool,
+    info: bool,
+    dat: Option<String>,
+    link_listen: Option<String>,
+    link_connect: Option<String>,
+    link_latency: u32,
+    serial: Option<String>,
+    serial_console: bool,
+    cpu_cache: bool,
+    script: Option<String>,
+    watch: Vec<Watchpoint>,
+    headless: bool,
+    exit_frames: Option<u64>,
+    exit_seconds: Option<f64>,
+    watchdog: Option<f64>,
+}
+
+impl Config {
+    fn parse() -> Self {
+        let mut c = Self {
+            rom: String::from(""),
+            audio: false,
+            scale: 2,
+            filter: Filter::None,
+            blend: false,
+            no_throttle: false,
+            limiter_step: None,
+            fast_forward: 4,
+            fast_audio: stretch::Mode::Mute,
+            wav: None,
+            model: None,
+            revision: None,
+            raw: false,
+            mmap: false,
+            boot_rom: None,
+            rtc_sync: RtcSync::RealTime,
+            overflow: false,
+            color: ColorCorrection::Cgb,
+            palette: None,
+            palette_name: None,
+            input: false,
+            stats: false,
+            ffmpeg: false,
+            rewind: false,
+            keys: None,
+            bind: vec![],
+            keys2: None,
+            bind2: vec![],
+            tilt: 1.0,
+            sgb: false,
+            record_movie: None,
+            play_movie: None,
+            paused: false,
+            pause_on_suspend: false,
+            debug: false,
+            trace: None,
+            ly_stub: false,
+            disassemble: false,
+            info: false,
+            dat: None,
+            link_listen: None,
+            link_connect: None,
+            link_latency: 2,
+            serial: None,
+            serial_console: false,
+            cpu_cache: false,
+            script: None,
+            watch: vec![],
+            headless: false,
+            exit_frames: None,
+            exit_seconds: None,
+            watchdog: None,
+        };
+        {
+            let mut ap = argparse::ArgumentParser::new();
+            ap.set_description("Gameboy emulator");
+            ap.refer(&mut c.audio).add_option(&["-a", "--enable-audio"], argparse::StoreTrue, "Enable audio");
+            ap.refer(&mut c.scale).add_option(
+                &["-x", "--scale-factor"],
+                argparse::Store,
+                "Scale the video by a factor of 1, 2, 4, or 8",
+            );
+            ap.refer(&mut c.filter).add_option(
+                &["--filter"],
+                argparse::Store,
+                "Filter the video: none (default), scanlines, lcd or xbr",
+            );
+            ap.refer(&mut c.blend).add_option(
+                &["--frame-blend"],
+                argparse::StoreTrue,
+                "Mix each frame with the last one, like the slow LCD of the DMG",
+            );
+            ap.refer(&mut c.no_throttle).add_option(
+                &["--no-throttle"],
+                argparse::StoreTrue,
+                "Run as fast as possible, without speed limit and audio",
+            );
+            ap.refer(&mut c.limiter_step).add_option(
+                &["--limiter-step"],
+                argparse::StoreOption,
+                "Milliseconds between two sleeps of the speed limiter, default is one frame",
+            );
+            ap.refer(&mut c.fast_forward).add_option(
+                &["--fast-forward"],
+                argparse::Store,
+                "Speed while Tab is held, in times the normal speed, default is 4",
+            );
+            ap.refer(&mut c.fast_audio).add_option(
+                &["--fast-forward-audio"],
+                argparse::Store,
+                "Sound while fast-forwarding: mute (default), decimate or stretch",
+            );
+            ap.refer(&mut c.wav).add_option(
+                &["--wav-out"],
+                argparse::StoreOption,
+                "Write the sound to a WAV file, with or without audio playback",
+            );
+            ap.refer(&mut c.model).add_option(
+                &["--model"],
+                argparse::StoreOption,
+                "Run the game on a model instead of the one it asks for: dmg, mgb, cgb or sgb",
+            );
+            ap.refer(&mut c.revision).add_option(
+                &["--revision"],
+                argparse::StoreOption,
+                "Emulate the quirks of a chip revision of the model: dmg0, dmgb or cgbe",
+            );
+            ap.refer(&mut c.raw).add_option(
+                &["--raw"],
+                argparse::StoreTrue,
+                "Load the rom as a plain 32KB rom, without checking its header",
+            );
+            ap.refer(&mut c.mmap).add_option(
+                &["--mmap"],
+                argparse::StoreTrue,
+                "Map the rom from its file instead of reading it into memory",
+            );
+            ap.refer(&mut c.boot_rom).add_option(
+                &["--boot-rom"],
+                argparse::StoreOption,
+                "Start from a DMG or CGB boot ROM image, with its logo animation",
+            );
+            ap.refer(&mut c.rtc_sync).add_option(
+                &["--rtc-on-load"],
+                argparse::Store,
+                "What loading a state does to the cartridge clock: real (default) or emulated",
+            );
+            ap.refer(&mut c.overflow).add_option(
+                &["--show-sprite-overflow"],
+                argparse::StoreTrue,
+                "Tint the scanlines with more than 10 sprites in red",
+            );
+            ap.refer(&mut c.color).add_option(
+                &["--color-correction"],
+                argparse::Store,
+                "Colors of GBC games: raw, cgb (default) or gba",
+            );
+            ap.refer(&mut c.palette_name).add_option(
+                &["--palette"],
+                argparse::StoreOption,
+                "Show DMG games in built-in colors: gray (default), green, pocket, light, sepia or cgb, those of a CGB",
+            );
+            ap.refer(&mut c.palette).add_option(
+                &["--palette-file"],
+                argparse::StoreOption,
+                "Show DMG games in the colors of a palette file: .pal, .gpl or .hex",
+            );
+            ap.refer(&mut c.input).add_option(
+                &["--show-input"],
+                argparse::StoreTrue,
+                "Show the buttons being held in the bottom left corner",
+            );
+            ap.refer(&mut c.stats).add_option(
+                &["--show-stats"],
+                argparse::StoreTrue,
+                "Show where the time of each frame goes in the top left corner, and the average at exit",
+            );
+            ap.refer(&mut c.ffmpeg).add_option(
+                &["--record-with-ffmpeg"],
+                argparse::StoreTrue,
+                "Record to mp4 through ffmpeg instead of GIF when Ctrl+F9 starts a recording",
+            );
+            ap.refer(&mut c.rewind).add_option(
+                &["--rewind"],
+                argparse::StoreTrue,
+                "Record the last 10 seconds of play, hold R to rewind",
+            );
+            ap.refer(&mut c.keys).add_option(
+                &["--keys"],
+                argparse::StoreOption,
+                "Read key bindings from a file, one <joypad key>=<keyboard key> per line",
+            );
+            ap.refer(&mut c.bind).add_option(
+                &["--bind"],
+                argparse::Collect,
+                "Bind a keyboard key to a joypad key, e.g. --bind a=K, can be repeated",
+            );
+            ap.refer(&mut c.keys2).add_option(
+                &["--keys2"],
+                argparse::StoreOption,
+                "Read the key bindings of the second player from a file",
+            );
+            ap.refer(&mut c.bind2).add_option(
+                &["--bind2"],
+                argparse::Collect,
+                "Bind a keyboard key to a joypad key of the second player, can be repeated",
+            );
+            ap.refer(&mut c.tilt).add_option(
+                &["--tilt"],
+                argparse::Store,
+                "Tilt in g of a tilt cartridge with the stick pushed fully or a direction held, 0.1 to 2, default is 1",
+            );
+            ap.refer(&mut c.sgb).add_option(
+                &["--sgb"],
+                argparse::StoreTrue,
+                "Answer the multiplayer requests of Super Game Boy games, for 2-player modes",
+            );
+            ap.refer(&mut c.record_movie).add_option(
+                &["--record-movie"],
+                argparse::StoreOption,
+                "Write the input of every frame to a movie file",
+            );
+            ap.refer(&mut c.play_movie).add_option(
+                &["--play-movie"],
+                argparse::StoreOption,
+                "Play the input of a movie file instead of the keyboard",
+            );
+            ap.refer(&mut c.paused).add_option(
+                &["--paused"],
+                argparse::StoreTrue,
+                "Start paused, press P to run or N to run a single frame",
+            );
+            ap.refer(&mut c.pause_on_suspend).add_option(
+                &["--pause-on-suspend"],
+                argparse::StoreTrue,
+                "Pause when the computer wakes up from sleep, e.g. after the lid of a laptop was closed",
+            );
+            ap.refer(&mut c.debug).add_option(
+                &["--debug"],
+                argparse::StoreTrue,
+                "Stop before the first instruction and read debugger commands from the terminal",
+            );
+            ap.refer(&mut c.trace).add_option(
+                &["--trace"],
+                argparse::StoreOption,
+                "Log the registers before every instruction to a file, in the format of Gameboy Doctor",
+            );
+            ap.refer(&mut c.ly_stub).add_option(
+                &["--ly-stub"],
+                argparse::StoreTrue,
+                "Read LY as 0x90 at all times, like the logs of Gameboy Doctor were made",
+            );
+            ap.refer(&mut c.disassemble).add_option(
+                &["--disassemble"],
+                argparse::StoreTrue,
+                "Print the disassembly of the first two rom banks and exit",
+            );
+            ap.refer(&mut c.info).add_option(
+                &["--info"],
+                argparse::StoreTrue,
+                "Print the header and the CRC32 and SHA-1 of the rom and exit",
+            );
+            ap.refer(&mut c.dat).add_option(
+                &["--dat"],
+                argparse::StoreOption,
+                "Check the rom against the known good dumps of a No-Intro DAT file",
+            );
+            ap.refer(&mut c.link_listen).add_option(
+                &["--link-listen"],
+                argparse::StoreOption,
+                "Wait for a link cable connection on the given address, e.g. 0.0.0.0:8765",
+            );
+            ap.refer(&mut c.link_connect).add_option(
+                &["--link-connect"],
+                argparse::StoreOption,
+                "Connect the link cable to the given address",
+            );
+            ap.refer(&mut c.link_latency).add_option(
+                &["--link-latency"],
+                argparse::Store,
+                "Frames a link cable transfer can wait for the network before the emulation blocks",
+            );
+            ap.refer(&mut c.serial).add_option(
+                &["--serial"],
+                argparse::StoreOption,
+                "Plug the link cable into stdout, stdin or both (stdio), to test link protocols from a script",
+            );
+            ap.refer(&mut c.serial_console).add_option(
+                &["--serial-console"],
+                argparse::StoreTrue,
+                "Print the bytes the game sends on the link cable to stdout, e.g. the debug output of test roms",
+            );
+            ap.refer(&mut c.headless).add_option(
+                &["--headless"],
+                argparse::StoreTrue,
+                "Run without a window, keyboard or speed limit, e.g. for tests",
+            );
+            ap.refer(&mut c.exit_frames).add_option(
+                &["--exit-after-frames"],
+                argparse::StoreOption,
+                "Exit after the given number of frames",
+            );
+            ap.refer(&mut c.exit_seconds).add_option(
+                &["--exit-after-seconds"],
+                argparse::StoreOption,
+                "Exit after the given seconds of emulated time",
+            );
+            ap.refer(&mut c.watchdog).add_option(
+                &["--watchdog"],
+                argparse::StoreOption,
+                "Exit with status 2 when the CPU stays at the same address for the given seconds",
+            );
+            ap.refer(&mut c.cpu_cache).add_option(
+                &["--cpu-cache"],
+                argparse::StoreTrue,
+                "Run the instructions of the rom from a cache of decoded blocks",
+            );
+            ap.refer(&mut c.script).add_option(
+                &["--script"],
+                argparse::StoreOption,
+                "Run a Rhai script after every frame, e.g. to show values of RAM over the game",
+            );
+            ap.refer(&mut c.watch).add_option(
+                &["--watch"],
+                argparse::Collect,
+                "Pause when the CPU writes to an address range, e.g. a000-bfff, or reads it with :r or :rw",
+            );
+            ap.refer(&mut c.rom).add_argument("rom", argparse::Store, "Rom name, or a directory to pick a rom from");
+            ap.parse_args_or_exit();
+        }
+        c.check();
+        c
+    }
+
+    // The values argparse can't check by their type.
+    fn check(&self) {
+        self.serial();
+        if ![1, 2, 4, 8].contains(&self.scale) {
+            fatal("Supported scale: 1, 2, 4 or 8");
+        }
+        if self.fast_forward == 0 {
+            fatal("Supported fast-forward speeds are 1 or more");
+        }
+        if !(0.1..=2.0).contains(&self.tilt) {
+            fatal("Supported tilt: 0.1 to 2");
+        }
+        if let (Some(revision), Some(model)) = (self.revision, self.model) {
+            if revision.model() != model {
+                fatal("Supported revisions: dmg0 or dmgb with --model dmg, cgbe with --model cgb");
+            }
+        }
+    }
+
+    // Whether the link cable writes to stdout and reads from stdin.
+    fn serial(&self) -> Option<(bool, bool)> {
+        self.serial.as_deref().map(|s| match s {
+            "stdout" => (true, false),
+            "stdin" => (false, true),
+            "stdio" => (true, true),
+            _ => fatal("Supported serial: stdout, stdin or stdio"),
+        })
+    }
+}
+
+// Report what the user asked for and can't be done, e.g. a file which can't be read, and exit with status 1.
+fn fatal(msg: impl std::fmt::Display) -> ! {
+    rog::println!("{}", msg);
+    std::process::exit(1);
+}
 
 fn main() {
     rog::reg("gameboy");
-    rog::reg("gameboy::cartridge");
 
-    let mut rom = String::from("");
-    let mut c_audio = false;
-    let mut c_scale = 2;
-    {
-        let mut ap = argparse::ArgumentParser::new();
-        ap.set_description("Gameboy emulator");
-        ap.refer(&mut c_audio).add_option(&["-a", "--enable-audio"], argparse::StoreTrue, "Enable audio");
-        ap.refer(&mut c_scale).add_option(
-            &["-x", "--scale-factor"],
-            argparse::Store,
-            "Scale the video by a factor of 1, 2, 4, or 8",
-        );
-        ap.refer(&mut rom).add_argument("rom", argparse::Store, "Rom name");
-        ap.parse_args_or_exit();
-    }
-
-    let mut mbrd = MotherBoard::power_up(rom);
-    let rom_name = mbrd.mmu.borrow().cartridge.title();
-
-    let mut option = minifb::WindowOptions::default();
-    option.resize = true;
-    option.scale = match c_scale {
-        1 => minifb::Scale::X1,
-        2 => minifb::Scale::X2,
-        4 => minifb::Scale::X4,
-        8 => minifb::Scale::X8,
-        _ => panic!("Supported scale: 1, 2, 4 or 8"),
+    let config = Config::parse();
+    let serial = config.serial();
+    // The log of the cartridge would mix with the bytes of the game.
+    if !serial.is_some_and(|(output, _)| output) && !config.serial_console {
+        rog::reg("gameboy::cartridge");
+    }
+    // Bindings from the command line come after the ones of the file, and win over them. The second player has no
+    // default keys, and the keyboard keys bound to it are taken from the first player.
+    let keymap2 = keymap(vec![], &bindings(&config.keys2, config.bind2));
+    let mut keymap = keymap(default_keymap(), &bindings(&config.keys, config.bind));
+    keymap.retain(|(rk, _)| !keymap2.iter().any(|(k, _)| k == rk));
+    let players = if config.sgb { 2 } else { 1 };
+    if !keymap2.is_empty() && players == 1 {
+        rog::println!("The keys of the second player are only read with --sgb");
+    }
+
+    let rom = if std::path::Path::new(&config.rom).is_dir() { pick(&config.rom) } else { config.rom.clone() };
+
+    let cart = if config.raw {
+        gameboy::cartridge::power_up_raw(&rom)
+    } else if config.mmap {
+        gameboy::cartridge::power_up_mapped(&rom)
+    } else {
+        gameboy::cartridge::power_up(&rom)
+    };
+    let cart = cart.unwrap_or_else(|e| fatal(format!("Rom not loaded: {}", e)));
+    // A revision without a model runs on its own model.
+    let model = config.model.or(config.revision.map(Revision::model)).unwrap_or_else(|| Mmunit::model(cart.as_ref()));
+    let mut mbrd = MotherBoard::power_up_model(cart, model);
+    let rom_name = mbrd.mmu.cartridge.title();
+    let slots = Slots::power_up(std::path::Path::new(&rom).parent().unwrap(), mbrd.mmu.cartridge.as_ref());
+    if config.info || config.dat.is_some() {
+        let mmu = &mbrd.mmu;
+        let digest = Digest::of(mmu.cartridge.rom());
+        let dump = config.dat.as_ref().map(|path| {
+            let entries = verify::load(path).unwrap_or_else(|e| fatal(format!("DAT not loaded: {}", e)));
+            match verify::find(&entries, &digest) {
+                Some(entry) => format!("good dump of {}", entry.name),
+                None => String::from("not in the DAT, the dump may be bad or modified"),
+            }
+        });
+        if config.info {
+            println!("Title    {}", rom_name);
+            println!("Type     {}", gameboy::cartridge::mbc_info(mmu.cartridge.rom()[0x0147]));
+            println!("CGB      {}", if mmu.term == Term::GBC { "yes" } else { "no" });
+            println!("Size     {}", digest.size);
+            println!("CRC32    {:08x}", digest.crc32);
+            println!("SHA-1    {}", digest.sha1_hex());
+            if let Some(dump) = &dump {
+                println!("Dump     {}", dump);
+            }
+            return;
+        }
+        rog::println!("Rom {}: {}", digest, dump.unwrap());
+    }
+    if config.disassemble {
+        let mmu = &mbrd.mmu;
+        let mut a: u32 = 0x0000;
+        while a < 0x8000 {
+            let i = gameboy::disasm::decode(mmu, a as u16);
+            println!("{}", i);
+            a += i.bytes.len() as u32;
+        }
+        return;
+    }
+    mbrd.mmu.gpu.overflow_tint = config.overflow;
+    mbrd.mmu.gpu.color_correction = config.color;
+    if let Some(rtc) = mbrd.mmu.cartridge.rtc() {
+        rtc.sync = config.rtc_sync;
+    }
+    if config.sgb || config.model == Some(Term::SGB) {
+        mbrd.mmu.joypad.sgb = Some(gameboy::sgb::Sgb::power_up());
+    }
+    // The palettes Ctrl+F6 cycles through: the built-in ones and the colors of the CGB, after the one of the palette
+    // file if there is one.
+    let mut palettes: Vec<(String, gameboy::palette::DmgPalette)> =
+        gameboy::palette::BUILTIN.iter().map(|(n, p)| (n.to_string(), *p)).collect();
+    palettes.push((String::from("cgb"), gameboy::palette::cgb(mbrd.mmu.cartridge.as_ref(), config.color)));
+    if let Some(path) = &config.palette {
+        let palette = gameboy::palette::load(path).unwrap_or_else(|e| fatal(format!("Palette not loaded: {}", e)));
+        palettes.insert(0, (path.clone(), palette));
+    }
+    let mut palette_index = 0;
+    // A DMG game on a CGB is in the colors of the CGB, unless told otherwise.
+    if config.palette.is_none() && model == Term::GBC {
+        palette_index = palettes.len() - 1;
+    }
+    if let Some(name) = &config.palette_name {
+        match palettes.iter().position(|(n, _)| *n == name.to_lowercase()) {
+            Some(i) => palette_index = i,
+            None => fatal("Supported palettes are gray, green, pocket, light, sepia or cgb"),
+        }
+    }
+    mbrd.mmu.gpu.dmg_palette = palettes[palette_index].1;
+    if let Some(path) = &config.trace {
+        match std::fs::File::create(path) {
+            Ok(f) => mbrd.trace(Box::new(std::io::BufWriter::new(f)), config.ly_stub),
+            Err(e) => fatal(format!("Trace not started: {}", e)),
+        }
+    }
+    if config.cpu_cache {
+        mbrd.cpu.cpu.cache = Some(BlockCache::power_up());
+    }
+    if config.debug {
+        Debugger::power_up().attach(&mut mbrd.cpu.cpu);
+    }
+    // The debugger stops at the hits itself.
+    let watching = !config.watch.is_empty() && !config.debug;
+    if config.stats {
+        mbrd.measure();
+    }
+    mbrd.cpu.cpu.watchpoints = config.watch;
+    if config.rewind {
+        // A snapshot every 15 frames, 40 of them make 10 seconds.
+        mbrd.rewind = Some(Rewind::power_up(15, 40));
+    }
+    let movie = match (&config.play_movie, &config.record_movie) {
+        (Some(path), _) => Some(Movie::play(path)),
+        (None, Some(path)) => Some(Movie::record(path)),
+        (None, None) => None,
     };
-    let mut window =
-        minifb::Window::new(format!("Gameboy - {}", rom_name).as_str(), SCREEN_W, SCREEN_H, option).unwrap();
-    let mut window_buffer = vec![0x00; SCREEN_W * SCREEN_H];
-    window.update_with_buffer(window_buffer.as_slice(), SCREEN_W, SCREEN_H).unwrap();
+    mbrd.movie = movie.map(|m| m.unwrap_or_else(|e| fatal(format!("Movie not opened: {}", e))));
+    if let Some(revision) = config.revision {
+        mbrd.set_revision(revision);
+    }
+    // After the revision, which sets DIV as the boot ROM leaves it.
+    if let Some(path) = &config.boot_rom {
+        let rom = std::fs::read(path).unwrap_or_else(|e| fatal(format!("Boot ROM not loaded: {}", e)));
+        mbrd.boot(rom).unwrap_or_else(|e| fatal(e));
+    }
+
+    let link = |r: std::io::Result<TcpLink>| r.unwrap_or_else(|e| fatal(format!("Link cable not connected: {}", e)));
+    if let Some(addr) = &config.link_listen {
+        mbrd.mmu.serial.link = Some(Box::new(link(TcpLink::listen(addr.as_str(), config.link_latency))));
+    } else if let Some(addr) = &config.link_connect {
+        mbrd.mmu.serial.link = Some(Box::new(link(TcpLink::connect(addr.as_str(), config.link_latency))));
+    } else if let Some((output, input)) = serial {
+        mbrd.mmu.serial.link = Some(Box::new(StdioLink::power_up(output, input)));
+    }
+    if config.serial_console {
+        mbrd.mmu.serial.console = Some(Box::new(std::io::stdout()));
+    }
+
+    // Without speed limit, audio would be generated way faster than it can be played. The APU still runs, but does
+    // not produce samples.
+    if let Some(ms) = config.limiter_step {
+        mbrd.cpu.step = (ms * f64::from(CLOCK_FREQUENCY) / 1000.0).max(1.0) as u32;
+    }
+    if config.no_throttle || config.headless {
+        mbrd.cpu.throttle = false;
+        mbrd.mmu.apu.output = false;
+    }
 
     // Initialize audio related. It is necessary to ensure that the stream object remains alive.
     let stream: cpal::Stream;
-    if c_audio {
+    if config.audio && !config.no_throttle && !config.headless {
         let host = cpal::default_host();
         let device = host.default_output_device().unwrap();
         rog::debugln!("Open the audio player: {}", device.name().unwrap());
-        let config = device.default_output_config().unwrap();
-        let sample_format = config.sample_format();
+        let output = device.default_output_config().unwrap();
+        let sample_format = output.sample_format();
         rog::debugln!("Sample format: {}", sample_format);
-        let config: cpal::StreamConfig = config.into();
-        rog::debugln!("Stream config: {:?}", config);
+        let output: cpal::StreamConfig = output.into();
+        rog::debugln!("Stream config: {:?}", output);
 
-        let apu = Apu::power_up(config.sample_rate.0);
+        let mut apu = Apu::power_up(output.sample_rate.0, &mut mbrd.mmu.clock);
+        apu.revision = mbrd.mmu.revision;
+        apu.rate_control = true;
         let apu_data = apu.buffer.clone();
-        mbrd.mmu.borrow_mut().apu = apu;
+        mbrd.mmu.apu = apu;
+        // Only one of the two streams below is built.
+        let mut scratch = vec![];
 
         stream = match sample_format {
             cpal::SampleFormat::F32 => device
                 .build_output_stream(
-                    &config,
+                    &output,
                     move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                        let len = std::cmp::min(data.len() / 2, apu_data.lock().unwrap().len());
-                        for (i, (data_l, data_r)) in apu_data.lock().unwrap().drain(..len).enumerate() {
-                            data[i * 2 + 0] = data_l;
+                        let samples = pop_samples(&apu_data, &mut scratch, data.len() / 2);
+                        for (i, &(data_l, data_r)) in samples.iter().enumerate() {
+                            data[i * 2] = data_l;
                             data[i * 2 + 1] = data_r;
                         }
                     },
@@ -76,11 +1194,11 @@ fn main() {
                 .unwrap(),
             cpal::SampleFormat::F64 => device
                 .build_output_stream(
-                    &config,
+                    &output,
                     move |data: &mut [f64], _: &cpal::OutputCallbackInfo| {
-                        let len = std::cmp::min(data.len() / 2, apu_data.lock().unwrap().len());
-                        for (i, (data_l, data_r)) in apu_data.lock().unwrap().drain(..len).enumerate() {
-                            data[i * 2 + 0] = data_l.to_sample::<f64>();
+                        let samples = pop_samples(&apu_data, &mut scratch, data.len() / 2);
+                        for (i, &(data_l, data_r)) in samples.iter().enumerate() {
+                            data[i * 2] = data_l.to_sample::<f64>();
                             data[i * 2 + 1] = data_r.to_sample::<f64>();
                         }
                     },
@@ -93,59 +1211,402 @@ fn main() {
         stream.play().unwrap();
     }
     let _ = stream;
+    mbrd.mmu.apu.stretch.mode = config.fast_audio;
+    if let Some(path) = &config.wav {
+        let mmu = &mut mbrd.mmu;
+        let sample_rate = mmu.apu.sample_rate();
+        let wav = WavWriter::create(path, sample_rate).unwrap_or_else(|e| fatal(format!("Sound not recorded: {}", e)));
+        mmu.apu.sinks.push(Box::new(wav));
+        // The file gets every sample, also when running faster than real time.
+        mmu.apu.output = true;
+    }
 
-    loop {
+    // Killing the emulator from the terminal should not lose the battery RAM: the loop is left as if the window was
+    // closed, and the save below still happens.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst)).unwrap();
+    }
+    let mut limits = Limits::power_up(config.exit_frames, config.exit_seconds, config.watchdog);
+    let mut script = config.script.as_ref().map(|path| match Script::load(path, &mut mbrd) {
+        Ok(ok) => ok,
+        Err(e) => fatal(format!("Script not loaded: {}", e)),
+    });
+
+    // Without a window, the game runs with the input of the movie if any, and none otherwise.
+    if config.headless {
+        let r = loop {
+            if interrupted.load(Ordering::Relaxed) {
+                rog::debugln!("Interrupted");
+                break None;
+            }
+            mbrd.next();
+            if watching {
+                if let Some(hit) = mbrd.cpu.cpu.watch_hit.take() {
+                    rog::println!("Watchpoint: {}", hit);
+                }
+            }
+            let frame = mbrd.check_and_reset_gpu_updated();
+            if frame {
+                run_script(&mut script, &mut mbrd);
+            }
+            if let Some(r) = limits.check(&mbrd, frame) {
+                break Some(r);
+            }
+        };
+        shutdown(&mut mbrd);
+        exit(r);
+        return;
+    }
+
+    let option = minifb::WindowOptions {
+        resize: true,
+        scale: match config.scale {
+            1 => minifb::Scale::X1,
+            2 => minifb::Scale::X2,
+            4 => minifb::Scale::X4,
+            8 => minifb::Scale::X8,
+            _ => unreachable!("checked by Config::check"),
+        },
+        ..Default::default()
+    };
+    // The filter makes the picture larger, the window is scaled on top of that.
+    let (window_w, window_h) = (SCREEN_W * config.filter.factor(), SCREEN_H * config.filter.factor());
+    let title = format!("Gameboy - {}", rom_name);
+    let mut window = minifb::Window::new(&title, window_w, window_h, option).unwrap();
+    // In fullscreen, the window buffer is scaled by zoom into zoomed.
+    let mut is_fullscreen = false;
+    let mut zoom = 1;
+    let mut zoomed = vec![];
+    let mut screen = vec![0x00; SCREEN_W * SCREEN_H];
+    let mut window_buffer = vec![0x00; window_w * window_h];
+    let mut blend = if config.blend { Some(Blend::power_up()) } else { None };
+    let mut views = [
+        View::new("Gameboy - VRAM tiles", minifb::Key::F2, Gpu::tiles),
+        View::new("Gameboy - Tile maps", minifb::Key::F3, Gpu::tile_maps),
+    ];
+    let mut memory_view = MemoryView::power_up();
+    present(&mut window, &window_buffer, window_w, window_h, zoom, &mut zoomed);
+
+    // Whether a frame was shown since the last CPU step.
+    let mut framed = false;
+    // Keys released in the same frame they were pressed, released after the next frame.
+    let mut tapped: Vec<JoypadKey> = vec![];
+    // Whether a recording was started, to tell when the recorder stopped on an error.
+    let mut recording = false;
+    // Nothing runs while paused, not even the first instruction: breakpoints can be set before the boot sequence.
+    let mut paused = config.paused;
+    if paused {
+        rog::println!("Paused, press P to run or N to run a single frame");
+    }
+    // Gamepads are optional, the keyboard still works without them.
+    let mut gilrs = match gilrs::Gilrs::new() {
+        Ok(ok) => Some(ok),
+        Err(e) => {
+            rog::debugln!("Gamepads not available: {}", e);
+            None
+        }
+    };
+    // The keys held on the gamepads.
+    let mut pad: u8 = 0x00;
+    let mut tilt = Tilt::power_up(config.tilt);
+    // Rumble carts shake the gamepads, if they can.
+    let mut rumble = match gilrs.as_mut() {
+        Some(g) if mbrd.mmu.cartridge.rumble().is_some() => Rumble::power_up(g),
+        _ => None,
+    };
+    // Whether a movie is played, the keyboard is then ignored.
+    let mut playing = config.play_movie.is_some();
+
+    let r = loop {
         // Stop the program, if the GUI is closed by the user
         if !window.is_open() {
-            break;
+            break None;
+        }
+        if interrupted.load(Ordering::Relaxed) {
+            rog::debugln!("Interrupted");
+            break None;
+        }
+        if paused {
+            if let Some(r) = rumble.as_mut() {
+                r.set(false);
+            }
+            // Keep the window alive at about 60 frames per second.
+            std::thread::sleep(std::time::Duration::from_millis(16));
+            window.update();
+            // Memory can be poked while paused, and is seen by the next frame.
+            if memory_view.update(&mut mbrd.mmu) {
+                mbrd.cpu.cpu.flush();
+            }
+            if window.is_key_down(minifb::Key::Escape) {
+                break None;
+            }
+            if window.is_key_pressed(minifb::Key::P, minifb::KeyRepeat::No) {
+                paused = false;
+                // The keyboard and gamepads take over the keys set for frame advance.
+                if !playing {
+                    pad = gilrs.as_mut().map_or([0x00; 2], |g| gamepad_keys(g, players))[0];
+                    mbrd.mmu.joypad.set_state(keyboard_keys(&window, &keymap) | pad);
+                }
+                continue;
+            }
+            // Frame advance: a key press toggles the key for the next frames, and N runs a single frame with the keys
+            // set this way. The keys reach the movie being recorded like any other input.
+            let mut changed = false;
+            if !playing {
+                for rk in window.get_keys_pressed(minifb::KeyRepeat::No) {
+                    if let Some(vk) = joypad_key(&keymap, rk) {
+                        let mmu = &mut mbrd.mmu;
+                        if mmu.joypad.is_pressed(vk.clone()) {
+                            mmu.joypad.keyup(vk);
+                        } else {
+                            mmu.joypad.keydown(vk);
+                        }
+                        changed = true;
+                    }
+                }
+            }
+            // Holding N steps at the key repeat rate.
+            if window.is_key_pressed(minifb::Key::N, minifb::KeyRepeat::Yes) {
+                mbrd.run_to_next_vblank();
+                run_script(&mut script, &mut mbrd);
+                changed = true;
+            }
+            if changed {
+                draw(&mbrd, &mut screen, config.input, &script, &mut blend, config.filter, &mut window_buffer);
+                present(&mut window, &window_buffer, window_w, window_h, zoom, &mut zoomed);
+                for view in views.iter_mut() {
+                    view.update(&mbrd.mmu.gpu);
+                }
+            }
+            continue;
         }
 
         // Execute an instruction
         mbrd.next();
+        if watching {
+            if let Some(hit) = mbrd.cpu.cpu.watch_hit.take() {
+                rog::println!("Watchpoint: {}", hit);
+                rog::println!("Paused, press P to run or N to run a single frame");
+                paused = true;
+            }
+        }
+        if let Some(d) = mbrd.cpu.suspended() {
+            rog::println!("Resumed after {} seconds of sleep", d.as_secs());
+            if config.pause_on_suspend {
+                rog::println!("Paused, press P to run or N to run a single frame");
+                paused = true;
+                continue;
+            }
+        }
 
         // Update the window
-        if mbrd.check_and_reset_gpu_updated() {
-            let mut i: usize = 0;
-            for l in mbrd.mmu.borrow().gpu.data.iter() {
-                for w in l.iter() {
-                    let b = u32::from(w[0]) << 16;
-                    let g = u32::from(w[1]) << 8;
-                    let r = u32::from(w[2]);
-                    let a = 0xff00_0000;
-
-                    window_buffer[i] = a | b | g | r;
-                    i += 1;
-                }
+        let frame = mbrd.check_and_reset_gpu_updated();
+        if let Some(r) = limits.check(&mbrd, frame) {
+            break Some(r);
+        }
+        if frame {
+            run_script(&mut script, &mut mbrd);
+            draw(&mbrd, &mut screen, config.input, &script, &mut blend, config.filter, &mut window_buffer);
+            present(&mut window, &window_buffer, window_w, window_h, zoom, &mut zoomed);
+            for view in views.iter_mut() {
+                view.update(&mbrd.mmu.gpu);
             }
-            window.update_with_buffer(window_buffer.as_slice(), SCREEN_W, SCREEN_H).unwrap();
+            if memory_view.update(&mut mbrd.mmu) {
+                mbrd.cpu.cpu.flush();
+            }
+            framed = true;
         }
 
-        if !mbrd.cpu.flip() {
+        // Input is read right after each frame is shown, so that it reaches the game before the next frame is
+        // emulated. While the LCD is off there are no frames, the window is then updated at the CPU step rate.
+        if mbrd.cpu.flip() {
+            if !framed {
+                window.update();
+                if memory_view.update(&mut mbrd.mmu) {
+                    mbrd.cpu.cpu.flush();
+                }
+            } else {
+                framed = false;
+                if !frame {
+                    continue;
+                }
+            }
+        } else if !frame {
             continue;
         }
 
         // Handling keyboard events
         if window.is_key_down(minifb::Key::Escape) {
-            break;
-        }
-        let keys = vec![
-            (minifb::Key::Right, gameboy::joypad::JoypadKey::Right),
-            (minifb::Key::Up, gameboy::joypad::JoypadKey::Up),
-            (minifb::Key::Left, gameboy::joypad::JoypadKey::Left),
-            (minifb::Key::Down, gameboy::joypad::JoypadKey::Down),
-            (minifb::Key::Z, gameboy::joypad::JoypadKey::A),
-            (minifb::Key::X, gameboy::joypad::JoypadKey::B),
-            (minifb::Key::Space, gameboy::joypad::JoypadKey::Select),
-            (minifb::Key::Enter, gameboy::joypad::JoypadKey::Start),
-        ];
-        for (rk, vk) in &keys {
-            if window.is_key_down(*rk) {
-                mbrd.mmu.borrow_mut().joypad.keydown(vk.clone());
+            break None;
+        }
+        if window.is_key_pressed(minifb::Key::P, minifb::KeyRepeat::No) {
+            rog::println!("Paused, press P to run or N to run a single frame");
+            paused = true;
+        }
+        // F1 to F10 load the state of their slot, with Shift they save it. The tools are on the same keys with Ctrl.
+        let shift = window.is_key_down(minifb::Key::LeftShift) || window.is_key_down(minifb::Key::RightShift);
+        let ctrl = window.is_key_down(minifb::Key::LeftCtrl) || window.is_key_down(minifb::Key::RightCtrl);
+        for (n, key) in SLOT_KEYS.into_iter().enumerate().map(|(i, k)| (i + 1, k)) {
+            if ctrl || !window.is_key_pressed(key, minifb::KeyRepeat::No) {
+                continue;
+            }
+            if shift {
+                match slots.save(n, &mbrd) {
+                    Ok(path) => rog::println!("State saved to slot {} {:?}", n, path),
+                    Err(e) => rog::println!("State not saved: {}", e),
+                }
             } else {
-                mbrd.mmu.borrow_mut().joypad.keyup(vk.clone());
+                match slots.load(n, &mut mbrd) {
+                    Ok(path) => rog::println!("State loaded from slot {} {:?}", n, path),
+                    Err(e) => rog::println!("State not loaded: {}", e),
+                }
             }
         }
-    }
+        if ctrl && window.is_key_pressed(minifb::Key::F6, minifb::KeyRepeat::No) {
+            palette_index = (palette_index + 1) % palettes.len();
+            mbrd.mmu.gpu.dmg_palette = palettes[palette_index].1;
+            rog::println!("Palette {}", palettes[palette_index].0);
+        }
+        let alt = window.is_key_down(minifb::Key::LeftAlt) || window.is_key_down(minifb::Key::RightAlt);
+        if alt && window.is_key_pressed(minifb::Key::Enter, minifb::KeyRepeat::No) {
+            if is_fullscreen {
+                window = minifb::Window::new(&title, window_w, window_h, option).unwrap();
+                is_fullscreen = false;
+                zoom = 1;
+            } else {
+                match fullscreen(&title, window_w, window_h) {
+                    Some((w, z)) => {
+                        window = w;
+                        is_fullscreen = true;
+                        zoom = z;
+                    }
+                    None => rog::println!("Fullscreen is not available"),
+                }
+            }
+            present(&mut window, &window_buffer, window_w, window_h, zoom, &mut zoomed);
+        }
+        let channels = [
+            (minifb::Key::Key1, "Square 1"),
+            (minifb::Key::Key2, "Square 2"),
+            (minifb::Key::Key3, "Wave"),
+            (minifb::Key::Key4, "Noise"),
+        ];
+        for (n, (key, name)) in channels.into_iter().enumerate() {
+            if window.is_key_pressed(key, minifb::KeyRepeat::No) {
+                let muted = &mut mbrd.mmu.apu.muted[n];
+                *muted = !*muted;
+                rog::println!("{} {}", name, if *muted { "muted" } else { "unmuted" });
+            }
+        }
+        for view in views.iter_mut() {
+            if ctrl && window.is_key_pressed(view.key, minifb::KeyRepeat::No) {
+                view.toggle(&mbrd.mmu.gpu);
+            }
+        }
+        if ctrl && window.is_key_pressed(minifb::Key::F4, minifb::KeyRepeat::No) {
+            memory_view.toggle(&mut mbrd.mmu);
+        }
+        if window.is_key_pressed(minifb::Key::F12, minifb::KeyRepeat::No) {
+            let path = free_path(&rom, "png");
+            match mbrd.screenshot(&path) {
+                Ok(()) => rog::println!("Screenshot saved to {:?}", path),
+                Err(e) => rog::println!("Screenshot not saved: {}", e),
+            }
+        }
+        if ctrl && window.is_key_pressed(minifb::Key::F9, minifb::KeyRepeat::No) {
+            match mbrd.recorder.take() {
+                Some(r) => {
+                    recording = false;
+                    match r.finish() {
+                        Ok(()) => rog::println!("Recording saved"),
+                        Err(e) => rog::println!("Recording not saved: {}", e),
+                    }
+                }
+                None => {
+                    let path = free_path(&rom, if config.ffmpeg { "mp4" } else { "gif" });
+                    let r = if config.ffmpeg { Recorder::ffmpeg(&path) } else { Recorder::gif(&path) };
+                    match r {
+                        Ok(r) => {
+                            rog::println!("Recording to {:?}, press Ctrl+F9 to stop", path);
+                            mbrd.recorder = Some(r);
+                            recording = true;
+                        }
+                        Err(e) => rog::println!("Recording not started: {}", e),
+                    }
+                }
+            }
+        }
+        if recording && mbrd.recorder.is_none() {
+            rog::println!("Recording stopped");
+            recording = false;
+        }
+        if playing && mbrd.movie.is_none() {
+            rog::println!("Movie finished");
+            playing = false;
+        }
+        if window.is_key_down(minifb::Key::R) {
+            if let Err(e) = mbrd.rewind() {
+                rog::println!("Rewind failed, the history is cleared: {}", e);
+            }
+        }
+        let rate = if window.is_key_down(minifb::Key::Tab) { config.fast_forward } else { 1 };
+        if rate != mbrd.cpu.rate {
+            mbrd.set_rate(rate);
+        }
+        if playing {
+            continue;
+        }
+        // Keys are applied as press and release events. A key pressed and released between two frames is held for
+        // one frame, or the game would never see the tap.
+        // A key held on the gamepad stays pressed when it is released on the keyboard, and the other way around.
+        for vk in tapped.drain(..) {
+            if pad & vk.clone() as u8 == 0x00 {
+                mbrd.mmu.joypad.keyup(vk);
+            }
+        }
+        let pressed = window.get_keys_pressed(minifb::KeyRepeat::No);
+        for rk in &pressed {
+            if let Some(vk) = joypad_key(&keymap, *rk) {
+                mbrd.mmu.joypad.keydown(vk);
+            }
+        }
+        for rk in window.get_keys_released() {
+            if let Some(vk) = joypad_key(&keymap, rk) {
+                if pressed.contains(&rk) {
+                    tapped.push(vk);
+                } else if pad & vk.clone() as u8 == 0x00 {
+                    mbrd.mmu.joypad.keyup(vk);
+                }
+            }
+        }
+        let pads = gilrs.as_mut().map_or([0x00; 2], |g| gamepad_keys(g, players));
+        if gilrs.is_some() {
+            let keys = pads[0];
+            let held = tapped.iter().fold(keyboard_keys(&window, &keymap), |k, vk| k | vk.clone() as u8);
+            let mut joypad = mbrd.mmu.joypad.state();
+            joypad |= keys & !pad;
+            joypad &= !(pad & !keys & !held);
+            mbrd.mmu.joypad.set_state(joypad);
+            pad = keys;
+        }
+        // The second player is only read at frame rate, its taps may be missed.
+        if players > 1 {
+            mbrd.mmu.joypad.set_player_state(1, keyboard_keys(&window, &keymap2) | pads[1]);
+        }
+        // Tilt cartridges take the directions held as the tilt of the console, or the left stick for finer control.
+        let mmu = &mut mbrd.mmu;
+        let keys = mmu.joypad.state();
+        if let Some(acc) = mmu.cartridge.accelerometer() {
+            let (x, y) = tilt.update(keys, gilrs.as_ref().and_then(gamepad_stick));
+            acc.set(x, y);
+        }
+        if let Some(r) = rumble.as_mut() {
+            r.set(mbrd.mmu.cartridge.rumble() == Some(true));
+        }
+    };
 
-    mbrd.mmu.borrow_mut().cartridge.sav();
+    shutdown(&mut mbrd);
+    exit(r);
 }
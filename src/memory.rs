@@ -18,12 +18,19 @@ pub trait Memory {
 
     fn set(&mut self, a: u16, v: u8);
 
+    // The bank of the rom behind the 16KB area of a, 0000-3FFF or 4000-7FFF, for the caches of what the CPU reads
+    // there, see cpu::BlockCache. None when the area doesn't read as plain rom, e.g. while the boot ROM is mapped over
+    // it, and for a bus without a cartridge.
+    fn rom_bank_at(&self, _a: u16) -> Option<usize> {
+        None
+    }
+
     fn get_word(&self, a: u16) -> u16 {
-        u16::from(self.get(a)) | (u16::from(self.get(a + 1)) << 8)
+        u16::from(self.get(a)) | (u16::from(self.get(a.wrapping_add(1))) << 8)
     }
 
     fn set_word(&mut self, a: u16, v: u16) {
         self.set(a, (v & 0xFF) as u8);
-        self.set(a + 1, (v >> 8) as u8)
+        self.set(a.wrapping_add(1), (v >> 8) as u8)
     }
 }
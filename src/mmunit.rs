@@ -3,16 +3,34 @@
 // to physical addresses.
 use super::apu::Apu;
 use super::cartridge::{self, Cartridge};
-use super::convention::Term;
+use super::clock::Scheduler;
+use super::convention::{Revision, Term};
+use super::error;
 use super::gpu::{Gpu, Hdma, HdmaMode};
+use super::infrared::Infrared;
 use super::intf::Intf;
 use super::joypad::Joypad;
 use super::memory::Memory;
+use super::palette;
 use super::serial::Serial;
+use super::state::{Reader, Snapshot, Writer};
+use super::stats::Stats;
 use super::timer::Timer;
-use std::cell::RefCell;
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
 use std::path::Path;
-use std::rc::Rc;
+
+// A region of the address space and where it is routed, for tools that label addresses such as debuggers and memory
+// viewers.
+pub struct Region {
+    pub name: &'static str,
+    pub start: u16,
+    // Inclusive.
+    pub end: u16,
+    pub component: &'static str,
+    // The bank currently mapped, for the regions that are banked.
+    pub bank: Option<usize>,
+}
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub enum Speed {
@@ -20,48 +38,121 @@ pub enum Speed {
     Double = 0x02,
 }
 
+// OAM DMA, started by a write to FF46, copies 160 bytes from XX00-XX9F to OAM, one byte per M-cycle after a delay of
+// one M-cycle. Meanwhile the DMA owns the bus: the CPU reads FFh and its writes are lost, everywhere but in the
+// registers and HRAM at FF00-FFFF. Games copy the sprites from a routine in HRAM that waits for the end of the
+// transfer. Sources E000-FFFF read the WRAM echoed at C000-DFFF.
+//
+// See: https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+struct OamDma {
+    active: bool,
+    // The write to FF46 happens on the last M-cycle of its instruction, the transfer starts after it.
+    start: bool,
+    delay: u8,
+    src: u16,
+    // Bytes copied so far.
+    n: u16,
+}
+
+impl OamDma {
+    fn power_up() -> Self {
+        Self { active: false, start: false, delay: 0, src: 0x0000, n: 0 }
+    }
+}
+
+// Called after a write to an address of its range, see MotherBoard::on_mem_write.
+pub type WriteHook = Box<dyn FnMut(u16, u8) + Send>;
+
 pub struct Mmunit {
     pub cartridge: Box<dyn Cartridge>,
+    // The tick source of the timer, the serial port, the GPU and the APU.
+    pub clock: Scheduler,
     pub apu: Apu,
     pub gpu: Gpu,
+    pub infrared: Infrared,
     pub joypad: Joypad,
+    pub revision: Revision,
     pub serial: Serial,
     pub shift: bool,
     pub speed: Speed,
+    // The performance statistics, once MotherBoard::measure was called.
+    pub stats: Option<Stats>,
     pub term: Term,
     pub timer: Timer,
+    // The hooks of MotherBoard::on_mem_write, with their ranges.
+    pub write_hooks: Vec<(RangeInclusive<u16>, WriteHook)>,
     inte: u8,
-    intf: Rc<RefCell<Intf>>,
+    // The interrupts requested by the CPU itself when it writes IF. Each component keeps those it raised, IF reads
+    // them all at once and a write to IF overrides them all.
+    intf: Intf,
     hdma: Hdma,
+    dma: OamDma,
     hram: [u8; 0x7f],
     wram: [u8; 0x8000],
     wram_bank: usize,
+    // The boot ROM, if the machine starts from it, and whether it is still mapped over the cartridge.
+    boot_rom: Option<Vec<u8>>,
+    boot: bool,
 }
 
 impl Mmunit {
-    pub fn power_up(path: impl AsRef<Path>) -> Self {
-        let cart = cartridge::power_up(path);
-        let term = match cart.get(0x0143) & 0x80 {
+    pub fn power_up(path: impl AsRef<Path>) -> error::Result<Self> {
+        Ok(Self::power_up_cartridge(cartridge::power_up(path)?))
+    }
+
+    pub fn power_up_cartridge(cart: Box<dyn Cartridge>) -> Self {
+        let model = Self::model(cart.as_ref());
+        Self::power_up_model(cart, model)
+    }
+
+    // The model a game runs on unless told otherwise: a CGB if it supports it, a DMG else.
+    pub fn model(cart: &dyn Cartridge) -> Term {
+        match cart.get(0x0143) & 0x80 {
             0x80 => Term::GBC,
             _ => Term::GB,
+        }
+    }
+
+    // Run the game on a given model, e.g. to see a game made for several models on each of them. A CGB runs a DMG game
+    // in its compatibility mode: the game is shown as on a DMG, in the colors the boot ROM picks, see palette::cgb.
+    // The other models run a CGB game as a DMG game, which usually tells the player it needs a CGB.
+    pub fn power_up_model(cart: Box<dyn Cartridge>, model: Term) -> Self {
+        let term = match model {
+            Term::GBC if Self::model(cart.as_ref()) != Term::GBC => Term::GB,
+            _ => model,
         };
-        let intf = Rc::new(RefCell::new(Intf::power_up()));
+        let mut clock = Scheduler::power_up();
+        let apu = Apu::power_up(48000, &mut clock);
+        let gpu = Gpu::power_up(term, &mut clock);
+        let serial = Serial::power_up(&mut clock);
+        let timer = Timer::power_up(&mut clock);
         let mut r = Self {
             cartridge: cart,
-            apu: Apu::power_up(48000),
-            gpu: Gpu::power_up(term, intf.clone()),
-            joypad: Joypad::power_up(intf.clone()),
-            serial: Serial::power_up(intf.clone()),
+            clock,
+            apu,
+            gpu,
+            infrared: Infrared::power_up(),
+            joypad: Joypad::power_up(),
+            revision: match model {
+                Term::GBC => Revision::CgbE,
+                _ => Revision::DmgB,
+            },
+            serial,
             shift: false,
             speed: Speed::Normal,
+            stats: None,
             term,
-            timer: Timer::power_up(intf.clone()),
+            timer,
+            write_hooks: vec![],
             inte: 0x00,
-            intf: intf.clone(),
+            intf: Intf::power_up(),
             hdma: Hdma::power_up(),
+            dma: OamDma::power_up(),
             hram: [0x00; 0x7f],
             wram: [0x00; 0x8000],
             wram_bank: 0x01,
+            boot_rom: None,
+            boot: false,
         };
         r.set(0xff05, 0x00);
         r.set(0xff06, 0x00);
@@ -69,7 +160,8 @@ impl Mmunit {
         r.set(0xff10, 0x80);
         r.set(0xff11, 0xbf);
         r.set(0xff12, 0xf3);
-        r.set(0xff14, 0xbf);
+        // The chime of the boot ROM leaves the first channel playing, but on the SGB which has no chime.
+        r.set(0xff14, if term == Term::SGB { 0x3f } else { 0xbf });
         r.set(0xff16, 0x3f);
         r.set(0xff16, 0x3f);
         r.set(0xff17, 0x00);
@@ -94,22 +186,169 @@ impl Mmunit {
         r.set(0xff49, 0xff);
         r.set(0xff4a, 0x00);
         r.set(0xff4b, 0x00);
+        // DMA reads the source of the last transfer: FFh after the DMG boot ROMs, 00h after the CGB one.
+        if model != Term::GBC {
+            r.dma.src = 0xff00;
+        }
+        r.set_revision(r.revision);
+        // The colors of the compatibility mode, through the default color correction.
+        if model == Term::GBC && term != Term::GBC {
+            r.gpu.dmg_palette = palette::cgb(r.cartridge.as_ref(), r.gpu.color_correction);
+        }
         r
     }
 }
 
 impl Mmunit {
+    // Map a boot ROM over the cartridge until the game writes to BANK (FF50), as the real hardware does at power on.
+    // The DMG boot ROM is 256 bytes mapped at 0000-00FF. The CGB one is 2304 bytes: its first 256 bytes are mapped at
+    // 0000-00FF too, the rest at 0200-08FF, and the cartridge header stays visible in between.
+    //
+    // The registers are put back as they are at power on and left for the boot ROM to set up: the LCD and the sound
+    // are off, DIV counts from zero.
+    pub fn load_boot_rom(&mut self, rom: Vec<u8>) -> error::Result<()> {
+        match (rom.len(), self.term) {
+            (0x0100, Term::GBC) => return Err(error::Error::BootRom(String::from("a CGB game needs a CGB boot ROM"))),
+            (0x0100, _) | (0x0900, _) => {}
+            (n, _) => return Err(error::Error::BootRom(format!("256 or 2304 bytes expected, not {}", n))),
+        }
+        self.boot_rom = Some(rom);
+        self.boot = true;
+        self.set(0xff26, 0x00);
+        self.set(0xff40, 0x00);
+        self.set(0xff47, 0x00);
+        self.timer.set_div(&mut self.clock, 0x00);
+        Ok(())
+    }
+
+    fn get_intf(&self) -> u8 {
+        self.intf.data | self.gpu.intf.data | self.timer.intf.data | self.serial.intf.data | self.joypad.intf.data
+    }
+
+    fn set_intf(&mut self, v: u8) {
+        self.intf.data = v;
+        self.gpu.intf.data = 0x00;
+        self.timer.intf.data = 0x00;
+        self.serial.intf.data = 0x00;
+        self.joypad.intf.data = 0x00;
+    }
+
+    // Whether the boot ROM is still running.
+    pub fn booting(&self) -> bool {
+        self.boot
+    }
+
+    fn boot_rom_get(&self, a: u16) -> Option<u8> {
+        let rom = self.boot_rom.as_ref().filter(|_| self.boot)?;
+        match a {
+            0x0000..=0x00ff | 0x0200..=0x08ff => rom.get(a as usize).copied(),
+            _ => None,
+        }
+    }
+
     pub fn next(&mut self, cycles: u32) -> u32 {
+        self.run_oam_dma(cycles / 4);
         let cpu_divider = self.speed as u32;
         let vram_cycles = self.run_dma();
         let gpu_cycles = cycles / cpu_divider + vram_cycles;
-        let cpu_cycles = cycles + vram_cycles * cpu_divider;
-        self.timer.next(cpu_cycles);
-        self.gpu.next(gpu_cycles);
-        self.apu.next(gpu_cycles);
+        self.clock.next(gpu_cycles, cpu_divider);
+        self.timer.next(&mut self.clock);
+        self.serial.next(&mut self.clock);
+        if let Some(s) = self.stats.as_mut() {
+            s.add(|f| &mut f.other);
+        }
+        self.gpu.next(&mut self.clock);
+        if let Some(s) = self.stats.as_mut() {
+            s.add(|f| &mut f.gpu);
+        }
+        self.apu.next(&mut self.clock);
+        if let Some(s) = self.stats.as_mut() {
+            s.add(|f| &mut f.apu);
+        }
+        if let Some(rtc) = self.cartridge.rtc() {
+            rtc.next(gpu_cycles);
+        }
         gpu_cycles
     }
 
+    // Time passes in STOP mode with the oscillator off: nothing is clocked but the real time clock of the cartridge,
+    // which has its own crystal.
+    pub fn stop(&mut self, cycles: u32) {
+        let cycles = cycles / self.speed as u32;
+        self.clock.now += u64::from(cycles);
+        if let Some(rtc) = self.cartridge.rtc() {
+            rtc.next(cycles);
+        }
+    }
+
+    // Emulate the quirks of a specific chip revision, one of the model the game runs on, see Revision::model. Call it
+    // right after power up, it resets DIV.
+    pub fn set_revision(&mut self, revision: Revision) {
+        self.revision = revision;
+        self.apu.revision = revision;
+        self.timer.set_div(&mut self.clock, revision.div());
+    }
+
+    // The memory map as routed by get and set, with the banks mapped right now. Addresses not listed read 0x00,
+    // except the registers of the CGB which read 0xff on the other models.
+    pub fn memory_map(&self) -> Vec<Region> {
+        let cgb = self.term == Term::GBC;
+        let region = |name, start, end, component, bank| Region { name, start, end, component, bank };
+        let mut regions = vec![];
+        if self.boot {
+            regions.push(region("BOOT", 0x0000, 0x00ff, "boot", None));
+            if self.boot_rom.as_ref().is_some_and(|r| r.len() == 0x0900) {
+                regions.push(region("BOOT", 0x0200, 0x08ff, "boot", None));
+            }
+        }
+        regions.extend([
+            region("ROM0", 0x0000, 0x3fff, "cartridge", None),
+            region("ROMX", 0x4000, 0x7fff, "cartridge", Some(self.cartridge.rom_bank())),
+            region("VRAM", 0x8000, 0x9fff, "gpu", cgb.then(|| usize::from(self.gpu.get(0xff4f) & 0x01))),
+            region("SRAM", 0xa000, 0xbfff, "cartridge", self.cartridge.ram_bank()),
+            region("WRAM0", 0xc000, 0xcfff, "wram", None),
+            region("WRAMX", 0xd000, 0xdfff, "wram", cgb.then_some(self.wram_bank)),
+            region("ECHO0", 0xe000, 0xefff, "wram", None),
+            region("ECHOX", 0xf000, 0xfdff, "wram", cgb.then_some(self.wram_bank)),
+            region("OAM", 0xfe00, 0xfe9f, "gpu", None),
+            region("UNUSED", 0xfea0, 0xfeff, "mmu", None),
+            region("JOYP", 0xff00, 0xff00, "joypad", None),
+            region("SERIAL", 0xff01, 0xff02, "serial", None),
+            region("TIMER", 0xff04, 0xff07, "timer", None),
+            region("IF", 0xff0f, 0xff0f, "intf", None),
+            region("APU", 0xff10, 0xff3f, "apu", None),
+            region("LCD", 0xff40, 0xff45, "gpu", None),
+            region("DMA", 0xff46, 0xff46, "mmu", None),
+            region("LCD", 0xff47, 0xff4b, "gpu", None),
+            region("KEY1", 0xff4d, 0xff4d, "mmu", None),
+            region("VBK", 0xff4f, 0xff4f, "gpu", None),
+            region("BANK", 0xff50, 0xff50, "mmu", None),
+            region("HDMA", 0xff51, 0xff55, "hdma", None),
+            region("RP", 0xff56, 0xff56, "infrared", None),
+            region("PALETTE", 0xff68, 0xff6b, "gpu", None),
+            region("SVBK", 0xff70, 0xff70, "mmu", None),
+            region("HRAM", 0xff80, 0xfffe, "hram", None),
+            region("IE", 0xffff, 0xffff, "mmu", None),
+        ]);
+        if !cgb {
+            regions.retain(|r| !matches!(r.name, "KEY1" | "VBK" | "HDMA" | "RP" | "PALETTE" | "SVBK"));
+        }
+        regions
+    }
+
+    // The work RAM with all its banks, and the bank mapped at D000-DFFF, e.g. for a MemorySnapshot.
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
+    }
+
+    pub fn wram_bank(&self) -> usize {
+        self.wram_bank
+    }
+
+    pub fn hram(&self) -> &[u8] {
+        &self.hram
+    }
+
     pub fn switch_speed(&mut self) {
         if self.shift {
             if self.speed == Speed::Double {
@@ -121,6 +360,33 @@ impl Mmunit {
         self.shift = false;
     }
 
+    // Advance the OAM DMA by the M-cycles of an instruction, at the speed of the CPU.
+    fn run_oam_dma(&mut self, m: u32) {
+        if !self.dma.active {
+            return;
+        }
+        if std::mem::take(&mut self.dma.start) {
+            return;
+        }
+        for _ in 0..m {
+            if self.dma.delay > 0 {
+                self.dma.delay -= 1;
+                continue;
+            }
+            let src = match self.dma.src + self.dma.n {
+                a @ 0xe000..=0xffff => a - 0x2000,
+                a => a,
+            };
+            let b = self.bus_get(src);
+            self.gpu.set(0xfe00 + self.dma.n, b);
+            self.dma.n += 1;
+            if self.dma.n == 0xa0 {
+                self.dma.active = false;
+                return;
+            }
+        }
+    }
+
     fn run_dma(&mut self) -> u32 {
         if !self.hdma.active {
             return 0;
@@ -150,11 +416,12 @@ impl Mmunit {
     fn run_dma_hrampart(&mut self) {
         let mmu_src = self.hdma.src;
         for i in 0..0x10 {
-            let b: u8 = self.get(mmu_src + i);
+            let b: u8 = self.bus_get(mmu_src.wrapping_add(i));
             self.gpu.set(self.hdma.dst + i, b);
         }
-        self.hdma.src += 0x10;
-        self.hdma.dst += 0x10;
+        // Both addresses wrap: the source around the address space, the destination around VRAM.
+        self.hdma.src = self.hdma.src.wrapping_add(0x10);
+        self.hdma.dst = 0x8000 | (self.hdma.dst + 0x10) & 0x1ff0;
         if self.hdma.remain == 0 {
             self.hdma.remain = 0x7f;
         } else {
@@ -163,8 +430,12 @@ impl Mmunit {
     }
 }
 
-impl Memory for Mmunit {
-    fn get(&self, a: u16) -> u8 {
+impl Mmunit {
+    // A read of the bus, whoever drives it.
+    fn bus_get(&self, a: u16) -> u8 {
+        if let Some(v) = self.boot_rom_get(a) {
+            return v;
+        }
         match a {
             0x0000..=0x7fff => self.cartridge.get(a),
             0x8000..=0x9fff => self.gpu.get(a),
@@ -178,15 +449,20 @@ impl Memory for Mmunit {
             0xff00 => self.joypad.get(a),
             0xff01..=0xff02 => self.serial.get(a),
             0xff04..=0xff07 => self.timer.get(a),
-            0xff0f => self.intf.borrow().data,
+            0xff0f => self.get_intf(),
+            // The registers of the CGB aren't there on the other models, nor in the compatibility mode of the CGB.
+            0xff4d | 0xff4f | 0xff51..=0xff56 | 0xff68..=0xff6b | 0xff70 if self.term != Term::GBC => 0xff,
             0xff10..=0xff3f => self.apu.get(a),
+            0xff46 => (self.dma.src >> 8) as u8,
+            0xff50 => 0xff,
             0xff4d => {
                 let a = if self.speed == Speed::Double { 0x80 } else { 0x00 };
                 let b = if self.shift { 0x01 } else { 0x00 };
-                a | b
+                0x7e | a | b
             }
             0xff40..=0xff45 | 0xff47..=0xff4b | 0xff4f => self.gpu.get(a),
             0xff51..=0xff55 => self.hdma.get(a),
+            0xff56 => self.infrared.get(a),
             0xff68..=0xff6b => self.gpu.get(a),
             0xff70 => self.wram_bank as u8,
             0xff80..=0xfffe => self.hram[a as usize - 0xff80],
@@ -194,8 +470,41 @@ impl Memory for Mmunit {
             _ => 0x00,
         }
     }
+}
+
+impl Memory for Mmunit {
+    fn get(&self, a: u16) -> u8 {
+        if self.dma.active && a < 0xff00 {
+            return 0xff;
+        }
+        self.bus_get(a)
+    }
+
+    fn rom_bank_at(&self, a: u16) -> Option<usize> {
+        // The CPU reads FFh while an OAM DMA runs, and the boot ROM is mapped over the low area.
+        if self.dma.active || (self.boot && a < 0x4000) {
+            return None;
+        }
+        // A rom of less than two banks, or of a part of a bank, reads as no bank.
+        let len = self.cartridge.rom().len();
+        if len < 0x8000 || !len.is_multiple_of(0x4000) {
+            return None;
+        }
+        let bank = match a {
+            0x0000..=0x3fff => self.cartridge.rom_bank_0(),
+            0x4000..=0x7fff => self.cartridge.rom_bank(),
+            _ => return None,
+        };
+        Some(bank % (len / 0x4000))
+    }
 
     fn set(&mut self, a: u16, v: u8) {
+        if self.dma.active && a < 0xff00 {
+            return;
+        }
+        if self.revision.oam_bug() && (0xfe00..=0xfeff).contains(&a) {
+            self.gpu.oam_bug_write();
+        }
         match a {
             0x0000..=0x7fff => self.cartridge.set(a, v),
             0x8000..=0x9fff => self.gpu.set(a, v),
@@ -208,24 +517,22 @@ impl Memory for Mmunit {
             0xfea0..=0xfeff => {}
             0xff00 => self.joypad.set(a, v),
             0xff01..=0xff02 => self.serial.set(a, v),
-            0xff04..=0xff07 => self.timer.set(a, v),
-            0xff10..=0xff3f => self.apu.set(a, v),
+            0xff04..=0xff07 => self.timer.set(&mut self.clock, a, v),
+            0xff4d | 0xff4f | 0xff51..=0xff56 | 0xff68..=0xff6b | 0xff70 if self.term != Term::GBC => {}
+            0xff10..=0xff3f => self.apu.set(&mut self.clock, a, v),
+            // Writing to this register launches a DMA transfer from ROM or RAM to OAM memory (sprite attribute table),
+            // see OamDma. A write during a transfer starts it again from the new source.
             0xff46 => {
-                // Writing to this register launches a DMA transfer from ROM or RAM to OAM memory (sprite attribute
-                // table).
-                // See: http://gbdev.gg8.se/wiki/articles/Video_Display#FF46_-_DMA_-_DMA_Transfer_and_Start_Address_.28R.2FW.29
-                assert!(v <= 0xf1);
-                let base = u16::from(v) << 8;
-                for i in 0..0xa0 {
-                    let b = self.get(base + i);
-                    self.set(0xfe00 + i, b);
-                }
+                self.dma = OamDma { active: true, start: true, delay: 1, src: u16::from(v) << 8, n: 0 };
             }
             0xff4d => self.shift = (v & 0x01) == 0x01,
+            // Unmap the boot ROM, for good. The boot ROM does it as its last instruction.
+            0xff50 if v != 0x00 => self.boot = false,
             0xff40..=0xff45 | 0xff47..=0xff4b | 0xff4f => self.gpu.set(a, v),
             0xff51..=0xff55 => self.hdma.set(a, v),
+            0xff56 => self.infrared.set(a, v),
             0xff68..=0xff6b => self.gpu.set(a, v),
-            0xff0f => self.intf.borrow_mut().data = v,
+            0xff0f => self.set_intf(v),
             0xff70 => {
                 self.wram_bank = match v & 0x7 {
                     0 => 1,
@@ -236,5 +543,62 @@ impl Memory for Mmunit {
             0xffff => self.inte = v,
             _ => {}
         }
+        if !self.write_hooks.is_empty() {
+            for (r, f) in self.write_hooks.iter_mut() {
+                if r.contains(&a) {
+                    f(a, v);
+                }
+            }
+        }
+    }
+}
+
+// Only the memory and registers owned by the MMU itself, each component has its own section in a save state.
+impl Snapshot for Mmunit {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&self.wram);
+        w.u8(self.wram_bank as u8);
+        w.bytes(&self.hram);
+        w.u8(self.inte);
+        w.u8(self.get_intf());
+        w.bool(self.shift);
+        w.bool(self.speed == Speed::Double);
+        self.hdma.save(w);
+        self.joypad.save(w);
+        self.infrared.save(w);
+        w.bool(self.boot);
+        w.bool(self.dma.active);
+        w.bool(self.dma.start);
+        w.u8(self.dma.delay);
+        w.u16(self.dma.src);
+        w.u16(self.dma.n);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        r.bytes(&mut self.wram)?;
+        self.wram_bank = usize::from(r.u8()?);
+        if !(1..=7).contains(&self.wram_bank) {
+            return Err(Error::new(ErrorKind::InvalidData, "State: work RAM bank out of range"));
+        }
+        r.bytes(&mut self.hram)?;
+        self.inte = r.u8()?;
+        self.set_intf(r.u8()?);
+        self.shift = r.bool()?;
+        self.speed = if r.bool()? { Speed::Double } else { Speed::Normal };
+        self.hdma.load(r)?;
+        self.joypad.load(r)?;
+        self.infrared.load(r)?;
+        // The boot ROM itself is not saved, a state saved while it runs needs it loaded again.
+        let boot = r.bool()?;
+        if boot && self.boot_rom.is_none() {
+            return Err(Error::new(ErrorKind::InvalidData, "State: saved while the boot ROM was running"));
+        }
+        self.boot = boot;
+        self.dma.active = r.bool()?;
+        self.dma.start = r.bool()?;
+        self.dma.delay = r.u8()?;
+        self.dma.src = r.u16()?;
+        self.dma.n = r.u16()?.min(0x9f);
+        Ok(())
     }
 }
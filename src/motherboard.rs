@@ -1,34 +1,475 @@
-use super::cpu::Rtc;
-use super::memory::Memory;
+use super::cartridge::{self, Cartridge};
+use super::convention::{Revision, Term};
+use super::cpu::{Cpu, Rtc};
+use super::error;
+use super::gpu::{Framebuffer, SCREEN_H, SCREEN_W};
+use super::link::FRAME_CYCLES;
 use super::mmunit::Mmunit;
-use std::cell::RefCell;
+use super::movie::Movie;
+use super::peek::MemorySnapshot;
+use super::recorder::Recorder;
+use super::register::Register;
+use super::state::{self, Reader, Snapshot, Writer};
+use super::stats::{FrameStats, Stats};
+use super::watch::{Hit, Watchpoint};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::ops::RangeInclusive;
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::Arc;
+
+// Rewind keeps the recent history of the machine as snapshots taken every few frames. Only the newest snapshot is
+// kept whole, each older one is stored as the difference to its successor: consecutive snapshots differ in a few KB
+// out of more than 100KB, so a few seconds of history take little memory.
+pub struct Rewind {
+    // Frames between two snapshots.
+    interval: u32,
+    frames: u32,
+    // Maximum number of snapshots kept, the oldest ones are dropped.
+    capacity: usize,
+    head: Option<Vec<u8>>,
+    // Older snapshots, newest first.
+    diffs: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn power_up(interval: u32, capacity: usize) -> Self {
+        Self { interval, frames: 0, capacity, head: None, diffs: VecDeque::new() }
+    }
+
+    fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(head) = self.head.take() {
+            self.diffs.push_front(Self::diff(&head, &snapshot));
+            self.diffs.truncate(self.capacity.saturating_sub(1));
+        }
+        self.head = Some(snapshot);
+    }
+
+    fn clear(&mut self) {
+        self.frames = 0;
+        self.head = None;
+        self.diffs.clear();
+    }
+
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        let head = self.head.take()?;
+        self.head = self.diffs.pop_front().map(|d| Self::patch(&head, &d));
+        Some(head)
+    }
+
+    // The xor of both snapshots, as a sequence of (zeros: u16, len: u16, [u8; len]). Snapshots of the same cartridge
+    // always have the same size.
+    fn diff(a: &[u8], b: &[u8]) -> Vec<u8> {
+        assert_eq!(a.len(), b.len());
+        let mut r = vec![];
+        let mut i = 0;
+        while i < a.len() {
+            let zero = i;
+            while i < a.len() && i - zero < 0xffff && a[i] == b[i] {
+                i += 1;
+            }
+            let data = i;
+            while i < a.len() && i - data < 0xffff && a[i] != b[i] {
+                i += 1;
+            }
+            r.extend_from_slice(&((data - zero) as u16).to_le_bytes());
+            r.extend_from_slice(&((i - data) as u16).to_le_bytes());
+            r.extend(a[data..i].iter().zip(&b[data..i]).map(|(x, y)| x ^ y));
+        }
+        r
+    }
+
+    fn patch(b: &[u8], d: &[u8]) -> Vec<u8> {
+        let mut a = b.to_vec();
+        let mut i = 0;
+        let mut j = 0;
+        while j < d.len() {
+            i += usize::from(u16::from_le_bytes([d[j], d[j + 1]]));
+            let n = usize::from(u16::from_le_bytes([d[j + 2], d[j + 3]]));
+            j += 4;
+            for k in 0..n {
+                a[i + k] ^= d[j + k];
+            }
+            i += n;
+            j += n;
+        }
+        a
+    }
+}
+
+// Called with every frame, see MotherBoard::on_frame.
+pub type FrameHook = Box<dyn FnMut(&Framebuffer) + Send>;
+
+// Called when the CPU hits a watchpoint, see MotherBoard::on_watch.
+pub type WatchHook = Box<dyn FnMut(&Hit) + Send>;
 
 pub struct MotherBoard {
-    pub mmu: Rc<RefCell<Mmunit>>,
+    // The bus, lent to the CPU for each instruction.
+    pub mmu: Mmunit,
     pub cpu: Rtc,
+    pub rewind: Option<Rewind>,
+    // Every frame is handed to the recorder, if any. Stop a recording with recorder.take() and Recorder::finish.
+    pub recorder: Option<Recorder>,
+    // The input of every frame is written to the movie, or read from it. A movie being played is dropped at its end.
+    pub movie: Option<Movie>,
+    // The hooks of on_frame and on_watch.
+    pub frame_hooks: Vec<FrameHook>,
+    pub watch_hooks: Vec<WatchHook>,
+    // The RAM at the end of the last frame, once publish_memory was called.
+    pub peek: Option<Arc<MemorySnapshot>>,
+    // Whether the next instruction starts a frame.
+    frame_start: bool,
 }
 
 impl MotherBoard {
-    pub fn power_up(path: impl AsRef<Path>) -> Self {
-        let mmu = Rc::new(RefCell::new(Mmunit::power_up(path)));
-        let cpu = Rtc::power_up(mmu.borrow().term, mmu.clone());
-        Self { mmu, cpu }
+    pub fn power_up(path: impl AsRef<Path>) -> error::Result<Self> {
+        Ok(Self::power_up_cartridge(cartridge::power_up(path)?))
+    }
+
+    pub fn power_up_cartridge(cart: Box<dyn Cartridge>) -> Self {
+        let model = Mmunit::model(cart.as_ref());
+        Self::power_up_model(cart, model)
     }
 
+    // Run the game on a given model instead of the one it asks for, see Mmunit::power_up_model. The registers are
+    // left as the boot ROM of the model leaves them.
+    pub fn power_up_model(cart: Box<dyn Cartridge>, model: Term) -> Self {
+        let mmu = Mmunit::power_up_model(cart, model);
+        let mut cpu = Rtc::power_up(mmu.term);
+        if model == Term::GBC && mmu.term != Term::GBC {
+            cpu.cpu.reg = Register::power_up_compat(mmu.cartridge.title_sum().unwrap_or(0x00));
+        }
+        Self {
+            mmu,
+            cpu,
+            rewind: None,
+            recorder: None,
+            movie: None,
+            frame_hooks: vec![],
+            watch_hooks: vec![],
+            peek: None,
+            frame_start: false,
+        }
+    }
+
+    // Emulate the quirks of a chip revision of the model the game runs on, see Mmunit::set_revision. Call it right
+    // after power up, the registers are left as the boot ROM of the revision leaves them.
+    pub fn set_revision(&mut self, revision: Revision) {
+        self.mmu.set_revision(revision);
+        if revision == Revision::Dmg0 {
+            self.cpu.cpu.reg = Register::power_up_dmg0();
+        }
+    }
+
+    // Start from a boot ROM at power on instead of at 0x0100 with the registers the boot ROM leaves, see
+    // Mmunit::load_boot_rom. The boot logo scrolls down and the game starts as on the real hardware.
+    pub fn boot(&mut self, rom: Vec<u8>) -> error::Result<()> {
+        self.mmu.load_boot_rom(rom)?;
+        self.cpu.cpu.reg = Register::power_up_cold();
+        self.cpu.cpu.ei = false;
+        Ok(())
+    }
+
+    // Execute one instruction. With cpu.throttle, which is on by default, this sleeps every few milliseconds to keep
+    // the speed of the real hardware. Hosts with their own event loop and pacing turn it off or use finish_frame.
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u32 {
-        if self.mmu.borrow().get(self.cpu.cpu.reg.pc) == 0x10 {
-            self.mmu.borrow_mut().switch_speed();
+        if self.frame_start {
+            self.frame_start = false;
+            self.movie_frame();
+        }
+        let speed = self.mmu.speed as u32;
+        let slept = self.cpu.slept;
+        if let Some(s) = self.mmu.stats.as_mut() {
+            s.lap();
+        }
+        let cycles = self.cpu.next(&mut self.mmu, speed);
+        if let Some(s) = self.mmu.stats.as_mut() {
+            let sleep = self.cpu.slept - slept;
+            let d = s.lap();
+            s.current.cpu += d.saturating_sub(sleep);
+            s.current.sleep += sleep;
+            s.current.instructions += 1;
+        }
+        if std::mem::take(&mut self.cpu.cpu.speed_switch) {
+            self.mmu.switch_speed();
+        }
+        if !self.watch_hooks.is_empty() {
+            if let Some(hit) = self.cpu.cpu.watch_hit.take() {
+                for f in self.watch_hooks.iter_mut() {
+                    f(&hit);
+                }
+            }
+        }
+        if self.cpu.cpu.stopped {
+            self.mmu.stop(cycles);
+        } else {
+            let gpu_cycles = self.mmu.next(cycles);
+            if let Some(s) = self.mmu.stats.as_mut() {
+                s.current.cycles += u64::from(gpu_cycles);
+            }
         }
-        let cycles = self.cpu.next();
-        self.mmu.borrow_mut().next(cycles);
         cycles
     }
 
+    // Hooks for tools built on the emulator, e.g. a tracker of the RAM of a game or a bot, without patching it. A hook
+    // which isn't registered costs nothing, the rest of the emulation runs as before.
+    //
+    // f gets every frame handed over by check_and_reset_gpu_updated, like the recorder.
+    pub fn on_frame(&mut self, f: impl FnMut(&Framebuffer) + Send + 'static) {
+        self.frame_hooks.push(Box::new(f));
+    }
+
+    // f gets the address and the value of every write to the range, through the bus, after it was done. The writes
+    // of the DMAs don't go through the bus.
+    pub fn on_mem_write(&mut self, range: RangeInclusive<u16>, f: impl FnMut(u16, u8) + Send + 'static) {
+        self.mmu.write_hooks.push((range, Box::new(f)));
+    }
+
+    // f gets the CPU before each instruction at the address is executed.
+    pub fn on_pc(&mut self, addr: u16, f: impl FnMut(&Cpu) + Send + 'static) {
+        self.cpu.cpu.pc_hooks.push((addr, Box::new(f)));
+    }
+
+    // Watch the accesses of the CPU to a range, see the watch module. f gets the hits of all the watchpoints, right
+    // after the instruction which did the access. Without a hook they are left in cpu.watch_hit for the caller.
+    pub fn on_watch(&mut self, w: Watchpoint, f: impl FnMut(&Hit) + Send + 'static) {
+        self.cpu.cpu.watchpoints.push(w);
+        self.watch_hooks.push(Box::new(f));
+    }
+
+    // Publish a copy of the RAM in peek at the end of every frame, see the peek module. The first one is taken now.
+    pub fn publish_memory(&mut self) {
+        let mut peek = MemorySnapshot::power_up();
+        peek.update(&self.mmu);
+        self.peek = Some(Arc::new(peek));
+    }
+
+    // Measure the performance of every frame from now on, see the stats module.
+    pub fn measure(&mut self) {
+        self.mmu.stats = Some(Stats::power_up());
+    }
+
+    // The performance statistics of the last frame handed over by check_and_reset_gpu_updated, once measure was
+    // called.
+    pub fn stats(&self) -> Option<FrameStats> {
+        self.mmu.stats.as_ref().and_then(|s| s.last)
+    }
+
+    // Run rate times faster than real time, 1 for the normal speed. The sound played is fitted to real time the way
+    // set in apu.stretch.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.cpu.rate = rate;
+        self.mmu.apu.stretch.rate = rate;
+    }
+
+    // Run until the next VBlank as fast as possible, for tools that need a complete frame right now: screenshots,
+    // save state thumbnails or the web frontend. The pacing sleep is skipped, the next one simply finds itself late
+    // and resynchronizes, so the real time speed is not disturbed. The frame is left pending for
+    // check_and_reset_gpu_updated, and a frame that is already pending returns immediately.
+    //
+    // While the LCD is off there is no VBlank, it then gives up after the length of a frame and returns false.
+    pub fn finish_frame(&mut self) -> bool {
+        let throttle = std::mem::replace(&mut self.cpu.throttle, false);
+        let limit = FRAME_CYCLES * self.mmu.speed as u32;
+        let mut cycles = 0;
+        while !self.mmu.gpu.v_blank && cycles < limit {
+            cycles += self.next();
+        }
+        self.cpu.throttle = throttle;
+        self.mmu.gpu.v_blank
+    }
+
+    // Run exactly one frame, for frame advance: a pending frame is handed over first as by
+    // check_and_reset_gpu_updated, then the emulation runs to the next VBlank, which is left pending. Returns false
+    // while the LCD is off, see finish_frame.
+    pub fn run_to_next_vblank(&mut self) -> bool {
+        self.check_and_reset_gpu_updated();
+        self.finish_frame()
+    }
+
+    // Serialize the whole machine. See the state module for the format.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mmu = &self.mmu;
+        let section = |f: &dyn Fn(&mut Writer)| {
+            let mut w = Writer::default();
+            f(&mut w);
+            w.data
+        };
+        state::encode(&[
+            (b"CPU ", section(&|w| self.cpu.save(w))),
+            (b"MMU ", section(&|w| mmu.save(w))),
+            (b"GPU ", section(&|w| mmu.gpu.save(w))),
+            (b"APU ", section(&|w| mmu.apu.save(w))),
+            (b"TIME", section(&|w| mmu.timer.save(w))),
+            (b"SERI", section(&|w| mmu.serial.save(w))),
+            (b"CLCK", section(&|w| mmu.clock.save(w))),
+            (
+                b"CART",
+                section(&|w| {
+                    // The checksums identify the game the state belongs to.
+                    w.u8(mmu.cartridge.get(0x014d));
+                    w.u8(mmu.cartridge.get(0x014e));
+                    w.u8(mmu.cartridge.get(0x014f));
+                    mmu.cartridge.save(w);
+                }),
+            ),
+        ])
+    }
+
+    // Restore a snapshot. If it turns out to be invalid, the machine is restored as it was from a snapshot taken
+    // before, and should that fail too its error is returned.
+    pub fn restore(&mut self, data: &[u8]) -> error::Result<()> {
+        let sections = state::decode(data).map_err(error::Error::State)?;
+        let backup = self.snapshot();
+        if let Err(e) = self.restore_sections(&sections) {
+            state::decode(&backup).and_then(|s| self.restore_sections(&s)).map_err(error::Error::State)?;
+            return Err(error::Error::State(e));
+        }
+        Ok(())
+    }
+
+    fn restore_sections(&mut self, sections: &[([u8; 4], &[u8])]) -> std::io::Result<()> {
+        if sections.len() != 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "State: missing sections"));
+        }
+        let mmu = &mut self.mmu;
+        for (tag, data) in sections {
+            let mut r = Reader::new(data);
+            match tag {
+                b"CPU " => self.cpu.load(&mut r)?,
+                b"MMU " => mmu.load(&mut r)?,
+                b"GPU " => mmu.gpu.load(&mut r)?,
+                b"APU " => mmu.apu.load(&mut r)?,
+                b"TIME" => mmu.timer.load(&mut r)?,
+                b"SERI" => mmu.serial.load(&mut r)?,
+                b"CLCK" => mmu.clock.load(&mut r)?,
+                b"CART" => {
+                    let sum = [r.u8()?, r.u8()?, r.u8()?];
+                    if sum != [mmu.cartridge.get(0x014d), mmu.cartridge.get(0x014e), mmu.cartridge.get(0x014f)] {
+                        return Err(Error::new(ErrorKind::InvalidData, "State: saved from another cartridge"));
+                    }
+                    mmu.cartridge.load(&mut r)?
+                }
+                _ => {
+                    let tag = String::from_utf8_lossy(tag);
+                    return Err(Error::new(ErrorKind::InvalidData, format!("State: unknown section {}", tag)));
+                }
+            }
+            r.finish()?;
+        }
+        Ok(())
+    }
+
+    pub fn save_state(&self, path: impl AsRef<Path>) -> error::Result<()> {
+        Ok(std::fs::write(path, self.snapshot())?)
+    }
+
+    // Write the screen as the GPU drew it to a PNG file, e.g. for bug reports or to compare against a reference.
+    pub fn screenshot(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let data: Vec<u8> = self.mmu.gpu.data.iter().flatten().flatten().copied().collect();
+        let f = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let mut encoder = png::Encoder::new(f, SCREEN_W as u32, SCREEN_H as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut w = encoder.write_header()?;
+        w.write_image_data(&data)?;
+        w.finish()?;
+        Ok(())
+    }
+
+    // Log every instruction executed from now on to w, in the format of Gameboy Doctor, see Cpu::trace. With ly_stub,
+    // LY reads 0x90 like in the logs of Gameboy Doctor. Wrap files in a BufWriter, there is a line per instruction.
+    pub fn trace(&mut self, w: Box<dyn std::io::Write + Send>, ly_stub: bool) {
+        self.cpu.cpu.trace = Some(w);
+        self.mmu.gpu.ly_stub = ly_stub;
+    }
+
+    // Stop the trace, and flush what is left of it.
+    pub fn finish_trace(&mut self) -> std::io::Result<()> {
+        match self.cpu.cpu.trace.take() {
+            Some(mut w) => w.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn load_state(&mut self, path: impl AsRef<Path>) -> error::Result<()> {
+        let data = std::fs::read(path)?;
+        self.restore(&data)
+    }
+
+    // Go back to the last snapshot of the rewind history. Returns false when the history is exhausted. A snapshot that
+    // does not restore makes the rest of the history useless, it is cleared.
+    pub fn rewind(&mut self) -> error::Result<bool> {
+        let snapshot = match self.rewind.as_mut().and_then(|r| r.pop()) {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        if let Err(e) = self.restore(&snapshot) {
+            self.rewind.as_mut().unwrap().clear();
+            return Err(e);
+        }
+        self.rewind.as_mut().unwrap().frames = 0;
+        Ok(true)
+    }
+
     pub fn check_and_reset_gpu_updated(&mut self) -> bool {
-        let result = self.mmu.borrow().gpu.v_blank;
-        self.mmu.borrow_mut().gpu.v_blank = false;
+        let result = self.mmu.gpu.v_blank;
+        self.mmu.gpu.v_blank = false;
+        let due = match self.rewind.as_mut() {
+            Some(r) if result => {
+                r.frames += 1;
+                r.frames >= r.interval
+            }
+            _ => false,
+        };
+        if due {
+            let snapshot = self.snapshot();
+            let r = self.rewind.as_mut().unwrap();
+            r.frames = 0;
+            r.push(snapshot);
+        }
+        if result {
+            if let Some(s) = self.mmu.stats.as_mut() {
+                s.frame();
+            }
+            let e = match self.recorder.as_mut() {
+                Some(r) => r.frame(&self.mmu.gpu.data).err(),
+                None => None,
+            };
+            if let Some(e) = e {
+                rog::debugln!("Recording stopped: {}", e);
+                self.recorder = None;
+            }
+            if let Some(peek) = self.peek.as_mut() {
+                // The buffers of the last copy are reused, unless someone still holds it.
+                let peek = Arc::make_mut(peek);
+                peek.frame += 1;
+                peek.update(&self.mmu);
+            }
+            for f in self.frame_hooks.iter_mut() {
+                f(&self.mmu.gpu.data);
+            }
+            self.frame_start = true;
+        }
         result
     }
+
+    fn movie_frame(&mut self) {
+        let movie = match self.movie.as_mut() {
+            Some(m) => m,
+            None => return,
+        };
+        match movie.frame(&mut self.mmu.joypad) {
+            Ok(true) => {}
+            Ok(false) => {
+                rog::debugln!("Movie finished");
+                self.movie = None;
+            }
+            Err(e) => {
+                rog::debugln!("Movie stopped: {}", e);
+                self.movie = None;
+            }
+        }
+    }
 }
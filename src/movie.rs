@@ -0,0 +1,84 @@
+// A movie is the input of every frame. Replayed from power up it plays the same game again, as the emulation is
+// deterministic. It is a text file with a line per frame and a character per key in the order of JoypadKey, or a dot
+// when the key is released:
+//
+//   RLUDABsS    All keys pressed
+//   ....A...    Only A pressed
+//
+// The input is taken at the start of each frame, right after VBlank. While the LCD is off there are no frames, the
+// input of that time is not part of the movie.
+use super::joypad::Joypad;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+const KEYS: &[u8; 8] = b"RLUDABsS";
+
+enum Mode {
+    Record(BufWriter<File>),
+    Play(std::vec::IntoIter<u8>),
+}
+
+pub struct Movie {
+    mode: Mode,
+}
+
+impl Movie {
+    pub fn record(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self { mode: Mode::Record(BufWriter::new(File::create(path)?)) })
+    }
+
+    pub fn play(path: impl AsRef<Path>) -> Result<Self> {
+        let mut frames = vec![];
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.len() != KEYS.len() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Movie: line {} is not 8 keys", i + 1)));
+            }
+            let mut keys = 0x00;
+            for (j, c) in line.bytes().enumerate() {
+                match c {
+                    b'.' => {}
+                    c if c == KEYS[j] => keys |= 1 << j,
+                    _ => return Err(Error::new(ErrorKind::InvalidData, format!("Movie: bad key on line {}", i + 1))),
+                }
+            }
+            frames.push(keys);
+        }
+        Ok(Self { mode: Mode::Play(frames.into_iter()) })
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self.mode, Mode::Play(_))
+    }
+
+    // Called at the start of a frame: write down the keys pressed, or press the keys of the movie. Returns false once
+    // a movie being played is over.
+    pub fn frame(&mut self, joypad: &mut Joypad) -> Result<bool> {
+        match &mut self.mode {
+            Mode::Record(w) => {
+                let keys = joypad.state();
+                let line: Vec<u8> =
+                    KEYS.iter().enumerate().map(|(i, &c)| if keys & (1 << i) != 0 { c } else { b'.' }).collect();
+                w.write_all(&line)?;
+                w.write_all(b"\n")?;
+                Ok(true)
+            }
+            Mode::Play(frames) => match frames.next() {
+                Some(keys) => {
+                    joypad.set_state(keys);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+        }
+    }
+
+    // Complete a recording.
+    pub fn finish(self) -> Result<()> {
+        match self.mode {
+            Mode::Record(mut w) => w.flush(),
+            Mode::Play(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// On screen display, drawn over a frame after it was copied out of the GPU, e.g. into the window buffer. It is not
+// part of the emulated screen: Gpu::data, and everything built on it like save states, is never touched.
+//
+// The buffer has one u32 per pixel in 0RGB, SCREEN_W pixels per line.
+use super::gpu::{SCREEN_H, SCREEN_W};
+use super::joypad::{Joypad, JoypadKey};
+use super::stats::FrameStats;
+
+// Characters 20h to 5Fh of ASCII, 3x5 pixels, one row of 3 bits per byte. Lower case letters are drawn in upper case.
+#[rustfmt::skip]
+const FONT: [[u8; 5]; 64] = [
+    [0b000, 0b000, 0b000, 0b000, 0b000],
+    [0b010, 0b010, 0b010, 0b000, 0b010],
+    [0b101, 0b101, 0b000, 0b000, 0b000],
+    [0b101, 0b111, 0b101, 0b111, 0b101],
+    [0b011, 0b110, 0b010, 0b011, 0b110],
+    [0b101, 0b001, 0b010, 0b100, 0b101],
+    [0b010, 0b101, 0b010, 0b101, 0b011],
+    [0b010, 0b010, 0b000, 0b000, 0b000],
+    [0b001, 0b010, 0b010, 0b010, 0b001],
+    [0b100, 0b010, 0b010, 0b010, 0b100],
+    [0b000, 0b101, 0b010, 0b101, 0b000],
+    [0b000, 0b010, 0b111, 0b010, 0b000],
+    [0b000, 0b000, 0b000, 0b010, 0b100],
+    [0b000, 0b000, 0b111, 0b000, 0b000],
+    [0b000, 0b000, 0b000, 0b000, 0b010],
+    [0b001, 0b001, 0b010, 0b100, 0b100],
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b000, 0b010, 0b000, 0b010, 0b000],
+    [0b000, 0b010, 0b000, 0b010, 0b100],
+    [0b001, 0b010, 0b100, 0b010, 0b001],
+    [0b000, 0b111, 0b000, 0b111, 0b000],
+    [0b100, 0b010, 0b001, 0b010, 0b100],
+    [0b111, 0b001, 0b010, 0b000, 0b010],
+    [0b010, 0b101, 0b111, 0b100, 0b011],
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b111, 0b100, 0b111],
+    [0b111, 0b100, 0b111, 0b100, 0b100],
+    [0b011, 0b100, 0b101, 0b101, 0b011],
+    [0b101, 0b101, 0b111, 0b101, 0b101],
+    [0b111, 0b010, 0b010, 0b010, 0b111],
+    [0b001, 0b001, 0b001, 0b101, 0b010],
+    [0b101, 0b101, 0b110, 0b101, 0b101],
+    [0b100, 0b100, 0b100, 0b100, 0b111],
+    [0b101, 0b111, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b101, 0b101, 0b101],
+    [0b010, 0b101, 0b101, 0b101, 0b010],
+    [0b110, 0b101, 0b110, 0b100, 0b100],
+    [0b010, 0b101, 0b101, 0b110, 0b011],
+    [0b110, 0b101, 0b110, 0b101, 0b101],
+    [0b011, 0b100, 0b010, 0b001, 0b110],
+    [0b111, 0b010, 0b010, 0b010, 0b010],
+    [0b101, 0b101, 0b101, 0b101, 0b111],
+    [0b101, 0b101, 0b101, 0b101, 0b010],
+    [0b101, 0b101, 0b111, 0b111, 0b101],
+    [0b101, 0b101, 0b010, 0b101, 0b101],
+    [0b101, 0b101, 0b010, 0b010, 0b010],
+    [0b111, 0b001, 0b010, 0b100, 0b111],
+    [0b011, 0b010, 0b010, 0b010, 0b011],
+    [0b100, 0b100, 0b010, 0b001, 0b001],
+    [0b110, 0b010, 0b010, 0b010, 0b110],
+    [0b010, 0b101, 0b000, 0b000, 0b000],
+    [0b000, 0b000, 0b000, 0b000, 0b111],
+];
+
+pub struct Osd<'a> {
+    buf: &'a mut [u32],
+}
+
+impl<'a> Osd<'a> {
+    pub fn new(buf: &'a mut [u32]) -> Self {
+        assert_eq!(buf.len(), SCREEN_W * SCREEN_H);
+        Self { buf }
+    }
+
+    // Fill a rectangle, blended over the frame with an opacity from 0 to 255. It is clipped to the screen.
+    pub fn rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: u32, alpha: u8) {
+        let a = u32::from(alpha);
+        for py in y..(y + h).min(SCREEN_H) {
+            for px in x..(x + w).min(SCREEN_W) {
+                let p = &mut self.buf[py * SCREEN_W + px];
+                let mut r = 0;
+                for shift in [0, 8, 16] {
+                    let src = color >> shift & 0xff;
+                    let dst = *p >> shift & 0xff;
+                    r |= ((src * a + dst * (255 - a)) / 255) << shift;
+                }
+                *p = r;
+            }
+        }
+    }
+
+    // A line of text with its top left corner at x, y, on a dark backdrop. A character takes 4x7 pixels, those which
+    // are not in the font are drawn as '?'. It is clipped to the screen.
+    pub fn text(&mut self, x: usize, y: usize, s: &str, color: u32) {
+        let n = s.chars().count();
+        self.rect(x, y, n * 4 + 1, 7, 0x000000, 0x80);
+        for (i, c) in s.chars().enumerate() {
+            let c = match c.to_ascii_uppercase() {
+                c @ ' '..='_' => c as usize - 0x20,
+                _ => '?' as usize - 0x20,
+            };
+            for (j, bits) in FONT[c].iter().enumerate() {
+                for k in 0..3 {
+                    let (px, py) = (x + 1 + i * 4 + k, y + 1 + j);
+                    if bits >> (2 - k) & 1 != 0 && px < SCREEN_W && py < SCREEN_H {
+                        self.buf[py * SCREEN_W + px] = color;
+                    }
+                }
+            }
+        }
+    }
+
+    // The buttons held on the joypad, like the input displays of streams. It sits in the bottom left corner on a
+    // dark backdrop, held buttons are white and the others gray.
+    pub fn input(&mut self, joypad: &Joypad) {
+        let x = 4;
+        let y = SCREEN_H - 13;
+        let buttons = [
+            (JoypadKey::Up, x + 3, y, 3, 3),
+            (JoypadKey::Left, x, y + 3, 3, 3),
+            (JoypadKey::Right, x + 6, y + 3, 3, 3),
+            (JoypadKey::Down, x + 3, y + 6, 3, 3),
+            (JoypadKey::Select, x + 12, y + 7, 5, 2),
+            (JoypadKey::Start, x + 19, y + 7, 5, 2),
+            (JoypadKey::B, x + 27, y + 4, 4, 4),
+            (JoypadKey::A, x + 33, y + 1, 4, 4),
+        ];
+        self.rect(x - 2, y - 2, 43, 13, 0x000000, 0x80);
+        self.rect(x + 3, y + 3, 3, 3, 0xc0c0c0, 0xc0);
+        for (key, bx, by, bw, bh) in buttons {
+            if joypad.is_pressed(key) {
+                self.rect(bx, by, bw, bh, 0xffffff, 0xff);
+            } else {
+                self.rect(bx, by, bw, bh, 0xc0c0c0, 0xc0);
+            }
+        }
+    }
+
+    // The performance statistics of a frame, in the top left corner: the frames per second it would run at, its
+    // clock cycles, then the milliseconds spent in each part of the machine and asleep.
+    pub fn stats(&mut self, s: &FrameStats) {
+        let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+        let fps = 1.0 / s.total.as_secs_f64().max(1e-6);
+        let lines = [
+            format!("{:.1} FPS {} CYCLES", fps, s.cycles),
+            format!("CPU {:.2} GPU {:.2} APU {:.2}", ms(s.cpu), ms(s.gpu), ms(s.apu)),
+            format!("ETC {:.2} SLEEP {:.2} MS", ms(s.other), ms(s.sleep)),
+        ];
+        for (i, l) in lines.iter().enumerate() {
+            self.text(1, 1 + i * 7, l, 0xffffff);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// Colors of the DMG screen. The DMG has four shades, from white to black, picked per color number by BGP, OBP0 and
+// OBP1. A palette gives the color shown for each shade, and can give different ones to the background and to each of
+// the sprite palettes, the way the CGB colorizes old games.
+//
+// Palettes are read from the files of the usual palette editors, as found in the palette packs shared online:
+//   JASC-PAL  "JASC-PAL", "0100", the number of colors, then a "r g b" line per color. The .pal of Paint Shop Pro.
+//   GIMP      "GIMP Palette", optional "Name:" and "Columns:" lines and # comments, then "r g b [name]" lines.
+//   Hex       A "rrggbb" line per color, with or without #. The .hex of Lospec.
+// A file of 4 colors is used for everything, one of 12 colors gives the background, OBP0 and OBP1 in this order.
+// Packs list the colors from light to dark or the other way around, so each group of 4 is sorted by brightness.
+//
+// A few palettes are built in, for the looks of the real screens and a classic tint, and the colors the CGB gives
+// the game, see cgb.
+use super::cartridge::Cartridge;
+use super::error::{Error, Result};
+use super::gpu::{ColorCorrection, GrayShades};
+use std::path::Path;
+
+// Background, OBP0 and OBP1, each from white to black.
+pub type DmgPalette = [[[u8; 3]; 4]; 3];
+
+pub const GRAY: DmgPalette = [[
+    [GrayShades::White as u8; 3],
+    [GrayShades::Light as u8; 3],
+    [GrayShades::Dark as u8; 3],
+    [GrayShades::Black as u8; 3],
+]; 3];
+
+// The yellow green of the DMG screen.
+pub const GREEN: DmgPalette = [[[0x9b, 0xbc, 0x0f], [0x8b, 0xac, 0x0f], [0x30, 0x62, 0x30], [0x0f, 0x38, 0x0f]]; 3];
+
+// The black and white screen of the Game Boy Pocket, slightly green.
+pub const POCKET: DmgPalette = [[[0xc4, 0xcf, 0xa1], [0x8b, 0x95, 0x6d], [0x4d, 0x53, 0x3c], [0x1f, 0x1f, 0x1f]]; 3];
+
+// The blue green backlight of the Game Boy Light.
+pub const LIGHT: DmgPalette = [[[0x00, 0xb5, 0x81], [0x00, 0x9a, 0x71], [0x00, 0x69, 0x4a], [0x00, 0x4f, 0x3b]]; 3];
+
+pub const SEPIA: DmgPalette = [[[0xe8, 0xd8, 0xb0], [0xb8, 0x9c, 0x70], [0x78, 0x60, 0x48], [0x30, 0x24, 0x18]]; 3];
+
+// The built-in palettes by name, in the order they are cycled through.
+pub const BUILTIN: [(&str, DmgPalette); 5] =
+    [("gray", GRAY), ("green", GREEN), ("pocket", POCKET), ("light", LIGHT), ("sepia", SEPIA)];
+
+// The CGB runs DMG games in colors its boot ROM picks. It recognizes the games licensed by Nintendo by the sum of the
+// 16 bytes of their title, and by its 4th letter where titles share a sum, and gives each one of the 51 combinations
+// of its palettes: 4 colors for the background, OBP0 and OBP1 each. Every other game gets the default colors, green
+// and blue for the background and red for the sprites. Mmunit::power_up_model sets them when a DMG game runs on a CGB.
+//
+// See: https://gbdev.io/pandocs/Power_Up_Sequence.html#compatibility-palettes
+// The 15-bit colors of the boot ROM, 30 palettes from white to black.
+const CGB_COLORS: [u16; 120] = [
+    0x7fff, 0x32bf, 0x00d0, 0x0000, // 0
+    0x639f, 0x4279, 0x15b0, 0x04cb, // 1
+    0x7fff, 0x6e31, 0x454a, 0x0000, // 2
+    0x7fff, 0x1bef, 0x0200, 0x0000, // 3
+    0x7fff, 0x421f, 0x1cf2, 0x0000, // 4
+    0x7fff, 0x5294, 0x294a, 0x0000, // 5
+    0x7fff, 0x03ff, 0x012f, 0x0000, // 6
+    0x7fff, 0x03ef, 0x01d6, 0x0000, // 7
+    0x7fff, 0x42b5, 0x3dc8, 0x0000, // 8
+    0x7e74, 0x03ff, 0x0180, 0x0000, // 9
+    0x67ff, 0x77ac, 0x1a13, 0x2d6b, // 10
+    0x7ed6, 0x4bff, 0x2175, 0x0000, // 11
+    0x53ff, 0x4a5f, 0x7e52, 0x0000, // 12
+    0x4fff, 0x7ed2, 0x3a4c, 0x1ce0, // 13
+    0x03ed, 0x7fff, 0x255f, 0x0000, // 14
+    0x036a, 0x021f, 0x03ff, 0x7fff, // 15
+    0x7fff, 0x01df, 0x0112, 0x0000, // 16
+    0x231f, 0x035f, 0x00f2, 0x0009, // 17
+    0x7fff, 0x03ea, 0x011f, 0x0000, // 18
+    0x299f, 0x001a, 0x000c, 0x0000, // 19
+    0x7fff, 0x027f, 0x001f, 0x0000, // 20
+    0x7fff, 0x03e0, 0x0206, 0x0120, // 21
+    0x7fff, 0x7eeb, 0x001f, 0x7c00, // 22
+    0x7fff, 0x3fff, 0x7e00, 0x001f, // 23
+    0x7fff, 0x03ff, 0x001f, 0x0000, // 24
+    0x03ff, 0x001f, 0x000c, 0x0000, // 25
+    0x7fff, 0x033f, 0x0193, 0x0000, // 26
+    0x0000, 0x4200, 0x037f, 0x7fff, // 27
+    0x7fff, 0x7e8c, 0x7c00, 0x0000, // 28
+    0x7fff, 0x1bef, 0x6180, 0x0000, // 29
+];
+
+// The colors of OBP0, OBP1 and the background of each combination, as their offset in CGB_COLORS. Three of them start
+// one color before a palette, as in the boot ROM: the sprites never show their color 0, they get 3 colors of the next
+// palette.
+const CGB_COMBINATIONS: [[usize; 3]; 51] = [
+    [16, 16, 116],   // 0
+    [72, 72, 72],    // 1
+    [80, 80, 80],    // 2
+    [96, 96, 96],    // 3
+    [36, 36, 36],    // 4
+    [0, 0, 0],       // 5
+    [108, 108, 108], // 6
+    [20, 20, 20],    // 7
+    [48, 48, 48],    // 8
+    [104, 104, 104], // 9
+    [64, 32, 32],    // 10
+    [16, 112, 112],  // 11
+    [16, 8, 8],      // 12
+    [12, 16, 16],    // 13
+    [16, 116, 116],  // 14
+    [112, 16, 112],  // 15
+    [8, 68, 8],      // 16
+    [64, 64, 32],    // 17
+    [16, 16, 28],    // 18
+    [16, 16, 72],    // 19
+    [16, 16, 80],    // 20
+    [76, 76, 36],    // 21
+    [15, 15, 44],    // 22
+    [68, 68, 8],     // 23
+    [16, 16, 8],     // 24
+    [16, 16, 12],    // 25
+    [112, 112, 0],   // 26
+    [12, 12, 0],     // 27
+    [0, 0, 4],       // 28
+    [72, 88, 72],    // 29
+    [80, 88, 80],    // 30
+    [96, 88, 96],    // 31
+    [64, 88, 32],    // 32
+    [68, 16, 52],    // 33
+    [111, 0, 56],    // 34
+    [111, 16, 60],   // 35
+    [76, 88, 36],    // 36
+    [64, 112, 40],   // 37
+    [16, 92, 112],   // 38
+    [68, 88, 8],     // 39
+    [16, 0, 8],      // 40
+    [16, 112, 12],   // 41
+    [112, 12, 0],    // 42
+    [12, 112, 16],   // 43
+    [84, 112, 16],   // 44
+    [12, 112, 0],    // 45
+    [100, 12, 112],  // 46
+    [0, 112, 32],    // 47
+    [16, 12, 112],   // 48
+    [112, 12, 24],   // 49
+    [16, 112, 116],  // 50
+];
+
+// The sums of the titles the boot ROM knows. The last 29 are shared by several titles, and only count for the one with
+// the 4th letter of CGB_LETTERS at the same place.
+const CGB_SUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xd1, 0xdb, 0xf2, 0x3c, 0x8c, 0x92, 0x3d, 0x5c, 0x58, 0xc9, 0x3e, 0x70, 0x1d, 0x59, 0x69,
+    0x19, 0x35, 0xa8, 0x14, 0xaa, 0x75, 0x95, 0x99, 0x34, 0x6f, 0x15, 0xff, 0x97, 0x4b, 0x90, 0x17, 0x10, 0x39, 0xf7,
+    0xf6, 0xa2, 0x49, 0x4e, 0x43, 0x68, 0xe0, 0x8b, 0xf0, 0xce, 0x0c, 0x29, 0xe8, 0xb7, 0x86, 0x9a, 0x52, 0x01, 0x9d,
+    0x71, 0x9c, 0xbd, 0x5d, 0x6d, 0x67, 0x3f, 0x6b, 0xb3, 0x46, 0x28, 0xa5, 0xc6, 0xd3, 0x27, 0x61, 0x18, 0x66, 0x6a,
+    0xbf, 0x0d, 0xf4, 0xb3, 0x46, 0x28, 0xa5, 0xc6, 0xd3, 0x27, 0x61, 0x18, 0x66, 0x6a, 0xbf, 0x0d, 0xf4, 0xb3,
+];
+
+const CGB_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// The combination of each title of CGB_SUMS. The first one is the default, for the titles not listed and the games of
+// other licensees.
+const CGB_TITLES: [usize; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42,
+    5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23,
+    18, 29,
+];
+
+// The colors the CGB gives the game, through the color correction of the screen.
+pub fn cgb(cart: &dyn Cartridge, correction: ColorCorrection) -> DmgPalette {
+    let letter = cart.get(0x0137);
+    let known = |(i, s): &(usize, &u8)| Some(**s) == cart.title_sum() && (*i < 65 || CGB_LETTERS[*i - 65] == letter);
+    let title = CGB_SUMS.iter().enumerate().find(known).map_or(0, |(i, _)| i);
+    let [obp0, obp1, bg] = CGB_COMBINATIONS[CGB_TITLES[title]];
+    [bg, obp0, obp1].map(|offset| {
+        let colors: [u16; 4] = CGB_COLORS[offset..offset + 4].try_into().unwrap();
+        colors.map(|c| correction.rgb(c as u8 & 0x1f, (c >> 5) as u8 & 0x1f, (c >> 10) as u8 & 0x1f))
+    })
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<DmgPalette> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+pub fn parse(text: &str) -> Result<DmgPalette> {
+    let invalid = Error::Palette;
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty()).peekable();
+    let mut colors: Vec<[u8; 3]> = vec![];
+    match lines.peek().copied() {
+        Some("JASC-PAL") => {
+            let count = lines.nth(2).and_then(|l| l.parse::<usize>().ok());
+            for line in lines {
+                colors.push(rgb(line).ok_or_else(|| invalid(format!("bad color {:?}", line)))?);
+            }
+            if count != Some(colors.len()) {
+                return Err(invalid(String::from("the number of colors does not match the header")));
+            }
+        }
+        Some("GIMP Palette") => {
+            for line in lines.skip(1) {
+                if line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+                    continue;
+                }
+                colors.push(rgb(line).ok_or_else(|| invalid(format!("bad color {:?}", line)))?);
+            }
+        }
+        _ => {
+            for line in lines {
+                colors.push(hex(line).ok_or_else(|| invalid(format!("bad color {:?}", line)))?);
+            }
+        }
+    }
+    let mut palette = match colors.len() {
+        4 => [[colors[0], colors[1], colors[2], colors[3]]; 3],
+        12 => [0, 1, 2].map(|i| [0, 1, 2, 3].map(|j| colors[i * 4 + j])),
+        n => return Err(invalid(format!("{} colors, expected 4 or 12", n))),
+    };
+    for shades in palette.iter_mut() {
+        shades.sort_by_key(|c| std::cmp::Reverse(luma(c)));
+    }
+    Ok(palette)
+}
+
+// The first three numbers of a "r g b" line.
+fn rgb(line: &str) -> Option<[u8; 3]> {
+    let mut it = line.split_whitespace().map(|s| s.parse::<u8>());
+    Some([it.next()?.ok()?, it.next()?.ok()?, it.next()?.ok()?])
+}
+
+fn hex(line: &str) -> Option<[u8; 3]> {
+    let s = line.trim_start_matches('#');
+    if s.len() != 6 {
+        return None;
+    }
+    let v = u32::from_str_radix(s, 16).ok()?;
+    Some([(v >> 16) as u8, (v >> 8) as u8, v as u8])
+}
+
+// Perceived brightness, see ITU-R BT.601.
+fn luma(c: &[u8; 3]) -> u32 {
+    u32::from(c[0]) * 299 + u32::from(c[1]) * 587 + u32::from(c[2]) * 114
+}
@@ -0,0 +1,62 @@
+// Achievements, trackers and autosplitters read a few addresses of RAM every frame, e.g. the level or the lives of
+// the player. Reading them through the bus while the game runs disturbs nothing, but the bus is only reachable through
+// the motherboard, on the thread which runs it. A MemorySnapshot is a copy of the RAM of the game taken at the end of
+// a frame: MotherBoard::publish_memory turns it on, then each frame updates motherboard.peek, which is cheap to clone
+// and to send to another thread. The copy a consumer still holds is left as it is, the next frame writes a new one.
+//
+// The snapshot has the RAM only: the work RAM with all its banks, the high RAM and the whole RAM of the cartridge.
+// MemorySnapshot::get reads them at the addresses of the CPU, with the banks mapped at the end of the frame; the RAM
+// of the cartridge reads as if it was enabled. The registers, the video memory and the ROM read FFh.
+use super::mmunit::Mmunit;
+
+#[derive(Clone)]
+pub struct MemorySnapshot {
+    // The frames published since publish_memory.
+    pub frame: u64,
+    // All 8 banks of 4KB of the work RAM. The DMG has only banks 0 and 1.
+    pub wram: Vec<u8>,
+    // The bank mapped at D000-DFFF.
+    pub wram_bank: usize,
+    pub hram: Vec<u8>,
+    // All the banks of 8KB of the RAM of the cartridge, empty if it has none. The MBC2 has 512 half bytes.
+    pub sram: Vec<u8>,
+    // The bank mapped at A000-BFFF, see Cartridge::ram_bank.
+    pub sram_bank: Option<usize>,
+}
+
+impl MemorySnapshot {
+    pub fn power_up() -> Self {
+        Self { frame: 0, wram: vec![], wram_bank: 1, hram: vec![], sram: vec![], sram_bank: None }
+    }
+
+    // Copy the RAM of the MMU, into the buffers of the last copy.
+    pub fn update(&mut self, mmu: &Mmunit) {
+        let copy = |dst: &mut Vec<u8>, src: &[u8]| {
+            dst.clear();
+            dst.extend_from_slice(src);
+        };
+        copy(&mut self.wram, mmu.wram());
+        copy(&mut self.hram, mmu.hram());
+        copy(&mut self.sram, mmu.cartridge.ram());
+        self.wram_bank = mmu.wram_bank();
+        self.sram_bank = mmu.cartridge.ram_bank();
+    }
+
+    pub fn get(&self, a: u16) -> u8 {
+        let a = usize::from(a);
+        let r = match a {
+            0xa000..=0xbfff => self.sram_bank.and_then(|b| self.sram.get(a - 0xa000 + 0x2000 * b)),
+            0xc000..=0xcfff => self.wram.get(a - 0xc000),
+            0xd000..=0xdfff => self.wram.get(a - 0xd000 + 0x1000 * self.wram_bank),
+            0xe000..=0xfdff => return self.get(a as u16 - 0x2000),
+            0xff80..=0xfffe => self.hram.get(a - 0xff80),
+            _ => None,
+        };
+        r.copied().unwrap_or(0xff)
+    }
+
+    // A little endian word.
+    pub fn get_word(&self, a: u16) -> u16 {
+        u16::from_le_bytes([self.get(a), self.get(a.wrapping_add(1))])
+    }
+}
@@ -0,0 +1,179 @@
+// Record the gameplay as a video. The MotherBoard hands every frame to the recorder at VBlank, so recording works the
+// same with any frontend.
+//
+// Two outputs are supported:
+//   GIF     Encoded here. Consecutive identical frames are merged into one, and as GIF delays are counted in 1/100s
+//           and most viewers don't play delays below 2/100s, frames shorter than that are dropped, keeping the total
+//           duration right.
+//   ffmpeg  The raw frames are piped to an ffmpeg process, which picks the format from the extension of the file.
+//           ffmpeg must be installed.
+use super::gpu::{Framebuffer, SCREEN_H, SCREEN_W};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Error, Result, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+// Length of a frame in 1/100s: 70224 clock cycles at 4.194304 MHz.
+const FRAME_TIME: f64 = 70224.0 / 4194304.0 * 100.0;
+
+enum Sink {
+    Gif(Gif),
+    Ffmpeg(Child),
+}
+
+pub struct Recorder {
+    sink: Sink,
+}
+
+impl Recorder {
+    pub fn gif(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self { sink: Sink::Gif(Gif::create(path)?) })
+    }
+
+    pub fn ffmpeg(path: impl AsRef<Path>) -> Result<Self> {
+        let size = format!("{}x{}", SCREEN_W, SCREEN_H);
+        let scale = format!("scale={}:{}:flags=neighbor", SCREEN_W * 4, SCREEN_H * 4);
+        let child = Command::new("ffmpeg")
+            .args(["-y", "-loglevel", "error", "-f", "rawvideo", "-pix_fmt", "rgb24", "-s", &size])
+            .args(["-framerate", "59.7275", "-i", "-", "-vf", &scale, "-pix_fmt", "yuv420p"])
+            .arg(path.as_ref())
+            .stdin(Stdio::piped())
+            .spawn()?;
+        Ok(Self { sink: Sink::Ffmpeg(child) })
+    }
+
+    pub fn frame(&mut self, data: &Framebuffer) -> Result<()> {
+        match &mut self.sink {
+            Sink::Gif(gif) => gif.frame(data),
+            Sink::Ffmpeg(child) => {
+                let stdin = child.stdin.as_mut().unwrap();
+                let raw: Vec<u8> = data.iter().flatten().flatten().copied().collect();
+                stdin.write_all(&raw)
+            }
+        }
+    }
+
+    // Complete the file. Without this, a GIF misses its last frame and ffmpeg may not finish writing.
+    pub fn finish(self) -> Result<()> {
+        match self.sink {
+            Sink::Gif(gif) => gif.finish(),
+            Sink::Ffmpeg(mut child) => {
+                drop(child.stdin.take());
+                let status = child.wait()?;
+                if !status.success() {
+                    return Err(Error::other(format!("Recorder: ffmpeg failed with {}", status)));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Gif {
+    w: BufWriter<File>,
+    // Time of the current frame in 1/100s.
+    time: f64,
+    // The frame waiting to be written, and the time it started. Its delay is only known once it changes.
+    pending: Option<(Box<Framebuffer>, f64)>,
+}
+
+impl Gif {
+    fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(b"GIF89a")?;
+        // Logical screen descriptor, without a global color table: every frame has its own.
+        w.write_all(&(SCREEN_W as u16).to_le_bytes())?;
+        w.write_all(&(SCREEN_H as u16).to_le_bytes())?;
+        w.write_all(&[0x00, 0x00, 0x00])?;
+        // Loop forever.
+        w.write_all(&[0x21, 0xff, 0x0b])?;
+        w.write_all(b"NETSCAPE2.0")?;
+        w.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+        Ok(Self { w, time: 0.0, pending: None })
+    }
+
+    fn frame(&mut self, data: &Framebuffer) -> Result<()> {
+        let time = self.time;
+        self.time += FRAME_TIME;
+        match self.pending.take() {
+            None => self.pending = Some((Box::new(*data), time)),
+            Some((frame, start)) if *frame == *data => self.pending = Some((frame, start)),
+            // Too short to be shown, the new frame takes its place.
+            Some((_, start)) if time - start < 2.0 => self.pending = Some((Box::new(*data), start)),
+            Some((frame, start)) => {
+                self.image(&frame, delay(start, time))?;
+                self.pending = Some((Box::new(*data), time));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some((frame, start)) = self.pending.take() {
+            self.image(&frame, delay(start, self.time).max(2))?;
+        }
+        self.w.write_all(&[0x3b])?;
+        self.w.flush()
+    }
+
+    fn image(&mut self, data: &Framebuffer, delay: u16) -> Result<()> {
+        let (palette, indices) = index(data);
+        // The smallest color table holding the palette, at least 2 entries.
+        let bits = (usize::BITS - (palette.len() - 1).max(1).leading_zeros()) as u8;
+        // Graphic control extension: no disposal, the delay, no transparency.
+        self.w.write_all(&[0x21, 0xf9, 0x04, 0x04])?;
+        self.w.write_all(&delay.to_le_bytes())?;
+        self.w.write_all(&[0x00, 0x00])?;
+        // Image descriptor with a local color table.
+        self.w.write_all(&[0x2c, 0x00, 0x00, 0x00, 0x00])?;
+        self.w.write_all(&(SCREEN_W as u16).to_le_bytes())?;
+        self.w.write_all(&(SCREEN_H as u16).to_le_bytes())?;
+        self.w.write_all(&[0x80 | (bits - 1)])?;
+        for i in 0..1 << bits {
+            self.w.write_all(palette.get(i).unwrap_or(&[0x00; 3]))?;
+        }
+        let min_code_size = bits.max(2);
+        let lzw = weezl::encode::Encoder::new(weezl::BitOrder::Lsb, min_code_size)
+            .encode(&indices)
+            .map_err(|e| Error::other(format!("Recorder: {}", e)))?;
+        self.w.write_all(&[min_code_size])?;
+        for block in lzw.chunks(0xff) {
+            self.w.write_all(&[block.len() as u8])?;
+            self.w.write_all(block)?;
+        }
+        self.w.write_all(&[0x00])
+    }
+}
+
+// Delay in 1/100s between two times, rounded so that the errors don't add up.
+fn delay(start: f64, end: f64) -> u16 {
+    (end.round() - start.round()) as u16
+}
+
+// Split a frame into a palette of at most 256 colors and the index of every pixel. The DMG has 4 shades, but a CGB
+// game changing palettes between scanlines can show more colors than that: they are then reduced to 3-3-2 bits.
+fn index(data: &Framebuffer) -> (Vec<[u8; 3]>, Vec<u8>) {
+    for reduce in [false, true] {
+        let mut palette: Vec<[u8; 3]> = vec![];
+        let mut lookup: HashMap<[u8; 3], u8> = HashMap::new();
+        let mut indices = Vec::with_capacity(SCREEN_W * SCREEN_H);
+        for &c in data.iter().flatten() {
+            let c = if reduce { [c[0] & 0xe0, c[1] & 0xe0, c[2] & 0xc0] } else { c };
+            let i = match lookup.get(&c) {
+                Some(&i) => i,
+                None if palette.len() == 256 => break,
+                None => {
+                    palette.push(c);
+                    lookup.insert(c, (palette.len() - 1) as u8);
+                    (palette.len() - 1) as u8
+                }
+            };
+            indices.push(i);
+        }
+        if indices.len() == SCREEN_W * SCREEN_H {
+            return (palette, indices);
+        }
+    }
+    unreachable!()
+}
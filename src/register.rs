@@ -104,29 +104,23 @@ impl Register {
 }
 
 impl Register {
+    // The registers as the boot ROM of each model leaves them. Games made for several models tell them apart by A:
+    // 01h on the DMG and the SGB, FFh on the Pocket, 11h on the CGB.
+    //
+    // See: https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
     pub fn power_up(term: Term) -> Self {
         let mut r = Self::default();
-        match term {
-            Term::GB => {
-                r.a = 0x01;
-            }
-            Term::GBP => {
-                r.a = 0xff;
-            }
-            Term::GBC => {
-                r.a = 0x11;
-            }
-            Term::SGB => {
-                r.a = 0x01;
-            }
-        }
-        r.f = 0xb0;
-        r.b = 0x00;
-        r.c = 0x13;
-        r.d = 0x00;
-        r.e = 0xd8;
-        r.h = 0x01;
-        r.l = 0x4d;
+        let (a, f, bc, de, hl) = match term {
+            Term::GB => (0x01, 0xb0, 0x0013, 0x00d8, 0x014d),
+            Term::GBP => (0xff, 0xb0, 0x0013, 0x00d8, 0x014d),
+            Term::GBC => (0x11, 0x80, 0x0000, 0xff56, 0x000d),
+            Term::SGB => (0x01, 0x00, 0x0014, 0x0000, 0xc060),
+        };
+        r.a = a;
+        r.f = f;
+        r.set_bc(bc);
+        r.set_de(de);
+        r.set_hl(hl);
         // The GameBoy stack pointer is initialized to 0xfffe on power up but a programmer should not rely on this
         // setting and rather should explicitly set its value.
         r.sp = 0xfffe;
@@ -136,4 +130,32 @@ impl Register {
         r.pc = 0x0100;
         r
     }
+
+    // The registers the CGB leaves to a DMG game, which it runs in its compatibility mode. B is the sum of the title
+    // the boot ROM recognizes the game by, see Cartridge::title_sum, and HL depends on it.
+    pub fn power_up_compat(sum: u8) -> Self {
+        let mut r = Self::power_up(Term::GBC);
+        r.set_bc(u16::from(sum) << 8);
+        r.set_de(0x0008);
+        r.set_hl(if sum == 0x43 || sum == 0x58 { 0x991a } else { 0x007c });
+        r
+    }
+
+    // The registers the boot ROM of the first DMG revision leaves, see Revision::Dmg0. It does not check the header and
+    // leaves other values than the later DMG.
+    pub fn power_up_dmg0() -> Self {
+        let mut r = Self::power_up(Term::GB);
+        r.a = 0x01;
+        r.f = 0x00;
+        r.set_bc(0xff13);
+        r.set_de(0x00c1);
+        r.set_hl(0x8403);
+        r
+    }
+
+    // The registers at power on, before the boot ROM runs: everything is zero and the program counter points at the
+    // first instruction of the boot ROM. The boot ROM then leaves the registers as power_up sets them.
+    pub fn power_up_cold() -> Self {
+        Self::default()
+    }
 }
@@ -0,0 +1,79 @@
+// The samples on their way from the APU to the sound card. The emulation pushes them as it makes them, the audio
+// callback of the frontend pops them on its own thread when the sound card needs more: neither side waits on a lock
+// held by the other, a lock would make the sound crackle whenever the two meet.
+//
+// It is a ring of fixed size, for a single producer and a single consumer. Each stereo sample is kept in one atomic
+// slot, and the positions of the two ends tell which slots are full. Samples which don't fit, when the consumer is
+// too slow or gone, are dropped.
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+pub struct Ring {
+    slots: Box<[AtomicU64]>,
+    // The number of samples ever popped and pushed, the difference is the number in the ring.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+fn pack(s: (f32, f32)) -> u64 {
+    u64::from(s.0.to_bits()) << 32 | u64::from(s.1.to_bits())
+}
+
+fn unpack(v: u64) -> (f32, f32) {
+    (f32::from_bits((v >> 32) as u32), f32::from_bits(v as u32))
+}
+
+impl Ring {
+    pub fn power_up(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            slots: (0..capacity).map(|_| AtomicU64::new(0)).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    // The samples waiting to be played.
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Append as many samples as fit, from the producer only. Returns how many.
+    pub fn push(&self, samples: &[(f32, f32)]) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        let n = samples.len().min(self.capacity() - tail.wrapping_sub(head));
+        for (i, &s) in samples[..n].iter().enumerate() {
+            self.slots[tail.wrapping_add(i) % self.capacity()].store(pack(s), Ordering::Relaxed);
+        }
+        self.tail.store(tail.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    // Take the oldest samples into out, from the consumer only. Returns how many.
+    pub fn pop(&self, out: &mut [(f32, f32)]) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let n = out.len().min(tail.wrapping_sub(head));
+        for (i, s) in out[..n].iter_mut().enumerate() {
+            *s = unpack(self.slots[head.wrapping_add(i) % self.capacity()].load(Ordering::Relaxed));
+        }
+        self.head.store(head.wrapping_add(n), Ordering::Release);
+        n
+    }
+
+    // Take all the samples, from the consumer only.
+    pub fn drain(&self) -> Vec<(f32, f32)> {
+        let mut out = vec![(0.0, 0.0); self.len()];
+        let n = self.pop(&mut out);
+        out.truncate(n);
+        out
+    }
+}
@@ -0,0 +1,98 @@
+// The bytes of a cartridge rom. They are read into memory, or with the mmap feature on unix, mapped read-only from the
+// file: the pages are then loaded as the game touches them and can be dropped again by the system, which keeps a large
+// rom from taking its full size of RAM on low memory devices, and large collections from being read in full.
+//
+// A mapped rom reads the file as it is on disk while the game runs. The file must not be truncated or rewritten
+// meanwhile, the next read past its end would kill the process.
+use std::ops::Deref;
+use std::path::Path;
+
+pub struct Rom {
+    data: Data,
+}
+
+enum Data {
+    Owned(Vec<u8>),
+    #[cfg(all(unix, feature = "mmap"))]
+    Mapped(Mapping),
+}
+
+impl Rom {
+    // Map the rom from a file. Where mapping isn't available, or the file can't be mapped, e.g. it is empty, the rom is
+    // read instead.
+    pub fn map(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        #[cfg(all(unix, feature = "mmap"))]
+        {
+            if let Some(m) = Mapping::open(path.as_ref())? {
+                rog::debugln!("Rom: mapped {} bytes", m.len);
+                return Ok(Rom { data: Data::Mapped(m) });
+            }
+        }
+        Ok(Rom::from(std::fs::read(path)?))
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self.data, Data::Owned(_))
+    }
+}
+
+impl From<Vec<u8>> for Rom {
+    fn from(v: Vec<u8>) -> Self {
+        Rom { data: Data::Owned(v) }
+    }
+}
+
+impl Deref for Rom {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.data {
+            Data::Owned(v) => v,
+            #[cfg(all(unix, feature = "mmap"))]
+            Data::Mapped(m) => m.as_slice(),
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and owned by a single Rom, so it can move between threads like a Vec<u8>.
+#[cfg(all(unix, feature = "mmap"))]
+unsafe impl Send for Mapping {}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl Mapping {
+    fn open(path: &Path) -> std::io::Result<Option<Self>> {
+        use std::os::unix::io::AsRawFd;
+        let file = std::fs::File::open(path)?;
+        let len = match usize::try_from(file.metadata()?.len()) {
+            Ok(0) | Err(_) => return Ok(None),
+            Ok(n) => n,
+        };
+        // The mapping stays valid once the file is closed.
+        let ptr =
+            unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            rog::debugln!("Rom: mmap failed, {}", std::io::Error::last_os_error());
+            return Ok(None);
+        }
+        Ok(Some(Mapping { ptr, len }))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Scripts in Rhai (https://rhai.rs) run alongside the game, e.g. practice HUDs for speedrunners which show a value of
+// RAM, or bots which read the state of the game and poke it. A script runs once when it is loaded, and registers the
+// functions to call after every frame:
+//
+//   let best = 0;
+//   on_frame(|| {
+//       best = max(best, read(0xc0a0));
+//       text(0, 0, `SCORE ${read(0xc0a0)} BEST ${best}`);
+//   });
+//
+// The functions of the emulator:
+//   read(a)        The byte at address a, as the CPU reads it
+//   read16(a)      The little endian word at address a
+//   write(a, v)    Write the byte v at address a, as the CPU writes it
+//   reg(name)      A register of the CPU: a, f, b, c, d, e, h, l, af, bc, de, hl, sp or pc
+//   text(x, y, s)  Draw s over the screen, its top left corner at pixel x, y, until the next frame
+//   frame()        The frames run since the script was loaded
+//   on_frame(f)    Call the function f after every frame
+//
+// The script sees the machine as it is between two frames: reads come from a copy of the memory and the registers
+// taken before the callbacks, and writes are applied after them, in order.
+use super::memory::Memory;
+use super::motherboard::MotherBoard;
+use super::osd::Osd;
+use super::register::Register;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct View {
+    mem: Vec<u8>,
+    reg: Register,
+    writes: Vec<(u16, u8)>,
+    text: Vec<(usize, usize, String)>,
+    frame: i64,
+    callbacks: Vec<FnPtr>,
+}
+
+impl View {
+    fn update(&mut self, mbrd: &MotherBoard) {
+        self.mem = (0x0000..=0xffff).map(|a| mbrd.mmu.get(a)).collect();
+        self.reg = mbrd.cpu.cpu.reg.clone();
+    }
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    view: Arc<Mutex<View>>,
+}
+
+impl Script {
+    // Compile the script and run it once.
+    pub fn load(path: impl AsRef<Path>, mbrd: &mut MotherBoard) -> Result<Self> {
+        let view = Arc::new(Mutex::new(View::default()));
+        let mut engine = Engine::new();
+        engine.on_print(|s| rog::println!("{}", s));
+        let v = view.clone();
+        engine.register_fn("read", move |a: i64| i64::from(v.lock().unwrap().mem[a as u16 as usize]));
+        let v = view.clone();
+        engine.register_fn("read16", move |a: i64| {
+            let view = v.lock().unwrap();
+            let lo = view.mem[a as u16 as usize];
+            let hi = view.mem[(a as u16).wrapping_add(1) as usize];
+            i64::from(u16::from_le_bytes([lo, hi]))
+        });
+        let v = view.clone();
+        engine.register_fn("write", move |a: i64, b: i64| v.lock().unwrap().writes.push((a as u16, b as u8)));
+        let v = view.clone();
+        engine.register_fn("reg", move |name: &str| -> std::result::Result<i64, Box<EvalAltResult>> {
+            let r = &v.lock().unwrap().reg;
+            let n = match name {
+                "a" => u16::from(r.a),
+                "f" => u16::from(r.f),
+                "b" => u16::from(r.b),
+                "c" => u16::from(r.c),
+                "d" => u16::from(r.d),
+                "e" => u16::from(r.e),
+                "h" => u16::from(r.h),
+                "l" => u16::from(r.l),
+                "af" => r.get_af(),
+                "bc" => r.get_bc(),
+                "de" => r.get_de(),
+                "hl" => r.get_hl(),
+                "sp" => r.sp,
+                "pc" => r.pc,
+                _ => return Err(format!("Unsupported register: {}", name).into()),
+            };
+            Ok(i64::from(n))
+        });
+        let v = view.clone();
+        engine.register_fn("text", move |x: i64, y: i64, s: &str| {
+            v.lock().unwrap().text.push((x.max(0) as usize, y.max(0) as usize, s.to_string()));
+        });
+        let v = view.clone();
+        engine.register_fn("frame", move || v.lock().unwrap().frame);
+        let v = view.clone();
+        engine.register_fn("on_frame", move |f: FnPtr| v.lock().unwrap().callbacks.push(f));
+
+        let ast = engine.compile_file(path.as_ref().to_path_buf()).map_err(failed)?;
+        let r = Self { engine, ast, view };
+        r.view.lock().unwrap().update(mbrd);
+        r.engine.run_ast(&r.ast).map_err(failed)?;
+        r.apply(mbrd);
+        Ok(r)
+    }
+
+    // Call the callbacks, after a frame was run.
+    pub fn frame(&mut self, mbrd: &mut MotherBoard) -> Result<()> {
+        let callbacks = {
+            let mut view = self.view.lock().unwrap();
+            view.update(mbrd);
+            view.text.clear();
+            view.callbacks.clone()
+        };
+        for f in callbacks {
+            // What a callback returns is ignored.
+            let _: Dynamic = f.call(&self.engine, &self.ast, ()).map_err(failed)?;
+        }
+        self.apply(mbrd);
+        self.view.lock().unwrap().frame += 1;
+        Ok(())
+    }
+
+    // Draw the text of the last frame.
+    pub fn draw(&self, osd: &mut Osd) {
+        for (x, y, s) in &self.view.lock().unwrap().text {
+            osd.text(*x, *y, s, 0xffffff);
+        }
+    }
+
+    fn apply(&self, mbrd: &mut MotherBoard) {
+        let writes = std::mem::take(&mut self.view.lock().unwrap().writes);
+        if writes.is_empty() {
+            return;
+        }
+        for (a, v) in writes {
+            mbrd.mmu.set(a, v);
+        }
+        // The writes may have switched the banks of the rom under the block cache.
+        mbrd.cpu.cpu.flush();
+    }
+}
+
+fn failed(e: Box<EvalAltResult>) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Script: {}", e))
+}
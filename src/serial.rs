@@ -4,13 +4,38 @@
 // data byte at the time the transfer begins, the last one will go out again. Alternately, if it's ready to send the
 // next byte but the last one hasn't gone out yet, it has no choice but to wait.
 //
+// The emulated port drives the transfers with the clock cycles of its tick source: a master completes 8 bit periods
+// after it started, a slave completes when the byte of its master arrives. What is at the other end of the cable is
+// a SerialDevice, e.g. another emulator over the network or in the same process.
+//
 // See: http://gbdev.gg8.se/wiki/articles/Serial_Data_Transfer_(Link_Cable)
-use super::intf::Intf;
-use std::cell::RefCell;
-use std::rc::Rc;
+use super::clock::{Domain, Scheduler, Tick};
+use super::intf::{Flag, Intf};
+use super::link::Message;
+use super::state::{Reader, Snapshot, Writer};
+use std::io::Write;
+
+// The other side of the cable. It carries the messages of the protocol described in the link module.
+pub trait SerialDevice: Send {
+    fn send(&mut self, m: Message);
+
+    // Returns the next message if one has already arrived.
+    fn poll(&mut self) -> Option<Message>;
+
+    // Block until a message arrives, None if the other side is gone.
+    fn wait(&mut self) -> Option<Message>;
+
+    // Clock cycles a master transfer is allowed to overrun while waiting for the reply.
+    fn budget(&self) -> u32;
+
+    // Called while the game waits for a transfer clocked by the other side. A device that only clocks a byte in when
+    // the game is ready does it now, a peer with its own clock ignores it.
+    fn ready(&mut self) {}
+}
 
 pub struct Serial {
-    _intf: Rc<RefCell<Intf>>,
+    // The interrupts requested by the port, the MMU folds them into IF.
+    pub intf: Intf,
 
     // Before a transfer, it holds the next byte that will go out.
     // During a transfer, it has a blend of the outgoing and incoming bytes. Each cycle, the leftmost bit is shifted
@@ -20,11 +45,36 @@ pub struct Serial {
     // Bit 1 - Clock Speed (0=Normal, 1=Fast) ** CGB Mode Only **
     // Bit 0 - Shift Clock (0=External Clock, 1=Internal Clock)
     control: u8,
+
+    // The other side of the cable, if any. Without a cable, the input line is pulled up and a master reads 0xff.
+    pub link: Option<Box<dyn SerialDevice>>,
+    // Clock cycles left until all 8 bits of a transfer with internal clock have been shifted.
+    shift: u32,
+    // Clock cycles the current transfer has been waiting for the reply of the peer after its shift completed.
+    overrun: u32,
+    // The byte the peer shifted in during the current transfer with internal clock, if already received.
+    reply: Option<u8>,
+    // The link is polled once per bit, not on every instruction.
+    poll: u32,
+    // Every byte the game starts to send, with either clock, is also written here, whatever is at the other end of
+    // the cable. Homebrew and test roms print their debug output this way, e.g. Blargg's tests print their results.
+    pub console: Option<Box<dyn Write + Send>>,
 }
 
 impl Serial {
-    pub fn power_up(intf: Rc<RefCell<Intf>>) -> Self {
-        Self { _intf: intf, data: 0x00, control: 0x00 }
+    pub fn power_up(clock: &mut Scheduler) -> Self {
+        clock.register(Tick::Serial, Domain::Cpu, 1);
+        Self {
+            intf: Intf::power_up(),
+            data: 0x00,
+            control: 0x00,
+            link: None,
+            shift: 0,
+            overrun: 0,
+            reply: None,
+            poll: 0,
+            console: None,
+        }
     }
 
     pub fn get(&self, a: u16) -> u8 {
@@ -38,8 +88,129 @@ impl Serial {
     pub fn set(&mut self, a: u16, v: u8) {
         match a {
             0xff01 => self.data = v,
-            0xff02 => self.control = v,
+            0xff02 => {
+                self.control = v;
+                if let Some(console) = &mut self.console {
+                    // The reader may be gone, the game keeps running without it.
+                    if v & 0x80 == 0x80 && console.write_all(&[self.data]).and_then(|_| console.flush()).is_err() {
+                        self.console = None;
+                    }
+                }
+                if self.control & 0x81 == 0x81 {
+                    // The internal clock runs at 8192Hz, or 262144Hz in CGB fast mode. Double speed mode doubles both.
+                    let bit = if self.control & 0x02 == 0x02 { 16 } else { 512 };
+                    self.shift = bit * 8;
+                    self.overrun = 0;
+                    self.reply = None;
+                    if let Some(link) = &mut self.link {
+                        link.send(Message::Data(self.data));
+                    }
+                }
+            }
             _ => panic!("Only supports addresses 0xff01, 0xff02"),
         };
     }
+
+    pub fn next(&mut self, clock: &mut Scheduler) {
+        let cycles = clock.take(Tick::Serial);
+        if self.link.is_some() {
+            self.poll += cycles;
+            if self.poll >= 512 {
+                self.poll = 0;
+                if self.control & 0x81 == 0x80 {
+                    self.link.as_mut().unwrap().ready();
+                }
+                self.recv();
+            }
+        }
+        if self.control & 0x81 != 0x81 {
+            return;
+        }
+        if self.shift > cycles {
+            self.shift -= cycles;
+            return;
+        }
+        self.shift = 0;
+        if self.link.is_none() {
+            return self.done(0xff);
+        }
+        if self.reply.is_none() {
+            self.overrun += cycles;
+            if self.overrun <= self.link.as_ref().unwrap().budget() {
+                self.recv();
+            } else {
+                rog::debugln!("Link: latency budget exhausted, waiting for the peer");
+                let m = self.link.as_mut().unwrap().wait();
+                match m {
+                    Some(m) => self.handle(m),
+                    None => self.reply = Some(0xff),
+                }
+            }
+        }
+        if let Some(v) = self.reply.take() {
+            self.done(v);
+        }
+    }
+
+    fn recv(&mut self) {
+        while let Some(m) = self.link.as_mut().and_then(|link| link.poll()) {
+            self.handle(m);
+        }
+    }
+
+    fn handle(&mut self, m: Message) {
+        match m {
+            Message::Data(v) => {
+                if self.control & 0x81 == 0x81 {
+                    // Both sides are master: the bytes crossed on the wire.
+                    self.reply = Some(v);
+                    return;
+                }
+                let data = self.data;
+                self.link.as_mut().unwrap().send(Message::Reply(data));
+                if self.control & 0x81 == 0x80 {
+                    self.done(v);
+                }
+            }
+            Message::Reply(v) => {
+                // A reply to a transfer that already timed out is dropped.
+                if self.control & 0x81 == 0x81 {
+                    self.reply = Some(v);
+                }
+            }
+        }
+    }
+
+    // A transfer completes: the incoming byte is in the shift register, the start flag is reset and an interrupt is
+    // requested.
+    fn done(&mut self, v: u8) {
+        self.data = v;
+        self.control &= 0x7f;
+        self.intf.hi(Flag::Serial);
+    }
+}
+
+// The link cable itself is not part of the state, a transfer in flight is resolved by the peer as usual.
+impl Snapshot for Serial {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.data);
+        w.u8(self.control);
+        w.u32(self.shift);
+        w.u32(self.overrun);
+        w.bool(self.reply.is_some());
+        w.u8(self.reply.unwrap_or(0x00));
+        w.u32(self.poll);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.data = r.u8()?;
+        self.control = r.u8()?;
+        self.shift = r.u32()?;
+        self.overrun = r.u32()?;
+        let reply = r.bool()?;
+        let v = r.u8()?;
+        self.reply = if reply { Some(v) } else { None };
+        self.poll = r.u32()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,113 @@
+// The Super Game Boy runs the game on a SNES, and games talk to it by sending command packets through the joypad
+// register. Only the multiplayer command is emulated: with it, a game reads up to four joypads, e.g. for the
+// 2-player modes of some SGB games and homebrew. The other commands (borders, palettes, sound) are received and
+// ignored, the game keeps running as on a DMG.
+//
+// Packet transfer
+// A packet starts with a reset pulse, P14 and P15 both low. Then 128 bits follow, least significant bit of each byte
+// first: a pulse of P14 low is a 0, a pulse of P15 low is a 1, and both lines go high again between two pulses. A
+// last 0 bit ends the packet. The first byte of the first packet of a command is the command code times 8 plus the
+// number of packets of the command.
+//
+// MLT_REQ (11h)
+// Byte 1 selects the number of joypads: 0 for one, 1 for two, 3 for four. While more than one joypad is enabled,
+// reading JOYP with P14 and P15 both high returns the ID of the current joypad in the low nibble, 0Fh for the first
+// one down to 0Ch for the fourth, and raising both lines switches to the next joypad. Games detect the SGB this way.
+//
+// See: https://gbdev.io/pandocs/SGB_Command_System.html
+use super::state::{Reader, Snapshot, Writer};
+
+const MLT_REQ: u8 = 0x11;
+
+pub struct Sgb {
+    // Bits received since the reset pulse, None while no packet is being received.
+    bits: Option<Vec<bool>>,
+    // Packets left of the command being received, they carry data and no command code.
+    remaining: u8,
+    // P14 and P15 as last written.
+    lines: u8,
+    pub players: u8,
+    pub player: u8,
+}
+
+impl Sgb {
+    pub fn power_up() -> Self {
+        Self { bits: None, remaining: 0, lines: 0x30, players: 1, player: 0 }
+    }
+
+    // A write to JOYP.
+    pub fn set(&mut self, v: u8) {
+        let lines = v & 0x30;
+        if lines == self.lines {
+            return;
+        }
+        let previous = self.lines;
+        self.lines = lines;
+        match lines {
+            0x00 => self.bits = Some(Vec::with_capacity(128)),
+            0x10 | 0x20 if previous == 0x30 => {
+                let bit = lines == 0x10;
+                if let Some(bits) = &mut self.bits {
+                    if bits.len() < 128 {
+                        bits.push(bit);
+                    } else {
+                        // The stop bit.
+                        let packet = std::mem::take(bits);
+                        self.bits = None;
+                        self.packet(&packet);
+                    }
+                }
+            }
+            0x30 if self.players > 1 => self.player = (self.player + 1) % self.players,
+            _ => {}
+        }
+    }
+
+    fn packet(&mut self, bits: &[bool]) {
+        let byte = |i: usize| bits[i * 8..i * 8 + 8].iter().rev().fold(0u8, |b, &bit| (b << 1) | u8::from(bit));
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            return;
+        }
+        let command = byte(0) >> 3;
+        self.remaining = (byte(0) & 0x07).saturating_sub(1);
+        rog::debugln!("SGB: command {:02x}", command);
+        if command == MLT_REQ {
+            self.players = match byte(1) & 0x03 {
+                0x01 => 2,
+                0x03 => 4,
+                _ => 1,
+            };
+            self.player = 0;
+        }
+    }
+
+    // The low nibble of JOYP while neither P14 nor P15 is low.
+    pub fn id(&self) -> u8 {
+        0x0f - self.player
+    }
+}
+
+// A packet being received is lost, the game sends it again if it matters.
+impl Snapshot for Sgb {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.lines);
+        w.u8(self.players);
+        w.u8(self.player);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.lines = r.u8()?;
+        let players = r.u8()?;
+        let player = r.u8()?;
+        // The SGB only answers for 1, 2 or 4 players.
+        if ![1, 2, 4].contains(&players) || player >= players {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "State: SGB player out of range"));
+        }
+        self.players = players;
+        self.player = player;
+        self.bits = None;
+        self.remaining = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+// A save state is a snapshot of the whole machine, which can be written to a file and restored later.
+//
+// File format
+// The file starts with the magic "GBST" and a version byte, followed by one section per component. Each section is a
+// 4 bytes tag, the length of its payload as a little endian u32 and the payload itself:
+//   CPU   CPU registers and state
+//   MMU   Work RAM, high RAM, interrupt registers, HDMA, joypad select lines, SGB multiplayer state, infrared port
+//         and whether the boot ROM is mapped
+//   GPU   Video RAM, OAM, palettes, LCD registers and the frame being drawn
+//   APU   Sound registers and the internal state of the four channels
+//   TIME  Divider and timer
+//   CLCK  Counters of the tick sources
+//   SERI  Serial port
+//   CART  Cartridge RAM, banking registers and clock
+// Inside a payload, the fields are written in declaration order, all integers in little endian.
+use super::cartridge::Cartridge;
+use super::error;
+use super::motherboard::MotherBoard;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"GBST";
+const VERSION: u8 = 0x0a;
+
+pub trait Snapshot {
+    fn save(&self, w: &mut Writer);
+
+    fn load(&mut self, r: &mut Reader) -> Result<()>;
+}
+
+#[derive(Default)]
+pub struct Writer {
+    pub data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(u8::from(v));
+    }
+
+    // Fixed size data, the reader must know its length.
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+    }
+
+    // Variable size data, prefixed with its length.
+    pub fn vec(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.bytes(v);
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, i: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.i < n {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "State: truncated section"));
+        }
+        let r = &self.data[self.i..self.i + n];
+        self.i += n;
+        Ok(r)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0x00)
+    }
+
+    pub fn bytes(&mut self, v: &mut [u8]) -> Result<()> {
+        v.copy_from_slice(self.take(v.len())?);
+        Ok(())
+    }
+
+    // Read variable size data into a buffer that already has the expected length, e.g. the cartridge RAM.
+    pub fn vec(&mut self, v: &mut [u8]) -> Result<()> {
+        if self.u32()? as usize != v.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "State: size mismatch"));
+        }
+        self.bytes(v)
+    }
+
+    // A section must be consumed entirely, otherwise it was written by a different layout.
+    pub fn finish(&self) -> Result<()> {
+        if self.i != self.data.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "State: trailing data in section"));
+        }
+        Ok(())
+    }
+}
+
+pub fn encode(sections: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.bytes(MAGIC);
+    w.u8(VERSION);
+    for (tag, data) in sections {
+        w.bytes(&tag[..]);
+        w.vec(data);
+    }
+    w.data
+}
+
+pub fn decode(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    if data.len() < 5 || &data[..4] != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "State: not a save state"));
+    }
+    if data[4] != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("State: unsupported version {}", data[4])));
+    }
+    let mut r = Reader::new(&data[5..]);
+    let mut sections = vec![];
+    while r.i < r.data.len() {
+        let mut tag = [0x00; 4];
+        r.bytes(&mut tag)?;
+        let n = r.u32()? as usize;
+        sections.push((tag, r.take(n)?));
+    }
+    Ok(sections)
+}
+
+// The numbered save states of a game, 1 to SLOTS. A slot is a file named after the title and the global checksum of the
+// cartridge, e.g. "TETRIS-16bf.3.state", so that a game finds its states again when the rom is renamed and two games
+// with the same file name do not share theirs.
+pub const SLOTS: usize = 10;
+
+pub struct Slots {
+    dir: PathBuf,
+    name: String,
+}
+
+impl Slots {
+    // The slots of the game in cart, kept in dir.
+    pub fn power_up(dir: impl AsRef<Path>, cart: &dyn Cartridge) -> Self {
+        let title: String = cart.title().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        let checksum = u16::from_be_bytes([cart.get(0x014e), cart.get(0x014f)]);
+        Self { dir: dir.as_ref().to_path_buf(), name: format!("{}-{:04x}", title, checksum) }
+    }
+
+    pub fn path(&self, n: usize) -> PathBuf {
+        self.dir.join(format!("{}.{}.state", self.name, n))
+    }
+
+    pub fn save(&self, n: usize, mbrd: &MotherBoard) -> error::Result<PathBuf> {
+        let path = self.check(n)?;
+        mbrd.save_state(&path)?;
+        Ok(path)
+    }
+
+    pub fn load(&self, n: usize, mbrd: &mut MotherBoard) -> error::Result<PathBuf> {
+        let path = self.check(n)?;
+        if !path.is_file() {
+            let e = Error::new(ErrorKind::NotFound, format!("State: slot {} is empty", n));
+            return Err(error::Error::State(e));
+        }
+        mbrd.load_state(&path)?;
+        Ok(path)
+    }
+
+    fn check(&self, n: usize) -> error::Result<PathBuf> {
+        if !(1..=SLOTS).contains(&n) {
+            let e = Error::new(ErrorKind::InvalidInput, format!("State: no slot {}", n));
+            return Err(error::Error::State(e));
+        }
+        Ok(self.path(n))
+    }
+}
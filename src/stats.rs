@@ -0,0 +1,131 @@
+// Performance statistics of every frame: the clock cycles run, and where the time of the host went between the CPU,
+// the GPU, the APU, the rest of the machine and the sleeps of the speed limiter. Frontends draw them over the screen
+// as a profiler, and users report a slow game with them rather than with a feeling.
+//
+// MotherBoard::measure turns them on. Measuring reads the host clock a few times per instruction, which costs some
+// speed, so it is off by default. A frame ends when it is handed over by check_and_reset_gpu_updated, and its total
+// time runs from the end of the last one: what isn't in the other counters was spent by the caller, e.g. drawing the
+// window. The std clock is not there in the browser, the web frontend doesn't measure.
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameStats {
+    // The frames measured since MotherBoard::measure, this one included.
+    pub frame: u64,
+    // Clock cycles run at the clock of the GPU, 70224 for a frame of the LCD at either speed of the CPU.
+    pub cycles: u64,
+    pub instructions: u64,
+    // The CPU with its accesses to the bus, the sleeps of the speed limiter left out.
+    pub cpu: Duration,
+    pub gpu: Duration,
+    pub apu: Duration,
+    // The DMAs, the timer and the serial port.
+    pub other: Duration,
+    // Slept by the speed limiter, see Rtc::throttle.
+    pub sleep: Duration,
+    pub total: Duration,
+}
+
+impl FrameStats {
+    fn add(&mut self, o: &Self) {
+        self.cycles += o.cycles;
+        self.instructions += o.instructions;
+        self.cpu += o.cpu;
+        self.gpu += o.gpu;
+        self.apu += o.apu;
+        self.other += o.other;
+        self.sleep += o.sleep;
+        self.total += o.total;
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{} cycles, {} instructions, cpu {:.2} ms, gpu {:.2} ms, apu {:.2} ms, other {:.2} ms, sleep {:.2} ms, \
+             total {:.2} ms",
+            self.cycles,
+            self.instructions,
+            ms(self.cpu),
+            ms(self.gpu),
+            ms(self.apu),
+            ms(self.other),
+            ms(self.sleep),
+            ms(self.total)
+        )
+    }
+}
+
+pub struct Stats {
+    // The frame being measured.
+    pub current: FrameStats,
+    // The last frame measured, once there is one.
+    pub last: Option<FrameStats>,
+    // All the frames measured added up, frame is their count.
+    pub sum: FrameStats,
+    // The end of the last frame, and the last reading of the host clock.
+    start: Instant,
+    mark: Instant,
+}
+
+impl Stats {
+    pub fn power_up() -> Self {
+        let now = Instant::now();
+        Self {
+            current: FrameStats { frame: 1, ..FrameStats::default() },
+            last: None,
+            sum: FrameStats::default(),
+            start: now,
+            mark: now,
+        }
+    }
+
+    // The time since the last lap.
+    pub fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let r = now - self.mark;
+        self.mark = now;
+        r
+    }
+
+    // Add the time since the last lap to a counter of the current frame, e.g. add(|f| &mut f.gpu).
+    pub fn add(&mut self, counter: fn(&mut FrameStats) -> &mut Duration) {
+        let d = self.lap();
+        *counter(&mut self.current) += d;
+    }
+
+    // End the current frame.
+    pub fn frame(&mut self) {
+        let now = Instant::now();
+        self.current.total = now - self.start;
+        self.start = now;
+        self.sum.add(&self.current);
+        self.sum.frame = self.current.frame;
+        let next = FrameStats { frame: self.current.frame + 1, ..FrameStats::default() };
+        self.last = Some(std::mem::replace(&mut self.current, next));
+    }
+
+    // The mean of the frames measured, None before the first one.
+    pub fn average(&self) -> Option<FrameStats> {
+        let n = self.sum.frame;
+        if n == 0 {
+            return None;
+        }
+        let s = &self.sum;
+        let d = |d: Duration| d / n as u32;
+        Some(FrameStats {
+            frame: n,
+            cycles: s.cycles / n,
+            instructions: s.instructions / n,
+            cpu: d(s.cpu),
+            gpu: d(s.gpu),
+            apu: d(s.apu),
+            other: d(s.other),
+            sleep: d(s.sleep),
+            total: d(s.total),
+        })
+    }
+}
@@ -0,0 +1,102 @@
+// Fit the sound of an emulation running faster than real time, e.g. while fast-forwarding, into real time. At N times
+// the normal speed the APU makes N seconds of samples per second, and only one of them can be played:
+//
+//   Mute      Nothing is played.
+//   Decimate  Every N samples are averaged into one. The sound plays N times faster, and N times higher.
+//   Stretch   The samples are cut into grains of 20 ms and one grain of every N is played, each faded into the next
+//             one over 5 ms. The pitch is kept, so music and sound effects stay recognizable.
+//
+// This sits between the APU and the buffer played by the frontend, the sinks still get every sample.
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Mute,
+    Decimate,
+    Stretch,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mute" => Ok(Mode::Mute),
+            "decimate" => Ok(Mode::Decimate),
+            "stretch" => Ok(Mode::Stretch),
+            _ => Err(format!("Unsupported fast-forward audio: {}", s)),
+        }
+    }
+}
+
+pub struct Stretch {
+    pub mode: Mode,
+    // How many times faster than real time the samples come, 1 plays them all.
+    pub rate: u32,
+    // Samples of a grain, and of the fade between two grains.
+    grain: usize,
+    fade: usize,
+    // Position in the current period of rate grains.
+    n: usize,
+    // The samples which follow the last grain played, faded out into the next one.
+    tail: Vec<(f32, f32)>,
+    // Sum and count of the samples being averaged by Decimate.
+    sum: (f32, f32),
+    count: u32,
+}
+
+impl Stretch {
+    pub fn power_up(sample_rate: u32) -> Self {
+        let grain = (sample_rate / 50).max(1) as usize;
+        Self { mode: Mode::Mute, rate: 1, grain, fade: grain / 4, n: 0, tail: Vec::new(), sum: (0.0, 0.0), count: 0 }
+    }
+
+    // Append to out the part of the samples to play.
+    pub fn push(&mut self, samples: &[(f32, f32)], out: &mut Vec<(f32, f32)>) {
+        if self.rate <= 1 {
+            self.n = 0;
+            self.tail.clear();
+            self.count = 0;
+            out.extend_from_slice(samples);
+            return;
+        }
+        match self.mode {
+            Mode::Mute => {}
+            Mode::Decimate => {
+                for &(l, r) in samples {
+                    self.sum = (self.sum.0 + l, self.sum.1 + r);
+                    self.count += 1;
+                    if self.count >= self.rate {
+                        let n = self.count as f32;
+                        out.push((self.sum.0 / n, self.sum.1 / n));
+                        self.sum = (0.0, 0.0);
+                        self.count = 0;
+                    }
+                }
+            }
+            Mode::Stretch => {
+                let period = self.grain * self.rate as usize;
+                for &s in samples {
+                    let p = self.n;
+                    self.n = (self.n + 1) % period;
+                    if p < self.fade {
+                        match self.tail.get(p) {
+                            Some(&t) => {
+                                let k = (p as f32 + 0.5) / self.fade as f32;
+                                out.push((t.0 * (1.0 - k) + s.0 * k, t.1 * (1.0 - k) + s.1 * k));
+                            }
+                            None => out.push(s),
+                        }
+                    } else if p < self.grain {
+                        out.push(s);
+                    } else if p < self.grain + self.fade {
+                        if p == self.grain {
+                            self.tail.clear();
+                        }
+                        self.tail.push(s);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -4,10 +4,9 @@
 // with the contents of Timer Modulo (TMA).
 //
 // See: http://gbdev.gg8.se/wiki/articles/Timer_and_Divider_Registers
-use super::clock::Clock;
+use super::clock::{Domain, Scheduler, Tick};
 use super::intf::{Flag, Intf};
-use std::cell::RefCell;
-use std::rc::Rc;
+use super::state::{Reader, Snapshot, Writer};
 
 #[derive(Default)]
 struct Register {
@@ -34,15 +33,23 @@ struct Register {
 // setting Bit 2 in the IF Register (FF0F). When that interrupt is enabled, then the CPU will execute it by calling
 // the timer interrupt vector at 0050h.
 pub struct Timer {
-    intf: Rc<RefCell<Intf>>,
+    // The interrupts requested by the timer, the MMU folds them into IF.
+    pub intf: Intf,
     reg: Register,
-    div_clock: Clock,
-    tma_clock: Clock,
 }
 
 impl Timer {
-    pub fn power_up(intf: Rc<RefCell<Intf>>) -> Self {
-        Timer { intf, reg: Register::default(), div_clock: Clock::power_up(256), tma_clock: Clock::power_up(1024) }
+    pub fn power_up(clock: &mut Scheduler) -> Self {
+        clock.register(Tick::Div, Domain::Cpu, 256);
+        clock.register(Tick::Tima, Domain::Cpu, 1024);
+        clock.enable(Tick::Tima, false);
+        Timer { intf: Intf::power_up(), reg: Register::default() }
+    }
+
+    // Set DIV to the value the boot ROM leaves behind.
+    pub fn set_div(&mut self, clock: &mut Scheduler, v: u8) {
+        self.reg.div = v;
+        clock.reset(Tick::Div);
     }
 
     pub fn get(&self, a: u16) -> u8 {
@@ -55,47 +62,62 @@ impl Timer {
         }
     }
 
-    pub fn set(&mut self, a: u16, v: u8) {
+    pub fn set(&mut self, clock: &mut Scheduler, a: u16, v: u8) {
         match a {
             0xff04 => {
                 self.reg.div = 0x00;
-                self.div_clock.n = 0x00;
+                clock.reset(Tick::Div);
             }
             0xff05 => self.reg.tima = v,
             0xff06 => self.reg.tma = v,
             0xff07 => {
                 if (self.reg.tac & 0x03) != (v & 0x03) {
-                    self.tma_clock.n = 0x00;
-                    self.tma_clock.period = match v & 0x03 {
+                    let period = match v & 0x03 {
                         0x00 => 1024,
                         0x01 => 16,
                         0x02 => 64,
                         0x03 => 256,
                         _ => panic!(""),
                     };
+                    clock.set_period(Tick::Tima, period);
                     self.reg.tima = self.reg.tma;
                 }
+                // Timer Enable
+                clock.enable(Tick::Tima, v & 0x04 != 0x00);
                 self.reg.tac = v;
             }
             _ => panic!("Unsupported address"),
         }
     }
 
-    pub fn next(&mut self, cycles: u32) {
+    pub fn next(&mut self, clock: &mut Scheduler) {
         // Increment div at rate of 16384Hz. Because the clock cycles is 4194304, so div increment every 256 cycles.
-        self.reg.div = self.reg.div.wrapping_add(self.div_clock.next(cycles) as u8);
+        self.reg.div = self.reg.div.wrapping_add(clock.take(Tick::Div) as u8);
 
-        // Increment tima at rate of Clock / freq
-        // Timer Enable
-        if (self.reg.tac & 0x04) != 0x00 {
-            let n = self.tma_clock.next(cycles);
-            for _ in 0..n {
-                self.reg.tima = self.reg.tima.wrapping_add(1);
-                if self.reg.tima == 0x00 {
-                    self.reg.tima = self.reg.tma;
-                    self.intf.borrow_mut().hi(Flag::Timer);
-                }
+        // Increment tima at rate of Clock / freq, the source only counts while the timer is enabled.
+        for _ in 0..clock.take(Tick::Tima) {
+            self.reg.tima = self.reg.tima.wrapping_add(1);
+            if self.reg.tima == 0x00 {
+                self.reg.tima = self.reg.tma;
+                self.intf.hi(Flag::Timer);
             }
         }
     }
 }
+
+impl Snapshot for Timer {
+    fn save(&self, w: &mut Writer) {
+        w.u8(self.reg.div);
+        w.u8(self.reg.tima);
+        w.u8(self.reg.tma);
+        w.u8(self.reg.tac);
+    }
+
+    fn load(&mut self, r: &mut Reader) -> std::io::Result<()> {
+        self.reg.div = r.u8()?;
+        self.reg.tima = r.u8()?;
+        self.reg.tma = r.u8()?;
+        self.reg.tac = r.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+// Verify a rom dump. A bad dump crashes or glitches the same way an emulation bug does, so before looking for the
+// latter it helps to know the rom is good: its CRC32 and SHA-1 are compared with the ones of a known good dump, as
+// listed in the DAT files of No-Intro.
+//
+// Both DAT formats are read:
+//   Logiqx XML   <game name="..."><rom name="..." size="..." crc="..." sha1="..."/></game>, the format of DAT-o-MATIC.
+//   ClrMamePro   game ( name "..." rom ( name "..." size ... crc ... sha1 ... ) ), the older text format.
+// A rom is matched by its SHA-1 when the DAT has one, by its size and CRC32 otherwise.
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Digest {
+    pub size: u64,
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+
+impl Digest {
+    pub fn of(rom: &[u8]) -> Self {
+        Self { size: rom.len() as u64, crc32: crc32(rom), sha1: sha1(rom) }
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        self.sha1.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "crc32 {:08x} sha1 {}", self.crc32, self.sha1_hex())
+    }
+}
+
+// CRC-32 as used by zip and png: polynomial EDB88320, reflected, initial value and final xor FFFFFFFF.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(0xffff_ffff, |c, &b| CRC32_TABLE[((c ^ u32::from(b)) & 0xff) as usize] ^ (c >> 8))
+}
+
+// See: https://www.rfc-editor.org/rfc/rfc3174
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0];
+    // The message is padded with a 1 bit, zeros, and its length in bits, to a multiple of 64 bytes.
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0x00);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(chunk[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut r = [0u8; 20];
+    for (i, v) in h.iter().enumerate() {
+        r[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    r
+}
+
+// A rom of a DAT file, named after its game.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub size: Option<u64>,
+    pub crc32: Option<u32>,
+    pub sha1: Option<[u8; 20]>,
+}
+
+impl Entry {
+    fn matches(&self, digest: &Digest) -> bool {
+        match self.sha1 {
+            Some(sha1) => sha1 == digest.sha1,
+            None => self.crc32 == Some(digest.crc32) && self.size.is_none_or(|s| s == digest.size),
+        }
+    }
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Entry>> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+pub fn parse(text: &str) -> Result<Vec<Entry>> {
+    let entries = if text.trim_start().starts_with('<') { parse_xml(text) } else { parse_clrmamepro(text) };
+    if entries.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "Verify: no rom found in the DAT"));
+    }
+    Ok(entries)
+}
+
+// The game of a known good dump with the same digest, if any.
+pub fn find<'a>(dat: &'a [Entry], digest: &Digest) -> Option<&'a Entry> {
+    dat.iter().find(|e| e.matches(digest))
+}
+
+fn entry(name: &str, size: Option<&str>, crc: Option<&str>, sha1: Option<&str>) -> Entry {
+    Entry {
+        name: name.to_string(),
+        size: size.and_then(|s| s.parse().ok()),
+        crc32: crc.and_then(|s| u32::from_str_radix(s, 16).ok()),
+        sha1: sha1.and_then(parse_sha1),
+    }
+}
+
+fn parse_sha1(s: &str) -> Option<[u8; 20]> {
+    if s.len() != 40 || !s.is_ascii() {
+        return None;
+    }
+    let mut r = [0u8; 20];
+    for (i, b) in r.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(r)
+}
+
+fn parse_xml(text: &str) -> Vec<Entry> {
+    let mut entries = vec![];
+    for game in text.split("<game ").skip(1) {
+        let game = game.split("</game>").next().unwrap();
+        let name = attr(game, "name").unwrap_or_default();
+        for rom in game.split("<rom ").skip(1) {
+            let rom = rom.split('>').next().unwrap();
+            let (size, crc, sha1) = (attr(rom, "size"), attr(rom, "crc"), attr(rom, "sha1"));
+            entries.push(entry(&name, size.as_deref(), crc.as_deref(), sha1.as_deref()));
+        }
+    }
+    entries
+}
+
+// The value of the attribute key="value" in the text of a tag.
+fn attr(tag: &str, key: &str) -> Option<String> {
+    let pattern = format!("{}=\"", key);
+    let (start, _) =
+        tag.match_indices(&pattern).find(|&(i, _)| i == 0 || tag.as_bytes()[i - 1].is_ascii_whitespace())?;
+    let value = &tag[start + key.len() + 2..];
+    let value = &value[..value.find('"')?];
+    Some(
+        value
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&"),
+    )
+}
+
+fn parse_clrmamepro(text: &str) -> Vec<Entry> {
+    // Words, quoted strings and parentheses.
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            tokens.push(c.to_string());
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            tokens.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| !c.is_whitespace() && **c != '(' && **c != ')') {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        }
+    }
+    let mut entries = vec![];
+    let mut name = String::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match tokens[i].as_str() {
+            "game" if tokens.get(i + 1).map(String::as_str) == Some("(") => {
+                name = String::new();
+                i += 2;
+            }
+            "name" if name.is_empty() => {
+                name = tokens.get(i + 1).cloned().unwrap_or_default();
+                i += 2;
+            }
+            "rom" if tokens.get(i + 1).map(String::as_str) == Some("(") => {
+                let end = tokens[i..].iter().position(|t| t == ")").map_or(tokens.len(), |n| i + n);
+                let fields = &tokens[i + 2..end];
+                let field = |key: &str| fields.chunks(2).find(|kv| kv[0] == key && kv.len() == 2).map(|kv| &*kv[1]);
+                entries.push(entry(&name, field("size"), field("crc"), field("sha1")));
+                i = end + 1;
+            }
+            _ => i += 1,
+        }
+    }
+    entries
+}
@@ -0,0 +1,229 @@
+// Filters applied to a frame before it is shown, after it was copied out of the GPU like the on screen display. Each
+// filter makes a larger picture out of the 160x144 screen, so the frontend shows a buffer of factor() times the size:
+//
+//   None       The frame as it is.
+//   Scanlines  Every line doubled, the second one darker, like the dark gaps between the lines of a CRT.
+//   Lcd        Every pixel made of 3x3, with a darker right column and bottom row, like the grid of the DMG LCD.
+//   Xbr        Hyllian's 2xBR: edges between two colors are found by the difference of the colors around each
+//              pixel, and smoothed at twice the size. Pixel art keeps its sharp corners, which a plain bilinear
+//              scaling would blur.
+//
+// Blend mixes each frame with the one before it, before the filter. Zoom scales the result by a whole factor, e.g. to
+// fill a fullscreen window without blurring the pixels.
+//
+// The buffers have one u32 per pixel in 0RGB, as the window buffer of minifb, the top byte is ignored. They can have
+// any size, so other frontends, or a frontend which shows more than the screen, can use them as well.
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    None,
+    Scanlines,
+    Lcd,
+    Xbr,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Filter::None),
+            "scanlines" => Ok(Filter::Scanlines),
+            "lcd" => Ok(Filter::Lcd),
+            "xbr" => Ok(Filter::Xbr),
+            _ => Err(format!("Unsupported filter: {}", s)),
+        }
+    }
+}
+
+impl Filter {
+    // How many times wider and taller the filtered picture is.
+    pub fn factor(self) -> usize {
+        match self {
+            Filter::None => 1,
+            Filter::Scanlines => 2,
+            Filter::Lcd => 3,
+            Filter::Xbr => 2,
+        }
+    }
+
+    // Filter the w x h picture in src into dst, which is factor() times as wide and tall.
+    pub fn apply(self, src: &[u32], w: usize, h: usize, dst: &mut [u32]) {
+        let f = self.factor();
+        assert_eq!(src.len(), w * h);
+        assert_eq!(dst.len(), w * h * f * f);
+        match self {
+            Filter::None => dst.copy_from_slice(src),
+            Filter::Scanlines => {
+                for y in 0..h {
+                    for x in 0..w {
+                        let p = src[y * w + x];
+                        let o = y * 2 * w * 2 + x * 2;
+                        dst[o] = p;
+                        dst[o + 1] = p;
+                        dst[o + w * 2] = mix(p, 0x000000, 128);
+                        dst[o + w * 2 + 1] = mix(p, 0x000000, 128);
+                    }
+                }
+            }
+            Filter::Lcd => {
+                for y in 0..h {
+                    for x in 0..w {
+                        let p = src[y * w + x];
+                        let gap = mix(p, 0x000000, 64);
+                        for j in 0..3 {
+                            for i in 0..3 {
+                                dst[(y * 3 + j) * w * 3 + x * 3 + i] = if i == 2 || j == 2 { gap } else { p };
+                            }
+                        }
+                    }
+                }
+            }
+            Filter::Xbr => xbr(src, w, h, dst),
+        }
+    }
+}
+
+// Scale the w x h picture in src into dst by k, each pixel made of k x k.
+pub fn zoom(src: &[u32], w: usize, h: usize, k: usize, dst: &mut [u32]) {
+    assert_eq!(src.len(), w * h);
+    assert_eq!(dst.len(), w * h * k * k);
+    for (y, row) in dst.chunks_exact_mut(w * k).enumerate() {
+        let line = &src[y / k * w..(y / k + 1) * w];
+        for (x, p) in row.iter_mut().enumerate() {
+            *p = line[x / k];
+        }
+    }
+}
+
+// The pixels of the DMG LCD are slow: one that changes takes about a frame to get there, so a sprite shown on every
+// other frame looks half transparent, and fast moving sprites leave a trail. Some games draw their shadows, fog or
+// water this way. Blend shows each frame mixed half and half with the one before it, which looks the same.
+pub struct Blend {
+    // The last frame, as it came from the GPU.
+    prev: Vec<u32>,
+}
+
+impl Blend {
+    pub fn power_up() -> Self {
+        Self { prev: Vec::new() }
+    }
+
+    // Mix the frame with the last one, and keep it for the next.
+    pub fn apply(&mut self, frame: &mut [u32]) {
+        if self.prev.len() != frame.len() {
+            self.prev = frame.to_vec();
+        }
+        for (p, q) in frame.iter_mut().zip(self.prev.iter_mut()) {
+            let cur = *p;
+            *p = mix(cur, *q, 128);
+            *q = cur;
+        }
+    }
+}
+
+// Each 8 bit component of a blended with b, by k / 256 of b.
+fn mix(a: u32, b: u32, k: u32) -> u32 {
+    let mut r = 0;
+    for shift in [0, 8, 16] {
+        let ca = a >> shift & 0xff;
+        let cb = b >> shift & 0xff;
+        r |= ((ca * (256 - k) + cb * k) >> 8) << shift;
+    }
+    r
+}
+
+// The difference of two colors as seen by the eye, mostly in brightness, which is what the edges are found with.
+fn diff(a: u32, b: u32) -> i32 {
+    let yuv = |p: u32| {
+        let (r, g, b) = ((p >> 16 & 0xff) as i32, (p >> 8 & 0xff) as i32, (p & 0xff) as i32);
+        let y = (r * 299 + g * 587 + b * 114) / 1000;
+        let u = (-r * 169 - g * 331 + b * 500) / 1000;
+        let v = (r * 500 - g * 419 - b * 81) / 1000;
+        (y, u, v)
+    };
+    let (a, b) = (yuv(a), yuv(b));
+    (a.0 - b.0).abs() * 48 + (a.1 - b.1).abs() * 7 + (a.2 - b.2).abs() * 6
+}
+
+fn same(a: u32, b: u32) -> bool {
+    diff(a, b) < 155
+}
+
+// 2xBR. Every pixel E becomes 2x2 pixels, and each of its corners is checked for an edge which cuts it, with the
+// neighbours named as below for the bottom right corner. The other three corners are the same, rotated.
+//
+//           A1 B1 C1
+//        A0  A  B  C C4
+//        D0  D  E  F F4
+//        G0  G  H  I I4
+//           G5 H5 I5
+fn xbr(src: &[u32], width: usize, height: usize, dst: &mut [u32]) {
+    let at = |x: usize, y: usize, dx: i32, dy: i32| {
+        let x = (x as i32 + dx).clamp(0, width as i32 - 1) as usize;
+        let y = (y as i32 + dy).clamp(0, height as i32 - 1) as usize;
+        src[y * width + x] & 0xffffff
+    };
+    // Turn the offsets of the bottom right corner by a quarter, clockwise, as many times as asked.
+    let turn = |dx: i32, dy: i32, n: usize| (0..n).fold((dx, dy), |(x, y), _| (-y, x));
+    for y in 0..height {
+        for x in 0..width {
+            let mut out = [at(x, y, 0, 0); 4];
+            for n in 0..4 {
+                let p = |dx: i32, dy: i32| {
+                    let (dx, dy) = turn(dx, dy, n);
+                    at(x, y, dx, dy)
+                };
+                let (b, c, d, e, f, g, h, i) =
+                    (p(0, -1), p(1, -1), p(-1, 0), p(0, 0), p(1, 0), p(-1, 1), p(0, 1), p(1, 1));
+                let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+                if e == h || e == f {
+                    continue;
+                }
+                // The weight of an edge along H-F against one along E-I.
+                let we = diff(e, c) + diff(e, g) + diff(i, h5) + diff(i, f4) + (diff(h, f) << 2);
+                let wi = diff(h, d) + diff(h, i5) + diff(f, i4) + diff(f, b) + (diff(e, i) << 2);
+                let px = if diff(e, f) <= diff(e, h) { f } else { h };
+                // The 2x2 pixels of E, as the corner, the one left of it and the one above it, turned too.
+                let slot = |dx: i32, dy: i32| {
+                    let (dx, dy) = turn(dx, dy, n);
+                    usize::from(dy > 0) * 2 + usize::from(dx > 0)
+                };
+                let (n3, n2, n1) = (slot(1, 1), slot(-1, 1), slot(1, -1));
+                let edge = (!same(f, b) && !same(h, d))
+                    || (same(e, i) && !same(f, i4) && !same(h, i5))
+                    || same(e, g)
+                    || same(e, c);
+                if we < wi && edge {
+                    let ke = diff(f, g);
+                    let ki = diff(h, c);
+                    let ex2 = e != c && b != c;
+                    let ex3 = e != g && d != g;
+                    let left = (ke << 1) <= ki && ex3;
+                    let up = ke >= (ki << 1) && ex2;
+                    if left && up {
+                        out[n3] = px;
+                        out[n2] = mix(out[n2], px, 64);
+                        out[n1] = mix(out[n1], px, 64);
+                    } else if left {
+                        out[n3] = mix(out[n3], px, 192);
+                        out[n2] = mix(out[n2], px, 64);
+                    } else if up {
+                        out[n3] = mix(out[n3], px, 192);
+                        out[n1] = mix(out[n1], px, 64);
+                    } else {
+                        out[n3] = mix(out[n3], px, 128);
+                    }
+                } else if we <= wi {
+                    out[n3] = mix(out[n3], px, 128);
+                }
+            }
+            let o = y * 2 * width * 2 + x * 2;
+            dst[o] = out[0];
+            dst[o + 1] = out[1];
+            dst[o + width * 2] = out[2];
+            dst[o + width * 2 + 1] = out[3];
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// Watchpoints catch the instruction which reads or writes an address of a range, e.g. the one which corrupts a save
+// in the battery backed RAM. While there are watchpoints, the CPU runs each instruction on a Watched bus around the
+// real one, which notes the first access to a watched address. Only the accesses of the CPU are watched: the DMAs,
+// the debugger and the tools that read memory through the bus don't hit them. An instruction reading its own bytes
+// isn't a hit either, so a range of code can be watched for the data read from it.
+//
+// A watchpoint is written <start>[-<end>][:r|w|rw], in hex, e.g. c0a0, a000-bfff or ff40:rw. It watches writes
+// unless told otherwise.
+use super::memory::Memory;
+use std::cell::Cell;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[derive(Clone)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, mode) = s.split_once(':').unwrap_or((s, "w"));
+        let (read, write) = match mode {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" => (true, true),
+            _ => return Err(format!("Unsupported watchpoint mode: {}", mode)),
+        };
+        let addr = |s: &str| u16::from_str_radix(s.trim_start_matches("0x"), 16);
+        let (start, end) = match range.split_once('-') {
+            Some((a, b)) => (addr(a), addr(b)),
+            None => (addr(range), addr(range)),
+        };
+        match (start, end) {
+            (Ok(a), Ok(b)) if a <= b => Ok(Self { range: a..=b, read, write }),
+            _ => Err(format!("Unsupported watchpoint range: {}", range)),
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            _ => "w",
+        };
+        write!(f, "{:04x}-{:04x}:{}", self.range.start(), self.range.end(), mode)
+    }
+}
+
+// The access which hit a watchpoint, and the instruction which did it.
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub pc: u16,
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (op, dir) = if self.write { ("write", "to") } else { ("read", "from") };
+        write!(f, "{} {:02x} {} {:04x} at pc={:04x}", op, self.value, dir, self.addr, self.pc)
+    }
+}
+
+// The bus of one instruction, at pc.
+pub struct Watched<'a> {
+    mem: &'a mut dyn Memory,
+    points: &'a [Watchpoint],
+    pc: u16,
+    pub hit: Cell<Option<Hit>>,
+}
+
+impl<'a> Watched<'a> {
+    pub fn new(mem: &'a mut dyn Memory, points: &'a [Watchpoint], pc: u16) -> Self {
+        Self { mem, points, pc, hit: Cell::new(None) }
+    }
+
+    fn check(&self, addr: u16, value: u8, write: bool) {
+        if self.hit.get().is_some() {
+            return;
+        }
+        let watched = |w: &Watchpoint| w.range.contains(&addr) && if write { w.write } else { w.read };
+        if self.points.iter().any(watched) {
+            self.hit.set(Some(Hit { pc: self.pc, addr, value, write }));
+        }
+    }
+}
+
+impl<'a> Memory for Watched<'a> {
+    fn get(&self, a: u16) -> u8 {
+        let v = self.mem.get(a);
+        // The instruction itself is at most 3 bytes, a trace shows 4.
+        if a.wrapping_sub(self.pc) > 3 {
+            self.check(a, v, false);
+        }
+        v
+    }
+
+    fn set(&mut self, a: u16, v: u8) {
+        self.mem.set(a, v);
+        self.check(a, v, true);
+    }
+}
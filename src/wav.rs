@@ -0,0 +1,53 @@
+// Write the sound output to a WAV file: 16 bits PCM, stereo. The sizes in the header are only known at the end, they
+// are filled in by finish.
+use super::apu::SampleSink;
+use std::fs::File;
+use std::io::{BufWriter, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub struct WavWriter {
+    w: BufWriter<File>,
+    // Bytes of sample data written so far.
+    size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> Result<Self> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(b"RIFF")?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        // PCM, 2 channels, the sample rate, bytes per second, bytes per frame and bits per sample.
+        w.write_all(&1u16.to_le_bytes())?;
+        w.write_all(&2u16.to_le_bytes())?;
+        w.write_all(&sample_rate.to_le_bytes())?;
+        w.write_all(&(sample_rate * 4).to_le_bytes())?;
+        w.write_all(&4u16.to_le_bytes())?;
+        w.write_all(&16u16.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { w, size: 0 })
+    }
+}
+
+impl SampleSink for WavWriter {
+    fn push(&mut self, samples: &[(f32, f32)]) -> Result<()> {
+        for (l, r) in samples {
+            for v in [l, r] {
+                let v = (v.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+                self.w.write_all(&v.to_le_bytes())?;
+            }
+        }
+        self.size += samples.len() as u32 * 4;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.w.seek(SeekFrom::Start(4))?;
+        self.w.write_all(&(36 + self.size).to_le_bytes())?;
+        self.w.seek(SeekFrom::Start(40))?;
+        self.w.write_all(&self.size.to_le_bytes())?;
+        self.w.flush()
+    }
+}
@@ -0,0 +1,41 @@
+import init, { Emulator } from "./pkg/gameboy_web.js";
+
+await init();
+
+const ctx = document.getElementById("screen").getContext("2d");
+let emulator = null;
+
+document.getElementById("rom").addEventListener("change", async (e) => {
+  const file = e.target.files[0];
+  if (!file) {
+    return;
+  }
+  const first = emulator === null;
+  try {
+    emulator = new Emulator(new Uint8Array(await file.arrayBuffer()));
+  } catch (err) {
+    alert(err.message);
+    return;
+  }
+  if (first) {
+    requestAnimationFrame(loop);
+  }
+});
+
+function loop() {
+  const rgba = new Uint8ClampedArray(emulator.frame());
+  ctx.putImageData(new ImageData(rgba, emulator.width(), emulator.height()), 0, 0);
+  requestAnimationFrame(loop);
+}
+
+document.addEventListener("keydown", (e) => {
+  if (emulator !== null && emulator.key_down(e.code)) {
+    e.preventDefault();
+  }
+});
+
+document.addEventListener("keyup", (e) => {
+  if (emulator !== null && emulator.key_up(e.code)) {
+    e.preventDefault();
+  }
+});
@@ -0,0 +1,78 @@
+// Browser frontend. The page owns the timing: it calls frame() from requestAnimationFrame, draws the returned pixels
+// to a canvas and forwards the keyboard events. Build it with:
+//
+//   wasm-pack build web --target web
+use gameboy::cartridge;
+use gameboy::gpu::{SCREEN_H, SCREEN_W};
+use gameboy::joypad::JoypadKey;
+use wasm_bindgen::prelude::*;
+
+// Map KeyboardEvent.code to a button, with the same layout as the desktop frontend.
+fn joypad_key(code: &str) -> Option<JoypadKey> {
+    match code {
+        "ArrowRight" => Some(JoypadKey::Right),
+        "ArrowUp" => Some(JoypadKey::Up),
+        "ArrowLeft" => Some(JoypadKey::Left),
+        "ArrowDown" => Some(JoypadKey::Down),
+        "KeyZ" => Some(JoypadKey::A),
+        "KeyX" => Some(JoypadKey::B),
+        "Space" => Some(JoypadKey::Select),
+        "Enter" => Some(JoypadKey::Start),
+        _ => None,
+    }
+}
+
+#[wasm_bindgen]
+pub struct Emulator {
+    inner: gameboy::Emulator,
+    // The screen in RGBA, ready for an ImageData.
+    rgba: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: Vec<u8>) -> Result<Emulator, JsError> {
+        let mut inner = gameboy::Emulator::power_up_cartridge(cartridge::power_up_rom(rom)?);
+        // Nothing plays the samples yet.
+        inner.mbrd.mmu.apu.output = false;
+        Ok(Emulator { inner, rgba: vec![0xff; SCREEN_W * SCREEN_H * 4] })
+    }
+
+    pub fn width(&self) -> usize {
+        SCREEN_W
+    }
+
+    pub fn height(&self) -> usize {
+        SCREEN_H
+    }
+
+    // Run one frame and return the screen. This also advances while the LCD is off.
+    pub fn frame(&mut self) -> Vec<u8> {
+        for (i, p) in self.inner.run_frame().iter().flatten().enumerate() {
+            self.rgba[i * 4..i * 4 + 3].copy_from_slice(p);
+        }
+        self.rgba.clone()
+    }
+
+    // Returns whether the key is a button, so the page can prevent the default action, e.g. scrolling.
+    pub fn key_down(&mut self, code: &str) -> bool {
+        match joypad_key(code) {
+            Some(k) => {
+                self.inner.push_input(k, true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn key_up(&mut self, code: &str) -> bool {
+        match joypad_key(code) {
+            Some(k) => {
+                self.inner.push_input(k, false);
+                true
+            }
+            None => false,
+        }
+    }
+}